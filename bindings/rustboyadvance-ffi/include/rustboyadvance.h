@@ -0,0 +1,97 @@
+#ifndef RUSTBOYADVANCE_H
+#define RUSTBOYADVANCE_H
+
+/* Generated with cbindgen from src/lib.rs, do not edit by hand */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RBA_DISPLAY_WIDTH 240
+
+#define RBA_DISPLAY_HEIGHT 160
+
+// Sample rate of the audio, in stereo frames per second
+#define RBA_AUDIO_SAMPLE_RATE 44100
+
+#define RBA_KEY_A (1 << 0)
+
+#define RBA_KEY_B (1 << 1)
+
+#define RBA_KEY_SELECT (1 << 2)
+
+#define RBA_KEY_START (1 << 3)
+
+#define RBA_KEY_RIGHT (1 << 4)
+
+#define RBA_KEY_LEFT (1 << 5)
+
+#define RBA_KEY_UP (1 << 6)
+
+#define RBA_KEY_DOWN (1 << 7)
+
+#define RBA_KEY_R (1 << 8)
+
+#define RBA_KEY_L (1 << 9)
+
+typedef enum {
+  RBA_STATUS_OK = 0,
+  RBA_STATUS_NULL_POINTER = 1,
+  RBA_STATUS_INVALID_ROM = 2,
+  RBA_STATUS_INVALID_SAVE_STATE = 3,
+  RBA_STATUS_BUFFER_TOO_SMALL = 4,
+  // The emulator panicked, the handle should only be destroyed after this
+  RBA_STATUS_PANIC = 5,
+} RbaStatus;
+
+// An emulator instance, created with `rba_create` and freed with `rba_destroy`.
+// A handle can move between threads, but must not be used from two threads at once.
+typedef struct RbaEmulator RbaEmulator;
+
+// The message of the last error on this thread, valid until the next failing call on it
+const char *rba_last_error(void);
+
+// Creates an emulator running `rom`.
+// `bios` may be NULL, the bios is then emulated and the game starts right away.
+// Both buffers are copied, the save data is kept in memory only.
+RbaStatus rba_create(const uint8_t *bios,
+                     size_t bios_len,
+                     const uint8_t *rom,
+                     size_t rom_len,
+                     RbaEmulator **out);
+
+// Frees an emulator, NULL is ignored
+void rba_destroy(RbaEmulator *emu);
+
+// Runs the emulator until the next frame is drawn
+RbaStatus rba_run_frame(RbaEmulator *emu);
+
+// Copies the last frame, `RBA_DISPLAY_WIDTH * RBA_DISPLAY_HEIGHT` pixels in the 0x00RRGGBB format
+RbaStatus rba_get_frame_buffer(RbaEmulator *emu, uint32_t *out, size_t len);
+
+// Moves up to `len` of the buffered audio samples into `out`, interleaved left and right.
+// The number of samples moved is stored in `written`.
+RbaStatus rba_drain_audio(RbaEmulator *emu, int16_t *out, size_t len, size_t *written);
+
+// Sets the keys held from the next frame on, a mask of `RBA_KEY_*`
+RbaStatus rba_set_keys(RbaEmulator *emu, uint16_t pressed);
+
+// Saves the state into `out`, its size is stored in `size`.
+// When `out` is too small, the size needed is stored and `RBA_STATUS_BUFFER_TOO_SMALL` is returned.
+RbaStatus rba_save_state(RbaEmulator *emu, uint8_t *out, size_t len, size_t *size);
+
+// Restores a state saved with the same rom
+RbaStatus rba_load_state(RbaEmulator *emu, const uint8_t *state, size_t len);
+
+// Reads `len` bytes from the bus, starting at `addr`
+RbaStatus rba_read_memory(RbaEmulator *emu, uint32_t addr, uint8_t *out, size_t len);
+
+// Writes `len` bytes to the bus, starting at `addr`
+RbaStatus rba_write_memory(RbaEmulator *emu, uint32_t addr, const uint8_t *data, size_t len);
+
+// Copies the game title from the rom header, nul terminated
+RbaStatus rba_get_game_title(RbaEmulator *emu, char *out, size_t len);
+
+// Copies the game code from the rom header, nul terminated
+RbaStatus rba_get_game_code(RbaEmulator *emu, char *out, size_t len);
+
+#endif /* RUSTBOYADVANCE_H */
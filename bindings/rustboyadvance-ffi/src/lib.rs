@@ -0,0 +1,395 @@
+//! C bindings for rustboyadvance
+//!
+//! The emulator is behind an opaque `RbaEmulator` handle, every function reports failures with an
+//! `RbaStatus`, and panics are caught before they reach the caller.
+//! `include/rustboyadvance.h` is generated from this file with cbindgen, see `cbindgen.toml`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use rustboyadvance_core::core::keypad::KEYINPUT_ALL_RELEASED;
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::StereoSample;
+
+pub const RBA_DISPLAY_WIDTH: u32 = 240;
+pub const RBA_DISPLAY_HEIGHT: u32 = 160;
+/// Sample rate of the audio, in stereo frames per second
+pub const RBA_AUDIO_SAMPLE_RATE: u32 = 44100;
+
+pub const RBA_KEY_A: u16 = 1 << 0;
+pub const RBA_KEY_B: u16 = 1 << 1;
+pub const RBA_KEY_SELECT: u16 = 1 << 2;
+pub const RBA_KEY_START: u16 = 1 << 3;
+pub const RBA_KEY_RIGHT: u16 = 1 << 4;
+pub const RBA_KEY_LEFT: u16 = 1 << 5;
+pub const RBA_KEY_UP: u16 = 1 << 6;
+pub const RBA_KEY_DOWN: u16 = 1 << 7;
+pub const RBA_KEY_R: u16 = 1 << 8;
+pub const RBA_KEY_L: u16 = 1 << 9;
+
+/// Samples not drained are dropped past two seconds worth of audio
+const MAX_BUFFERED_SAMPLES: usize = 4 * RBA_AUDIO_SAMPLE_RATE as usize;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RbaStatus {
+    Ok = 0,
+    NullPointer = 1,
+    InvalidRom = 2,
+    InvalidSaveState = 3,
+    BufferTooSmall = 4,
+    /// The emulator panicked, the handle should only be destroyed after this
+    Panic = 5,
+}
+
+/// An emulator instance, created with `rba_create` and freed with `rba_destroy`.
+/// A handle can move between threads, but must not be used from two threads at once.
+pub struct RbaEmulator {
+    gba: GameBoyAdvance,
+    frontend: Arc<Mutex<Frontend>>,
+}
+
+struct Frontend {
+    audio_buffer: VecDeque<i16>,
+    keyinput: u16,
+}
+
+impl VideoInterface for Frontend {}
+
+impl AudioInterface for Frontend {
+    fn get_sample_rate(&self) -> i32 {
+        RBA_AUDIO_SAMPLE_RATE as i32
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        for &(left, right) in samples {
+            self.audio_buffer.push_back(left);
+            self.audio_buffer.push_back(right);
+        }
+        let excess = self.audio_buffer.len().saturating_sub(MAX_BUFFERED_SAMPLES);
+        self.audio_buffer.drain(..excess);
+    }
+}
+
+impl InputInterface for Frontend {
+    fn poll(&mut self) -> u16 {
+        self.keyinput
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn error(status: RbaStatus, message: String) -> RbaStatus {
+    // interior nul bytes can't be passed to C, and the message is only for humans anyway
+    let message = CString::new(message.replace('\0', "")).unwrap();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    status
+}
+
+/// Runs `f` with the panics turned into `RbaStatus::Panic`
+fn guard<F: FnOnce() -> Result<(), RbaStatus>>(f: F) -> RbaStatus {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => RbaStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(payload) => {
+            let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                message.to_string()
+            } else if let Some(message) = payload.downcast_ref::<String>() {
+                message.clone()
+            } else {
+                "unknown panic".to_string()
+            };
+            error(RbaStatus::Panic, format!("emulator panicked: {}", message))
+        }
+    }
+}
+
+unsafe fn emulator<'a>(emu: *mut RbaEmulator) -> Result<&'a mut RbaEmulator, RbaStatus> {
+    emu.as_mut()
+        .ok_or_else(|| error(RbaStatus::NullPointer, "null emulator".to_string()))
+}
+
+unsafe fn input<'a, T>(data: *const T, len: usize, what: &str) -> Result<&'a [T], RbaStatus> {
+    if data.is_null() {
+        Err(error(RbaStatus::NullPointer, format!("null {}", what)))
+    } else {
+        Ok(slice::from_raw_parts(data, len))
+    }
+}
+
+unsafe fn output<'a, T>(data: *mut T, len: usize, what: &str) -> Result<&'a mut [T], RbaStatus> {
+    if data.is_null() {
+        Err(error(RbaStatus::NullPointer, format!("null {}", what)))
+    } else {
+        Ok(slice::from_raw_parts_mut(data, len))
+    }
+}
+
+/// Copies `s` as a nul terminated string
+fn copy_string(s: &str, out: &mut [c_char]) -> Result<(), RbaStatus> {
+    let s = s.trim_end_matches('\0');
+    if s.len() >= out.len() {
+        return Err(error(
+            RbaStatus::BufferTooSmall,
+            format!("{} bytes are needed", s.len() + 1),
+        ));
+    }
+    for (dst, src) in out.iter_mut().zip(s.bytes()) {
+        *dst = src as c_char;
+    }
+    out[s.len()] = 0;
+    Ok(())
+}
+
+/// The message of the last error on this thread, valid until the next failing call on it
+#[no_mangle]
+pub extern "C" fn rba_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ptr())
+}
+
+/// Creates an emulator running `rom`.
+/// `bios` may be NULL, the bios is then emulated and the game starts right away.
+/// Both buffers are copied, the save data is kept in memory only.
+#[no_mangle]
+pub unsafe extern "C" fn rba_create(
+    bios: *const u8,
+    bios_len: usize,
+    rom: *const u8,
+    rom_len: usize,
+    out: *mut *mut RbaEmulator,
+) -> RbaStatus {
+    guard(|| {
+        let rom = input(rom, rom_len, "rom")?;
+        if out.is_null() {
+            return Err(error(RbaStatus::NullPointer, "null out".to_string()));
+        }
+        // the header alone takes this much
+        if rom.len() < 0xc0 {
+            return Err(error(RbaStatus::InvalidRom, "rom is too small".to_string()));
+        }
+        let gamepak = GamepakBuilder::new()
+            .buffer(rom)
+            .without_backup_to_file()
+            .build()
+            .map_err(|e| error(RbaStatus::InvalidRom, format!("{:?}", e)))?;
+
+        let frontend = Arc::new(Mutex::new(Frontend {
+            audio_buffer: VecDeque::new(),
+            keyinput: KEYINPUT_ALL_RELEASED,
+        }));
+        let gba = if bios.is_null() {
+            GameBoyAdvance::new_with_hle_bios(
+                gamepak,
+                frontend.clone(),
+                frontend.clone(),
+                frontend.clone(),
+            )
+        } else {
+            let bios = input(bios, bios_len, "bios")?;
+            GameBoyAdvance::new(
+                bios.to_vec().into_boxed_slice(),
+                gamepak,
+                frontend.clone(),
+                frontend.clone(),
+                frontend.clone(),
+            )
+        };
+
+        *out = Box::into_raw(Box::new(RbaEmulator {
+            gba: gba,
+            frontend: frontend,
+        }));
+        Ok(())
+    })
+}
+
+/// Frees an emulator, NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn rba_destroy(emu: *mut RbaEmulator) {
+    if !emu.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(emu))));
+    }
+}
+
+/// Runs the emulator until the next frame is drawn
+#[no_mangle]
+pub unsafe extern "C" fn rba_run_frame(emu: *mut RbaEmulator) -> RbaStatus {
+    guard(|| {
+        emulator(emu)?.gba.frame();
+        Ok(())
+    })
+}
+
+/// Copies the last frame, `RBA_DISPLAY_WIDTH * RBA_DISPLAY_HEIGHT` pixels in the 0x00RRGGBB format
+#[no_mangle]
+pub unsafe extern "C" fn rba_get_frame_buffer(
+    emu: *mut RbaEmulator,
+    out: *mut u32,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let out = output(out, len, "frame buffer")?;
+        let frame_buffer = emu.gba.get_frame_buffer();
+        if out.len() < frame_buffer.len() {
+            return Err(error(
+                RbaStatus::BufferTooSmall,
+                format!("{} pixels are needed", frame_buffer.len()),
+            ));
+        }
+        out[..frame_buffer.len()].copy_from_slice(frame_buffer);
+        Ok(())
+    })
+}
+
+/// Moves up to `len` of the buffered audio samples into `out`, interleaved left and right.
+/// The number of samples moved is stored in `written`.
+#[no_mangle]
+pub unsafe extern "C" fn rba_drain_audio(
+    emu: *mut RbaEmulator,
+    out: *mut i16,
+    len: usize,
+    written: *mut usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let out = output(out, len, "audio buffer")?;
+        let written = written
+            .as_mut()
+            .ok_or_else(|| error(RbaStatus::NullPointer, "null written".to_string()))?;
+        let mut frontend = emu.frontend.lock().unwrap();
+        // whole stereo frames only
+        let count = frontend.audio_buffer.len().min(out.len() & !1);
+        for (dst, src) in out.iter_mut().zip(frontend.audio_buffer.drain(..count)) {
+            *dst = src;
+        }
+        *written = count;
+        Ok(())
+    })
+}
+
+/// Sets the keys held from the next frame on, a mask of `RBA_KEY_*`
+#[no_mangle]
+pub unsafe extern "C" fn rba_set_keys(emu: *mut RbaEmulator, pressed: u16) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        emu.frontend.lock().unwrap().keyinput = KEYINPUT_ALL_RELEASED & !pressed;
+        Ok(())
+    })
+}
+
+/// Saves the state into `out`, its size is stored in `size`.
+/// When `out` is too small, the size needed is stored and `RBA_STATUS_BUFFER_TOO_SMALL` is returned.
+#[no_mangle]
+pub unsafe extern "C" fn rba_save_state(
+    emu: *mut RbaEmulator,
+    out: *mut u8,
+    len: usize,
+    size: *mut usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let size = size
+            .as_mut()
+            .ok_or_else(|| error(RbaStatus::NullPointer, "null size".to_string()))?;
+        let state = emu
+            .gba
+            .save_state()
+            .map_err(|e| error(RbaStatus::InvalidSaveState, format!("{:?}", e)))?;
+        *size = state.len();
+        if out.is_null() || len < state.len() {
+            return Err(error(
+                RbaStatus::BufferTooSmall,
+                format!("{} bytes are needed", state.len()),
+            ));
+        }
+        slice::from_raw_parts_mut(out, state.len()).copy_from_slice(&state);
+        Ok(())
+    })
+}
+
+/// Restores a state saved with the same rom
+#[no_mangle]
+pub unsafe extern "C" fn rba_load_state(
+    emu: *mut RbaEmulator,
+    state: *const u8,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let state = input(state, len, "state")?;
+        emu.gba
+            .restore_state(state)
+            .map_err(|e| error(RbaStatus::InvalidSaveState, format!("{:?}", e)))
+    })
+}
+
+/// Reads `len` bytes from the bus, starting at `addr`
+#[no_mangle]
+pub unsafe extern "C" fn rba_read_memory(
+    emu: *mut RbaEmulator,
+    addr: u32,
+    out: *mut u8,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let out = output(out, len, "buffer")?;
+        for (i, b) in out.iter_mut().enumerate() {
+            *b = emu.gba.sysbus.read_8(addr.wrapping_add(i as u32));
+        }
+        Ok(())
+    })
+}
+
+/// Writes `len` bytes to the bus, starting at `addr`
+#[no_mangle]
+pub unsafe extern "C" fn rba_write_memory(
+    emu: *mut RbaEmulator,
+    addr: u32,
+    data: *const u8,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        let data = input(data, len, "data")?;
+        for (i, b) in data.iter().enumerate() {
+            emu.gba.sysbus.write_8(addr.wrapping_add(i as u32), *b);
+        }
+        Ok(())
+    })
+}
+
+/// Copies the game title from the rom header, nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn rba_get_game_title(
+    emu: *mut RbaEmulator,
+    out: *mut c_char,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        copy_string(&emu.gba.get_game_title(), output(out, len, "buffer")?)
+    })
+}
+
+/// Copies the game code from the rom header, nul terminated
+#[no_mangle]
+pub unsafe extern "C" fn rba_get_game_code(
+    emu: *mut RbaEmulator,
+    out: *mut c_char,
+    len: usize,
+) -> RbaStatus {
+    guard(|| {
+        let emu = emulator(emu)?;
+        copy_string(&emu.gba.get_game_code(), output(out, len, "buffer")?)
+    })
+}
@@ -0,0 +1,97 @@
+/* Exercises the C API the way an embedder would, returns non zero on the first failed check */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "rustboyadvance.h"
+
+#define CHECK(cond)                                                        \
+    do {                                                                   \
+        if (!(cond)) {                                                     \
+            fprintf(stderr, "%s:%d: check failed: %s (last error: %s)\n", \
+                    __FILE__, __LINE__, #cond, rba_last_error());          \
+            return 1;                                                      \
+        }                                                                  \
+    } while (0)
+
+#define ROM_SIZE 0x400
+#define EWRAM 0x02000000
+
+/* A rom that spins forever, the bios is emulated so it starts right away */
+static void make_rom(uint8_t *rom)
+{
+    memset(rom, 0, ROM_SIZE);
+    /* b . */
+    rom[0] = 0xfe;
+    rom[1] = 0xff;
+    rom[2] = 0xff;
+    rom[3] = 0xea;
+    memcpy(&rom[0xa0], "CTEST", 5);
+    memcpy(&rom[0xac], "CTST", 4);
+}
+
+int main(void)
+{
+    static uint8_t rom[ROM_SIZE];
+    static uint32_t frame_buffer[RBA_DISPLAY_WIDTH * RBA_DISPLAY_HEIGHT];
+    static int16_t audio[RBA_AUDIO_SAMPLE_RATE * 2];
+    RbaEmulator *emu = NULL;
+    char title[16];
+    char code[2];
+    uint8_t value;
+    uint8_t *state;
+    size_t state_size = 0;
+    size_t written = 0;
+    size_t total_samples = 0;
+    int i;
+
+    make_rom(rom);
+    CHECK(rba_create(NULL, 0, NULL, 0, &emu) == RBA_STATUS_NULL_POINTER);
+    CHECK(rba_create(NULL, 0, rom, 16, &emu) == RBA_STATUS_INVALID_ROM);
+    CHECK(rba_create(NULL, 0, rom, ROM_SIZE, &emu) == RBA_STATUS_OK);
+    CHECK(emu != NULL);
+    CHECK(rba_run_frame(NULL) == RBA_STATUS_NULL_POINTER);
+
+    CHECK(rba_get_game_title(emu, title, sizeof(title)) == RBA_STATUS_OK);
+    CHECK(strcmp(title, "CTEST") == 0);
+    CHECK(rba_get_game_code(emu, code, sizeof(code)) == RBA_STATUS_BUFFER_TOO_SMALL);
+
+    CHECK(rba_set_keys(emu, RBA_KEY_A | RBA_KEY_START) == RBA_STATUS_OK);
+    for (i = 0; i < 60; i++) {
+        CHECK(rba_run_frame(emu) == RBA_STATUS_OK);
+        CHECK(rba_drain_audio(emu, audio, sizeof(audio) / sizeof(audio[0]), &written) ==
+              RBA_STATUS_OK);
+        total_samples += written;
+    }
+    /* about a second of stereo audio */
+    CHECK(total_samples > 88000 && total_samples < 89000);
+    CHECK(rba_get_frame_buffer(emu, frame_buffer, 16) == RBA_STATUS_BUFFER_TOO_SMALL);
+    CHECK(rba_get_frame_buffer(emu, frame_buffer, RBA_DISPLAY_WIDTH * RBA_DISPLAY_HEIGHT) ==
+          RBA_STATUS_OK);
+
+    /* KEYINPUT is active low */
+    CHECK(rba_read_memory(emu, 0x04000130, &value, 1) == RBA_STATUS_OK);
+    CHECK(value == (uint8_t)~(RBA_KEY_A | RBA_KEY_START));
+
+    value = 0x42;
+    CHECK(rba_write_memory(emu, EWRAM, &value, 1) == RBA_STATUS_OK);
+    CHECK(rba_save_state(emu, NULL, 0, &state_size) == RBA_STATUS_BUFFER_TOO_SMALL);
+    CHECK(state_size > 0);
+    state = malloc(state_size);
+    CHECK(rba_save_state(emu, state, state_size, &state_size) == RBA_STATUS_OK);
+
+    value = 0x24;
+    CHECK(rba_write_memory(emu, EWRAM, &value, 1) == RBA_STATUS_OK);
+    CHECK(rba_load_state(emu, state, state_size) == RBA_STATUS_OK);
+    CHECK(rba_read_memory(emu, EWRAM, &value, 1) == RBA_STATUS_OK);
+    CHECK(value == 0x42);
+
+    CHECK(rba_load_state(emu, rom, ROM_SIZE) == RBA_STATUS_INVALID_SAVE_STATE);
+    CHECK(strlen(rba_last_error()) > 0);
+
+    free(state);
+    rba_destroy(emu);
+    rba_destroy(NULL);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,66 @@
+//! Builds `tests/c_api.c` against the static library and the header, then runs it.
+//! Skipped when no C compiler is around, `CC` picks another one than `cc`,
+//! or when the static library can't be found next to the test.
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Looks for the static library in target/<profile>/deps, where the test is, and in target/<profile>
+fn find_static_library(deps_dir: &Path) -> Option<PathBuf> {
+    let dirs = [Some(deps_dir), deps_dir.parent()];
+    dirs.iter().flatten().find_map(|dir| {
+        let mut candidates: Vec<PathBuf> = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().unwrap().to_string_lossy();
+                name.starts_with("librustboyadvance_ffi") && name.ends_with(".a")
+            })
+            .collect();
+        // the most recent build, if there are a few with different hashes
+        candidates.sort_by_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok());
+        candidates.pop()
+    })
+}
+
+#[test]
+fn test_c_api() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let deps_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let library = match find_static_library(&deps_dir) {
+        Some(library) => library,
+        None => {
+            eprintln!(
+                "skipping, librustboyadvance_ffi.a not found in or above {}",
+                deps_dir.display()
+            );
+            return;
+        }
+    };
+    let program = deps_dir.join("c_api_test");
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&compiler)
+        .arg(manifest_dir.join("tests/c_api.c"))
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(&library)
+        .args(&["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status();
+    match status {
+        Ok(status) => assert!(status.success(), "failed to build the C test program"),
+        Err(e) => {
+            eprintln!("skipping, could not run {}: {}", compiler, e);
+            return;
+        }
+    }
+
+    let output = Command::new(&program).output().unwrap();
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+}
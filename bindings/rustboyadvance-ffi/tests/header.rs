@@ -0,0 +1,27 @@
+//! Checks that `include/rustboyadvance.h` is what cbindgen makes of `src/lib.rs`.
+//! Run with `RBA_BLESS=1` to regenerate the header after changing the API.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn test_header_is_up_to_date() {
+    let crate_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let header_path = crate_dir.join("include/rustboyadvance.h");
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::generate_with_config(&crate_dir, config)
+        .expect("cbindgen failed")
+        .write(&mut generated);
+
+    if env::var_os("RBA_BLESS").is_some() {
+        fs::write(&header_path, &generated).unwrap();
+        return;
+    }
+    let header = fs::read(&header_path).unwrap();
+    assert!(
+        header == generated,
+        "{} is out of date, run the tests with RBA_BLESS=1 to regenerate it",
+        header_path.display()
+    );
+}
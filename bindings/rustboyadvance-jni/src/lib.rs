@@ -2,11 +2,9 @@
 ///
 mod rom_helper;
 
-use std::cell::RefCell;
 use std::os::raw::c_void;
 use std::path::Path;
-use std::rc::Rc;
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use jni::objects::*;
 use jni::sys::*;
@@ -61,8 +59,17 @@ impl VideoInterface for Hardware {
 }
 impl AudioInterface for Hardware {
     fn push_sample(&mut self, sample: StereoSample<i16>) {
-        self.audio_buffer.prod.push(sample.0).unwrap();
-        self.audio_buffer.prod.push(sample.1).unwrap();
+        self.push_samples(&[sample]);
+    }
+
+    /// Samples that don't fit are dropped when the audio thread falls behind
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        let prod = &mut self.audio_buffer.prod;
+        let fitting = std::cmp::min(samples.len(), prod.remaining() / 2);
+        for &(left, right) in &samples[..fitting] {
+            let _ = prod.push(left);
+            let _ = prod.push(right);
+        }
     }
 }
 impl InputInterface for Hardware {
@@ -72,7 +79,7 @@ impl InputInterface for Hardware {
 }
 
 struct Context {
-    hwif: Rc<RefCell<Hardware>>,
+    hwif: Arc<Mutex<Hardware>>,
     gba: GameBoyAdvance,
 }
 
@@ -120,7 +127,7 @@ unsafe fn internal_open_context(
         audio_buffer: AudioRingBuffer::new(),
         key_state: 0xffff,
     };
-    let hw = Rc::new(RefCell::new(hw));
+    let hw = Arc::new(Mutex::new(hw));
 
     let mut gba = GameBoyAdvance::new(bios, gamepak, hw.clone(), hw.clone(), hw.clone());
 
@@ -203,12 +210,27 @@ pub mod bindings {
 
     fn internal_open_saved_state(
         env: &JNIEnv,
+        bios: jbyteArray,
+        rom: jbyteArray,
         state: jbyteArray,
         frame_buffer: jintArray,
+        save_file: JString,
     ) -> Result<Context, String> {
+        let bios = env
+            .convert_byte_array(bios)
+            .map_err(|e| format!("could not get bios buffer, error {}", e))?
+            .into_boxed_slice();
+        let rom = env
+            .convert_byte_array(rom)
+            .map_err(|e| format!("could not get rom buffer, error {}", e))?
+            .into_boxed_slice();
         let state = env
             .convert_byte_array(state)
             .map_err(|e| format!("could not get state buffer, error {}", e))?;
+        let save_file: String = env
+            .get_string(save_file)
+            .map_err(|_| String::from("could not get save path"))?
+            .into();
 
         let frame_buffer_global_ref = env
             .new_global_ref(JObject::from(frame_buffer))
@@ -220,15 +242,23 @@ pub mod bindings {
             audio_buffer: AudioRingBuffer::new(),
             key_state: 0xffff,
         };
-        let hw = Rc::new(RefCell::new(hw));
-
-        let gba = GameBoyAdvance::from_saved_state(&state, hw.clone(), hw.clone(), hw.clone())
-            .map_err(|e| {
-                format!(
-                    "failed to create GameBoyAdvance from saved state, error {:?}",
-                    e
-                )
-            })?;
+        let hw = Arc::new(Mutex::new(hw));
+
+        let gba = GameBoyAdvance::from_saved_state(
+            &state,
+            bios,
+            rom,
+            Some(Path::new(&save_file)),
+            hw.clone(),
+            hw.clone(),
+            hw.clone(),
+        )
+        .map_err(|e| {
+            format!(
+                "failed to create GameBoyAdvance from saved state, error {:?}",
+                e
+            )
+        })?;
 
         Ok(Context {
             gba: gba,
@@ -240,10 +270,13 @@ pub mod bindings {
     pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_openSavedState(
         env: JNIEnv,
         _obj: JClass,
+        bios: jbyteArray,
+        rom: jbyteArray,
         state: jbyteArray,
         frame_buffer: jintArray,
+        save_file: JString,
     ) -> jlong {
-        match internal_open_saved_state(&env, state, frame_buffer) {
+        match internal_open_saved_state(&env, bios, rom, state, frame_buffer, save_file) {
             Ok(ctx) => Box::into_raw(Box::new(Mutex::new(ctx))) as jlong,
             Err(msg) => {
                 env.throw_new(NATIVE_EXCEPTION_CLASS, msg).unwrap();
@@ -283,7 +316,7 @@ pub mod bindings {
     ) -> jshortArray {
         let ctx = lock_ctx(ctx);
 
-        let mut hw = ctx.hwif.borrow_mut();
+        let mut hw = ctx.hwif.lock().unwrap();
 
         let mut samples = Vec::with_capacity(1024);
 
@@ -305,7 +338,7 @@ pub mod bindings {
         key_state: jint,
     ) {
         let mut ctx = lock_ctx(ctx);
-        ctx.hwif.borrow_mut().key_state = key_state as u16;
+        ctx.hwif.lock().unwrap().key_state = key_state as u16;
     }
 
     #[no_mangle]
@@ -340,6 +373,39 @@ pub mod bindings {
         }
     }
 
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_enableRewind(
+        _env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+        interval: jint,
+        budget: jint,
+    ) {
+        let mut ctx = lock_ctx(ctx);
+        ctx.gba.enable_rewind(interval as usize, budget as usize);
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_rewind(
+        env: JNIEnv,
+        _obj: JClass,
+        ctx: jlong,
+        frames: jint,
+    ) -> jint {
+        let mut ctx = lock_ctx(ctx);
+        match ctx.gba.rewind(frames as usize) {
+            Ok(rewound) => rewound as jint,
+            Err(e) => {
+                env.throw_new(
+                    NATIVE_EXCEPTION_CLASS,
+                    format!("failed to rewind, error: {:?}", e),
+                )
+                .unwrap();
+                0
+            }
+        }
+    }
+
     #[no_mangle]
     pub unsafe extern "C" fn Java_com_mrmichel_rustboyadvance_EmulatorBindings_getGameTitle(
         env: JNIEnv,
@@ -0,0 +1,620 @@
+//! libretro core for rustboyadvance
+//!
+//! The frontend calls into the core from a single thread, so the state is kept in thread locals.
+//! The exported functions take the pointers described in `libretro.h`.
+#![allow(clippy::missing_safety_doc)]
+
+mod libretro;
+
+use std::cell::{Cell, RefCell};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+#[macro_use]
+extern crate log;
+
+use rustboyadvance_core::core::gpu::CYCLES_FULL_REFRESH;
+use rustboyadvance_core::core::keypad::{KEYINPUT_ALL_RELEASED, NUM_KEYS};
+use rustboyadvance_core::core::sched::CLOCK_FREQ;
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::StereoSample;
+
+use libretro::*;
+
+const SAMPLE_RATE: i32 = 44100;
+
+/// Savestates are stored behind their length, as the buffers are bigger than them
+const STATE_LENGTH_SIZE: usize = 4;
+
+const BIOS_FILE_NAME: &str = "gba_bios.bin";
+
+#[derive(Default, Clone, Copy)]
+struct Callbacks {
+    environment: Option<retro_environment_t>,
+    video_refresh: Option<retro_video_refresh_t>,
+    audio_sample_batch: Option<retro_audio_sample_batch_t>,
+    input_poll: Option<retro_input_poll_t>,
+    input_state: Option<retro_input_state_t>,
+}
+
+/// The libretro callbacks seen as the devices of the emulator
+struct Frontend {
+    audio_buffer: Vec<i16>,
+}
+
+impl VideoInterface for Frontend {}
+
+impl AudioInterface for Frontend {
+    fn get_sample_rate(&self) -> i32 {
+        SAMPLE_RATE
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        for &(left, right) in samples {
+            self.audio_buffer.push(left);
+            self.audio_buffer.push(right);
+        }
+    }
+}
+
+/// The joypad buttons, in the order of the KEYINPUT bits
+const KEY_MAP: [c_uint; NUM_KEYS] = [
+    RETRO_DEVICE_ID_JOYPAD_A,
+    RETRO_DEVICE_ID_JOYPAD_B,
+    RETRO_DEVICE_ID_JOYPAD_SELECT,
+    RETRO_DEVICE_ID_JOYPAD_START,
+    RETRO_DEVICE_ID_JOYPAD_RIGHT,
+    RETRO_DEVICE_ID_JOYPAD_LEFT,
+    RETRO_DEVICE_ID_JOYPAD_UP,
+    RETRO_DEVICE_ID_JOYPAD_DOWN,
+    RETRO_DEVICE_ID_JOYPAD_R,
+    RETRO_DEVICE_ID_JOYPAD_L,
+];
+
+impl InputInterface for Frontend {
+    fn poll(&mut self) -> u16 {
+        let callbacks = callbacks();
+        if let Some(input_poll) = callbacks.input_poll {
+            input_poll();
+        }
+        let mut keyinput = KEYINPUT_ALL_RELEASED;
+        if let Some(input_state) = callbacks.input_state {
+            for (bit, id) in KEY_MAP.iter().enumerate() {
+                if input_state(0, RETRO_DEVICE_JOYPAD, 0, *id) != 0 {
+                    keyinput &= !(1 << bit);
+                }
+            }
+        }
+        keyinput
+    }
+}
+
+struct Core {
+    gba: GameBoyAdvance,
+    frontend: Arc<Mutex<Frontend>>,
+    state_size: usize,
+    /// The save data handed to the frontend, which loads and stores it behind our back.
+    /// It stays put for the whole run, unlike the backup memory of the cartridge.
+    save_ram: Vec<u8>,
+    /// Whether the cartridge took the save data the frontend loaded before the first frame
+    save_ram_loaded: bool,
+}
+
+impl Core {
+    fn load_save_ram(&mut self) {
+        if !self.save_ram_loaded {
+            self.gba.sysbus.cartridge.load_backup(&self.save_ram);
+            self.save_ram_loaded = true;
+        }
+    }
+
+    fn store_save_ram(&mut self) {
+        self.gba.sysbus.cartridge.store_backup(&mut self.save_ram);
+    }
+}
+
+thread_local! {
+    static CALLBACKS: Cell<Callbacks> = Cell::new(Callbacks::default());
+    static CORE: RefCell<Option<Core>> = RefCell::new(None);
+}
+
+fn callbacks() -> Callbacks {
+    CALLBACKS.with(|callbacks| callbacks.get())
+}
+
+fn set_callbacks<F: FnOnce(&mut Callbacks)>(f: F) {
+    CALLBACKS.with(|callbacks| {
+        let mut updated = callbacks.get();
+        f(&mut updated);
+        callbacks.set(updated);
+    })
+}
+
+/// Runs `f` on the loaded game, if there is one
+fn with_core<T, F: FnOnce(&mut Core) -> T>(f: F) -> Option<T> {
+    CORE.with(|core| core.borrow_mut().as_mut().map(f))
+}
+
+fn set_core(core: Option<Core>) {
+    CORE.with(|current| *current.borrow_mut() = core);
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => environment(cmd, data),
+        None => false,
+    }
+}
+
+unsafe fn load_bios() -> Option<Vec<u8>> {
+    let mut system_dir: *const c_char = ptr::null();
+    if !environment(
+        RETRO_ENVIRONMENT_GET_SYSTEM_DIRECTORY,
+        &mut system_dir as *mut *const c_char as *mut c_void,
+    ) || system_dir.is_null()
+    {
+        return None;
+    }
+    let system_dir = CStr::from_ptr(system_dir).to_string_lossy().into_owned();
+    let bios_path = Path::new(&system_dir).join(BIOS_FILE_NAME);
+    read_bin_file(&bios_path).ok()
+}
+
+unsafe fn load_game(game: &retro_game_info) -> Result<Core, String> {
+    let mut pixel_format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(
+        RETRO_ENVIRONMENT_SET_PIXEL_FORMAT,
+        &mut pixel_format as *mut c_uint as *mut c_void,
+    ) {
+        return Err("the frontend doesn't support XRGB8888".to_string());
+    }
+
+    let rom = if !game.data.is_null() {
+        slice::from_raw_parts(game.data as *const u8, game.size).to_vec()
+    } else if !game.path.is_null() {
+        let path = CStr::from_ptr(game.path).to_string_lossy().into_owned();
+        read_bin_file(Path::new(&path)).map_err(|e| format!("cannot read {}: {}", path, e))?
+    } else {
+        return Err("no game data".to_string());
+    };
+
+    // the frontend keeps the save data, through `retro_get_memory_data`
+    let gamepak = GamepakBuilder::new()
+        .take_buffer(rom.into_boxed_slice())
+        .without_backup_to_file()
+        .build()
+        .map_err(|e| format!("failed to load rom: {:?}", e))?;
+    info!("Loaded ROM file {:?}", gamepak.header);
+
+    let frontend = Arc::new(Mutex::new(Frontend {
+        audio_buffer: Vec::new(),
+    }));
+    let gba = match load_bios() {
+        Some(bios) => GameBoyAdvance::new(
+            bios.into_boxed_slice(),
+            gamepak,
+            frontend.clone(),
+            frontend.clone(),
+            frontend.clone(),
+        ),
+        None => {
+            info!("{} not found, the bios will be emulated", BIOS_FILE_NAME);
+            GameBoyAdvance::new_with_hle_bios(
+                gamepak,
+                frontend.clone(),
+                frontend.clone(),
+                frontend.clone(),
+            )
+        }
+    };
+    let state_size = STATE_LENGTH_SIZE + gba.save_state_size_bound();
+    let mut save_ram = vec![0xff; gba.sysbus.cartridge.backup_size_bound()];
+    gba.sysbus.cartridge.store_backup(&mut save_ram);
+
+    Ok(Core {
+        gba: gba,
+        frontend: frontend,
+        state_size: state_size,
+        save_ram: save_ram,
+        save_ram_loaded: false,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: retro_environment_t) {
+    set_callbacks(|callbacks| callbacks.environment = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: retro_video_refresh_t) {
+    set_callbacks(|callbacks| callbacks.video_refresh = Some(callback));
+}
+
+/// Unused, the samples are sent in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: retro_audio_sample_t) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: retro_audio_sample_batch_t) {
+    set_callbacks(|callbacks| callbacks.audio_sample_batch = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: retro_input_poll_t) {
+    set_callbacks(|callbacks| callbacks.input_poll = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: retro_input_state_t) {
+    set_callbacks(|callbacks| callbacks.input_state = Some(callback));
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    set_core(None);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+    *info = retro_system_info {
+        library_name: b"RustBoyAdvance\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"gba|bin\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+    *info = retro_system_av_info {
+        geometry: retro_game_geometry {
+            base_width: DISPLAY_WIDTH as c_uint,
+            base_height: DISPLAY_HEIGHT as c_uint,
+            max_width: DISPLAY_WIDTH as c_uint,
+            max_height: DISPLAY_HEIGHT as c_uint,
+            aspect_ratio: DISPLAY_WIDTH as f32 / DISPLAY_HEIGHT as f32,
+        },
+        timing: retro_system_timing {
+            fps: CLOCK_FREQ as f64 / CYCLES_FULL_REFRESH as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    with_core(|core| core.gba.reset());
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    with_core(|core| {
+        core.load_save_ram();
+        core.gba.frame();
+        core.store_save_ram();
+        let callbacks = callbacks();
+
+        if let Some(video_refresh) = callbacks.video_refresh {
+            let frame_buffer = core.gba.get_frame_buffer();
+            video_refresh(
+                frame_buffer.as_ptr() as *const c_void,
+                DISPLAY_WIDTH as c_uint,
+                DISPLAY_HEIGHT as c_uint,
+                DISPLAY_WIDTH * 4,
+            );
+        }
+
+        let mut frontend = core.frontend.lock().unwrap();
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            let mut samples = &frontend.audio_buffer[..];
+            while !samples.is_empty() {
+                let frames = audio_sample_batch(samples.as_ptr(), samples.len() / 2);
+                if frames == 0 {
+                    break;
+                }
+                samples = &samples[frames * 2..];
+            }
+        }
+        frontend.audio_buffer.clear();
+    });
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    with_core(|core| core.state_size).unwrap_or(0)
+}
+
+fn serialize(core: &mut Core, buffer: &mut [u8]) -> bool {
+    core.load_save_ram();
+    // the same machine state always gives the same bytes, frontends compare them for netplay
+    let state = match core.gba.save_state_untimed() {
+        Ok(state) => state,
+        Err(e) => {
+            error!("failed to save state: {:?}", e);
+            return false;
+        }
+    };
+    if STATE_LENGTH_SIZE + state.len() > buffer.len() {
+        error!("savestate doesn't fit in {} bytes", buffer.len());
+        return false;
+    }
+    let (length, rest) = buffer.split_at_mut(STATE_LENGTH_SIZE);
+    length.copy_from_slice(&(state.len() as u32).to_le_bytes());
+    rest[..state.len()].copy_from_slice(&state);
+    for b in &mut rest[state.len()..] {
+        *b = 0;
+    }
+    true
+}
+
+fn unserialize(core: &mut Core, buffer: &[u8]) -> bool {
+    if buffer.len() < STATE_LENGTH_SIZE {
+        return false;
+    }
+    let mut length = [0; STATE_LENGTH_SIZE];
+    length.copy_from_slice(&buffer[..STATE_LENGTH_SIZE]);
+    let length = u32::from_le_bytes(length) as usize;
+    if STATE_LENGTH_SIZE + length > buffer.len() {
+        return false;
+    }
+    match core
+        .gba
+        .restore_state(&buffer[STATE_LENGTH_SIZE..STATE_LENGTH_SIZE + length])
+    {
+        Ok(_) => {
+            // the save data of the state wins over what the frontend loaded
+            core.save_ram_loaded = true;
+            core.store_save_ram();
+            true
+        }
+        Err(e) => {
+            error!("failed to restore state: {:?}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let buffer = slice::from_raw_parts_mut(data as *mut u8, size);
+    with_core(|core| serialize(core, buffer)).unwrap_or(false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() {
+        return false;
+    }
+    let buffer = slice::from_raw_parts(data as *const u8, size);
+    with_core(|core| unserialize(core, buffer)).unwrap_or(false)
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+    if game.is_null() {
+        return false;
+    }
+    match load_game(&*game) {
+        Ok(core) => {
+            set_core(Some(core));
+            true
+        }
+        Err(e) => {
+            error!("{}", e);
+            false
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(
+    _game_type: c_uint,
+    _info: *const retro_game_info,
+    _num_info: usize,
+) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    set_core(None);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// Only the save data is exposed, the frontend loads and stores it in its own save files.
+/// The frontend writes it before the first frame, and reads it once a frame is done.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match id {
+        RETRO_MEMORY_SAVE_RAM => with_core(|core| match core.save_ram.len() {
+            0 => ptr::null_mut(),
+            _ => core.save_ram.as_mut_ptr() as *mut c_void,
+        })
+        .unwrap_or(ptr::null_mut()),
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match id {
+        RETRO_MEMORY_SAVE_RAM => with_core(|core| core.save_ram.len()).unwrap_or(0),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static PIXEL_FORMAT_SET: AtomicBool = AtomicBool::new(false);
+    static VIDEO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static INPUT_POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn environment_cb(cmd: c_uint, data: *mut c_void) -> bool {
+        match cmd {
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                let format = unsafe { *(data as *const c_uint) };
+                PIXEL_FORMAT_SET.store(format == RETRO_PIXEL_FORMAT_XRGB8888, Ordering::SeqCst);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    extern "C" fn video_refresh_cb(
+        data: *const c_void,
+        width: c_uint,
+        height: c_uint,
+        pitch: usize,
+    ) {
+        assert!(!data.is_null());
+        assert_eq!((width, height, pitch), (240, 160, 960));
+        VIDEO_FRAMES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn audio_sample_batch_cb(_data: *const i16, frames: usize) -> usize {
+        AUDIO_FRAMES.fetch_add(frames, Ordering::SeqCst);
+        frames
+    }
+
+    extern "C" fn input_poll_cb() {
+        INPUT_POLLS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn input_state_cb(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        (port == 0 && device == RETRO_DEVICE_JOYPAD && id == RETRO_DEVICE_ID_JOYPAD_START) as i16
+    }
+
+    /// A ROM that spins forever, with an SRAM id string
+    fn make_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x400];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        rom[0x200..0x209].copy_from_slice(b"SRAM_V113");
+        rom
+    }
+
+    #[test]
+    fn test_frontend_shim() {
+        unsafe {
+            assert_eq!(retro_api_version(), RETRO_API_VERSION);
+            retro_set_environment(environment_cb);
+            retro_set_video_refresh(video_refresh_cb);
+            retro_set_audio_sample_batch(audio_sample_batch_cb);
+            retro_set_input_poll(input_poll_cb);
+            retro_set_input_state(input_state_cb);
+            retro_init();
+
+            let rom = make_rom();
+            let game = retro_game_info {
+                path: ptr::null(),
+                data: rom.as_ptr() as *const c_void,
+                size: rom.len(),
+                meta: ptr::null(),
+            };
+            assert!(retro_load_game(&game));
+            assert!(PIXEL_FORMAT_SET.load(Ordering::SeqCst));
+
+            let mut av_info = std::mem::zeroed::<retro_system_av_info>();
+            retro_get_system_av_info(&mut av_info);
+            assert!((av_info.timing.fps - 59.7275).abs() < 0.001);
+
+            // the frontend loads the save file before the first frame
+            assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0x8000);
+            let sram = retro_get_memory_data(RETRO_MEMORY_SAVE_RAM) as *mut u8;
+            *sram = 0x42;
+
+            for _ in 0..60 {
+                retro_run();
+            }
+            assert_eq!(VIDEO_FRAMES.load(Ordering::SeqCst), 60);
+            assert_eq!(INPUT_POLLS.load(Ordering::SeqCst), 60);
+            // about a second worth of audio
+            let audio_frames = AUDIO_FRAMES.load(Ordering::SeqCst);
+            assert!(
+                audio_frames > 44000 && audio_frames < 44400,
+                "{}",
+                audio_frames
+            );
+            let keyinput = with_core(|core| core.gba.sysbus.io.keyinput).unwrap();
+            assert_eq!(keyinput, KEYINPUT_ALL_RELEASED & !(1 << 3));
+
+            assert_eq!(
+                retro_get_memory_data(RETRO_MEMORY_SAVE_RAM) as *mut u8,
+                sram
+            );
+            let backup = with_core(|core| {
+                let mut backup = [0; 1];
+                core.gba.sysbus.cartridge.store_backup(&mut backup);
+                backup[0]
+            });
+            assert_eq!(backup, Some(0x42));
+
+            let mut state = vec![0xff; retro_serialize_size()];
+            assert!(retro_serialize(
+                state.as_mut_ptr() as *mut c_void,
+                state.len()
+            ));
+            let mut same_state = vec![0; retro_serialize_size()];
+            assert!(retro_serialize(
+                same_state.as_mut_ptr() as *mut c_void,
+                same_state.len()
+            ));
+            assert!(state == same_state, "states of the same frame differ");
+
+            // too small, and left alone
+            let length = u32::from_le_bytes([state[0], state[1], state[2], state[3]]) as usize;
+            let mut small = vec![0xaa; STATE_LENGTH_SIZE + length - 1];
+            assert!(!retro_serialize(
+                small.as_mut_ptr() as *mut c_void,
+                small.len()
+            ));
+            assert!(small.iter().all(|&b| b == 0xaa));
+            assert!(!retro_serialize(ptr::null_mut(), state.len()));
+
+            with_core(|core| core.gba.sysbus.cartridge.load_backup(&[0x24]));
+            retro_run();
+            assert_eq!(*sram, 0x24);
+            assert!(retro_unserialize(
+                state.as_ptr() as *const c_void,
+                state.len()
+            ));
+            assert_eq!(*sram, 0x42);
+
+            retro_unload_game();
+            assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+            retro_deinit();
+        }
+    }
+}
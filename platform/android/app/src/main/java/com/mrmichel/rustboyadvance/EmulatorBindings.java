@@ -29,12 +29,15 @@ public class EmulatorBindings {
 
     /**
      * Open a new emulator context from a saved state buffer
+     * @param bios bytearray of the GBA bios, as it is not part of the saved state
+     * @param rom bytearray of the rom the state was saved with, as it is not part of the saved state
      * @param savedState
      * @param frameBuffer
+     * @param saveFile path of the backup file, the saved state doesn't say where it is
      * @return
      * @throws NativeBindingException
      */
-    public static native long openSavedState(byte[] savedState, int[] frameBuffer) throws NativeBindingException;
+    public static native long openSavedState(byte[] bios, byte[] rom, byte[] savedState, int[] frameBuffer, String saveFile) throws NativeBindingException;
 
     /**
      * Make the emulator boot directly into the cartridge
@@ -103,6 +106,25 @@ public class EmulatorBindings {
      */
     public static native void loadState(long ctx, byte[] state) throws NativeBindingException;
 
+    /**
+     * Starts keeping snapshots to rewind to
+     *
+     * @param ctx
+     * @param interval frames between snapshots
+     * @param budget memory the snapshots can take, in bytes
+     */
+    public static native void enableRewind(long ctx, int interval, int budget);
+
+    /**
+     * Goes back to the newest snapshot at least `frames` old
+     *
+     * @param ctx
+     * @param frames
+     * @return how many frames were rewound
+     * @throws NativeBindingException
+     */
+    public static native int rewind(long ctx, int frames) throws NativeBindingException;
+
     /**
      * Logs the emulator state
      * @return non-zero value on failure
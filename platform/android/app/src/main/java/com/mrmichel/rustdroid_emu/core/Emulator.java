@@ -16,6 +16,11 @@ public class Emulator {
     private int[] frameBuffer;
     public Keypad keypad;
 
+    /// saved states don't include the bios, the rom and the save file, so they are kept around to open them
+    private byte[] bios;
+    private byte[] rom;
+    private String saveName;
+
     public Emulator() {
         this.frameBuffer = new int[240 * 160];
         this.keypad = new Keypad();
@@ -58,21 +63,35 @@ public class Emulator {
     }
 
 
+    public synchronized void enableRewind(int interval, int budget) {
+        EmulatorBindings.enableRewind(this.ctx, interval, budget);
+    }
+
+    public synchronized int rewind(int frames) throws EmulatorBindings.NativeBindingException {
+        return EmulatorBindings.rewind(this.ctx, frames);
+    }
+
     public synchronized void loadState(byte[] state) throws EmulatorBindings.NativeBindingException {
         if (ctx != -1) {
             EmulatorBindings.loadState(this.ctx, state);
         } else {
-            openSavedState(state);
+            openSavedState(this.bios, this.rom, state, this.saveName);
         }
     }
 
 
     public synchronized void open(byte[] bios, byte[] rom, String saveName, boolean skipBios) throws EmulatorBindings.NativeBindingException {
         this.ctx = EmulatorBindings.openEmulator(bios, rom, this.frameBuffer, saveName, skipBios);
+        this.bios = bios;
+        this.rom = rom;
+        this.saveName = saveName;
     }
 
-    public synchronized void openSavedState(byte[] savedState) throws EmulatorBindings.NativeBindingException {
-        this.ctx = EmulatorBindings.openSavedState(savedState, this.frameBuffer);
+    public synchronized void openSavedState(byte[] bios, byte[] rom, byte[] savedState, String saveName) throws EmulatorBindings.NativeBindingException {
+        this.ctx = EmulatorBindings.openSavedState(bios, rom, savedState, this.frameBuffer, saveName);
+        this.bios = bios;
+        this.rom = rom;
+        this.saveName = saveName;
     }
 
     public synchronized void close() {
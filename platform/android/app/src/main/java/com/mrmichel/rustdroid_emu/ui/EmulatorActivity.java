@@ -399,7 +399,13 @@ public class EmulatorActivity extends AppCompatActivity implements View.OnClickL
                 saveFile.delete();
 
                 byte[] savedState = outputStream.toByteArray();
-                emulator.openSavedState(savedState);
+
+                // the saved state doesn't include the rom
+                int romId = getIntent().getIntExtra("romId", -1);
+                this.romMetadata = RomManager.getInstance(this).getRomMetadata(romId);
+                byte[] romData = Util.readFile(romMetadata.getRomFile());
+
+                emulator.openSavedState(this.bios, romData, savedState, romMetadata.getBackupFile().getAbsolutePath());
 
                 createThreads();
 
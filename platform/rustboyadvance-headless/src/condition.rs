@@ -0,0 +1,124 @@
+use rustboyadvance_core::core::arm7tdmi::CpuState;
+use rustboyadvance_core::prelude::*;
+
+use super::output::frame_hash;
+use super::HeadlessGba;
+
+/// What ends the run before the frames run out
+#[derive(Debug, PartialEq)]
+pub enum StopCondition {
+    /// The cpu branches to itself forever
+    Loop,
+    /// The memory at `addr` holds `value`, read `width` bits wide
+    Memory { addr: u32, width: u32, value: u32 },
+    /// The CRC32 of the frame buffer
+    FrameHash(u32),
+}
+
+fn parse_number(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u32>()
+    };
+    result.map_err(|_| format!("invalid number {}", s))
+}
+
+impl StopCondition {
+    /// Parses `ADDR=VALUE` or `ADDR:WIDTH=VALUE`
+    pub fn parse_memory(s: &str) -> Result<StopCondition, String> {
+        let mut parts = s.splitn(2, '=');
+        let location = parts.next().unwrap();
+        let value = parts
+            .next()
+            .ok_or_else(|| format!("expected ADDR=VALUE, got {}", s))?;
+        let mut location = location.splitn(2, ':');
+        let addr = parse_number(location.next().unwrap())?;
+        let width = match location.next() {
+            Some(width) => parse_number(width)?,
+            None => 32,
+        };
+        if width != 8 && width != 16 && width != 32 {
+            return Err(format!("invalid width {}, expected 8, 16 or 32", width));
+        }
+        Ok(StopCondition::Memory {
+            addr: addr,
+            width: width,
+            value: parse_number(value)?,
+        })
+    }
+
+    pub fn parse_hash(s: &str) -> Result<StopCondition, String> {
+        let s = s.trim_start_matches("0x");
+        u32::from_str_radix(s, 16)
+            .map(StopCondition::FrameHash)
+            .map_err(|_| format!("invalid hash {}", s))
+    }
+
+    pub fn is_met(&self, gba: &HeadlessGba) -> bool {
+        match *self {
+            StopCondition::Loop => {
+                let pc = gba.cpu.get_next_pc();
+                match gba.cpu.get_cpu_state() {
+                    CpuState::ARM => gba.sysbus.read_32(pc) == 0xeafffffe, // b .
+                    CpuState::THUMB => gba.sysbus.read_16(pc) == 0xe7fe,   // b .
+                }
+            }
+            StopCondition::Memory { addr, width, value } => {
+                let current = match width {
+                    8 => gba.sysbus.read_8(addr) as u32,
+                    16 => gba.sysbus.read_16(addr) as u32,
+                    _ => gba.sysbus.read_32(addr),
+                };
+                current == value
+            }
+            StopCondition::FrameHash(hash) => frame_hash(gba.get_frame_buffer()) == hash,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(
+            StopCondition::parse_memory("0x03000000=0x1234"),
+            Ok(StopCondition::Memory {
+                addr: 0x0300_0000,
+                width: 32,
+                value: 0x1234
+            })
+        );
+        assert_eq!(
+            StopCondition::parse_memory("0x0200000A:8=255"),
+            Ok(StopCondition::Memory {
+                addr: 0x0200_000a,
+                width: 8,
+                value: 255
+            })
+        );
+        assert!(StopCondition::parse_memory("0x03000000").is_err());
+        assert!(StopCondition::parse_memory("0x03000000:12=1").is_err());
+        assert!(StopCondition::parse_memory("0x0300000g=1").is_err());
+        assert!(StopCondition::parse_memory("0x03000000=").is_err());
+        assert!(StopCondition::parse_memory("0x1_0000_0000=1").is_err());
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        assert_eq!(
+            StopCondition::parse_hash("0xdeadbeef"),
+            Ok(StopCondition::FrameHash(0xdead_beef))
+        );
+        assert_eq!(
+            StopCondition::parse_hash("1234abcd"),
+            Ok(StopCondition::FrameHash(0x1234_abcd))
+        );
+        assert!(StopCondition::parse_hash("").is_err());
+        assert!(StopCondition::parse_hash("0xnothex").is_err());
+        assert!(StopCondition::parse_hash("0x123456789").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+#[macro_use]
+extern crate clap;
+
+#[macro_use]
+extern crate log;
+
+use flexi_logger;
+
+use rustboyadvance_core::prelude::*;
+
+mod condition;
+mod output;
+mod script;
+
+use condition::StopCondition;
+use output::{frame_hash, write_png, AudioRecorder, NullVideo};
+use script::InputScript;
+
+/// The stop condition was met, or the frames ran out when there was none
+const EXIT_SUCCESS: i32 = 0;
+/// The frames ran out before the stop condition was met
+const EXIT_TIMEOUT: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+/// Statically dispatched to the devices, nothing here needs to swap them
+type HeadlessGba = GenericGameBoyAdvance<NullVideo, AudioRecorder, InputScript>;
+
+fn parse_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str) -> Result<T, String> {
+    let value = matches.value_of(name).unwrap();
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for --{}: {}", name.replace('_', "-"), value))
+}
+
+fn run(matches: &clap::ArgMatches) -> Result<i32, String> {
+    let frames: usize = parse_arg(matches, "frames")?;
+    let sample_rate: i32 = parse_arg(matches, "sample_rate")?;
+    let resampler: ResamplerType = parse_arg(matches, "resampler")?;
+
+    let mut conditions = Vec::new();
+    if matches.occurrences_of("until_loop") != 0 {
+        conditions.push(StopCondition::Loop);
+    }
+    if let Some(s) = matches.value_of("until_mem") {
+        conditions.push(StopCondition::parse_memory(s)?);
+    }
+    if let Some(s) = matches.value_of("until_hash") {
+        conditions.push(StopCondition::parse_hash(s)?);
+    }
+
+    let input = match matches.value_of("input") {
+        Some(path) => fs::read_to_string(path)
+            .map_err(|e| format!("cannot read input script {}: {}", path, e))?
+            .parse::<InputScript>()?,
+        None => InputScript::default(),
+    };
+
+    let rom_path = Path::new(matches.value_of("game_rom").unwrap());
+    let gamepak = GamepakBuilder::new()
+        .file(rom_path)
+        .without_backup_to_file()
+        .build()
+        .map_err(|e| format!("cannot load {}: {:?}", rom_path.display(), e))?;
+
+    let video = Arc::new(Mutex::new(NullVideo));
+    let audio = Arc::new(Mutex::new(AudioRecorder::new(sample_rate)));
+    let input = Arc::new(Mutex::new(input));
+
+    let mut gba = match matches.value_of("bios") {
+        Some(path) => {
+            let bios = read_bin_file(Path::new(path))
+                .map_err(|e| format!("cannot read bios {}: {}", path, e))?;
+            HeadlessGba::new(
+                bios.into_boxed_slice(),
+                gamepak,
+                video,
+                audio.clone(),
+                input,
+            )
+        }
+        None => HeadlessGba::new_with_hle_bios(gamepak, video, audio.clone(), input),
+    };
+    gba.set_resampler(resampler);
+    if matches.occurrences_of("skip_bios") != 0 {
+        gba.skip_bios();
+    }
+
+    let mut frames_run = 0;
+    let mut condition_met = false;
+    while frames_run < frames && !condition_met {
+        gba.frame();
+        frames_run += 1;
+        condition_met = conditions.iter().any(|c| c.is_met(&gba));
+    }
+
+    if let Some(path) = matches.value_of("screenshot") {
+        write_png(Path::new(path), gba.get_frame_buffer())?;
+    }
+    if let Some(path) = matches.value_of("audio") {
+        audio.lock().unwrap().write_wav(Path::new(path))?;
+    }
+    if matches.occurrences_of("print_hash") != 0 {
+        println!("{:08x}", frame_hash(gba.get_frame_buffer()));
+    }
+
+    if conditions.is_empty() || condition_met {
+        info!("stopped after {} frames", frames_run);
+        Ok(EXIT_SUCCESS)
+    } else {
+        warn!("stop condition not met after {} frames", frames_run);
+        Ok(EXIT_TIMEOUT)
+    }
+}
+
+fn main() {
+    flexi_logger::Logger::with_env_or_str("warn")
+        .start()
+        .unwrap();
+
+    let yaml = load_yaml!("cli.yml");
+    // clap exits with 1 on bad arguments, which would pass for a timeout
+    let matches = match clap::App::from_yaml(yaml).get_matches_safe() {
+        Ok(matches) => matches,
+        Err(e) => match e.kind {
+            clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ => {
+                eprintln!("{}", e.message);
+                process::exit(EXIT_ERROR);
+            }
+        },
+    };
+
+    let code = match run(&matches) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{}", e);
+            EXIT_ERROR
+        }
+    };
+    process::exit(code);
+}
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use rustboyadvance_core::prelude::*;
+use rustboyadvance_core::StereoSample;
+
+/// Collects the sound to write it out at the end of the run
+pub struct AudioRecorder {
+    sample_rate: i32,
+    samples: Vec<StereoSample<i16>>,
+}
+
+impl AudioRecorder {
+    pub fn new(sample_rate: i32) -> AudioRecorder {
+        AudioRecorder {
+            sample_rate: sample_rate,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn write_wav(&self, path: &Path) -> Result<(), String> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: self.sample_rate as u32,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).map_err(|e| e.to_string())?;
+        for &(left, right) in &self.samples {
+            writer.write_sample(left).map_err(|e| e.to_string())?;
+            writer.write_sample(right).map_err(|e| e.to_string())?;
+        }
+        writer.finalize().map_err(|e| e.to_string())
+    }
+}
+
+impl AudioInterface for AudioRecorder {
+    fn get_sample_rate(&self) -> i32 {
+        self.sample_rate
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        self.samples.extend_from_slice(samples);
+    }
+}
+
+/// Nothing is shown, the frame buffer is read from the emulator once the run is over
+pub struct NullVideo;
+
+impl VideoInterface for NullVideo {}
+
+/// CRC32 of the frame buffer, stable across platforms
+pub fn frame_hash(frame_buffer: &[u32]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for pixel in frame_buffer {
+        hasher.update(&pixel.to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+pub fn write_png(path: &Path, frame_buffer: &[u32]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        DISPLAY_WIDTH as u32,
+        DISPLAY_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    // pixels are 0x00RRGGBB
+    let mut data = Vec::with_capacity(frame_buffer.len() * 3);
+    for pixel in frame_buffer {
+        data.push((pixel >> 16) as u8);
+        data.push((pixel >> 8) as u8);
+        data.push(*pixel as u8);
+    }
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&data).map_err(|e| e.to_string())
+}
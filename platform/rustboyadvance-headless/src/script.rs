@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+use rustboyadvance_core::core::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+use rustboyadvance_core::InputInterface;
+
+/// Feeds the keys of an input script, one frame per poll.
+///
+/// Each line of the script holds the frame the keys are pressed from, followed by the keys,
+/// which stay pressed until the frame of the next line. Empty lines and lines starting with `#` are ignored.
+/// ```text
+/// # press start on the title screen, then walk right
+/// 120 START
+/// 125
+/// 200 RIGHT B
+/// ```
+#[derive(Default)]
+pub struct InputScript {
+    /// The frames the key states start from, in ascending order
+    entries: Vec<(usize, u16)>,
+    frame: usize,
+}
+
+fn parse_key(name: &str) -> Option<Keys> {
+    match name.to_uppercase().as_str() {
+        "A" => Some(Keys::ButtonA),
+        "B" => Some(Keys::ButtonB),
+        "SELECT" => Some(Keys::Select),
+        "START" => Some(Keys::Start),
+        "RIGHT" => Some(Keys::Right),
+        "LEFT" => Some(Keys::Left),
+        "UP" => Some(Keys::Up),
+        "DOWN" => Some(Keys::Down),
+        "R" => Some(Keys::ButtonR),
+        "L" => Some(Keys::ButtonL),
+        _ => None,
+    }
+}
+
+impl FromStr for InputScript {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<InputScript, String> {
+        let mut entries: Vec<(usize, u16)> = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut tokens = line.split_whitespace();
+            let frame = tokens
+                .next()
+                .unwrap()
+                .parse::<usize>()
+                .map_err(|_| format!("line {}: expected a frame number", i + 1))?;
+            if let Some(&(last, _)) = entries.last() {
+                if frame <= last {
+                    return Err(format!("line {}: frames must be in ascending order", i + 1));
+                }
+            }
+            // KEYINPUT bits are cleared for the pressed keys
+            let mut keys = KEYINPUT_ALL_RELEASED;
+            for token in tokens {
+                let key = parse_key(token)
+                    .ok_or_else(|| format!("line {}: unknown key {}", i + 1, token))?;
+                keys &= !(1 << key as u16);
+            }
+            entries.push((frame, keys));
+        }
+        Ok(InputScript {
+            entries: entries,
+            frame: 0,
+        })
+    }
+}
+
+impl InputInterface for InputScript {
+    fn poll(&mut self) -> u16 {
+        let frame = self.frame;
+        self.frame += 1;
+        self.entries
+            .iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or(KEYINPUT_ALL_RELEASED, |(_, keys)| *keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(keys: Vec<Keys>) -> u16 {
+        keys.into_iter()
+            .fold(KEYINPUT_ALL_RELEASED, |acc, key| acc & !(1 << key as u16))
+    }
+
+    #[test]
+    fn test_keys_held_until_the_next_line() {
+        let text = "# title screen\n\n2 start\n3\n5 RIGHT b\n";
+        let mut script: InputScript = text.parse().unwrap();
+        let polled: Vec<u16> = (0..7).map(|_| script.poll()).collect();
+        assert_eq!(
+            polled,
+            vec![
+                KEYINPUT_ALL_RELEASED,
+                KEYINPUT_ALL_RELEASED,
+                pressed(vec![Keys::Start]),
+                KEYINPUT_ALL_RELEASED,
+                KEYINPUT_ALL_RELEASED,
+                pressed(vec![Keys::Right, Keys::ButtonB]),
+                pressed(vec![Keys::Right, Keys::ButtonB]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_scripts() {
+        let errors = [
+            ("START", "line 1: expected a frame number"),
+            ("10 A\n10 B", "line 2: frames must be in ascending order"),
+            (
+                "10 A\n# comment\n5 B",
+                "line 3: frames must be in ascending order",
+            ),
+            ("1 A TURBO", "line 1: unknown key TURBO"),
+            ("-1 A", "line 1: expected a frame number"),
+        ];
+        for &(text, error) in errors.iter() {
+            assert_eq!(text.parse::<InputScript>().err().as_deref(), Some(error));
+        }
+    }
+}
@@ -1,7 +1,5 @@
-use std::cell::RefCell;
-use std::rc::Rc;
-
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time;
 
 #[macro_use]
@@ -15,57 +13,17 @@ use bit::BitIndex;
 use minifb;
 use minifb::{Key, Window, WindowOptions};
 
+/// The devices of the emulator, the window stays with the main loop which fills in the keys
+/// and draws the frame buffer
 struct MiniFb {
-    window: minifb::Window,
+    keyinput: u16,
 }
 
-impl VideoInterface for MiniFb {
-    fn render(&mut self, buffer: &[u32]) {
-        self.window.update_with_buffer(buffer).unwrap();
-    }
-}
+impl VideoInterface for MiniFb {}
 
 impl InputInterface for MiniFb {
     fn poll(&mut self) -> u16 {
-        let mut keyinput = keypad::KEYINPUT_ALL_RELEASED;
-        keyinput.set_bit(keypad::Keys::Up as usize, !self.window.is_key_down(Key::Up));
-        keyinput.set_bit(
-            keypad::Keys::Down as usize,
-            !self.window.is_key_down(Key::Down),
-        );
-        keyinput.set_bit(
-            keypad::Keys::Left as usize,
-            !self.window.is_key_down(Key::Left),
-        );
-        keyinput.set_bit(
-            keypad::Keys::Right as usize,
-            !self.window.is_key_down(Key::Right),
-        );
-        keyinput.set_bit(
-            keypad::Keys::ButtonB as usize,
-            !self.window.is_key_down(Key::Z),
-        );
-        keyinput.set_bit(
-            keypad::Keys::ButtonA as usize,
-            !self.window.is_key_down(Key::X),
-        );
-        keyinput.set_bit(
-            keypad::Keys::Start as usize,
-            !self.window.is_key_down(Key::Enter),
-        );
-        keyinput.set_bit(
-            keypad::Keys::Select as usize,
-            !self.window.is_key_down(Key::Space),
-        );
-        keyinput.set_bit(
-            keypad::Keys::ButtonL as usize,
-            !self.window.is_key_down(Key::A),
-        );
-        keyinput.set_bit(
-            keypad::Keys::ButtonR as usize,
-            !self.window.is_key_down(Key::S),
-        );
-        keyinput
+        self.keyinput
     }
 }
 
@@ -75,6 +33,30 @@ impl AudioInterface for MiniFb {
     }
 }
 
+fn read_keys(window: &Window) -> u16 {
+    let mut keyinput = keypad::KEYINPUT_ALL_RELEASED;
+    keyinput.set_bit(keypad::Keys::Up as usize, !window.is_key_down(Key::Up));
+    keyinput.set_bit(keypad::Keys::Down as usize, !window.is_key_down(Key::Down));
+    keyinput.set_bit(keypad::Keys::Left as usize, !window.is_key_down(Key::Left));
+    keyinput.set_bit(
+        keypad::Keys::Right as usize,
+        !window.is_key_down(Key::Right),
+    );
+    keyinput.set_bit(keypad::Keys::ButtonB as usize, !window.is_key_down(Key::Z));
+    keyinput.set_bit(keypad::Keys::ButtonA as usize, !window.is_key_down(Key::X));
+    keyinput.set_bit(
+        keypad::Keys::Start as usize,
+        !window.is_key_down(Key::Enter),
+    );
+    keyinput.set_bit(
+        keypad::Keys::Select as usize,
+        !window.is_key_down(Key::Space),
+    );
+    keyinput.set_bit(keypad::Keys::ButtonL as usize, !window.is_key_down(Key::A));
+    keyinput.set_bit(keypad::Keys::ButtonR as usize, !window.is_key_down(Key::S));
+    keyinput
+}
+
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = clap::App::from_yaml(yaml).get_matches();
@@ -89,18 +71,19 @@ fn main() {
     let bios_bin = read_bin_file(bios_path).unwrap();
     let cart = GamepakBuilder::new().file(rom_path).build().unwrap();
 
-    let minifb = Rc::new(RefCell::new(MiniFb {
-        window: Window::new(
-            "rustboyadvance-ng",
-            240,
-            160,
-            WindowOptions {
-                borderless: true,
-                scale: minifb::Scale::X4,
-                ..Default::default()
-            },
-        )
-        .unwrap(),
+    let mut window = Window::new(
+        "rustboyadvance-ng",
+        240,
+        160,
+        WindowOptions {
+            borderless: true,
+            scale: minifb::Scale::X4,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let minifb = Arc::new(Mutex::new(MiniFb {
+        keyinput: keypad::KEYINPUT_ALL_RELEASED,
     }));
 
     let mut fps_counter = FpsCounter::default();
@@ -120,12 +103,13 @@ fn main() {
     loop {
         let start_time = time::Instant::now();
 
+        minifb.lock().unwrap().keyinput = read_keys(&window);
         gba.frame();
+        window.update_with_buffer(gba.get_frame_buffer()).unwrap();
 
         if let Some(fps) = fps_counter.tick() {
             let title = format!("{} ({} fps)", rom_name, fps);
-            // video.borrow_mut().set_window_title(&title);
-            minifb.borrow_mut().window.set_title(&title);
+            window.set_title(&title);
         }
 
         if !no_framerate_limit {
@@ -6,13 +6,17 @@ use rustboyadvance_core::{AudioInterface, StereoSample};
 use ringbuf;
 use ringbuf::{Consumer, Producer, RingBuffer};
 
-struct GbaAudioCallback {
+/// Largest change of the output rate made to keep the ring buffer half full
+const MAX_RATE_ADJUSTMENT: f32 = 0.005;
+
+pub struct GbaAudioCallback {
     consumer: Consumer<StereoSample<i16>>,
     spec: AudioSpec,
 }
 
+/// The audio device of the emulator, feeding the samples to the callback of the `AudioDevice`,
+/// which stays on the main thread
 pub struct Sdl2AudioPlayer {
-    _device: AudioDevice<GbaAudioCallback>,
     producer: Producer<StereoSample<i16>>,
     freq: i32,
 }
@@ -35,6 +39,14 @@ impl AudioCallback for GbaAudioCallback {
     }
 }
 
+impl Sdl2AudioPlayer {
+    /// Asks for more samples while the ring buffer is less than half full, and less while it is fuller
+    pub fn rate_adjustment(&self) -> f32 {
+        let fill = self.producer.len() as f32 / self.producer.capacity() as f32;
+        (1.0 - 2.0 * fill) * MAX_RATE_ADJUSTMENT
+    }
+}
+
 impl AudioInterface for Sdl2AudioPlayer {
     fn get_sample_rate(&self) -> i32 {
         self.freq
@@ -44,9 +56,15 @@ impl AudioInterface for Sdl2AudioPlayer {
         #![allow(unused_must_use)]
         self.producer.push(sample);
     }
+
+    /// What doesn't fit in the ring buffer is dropped, the callback is behind anyway
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        self.producer.push_slice(samples);
+    }
 }
 
-pub fn create_audio_player(sdl: &sdl2::Sdl) -> Sdl2AudioPlayer {
+/// The device has to be kept alive for as long as the sound plays
+pub fn create_audio_player(sdl: &sdl2::Sdl) -> (AudioDevice<GbaAudioCallback>, Sdl2AudioPlayer) {
     let desired_spec = AudioSpecDesired {
         freq: Some(44_100),
         channels: Some(2), // stereo
@@ -81,9 +99,11 @@ pub fn create_audio_player(sdl: &sdl2::Sdl) -> Sdl2AudioPlayer {
 
     device.resume();
 
-    Sdl2AudioPlayer {
-        _device: device,
-        freq,
-        producer: producer.unwrap(),
-    }
+    (
+        device,
+        Sdl2AudioPlayer {
+            freq,
+            producer: producer.unwrap(),
+        },
+    )
 }
@@ -9,12 +9,10 @@ use sdl2::EventPump;
 use bytesize;
 use spin_sleep;
 
-use std::cell::RefCell;
-use std::rc::Rc;
-
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
 use std::time;
 
 use std::convert::TryFrom;
@@ -33,20 +31,67 @@ mod video;
 
 use audio::create_audio_player;
 use input::create_input;
-use video::{create_video_interface, SCREEN_HEIGHT, SCREEN_WIDTH};
+use video::{create_video_interface, NullVideo, SCREEN_HEIGHT, SCREEN_WIDTH};
 
 use rustboyadvance_core::core::cartridge::BackupType;
+use rustboyadvance_core::core::movie::Movie;
+use rustboyadvance_core::core::sio::StreamLink;
 use rustboyadvance_core::prelude::*;
 use rustboyadvance_core::util::spawn_and_run_gdb_server;
 use rustboyadvance_core::util::FpsCounter;
 
 const LOG_DIR: &str = ".logs";
 const DEFAULT_GDB_SERVER_ADDR: &'static str = "localhost:1337";
+/// Frames between rewind snapshots, holding the rewind key goes back one snapshot per frame
+const REWIND_INTERVAL: usize = 4;
+const REWIND_BUDGET: usize = 64 * 1024 * 1024;
 
 fn get_savestate_path(rom_filename: &Path) -> PathBuf {
     rom_filename.with_extension("savestate")
 }
 
+/// Video and audio files for a new recording, named after the rom and the time
+fn get_recording_paths(rom_filename: &Path) -> (PathBuf, PathBuf) {
+    let timestamp = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let stem = rom_filename.file_stem().unwrap().to_string_lossy();
+    let base = rom_filename.with_file_name(format!("{}-{}", stem, timestamp));
+    (base.with_extension("y4m"), base.with_extension("wav"))
+}
+
+fn stop_recording(gba: &mut GameBoyAdvance) {
+    let frames = match gba.av_recorder() {
+        Some(recorder) => recorder.lock().unwrap().frames(),
+        None => return,
+    };
+    match gba.stop_av_recording() {
+        Ok(_) => info!("Recording stopped ({} frames)", frames),
+        Err(e) => error!("Failed to write the recording: {:?}", e),
+    }
+}
+
+/// Creates the emulator, emulating the BIOS when no dump is available
+fn create_gba(
+    bios: &Option<Vec<u8>>,
+    gamepak: Cartridge,
+    video: Arc<Mutex<dyn VideoInterface>>,
+    audio: Arc<Mutex<dyn AudioInterface>>,
+    input: Arc<Mutex<dyn InputInterface>>,
+) -> GameBoyAdvance {
+    match bios {
+        Some(bios) => GameBoyAdvance::new(
+            bios.clone().into_boxed_slice(),
+            gamepak,
+            video,
+            audio,
+            input,
+        ),
+        None => GameBoyAdvance::new_with_hle_bios(gamepak, video, audio, input),
+    }
+}
+
 /// Waits for the user to drag a rom file to window
 fn wait_for_rom(event_pump: &mut EventPump) -> String {
     loop {
@@ -74,6 +119,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
 
     let mut frame_limiter = true;
+    let mut rewinding = false;
     let yaml = load_yaml!("cli.yml");
     let matches = clap::App::from_yaml(yaml).get_matches();
 
@@ -81,6 +127,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let debug = matches.occurrences_of("debug") != 0;
     let with_gdbserver = matches.occurrences_of("with_gdbserver") != 0;
+    let record_movie = matches.value_of("record_movie");
+    // rewinding would leave the recorded movie out of sync
+    let rewind = matches.occurrences_of("no_rewind") == 0 && record_movie.is_none();
 
     info!("Initializing SDL2 context");
     let sdl_context = sdl2::init().expect("failed to initialize sdl2");
@@ -110,12 +159,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // TODO also set window icon
 
-    let video = Rc::new(RefCell::new(create_video_interface(canvas)));
-    let audio = Rc::new(RefCell::new(create_audio_player(&sdl_context)));
-    let input = Rc::new(RefCell::new(create_input()));
+    let mut video = create_video_interface(canvas);
+    let (_audio_device, audio) = create_audio_player(&sdl_context);
+    let null_video = Arc::new(Mutex::new(NullVideo));
+    let audio = Arc::new(Mutex::new(audio));
+    let input = Arc::new(Mutex::new(create_input()));
 
     let bios_path = Path::new(matches.value_of("bios").unwrap_or_default());
-    let bios_bin = read_bin_file(bios_path).expect("cannot read bios file");
+    let bios_bin = if matches.occurrences_of("hle_bios") != 0 {
+        None
+    } else {
+        match read_bin_file(bios_path) {
+            Ok(bios_bin) => Some(bios_bin),
+            Err(e) => {
+                warn!(
+                    "cannot read bios file {} ({}), the bios will be emulated",
+                    bios_path.display(),
+                    e
+                );
+                None
+            }
+        }
+    };
 
     let mut rom_path = match matches.value_of("game_rom") {
         Some(path) => path.to_string(),
@@ -136,10 +201,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .file(Path::new(&rom_path))
         .build()?;
 
-    let mut gba = GameBoyAdvance::new(
-        bios_bin.into_boxed_slice(),
+    let mut gba = create_gba(
+        &bios_bin,
         gamepak,
-        video.clone(),
+        null_video.clone(),
         audio.clone(),
         input.clone(),
     );
@@ -148,6 +213,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         gba.skip_bios();
     }
 
+    if rewind {
+        gba.enable_rewind(REWIND_INTERVAL, REWIND_BUDGET);
+    }
+
+    if matches.occurrences_of("no_prefetch") != 0 {
+        gba.sysbus.prefetch_emulation = false;
+    }
+
+    if let Some(addr) = matches.value_of("link_listen") {
+        info!("Waiting for a link cable connection on {}...", addr);
+        gba.set_link_transport(Box::new(StreamLink::listen(addr)?));
+    } else if let Some(addr) = matches.value_of("link_connect") {
+        info!("Connecting link cable to {}...", addr);
+        gba.set_link_transport(Box::new(StreamLink::connect(addr)?));
+    }
+
+    let mut playing_movie = false;
+    if let Some(path) = matches.value_of("play_movie") {
+        let movie = Movie::from_bytes(&read_bin_file(Path::new(path))?)?;
+        info!("Playing movie {} ({} frames)", path, movie.len());
+        gba.play_movie(movie)?;
+        playing_movie = true;
+    } else if record_movie.is_some() {
+        info!("Recording movie from power on");
+        gba.start_movie_recording(true)?;
+    }
+
     if debug {
         #[cfg(feature = "debugger")]
         {
@@ -187,6 +279,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 } => {
                     frame_limiter = true;
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    rewinding = rewind;
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    rewinding = false;
+                }
                 #[cfg(feature = "debugger")]
                 Event::KeyUp {
                     keycode: Some(Keycode::F1),
@@ -219,6 +323,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         bytesize::ByteSize::b(save.len() as u64)
                     );
                 }
+                Event::KeyUp {
+                    keycode: Some(Keycode::F7),
+                    ..
+                } => {
+                    if gba.av_recorder().is_some() {
+                        stop_recording(&mut gba);
+                    } else {
+                        let (video_path, audio_path) = get_recording_paths(Path::new(&rom_path));
+                        match gba.start_av_recording(&video_path, &audio_path) {
+                            Ok(_) => info!("Recording to {:?} and {:?}", video_path, audio_path),
+                            Err(e) => error!("Failed to start recording: {:?}", e),
+                        }
+                    }
+                }
                 Event::KeyUp {
                     keycode: Some(Keycode::F9),
                     ..
@@ -226,8 +344,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     if savestate_path.is_file() {
                         let save = read_bin_file(&savestate_path)?;
                         info!("Restoring state from {:?}...", savestate_path);
-                        gba.restore_state(&save)?;
-                        info!("Restored!");
+                        match gba.restore_state(&save) {
+                            Ok(_) => info!("Restored!"),
+                            Err(e) => error!("Failed to restore state: {:?}", e),
+                        }
                     } else {
                         info!("Savestate not created, please create one by pressing F5");
                     }
@@ -236,13 +356,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     keycode: Some(keycode),
                     ..
                 } => {
-                    input.borrow_mut().on_keyboard_key_down(keycode);
+                    input.lock().unwrap().on_keyboard_key_down(keycode);
                 }
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
                 } => {
-                    input.borrow_mut().on_keyboard_key_up(keycode);
+                    input.lock().unwrap().on_keyboard_key_up(keycode);
                 }
                 Event::Quit { .. } => break 'running,
                 Event::DropFile { filename, .. } => {
@@ -251,27 +371,51 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     savestate_path = get_savestate_path(&Path::new(&rom_path));
                     rom_name = Path::new(&rom_path).file_name().unwrap().to_str().unwrap();
                     let gamepak = GamepakBuilder::new().file(Path::new(&rom_path)).build()?;
-                    let bios_bin = read_bin_file(bios_path).unwrap();
 
-                    // create a new emulator - TODO, export to a function
-                    gba = GameBoyAdvance::new(
-                        bios_bin.into_boxed_slice(),
+                    stop_recording(&mut gba);
+                    let link = gba.take_link_transport();
+                    gba = create_gba(
+                        &bios_bin,
                         gamepak,
-                        video.clone(),
+                        null_video.clone(),
                         audio.clone(),
                         input.clone(),
                     );
+                    if let Some(link) = link {
+                        gba.set_link_transport(link);
+                    }
                     gba.skip_bios();
+                    if rewind {
+                        gba.enable_rewind(REWIND_INTERVAL, REWIND_BUDGET);
+                    }
                 }
                 _ => {}
             }
         }
 
-        gba.frame();
+        if rewinding {
+            if let Err(e) = gba.rewind(REWIND_INTERVAL) {
+                error!("Failed to rewind: {:?}", e);
+                rewinding = false;
+            }
+        } else {
+            gba.frame();
+            gba.set_audio_rate_adjustment(audio.lock().unwrap().rate_adjustment());
+        }
+        video.render(gba.get_frame_buffer());
+
+        if playing_movie && !gba.is_playing_movie() {
+            playing_movie = false;
+            let desynced_at = gba.movie_player().unwrap().lock().unwrap().desynced_at();
+            match desynced_at {
+                Some(frame) => warn!("Movie ended, it desynced at frame {}", frame),
+                None => info!("Movie ended"),
+            }
+        }
 
         if let Some(fps) = fps_counter.tick() {
             let title = format!("{} ({} fps)", rom_name, fps);
-            video.borrow_mut().set_window_title(&title);
+            video.set_window_title(&title);
         }
 
         if frame_limiter {
@@ -286,5 +430,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    stop_recording(&mut gba);
+
+    if let Some(path) = record_movie {
+        if let Some(movie) = gba.stop_movie_recording() {
+            write_bin_file(Path::new(path), &movie.to_bytes())?;
+            info!("Saved movie to {} ({} frames)", path, movie.len());
+        }
+    }
+
     Ok(())
 }
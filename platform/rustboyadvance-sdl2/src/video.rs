@@ -6,6 +6,12 @@ use sdl2::video::WindowContext;
 use rustboyadvance_core::core::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
 use rustboyadvance_core::VideoInterface;
 
+/// The video device of the emulator, the canvas can't leave the main thread so the frames are
+/// taken from `GameBoyAdvance::get_frame_buffer` and drawn by `Sdl2Video` instead
+pub struct NullVideo;
+
+impl VideoInterface for NullVideo {}
+
 pub const SCREEN_WIDTH: u32 = DISPLAY_WIDTH as u32;
 pub const SCREEN_HEIGHT: u32 = DISPLAY_HEIGHT as u32;
 
@@ -19,10 +25,8 @@ impl<'a> Sdl2Video<'a> {
     pub fn set_window_title(&mut self, title: &str) {
         self.canvas.window_mut().set_title(&title).unwrap();
     }
-}
 
-impl<'a> VideoInterface for Sdl2Video<'a> {
-    fn render(&mut self, buffer: &[u32]) {
+    pub fn render(&mut self, buffer: &[u32]) {
         self.texture
             .update(
                 None,
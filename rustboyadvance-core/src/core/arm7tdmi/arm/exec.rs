@@ -57,7 +57,7 @@ impl Core {
 
     /// Cycles 2S+1N
     pub fn exec_arm_b_bl(&mut self, sb: &mut SysBus, insn: &ArmInstruction) -> CpuAction {
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
         if insn.link_flag() {
             self.set_reg(REG_LR, (insn.pc + (self.word_size() as u32)) & !0b1);
         }
@@ -70,8 +70,8 @@ impl Core {
 
     pub fn branch_exchange(&mut self, sb: &mut SysBus, mut addr: Addr) -> CpuAction {
         match self.cpsr.state() {
-            CpuState::ARM => self.S_cycle32(sb, self.pc),
-            CpuState::THUMB => self.S_cycle16(sb, self.pc),
+            CpuState::ARM => self.S_fetch32(sb, self.pc),
+            CpuState::THUMB => self.S_fetch16(sb, self.pc),
         }
         if addr.bit(0) {
             addr = addr & !0x1;
@@ -105,7 +105,7 @@ impl Core {
             self.cpsr.get()
         };
         self.set_reg(rd, result);
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -175,7 +175,7 @@ impl Core {
                 }
             }
         }
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -200,7 +200,7 @@ impl Core {
         use AluOpCode::*;
 
         let raw_insn = insn.raw;
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         let rn = raw_insn.bit_range(16..20) as usize;
         let rd = raw_insn.bit_range(12..16) as usize;
@@ -342,10 +342,10 @@ impl Core {
         };
 
         if load {
-            self.S_cycle32(sb, self.pc);
+            self.S_fetch32(sb, self.pc);
             let data = if insn.transfer_size() == 1 {
                 self.N_cycle8(sb, addr);
-                sb.read_8(addr) as u32
+                self.read_8(addr, sb) as u32
             } else {
                 self.N_cycle32(sb, addr);
                 self.ldr_word(addr, sb)
@@ -373,7 +373,7 @@ impl Core {
                 self.N_cycle32(sb, addr);
                 self.write_32(addr & !0x3, value, sb);
             };
-            self.N_cycle32(sb, self.pc);
+            self.N_fetch32(sb, self.pc);
         }
 
         if !load || base_reg != dest_reg {
@@ -449,11 +449,11 @@ impl Core {
         };
 
         if load {
-            self.S_cycle32(sb, self.pc);
+            self.S_fetch32(sb, self.pc);
             let data = match insn.halfword_data_transfer_type().unwrap() {
                 ArmHalfwordTransferType::SignedByte => {
                     self.N_cycle8(sb, addr);
-                    sb.read_8(addr) as u8 as i8 as u32
+                    self.read_8(addr, sb) as i8 as u32
                 }
                 ArmHalfwordTransferType::SignedHalfwords => {
                     self.N_cycle16(sb, addr);
@@ -485,7 +485,7 @@ impl Core {
                 ArmHalfwordTransferType::UnsignedHalfwords => {
                     self.N_cycle32(sb, addr);
                     self.write_16(addr, value as u16, sb);
-                    self.N_cycle32(sb, self.pc);
+                    self.N_fetch32(sb, self.pc);
                 }
                 _ => panic!("invalid HS flags for L=0"),
             };
@@ -559,7 +559,7 @@ impl Core {
         if rlist != 0 {
             if is_load {
                 self.add_cycle();
-                self.N_cycle32(sb, self.pc);
+                self.N_fetch32(sb, self.pc);
                 for r in 0..16 {
                     if rlist.bit(r) {
                         if r == base_reg {
@@ -569,8 +569,8 @@ impl Core {
                             addr = addr.wrapping_add(4);
                         }
 
-                        let val = sb.read_32(addr);
-                        self.S_cycle32(sb, self.pc);
+                        let val = self.read_32(addr, sb);
+                        self.S_fetch32(sb, self.pc);
 
                         self.set_reg(r, val);
 
@@ -627,7 +627,7 @@ impl Core {
                         }
                     }
                 }
-                self.N_cycle32(sb, self.pc);
+                self.N_fetch32(sb, self.pc);
             }
         } else {
             if is_load {
@@ -685,7 +685,7 @@ impl Core {
             self.cpsr.set_V(false);
         }
 
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -735,7 +735,7 @@ impl Core {
             self.cpsr.set_V(false);
         }
 
-        self.S_cycle32(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
@@ -744,7 +744,7 @@ impl Core {
         let base_addr = self.get_reg(insn.raw.bit_range(16..20) as usize);
         let rd = insn.raw.bit_range(12..16) as usize;
         if insn.transfer_size() == 1 {
-            let t = sb.read_8(base_addr);
+            let t = self.read_8(base_addr, sb);
             self.N_cycle8(sb, base_addr);
             sb.write_8(base_addr, self.get_reg(insn.rm()) as u8);
             self.S_cycle8(sb, base_addr);
@@ -757,7 +757,7 @@ impl Core {
             self.set_reg(rd, t as u32);
         }
         self.add_cycle();
-        self.N_cycle32(sb, self.pc);
+        self.N_fetch32(sb, self.pc);
 
         CpuAction::AdvancePC
     }
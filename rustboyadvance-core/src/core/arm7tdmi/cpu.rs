@@ -53,7 +53,8 @@ pub struct Core {
     #[cfg(feature = "debugger")]
     pub last_executed: Option<DecodedInstruction>,
 
-    pub cycles: usize,
+    /// Cycles since power on, 64 bits wide so it doesn't wrap on 32-bit hosts
+    pub cycles: u64,
 
     // store the gpr before executing an instruction to show diff in the Display impl
     gpr_previous: [u32; 15],
@@ -66,6 +67,11 @@ pub struct Core {
     pub trace_opcodes: bool,
 
     pub trace_exceptions: bool,
+
+    /// Software interrupts are emulated instead of going through the BIOS
+    pub hle_bios: bool,
+    /// Set while an emulated IntrWait is halted, so it doesn't start over once woken up
+    pub(crate) hle_intr_wait: bool,
 }
 
 impl Core {
@@ -153,34 +159,53 @@ impl Core {
         bus.write_8(addr, value);
     }
 
+    /// Data reads done by the cpu, unlike the plain `Bus` reads these may have side effects on I/O registers
+    pub(super) fn read_32(&mut self, addr: Addr, bus: &mut SysBus) -> u32 {
+        let value = bus.read_32(addr & !0x3);
+        bus.on_cpu_read(addr & !0x3, MemoryAccess32);
+        value
+    }
+
+    pub(super) fn read_16(&mut self, addr: Addr, bus: &mut SysBus) -> u16 {
+        let value = bus.read_16(addr & !0x1);
+        bus.on_cpu_read(addr & !0x1, MemoryAccess16);
+        value
+    }
+
+    pub(super) fn read_8(&mut self, addr: Addr, bus: &mut SysBus) -> u8 {
+        let value = bus.read_8(addr);
+        bus.on_cpu_read(addr, MemoryAccess8);
+        value
+    }
+
     /// Helper function for "ldr" instruction that handles misaligned addresses
-    pub(super) fn ldr_word(&mut self, addr: Addr, bus: &SysBus) -> u32 {
+    pub(super) fn ldr_word(&mut self, addr: Addr, bus: &mut SysBus) -> u32 {
         if addr & 0x3 != 0 {
             let rotation = (addr & 0x3) << 3;
-            let value = bus.read_32(addr & !0x3);
+            let value = self.read_32(addr & !0x3, bus);
             self.ror(value, rotation, self.cpsr.C(), false, false)
         } else {
-            bus.read_32(addr)
+            self.read_32(addr, bus)
         }
     }
 
     /// Helper function for "ldrh" instruction that handles misaligned addresses
-    pub(super) fn ldr_half(&mut self, addr: Addr, bus: &SysBus) -> u32 {
+    pub(super) fn ldr_half(&mut self, addr: Addr, bus: &mut SysBus) -> u32 {
         if addr & 0x1 != 0 {
             let rotation = (addr & 0x1) << 3;
-            let value = bus.read_16(addr & !0x1);
+            let value = self.read_16(addr & !0x1, bus);
             self.ror(value as u32, rotation, self.cpsr.C(), false, false)
         } else {
-            bus.read_16(addr) as u32
+            self.read_16(addr, bus) as u32
         }
     }
 
     /// Helper function for "ldrsh" instruction that handles misaligned addresses
-    pub(super) fn ldr_sign_half(&mut self, addr: Addr, bus: &SysBus) -> u32 {
+    pub(super) fn ldr_sign_half(&mut self, addr: Addr, bus: &mut SysBus) -> u32 {
         if addr & 0x1 != 0 {
-            bus.read_8(addr) as i8 as i32 as u32
+            self.read_8(addr, bus) as i8 as i32 as u32
         } else {
-            bus.read_16(addr) as i16 as i32 as u32
+            self.read_16(addr, bus) as i16 as i32 as u32
         }
     }
 
@@ -230,7 +255,7 @@ impl Core {
         }
     }
 
-    pub fn cycles(&self) -> usize {
+    pub fn cycles(&self) -> u64 {
         self.cycles
     }
 
@@ -251,40 +276,65 @@ impl Core {
         }
     }
 
+    // Opcode fetches are accounted separately from data accesses, as they may hit the prefetch buffer
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn S_fetch16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_fetch_cycles(self.cycles, addr, Seq, MemoryAccess16) as u64;
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn S_fetch32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_fetch_cycles(self.cycles, addr, Seq, MemoryAccess32) as u64;
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn N_fetch16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_fetch_cycles(self.cycles, addr, NonSeq, MemoryAccess16) as u64;
+    }
+
+    #[allow(non_snake_case)]
+    #[inline(always)]
+    pub(super) fn N_fetch32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_fetch_cycles(self.cycles, addr, NonSeq, MemoryAccess32) as u64;
+    }
+
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle32(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess32);
+    pub(super) fn S_cycle32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, Seq, MemoryAccess32) as u64;
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle16(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess16);
+    pub(super) fn S_cycle16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, Seq, MemoryAccess16) as u64;
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn S_cycle8(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, Seq, MemoryAccess8);
+    pub(super) fn S_cycle8(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, Seq, MemoryAccess8) as u64;
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle32(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess32);
+    pub(super) fn N_cycle32(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, NonSeq, MemoryAccess32) as u64;
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle16(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess16);
+    pub(super) fn N_cycle16(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, NonSeq, MemoryAccess16) as u64;
     }
 
     #[allow(non_snake_case)]
     #[inline(always)]
-    pub(super) fn N_cycle8(&mut self, sb: &SysBus, addr: u32) {
-        self.cycles += sb.get_cycles(addr, NonSeq, MemoryAccess8);
+    pub(super) fn N_cycle8(&mut self, sb: &mut SysBus, addr: u32) {
+        self.cycles += sb.get_data_cycles(addr, NonSeq, MemoryAccess8) as u64;
     }
 
     #[inline]
@@ -360,21 +410,23 @@ impl Core {
 
     #[inline(always)]
     pub fn reload_pipeline16(&mut self, sb: &mut SysBus) {
+        sb.set_fetch_addr(self.pc);
         self.pipeline[0] = sb.read_16(self.pc) as u32;
-        self.N_cycle16(sb, self.pc);
+        self.N_fetch16(sb, self.pc);
         self.advance_thumb();
         self.pipeline[1] = sb.read_16(self.pc) as u32;
-        self.S_cycle16(sb, self.pc);
+        self.S_fetch16(sb, self.pc);
         self.advance_thumb();
     }
 
     #[inline(always)]
     pub fn reload_pipeline32(&mut self, sb: &mut SysBus) {
+        sb.set_fetch_addr(self.pc);
         self.pipeline[0] = sb.read_32(self.pc);
-        self.N_cycle16(sb, self.pc);
+        self.N_fetch32(sb, self.pc);
         self.advance_arm();
         self.pipeline[1] = sb.read_32(self.pc);
-        self.S_cycle16(sb, self.pc);
+        self.S_fetch32(sb, self.pc);
         self.advance_arm();
     }
 
@@ -388,10 +440,31 @@ impl Core {
         self.pc = self.pc.wrapping_add(4)
     }
 
+    /// The last prefetched opcode stays on the bus, and is what reads from unmapped memory return.
+    /// In THUMB state, which halfwords end up on the bus depends on the region the code runs from.
+    #[inline(always)]
+    fn latch_open_bus(&self, sb: &mut SysBus) {
+        let value = match self.cpsr.state() {
+            CpuState::ARM => self.pipeline[1],
+            CpuState::THUMB => {
+                // pipeline[1] was just fetched from pc, 4 bytes past the opcode about to execute
+                let (lo, hi) = match self.pc >> 24 {
+                    0x00 | 0x03 | 0x07 if self.pc & 2 != 0 => (self.pipeline[0], self.pipeline[1]),
+                    0x00 | 0x07 => (self.pipeline[1], sb.read_16(self.pc.wrapping_add(2)) as u32),
+                    0x03 => (self.pipeline[1], self.pipeline[0]),
+                    _ => (self.pipeline[1], self.pipeline[1]),
+                };
+                lo | hi << 16
+            }
+        };
+        sb.set_open_bus(value);
+    }
+
     /// Perform a pipeline step
     /// If an instruction was executed in this step, return it.
     pub fn step(&mut self, bus: &mut SysBus) {
         let pc = self.pc;
+        bus.set_fetch_addr(pc);
 
         match self.cpsr.state() {
             CpuState::ARM => {
@@ -399,11 +472,12 @@ impl Core {
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now;
+                self.latch_open_bus(bus);
                 let cond =
                     ArmCond::from_u32(insn.bit_range(28..32)).expect("invalid arm condition");
                 if cond != ArmCond::AL {
                     if !self.check_arm_cond(cond) {
-                        self.S_cycle32(bus, self.pc);
+                        self.S_fetch32(bus, self.pc);
                         self.advance_arm();
                         return;
                     }
@@ -418,6 +492,7 @@ impl Core {
                 let insn = self.pipeline[0];
                 self.pipeline[0] = self.pipeline[1];
                 self.pipeline[1] = fetched_now as u32;
+                self.latch_open_bus(bus);
                 match self.step_thumb_exec(insn as u16, bus) {
                     CpuAction::AdvancePC => self.advance_thumb(),
                     CpuAction::FlushPipeline => {}
@@ -1,3 +1,4 @@
+use super::super::bios;
 use super::super::sysbus::SysBus;
 use super::cpu::Core;
 use super::{CpuMode, CpuState};
@@ -66,11 +67,26 @@ impl Core {
         }
     }
 
-    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, _cmt: u32) {
+    pub fn software_interrupt(&mut self, sb: &mut SysBus, lr: u32, cmt: u32) {
         match self.cpsr.state() {
-            CpuState::ARM => self.N_cycle32(sb, self.pc),
-            CpuState::THUMB => self.N_cycle16(sb, self.pc),
+            CpuState::ARM => self.N_fetch32(sb, self.pc),
+            CpuState::THUMB => self.N_fetch16(sb, self.pc),
         };
+        if self.hle_bios {
+            // ARM swis keep the function number in the upper byte of the comment
+            let function = match self.cpsr.state() {
+                CpuState::ARM => (cmt >> 16) & 0xff,
+                CpuState::THUMB => cmt & 0xff,
+            };
+            if let Some(next_pc) = bios::hle_swi(self, sb, function, lr) {
+                self.pc = next_pc;
+                match self.cpsr.state() {
+                    CpuState::ARM => self.reload_pipeline32(sb),
+                    CpuState::THUMB => self.reload_pipeline16(sb),
+                };
+                return;
+            }
+        }
         self.exception(sb, Exception::SoftwareInterrupt, lr);
     }
 }
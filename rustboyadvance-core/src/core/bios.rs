@@ -0,0 +1,904 @@
+//! High-level emulation of the BIOS, for running games without a BIOS dump.
+//!
+//! A small synthetic BIOS image provides the exception vectors and the IRQ dispatcher,
+//! while the software interrupts are implemented natively by `hle_swi`.
+use std::f64::consts::PI;
+
+use bit::BitIndex;
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::arm7tdmi::Core;
+use super::iodev::consts::*;
+use super::iodev::HaltState;
+use super::sysbus::consts::*;
+use super::sysbus::{MemoryAccessType::*, MemoryAccessWidth::*, SysBus};
+use super::Bus;
+
+pub const BIOS_SIZE: usize = 0x4000;
+
+/// What `GetBiosChecksum` returns on a GBA
+const BIOS_CHECKSUM: u32 = 0xBAAE_187F;
+
+/// Where the BIOS keeps the interrupts acknowledged by the user IRQ handler, used by `IntrWait`
+const BIOS_IF: u32 = 0x0300_7FF8;
+/// `SoftReset` boots into EWRAM instead of ROM when this byte is non-zero
+const BIOS_RESET_FLAG: u32 = 0x0300_7FFA;
+
+/// What the BIOS leaves on the bus when it returns from a software interrupt
+const BIOS_OPCODE_AFTER_SWI: u32 = 0xE3A0_2004;
+
+/// The code of the synthetic BIOS, the IRQ dispatcher is placed at the same address as on the real one
+#[rustfmt::skip]
+const HLE_BIOS_CODE: &[(u32, u32)] = &[
+    (0x0000, 0xE3A0_F302), // reset:    mov pc, #0x08000000
+    (0x0004, 0xE1B0_F00E), // undef:    movs pc, lr
+    (0x0008, 0xE1B0_F00E), // swi:      movs pc, lr (only reached by the SWIs that are not emulated)
+    (0x000C, 0xE25E_F004), // pabt:     subs pc, lr, #4
+    (0x0010, 0xE25E_F004), // dabt:     subs pc, lr, #4
+    (0x0014, 0xE1B0_F00E), // reserved: movs pc, lr
+    (0x0018, 0xEA00_0042), // irq:      b 0x128
+    (0x001C, 0xE25E_F004), // fiq:      subs pc, lr, #4
+    (0x0128, 0xE92D_500F), // stmfd sp!, {r0-r3, r12, lr}
+    (0x012C, 0xE3A0_0301), // mov r0, #0x04000000
+    (0x0130, 0xE28F_E000), // add lr, pc, #0
+    (0x0134, 0xE510_F004), // ldr pc, [r0, #-4]
+    (0x0138, 0xE8BD_500F), // ldmfd sp!, {r0-r3, r12, lr}
+    (0x013C, 0xE25E_F004), // subs pc, lr, #4
+];
+
+/// Builds the BIOS image used in place of a real BIOS dump
+pub fn hle_bios_rom() -> Box<[u8]> {
+    let mut rom = vec![0; BIOS_SIZE];
+    for &(offset, insn) in HLE_BIOS_CODE {
+        LittleEndian::write_u32(&mut rom[offset as usize..], insn);
+    }
+    rom.into_boxed_slice()
+}
+
+/// Memory accesses done on behalf of the emulated BIOS, charging the cpu with their cycles
+struct Hle<'a> {
+    cpu: &'a mut Core,
+    sb: &'a mut SysBus,
+}
+
+impl<'a> Hle<'a> {
+    fn reg(&self, r: usize) -> u32 {
+        self.cpu.gpr[r]
+    }
+
+    fn set_reg(&mut self, r: usize, value: u32) {
+        self.cpu.gpr[r] = value;
+    }
+
+    fn read_8(&mut self, addr: u32) -> u8 {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess8) as u64;
+        self.sb.read_8(addr)
+    }
+
+    fn read_16(&mut self, addr: u32) -> u16 {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess16) as u64;
+        self.sb.read_16(addr)
+    }
+
+    fn read_32(&mut self, addr: u32) -> u32 {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess32) as u64;
+        self.sb.read_32(addr)
+    }
+
+    fn write_8(&mut self, addr: u32, value: u8) {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess8) as u64;
+        self.sb.write_8(addr, value);
+    }
+
+    fn write_16(&mut self, addr: u32, value: u16) {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess16) as u64;
+        self.sb.write_16(addr, value);
+    }
+
+    fn write_32(&mut self, addr: u32, value: u32) {
+        self.cpu.cycles += self.sb.get_cycles(addr, Seq, MemoryAccess32) as u64;
+        self.sb.write_32(addr, value);
+    }
+
+    fn fill(&mut self, addr: u32, len: u32) {
+        for ofs in (0..len).step_by(4) {
+            self.write_32(addr + ofs, 0);
+        }
+    }
+
+    /// Writes the output of a decompression function, VRAM can only be written 16 bits at a time
+    fn write_output(&mut self, dst: u32, data: &[u8], vram: bool) {
+        if vram {
+            for (i, chunk) in data.chunks(2).enumerate() {
+                let value = chunk[0] as u16 | (*chunk.get(1).unwrap_or(&0) as u16) << 8;
+                self.write_16(dst + 2 * i as u32, value);
+            }
+        } else {
+            for (i, byte) in data.iter().enumerate() {
+                self.write_8(dst + i as u32, *byte);
+            }
+        }
+    }
+
+    fn soft_reset(&mut self) -> u32 {
+        let boot_from_ewram = self.sb.read_8(BIOS_RESET_FLAG) != 0;
+        self.fill(0x0300_7E00, 0x200);
+        self.cpu.gpr = [0; 15];
+        self.cpu.skip_bios();
+        if boot_from_ewram {
+            EWRAM_ADDR
+        } else {
+            GAMEPAK_WS0_LO
+        }
+    }
+
+    fn register_ram_reset(&mut self, flags: u32) {
+        if flags.bit(0) {
+            self.fill(EWRAM_ADDR, 0x4_0000);
+        }
+        if flags.bit(1) {
+            // the last 0x200 bytes hold the stacks and the BIOS variables
+            self.fill(IWRAM_ADDR, 0x7E00);
+        }
+        if flags.bit(2) {
+            self.fill(PALRAM_ADDR, 0x400);
+        }
+        if flags.bit(3) {
+            self.fill(VRAM_ADDR, 0x1_8000);
+        }
+        if flags.bit(4) {
+            self.fill(OAM_ADDR, 0x400);
+        }
+        if flags.bit(5) {
+            for addr in (REG_SIOMULTI0..=REG_SIODATA8).step_by(2) {
+                self.write_16(addr, 0);
+            }
+            self.write_16(REG_RCNT, 0x8000);
+        }
+        if flags.bit(6) {
+            for addr in (REG_SOUND1CNT_L..REG_SOUNDCNT_X).step_by(2) {
+                self.write_16(addr, 0);
+            }
+            self.write_16(REG_SOUNDCNT_X, 0);
+        }
+        if flags.bit(7) {
+            for addr in (REG_DISPCNT..REG_SOUND1CNT_L).step_by(2) {
+                if addr != REG_VCOUNT {
+                    self.write_16(addr, 0);
+                }
+            }
+            for addr in (REG_DMA0SAD..=REG_TM3CNT_H).step_by(2) {
+                self.write_16(addr, 0);
+            }
+            self.write_16(REG_IE, 0);
+            self.write_16(REG_IF, 0xffff);
+            self.write_16(REG_WAITCNT, 0);
+            self.write_16(REG_IME, 0);
+            self.write_16(REG_DISPCNT, 0x80);
+            for &reg in &[REG_BG2PA, REG_BG2PD, REG_BG3PA, REG_BG3PD] {
+                self.write_16(reg, 0x100);
+            }
+        }
+    }
+
+    /// Returns true if the wait is over, false if the cpu was halted and the swi needs to run again
+    fn intr_wait(&mut self, discard_old: bool, flags: u16) -> bool {
+        let io = &mut self.sb.io;
+        io.intc.interrupt_master_enable = true;
+        io.intc.sync_irq_line(&mut io.scheduler);
+        let mut bios_if = self.sb.read_16(BIOS_IF);
+        // when woken up the swi runs again, but must not discard what woke it up
+        let discard_old = discard_old && !self.cpu.hle_intr_wait;
+        if discard_old {
+            bios_if &= !flags;
+            self.sb.write_16(BIOS_IF, bios_if);
+        }
+        if bios_if & flags != 0 {
+            self.sb.write_16(BIOS_IF, bios_if & !flags);
+            self.cpu.hle_intr_wait = false;
+            true
+        } else {
+            self.cpu.hle_intr_wait = true;
+            self.sb.io.haltcnt = HaltState::Halt;
+            false
+        }
+    }
+
+    fn div(&mut self, num: i32, denom: i32) {
+        if denom == 0 {
+            // the real BIOS would hang
+            warn!("hle: division by zero ({} / 0)", num);
+            self.set_reg(0, if num < 0 { -1i32 as u32 } else { 1 });
+            self.set_reg(1, num as u32);
+            self.set_reg(3, 1);
+            return;
+        }
+        let quotient = num.wrapping_div(denom);
+        self.set_reg(0, quotient as u32);
+        self.set_reg(1, num.wrapping_rem(denom) as u32);
+        self.set_reg(3, quotient.wrapping_abs() as u32);
+    }
+
+    /// The BIOS computes this with 32-bit arithmetic, and games rely on how it overflows
+    fn arctan(&mut self, tan: i32) -> i32 {
+        let a = (tan.wrapping_mul(tan) >> 14).wrapping_neg();
+        let mut b = (0xA9i32.wrapping_mul(a) >> 14).wrapping_add(0x390);
+        for &c in &[0x91C, 0xFB6, 0x16AA, 0x2081, 0x3651, 0xA2F9] {
+            b = (b.wrapping_mul(a) >> 14).wrapping_add(c);
+        }
+        self.set_reg(1, a as u32);
+        self.set_reg(3, b as u32);
+        tan.wrapping_mul(b) >> 16
+    }
+
+    fn arctan2(&mut self, x: i32, y: i32) -> u32 {
+        let angle = if y == 0 {
+            if x >= 0 {
+                0
+            } else {
+                0x8000
+            }
+        } else if x == 0 {
+            if y >= 0 {
+                0x4000
+            } else {
+                0xC000
+            }
+        } else if y >= 0 {
+            if x >= 0 && x >= y {
+                self.arctan((y << 14) / x)
+            } else if x < 0 && -x >= y {
+                self.arctan((y << 14) / x) + 0x8000
+            } else {
+                0x4000 - self.arctan((x << 14) / y)
+            }
+        } else {
+            if x <= 0 && -x > -y {
+                self.arctan((y << 14) / x) + 0x8000
+            } else if x > 0 && x >= -y {
+                self.arctan((y << 14) / x) + 0x10000
+            } else {
+                0xC000 - self.arctan((x << 14) / y)
+            }
+        };
+        (angle as u32) & 0xffff
+    }
+
+    fn cpu_set(&mut self, mut src: u32, mut dst: u32, control: u32) {
+        let count = control & 0x1F_FFFF;
+        let fill = control.bit(24);
+        if control.bit(26) {
+            let value = self.read_32(src & !3);
+            for _ in 0..count {
+                let value = if fill { value } else { self.read_32(src & !3) };
+                self.write_32(dst & !3, value);
+                src += 4;
+                dst += 4;
+            }
+        } else {
+            let value = self.read_16(src & !1);
+            for _ in 0..count {
+                let value = if fill { value } else { self.read_16(src & !1) };
+                self.write_16(dst & !1, value);
+                src += 2;
+                dst += 2;
+            }
+        }
+    }
+
+    fn cpu_fast_set(&mut self, src: u32, dst: u32, control: u32) {
+        // the count is rounded up to a multiple of 8 words
+        let count = ((control & 0x1F_FFFF) + 7) & !7;
+        let cpu_set_control = count | (control & (1 << 24)) | (1 << 26);
+        self.cpu_set(src, dst, cpu_set_control);
+    }
+
+    fn affine_params(sx: f64, sy: f64, theta: u16) -> (f64, f64, f64, f64) {
+        let theta = (theta >> 8) as f64 / 128.0 * PI;
+        let (sin, cos) = theta.sin_cos();
+        (cos * sx, -sin * sx, sin * sy, cos * sy)
+    }
+
+    fn bg_affine_set(&mut self, mut src: u32, mut dst: u32, count: u32) {
+        for _ in 0..count {
+            let ox = self.read_32(src) as i32 as f64 / 256.0;
+            let oy = self.read_32(src + 4) as i32 as f64 / 256.0;
+            let cx = self.read_16(src + 8) as i16 as f64;
+            let cy = self.read_16(src + 10) as i16 as f64;
+            let sx = self.read_16(src + 12) as i16 as f64 / 256.0;
+            let sy = self.read_16(src + 14) as i16 as f64 / 256.0;
+            let theta = self.read_16(src + 16);
+            src += 20;
+
+            let (pa, pb, pc, pd) = Hle::affine_params(sx, sy, theta);
+            let x = ox - (pa * cx + pb * cy);
+            let y = oy - (pc * cx + pd * cy);
+
+            self.write_16(dst, (pa * 256.0) as i16 as u16);
+            self.write_16(dst + 2, (pb * 256.0) as i16 as u16);
+            self.write_16(dst + 4, (pc * 256.0) as i16 as u16);
+            self.write_16(dst + 6, (pd * 256.0) as i16 as u16);
+            self.write_32(dst + 8, (x * 256.0) as i32 as u32);
+            self.write_32(dst + 12, (y * 256.0) as i32 as u32);
+            dst += 16;
+        }
+    }
+
+    fn obj_affine_set(&mut self, mut src: u32, mut dst: u32, count: u32, stride: u32) {
+        for _ in 0..count {
+            let sx = self.read_16(src) as i16 as f64 / 256.0;
+            let sy = self.read_16(src + 2) as i16 as f64 / 256.0;
+            let theta = self.read_16(src + 4);
+            src += 8;
+
+            let (pa, pb, pc, pd) = Hle::affine_params(sx, sy, theta);
+            for &param in &[pa, pb, pc, pd] {
+                self.write_16(dst, (param * 256.0) as i16 as u16);
+                dst += stride;
+            }
+        }
+    }
+
+    fn bit_unpack(&mut self, mut src: u32, mut dst: u32, info: u32) {
+        let src_len = self.read_16(info) as u32;
+        let src_width = self.read_8(info + 2) as u32;
+        let dst_width = self.read_8(info + 3) as u32;
+        let data_offset = self.read_32(info + 4);
+        let zero_data = data_offset.bit(31);
+        let data_offset = data_offset & 0x7FFF_FFFF;
+
+        if ![1, 2, 4, 8].contains(&src_width) || ![1, 2, 4, 8, 16, 32].contains(&dst_width) {
+            warn!("hle: invalid BitUnPack widths {}->{}", src_width, dst_width);
+            return;
+        }
+
+        let src_mask = (1u32 << src_width) - 1;
+        let mut acc = 0u32;
+        let mut acc_bits = 0;
+        for _ in 0..src_len {
+            let byte = self.read_8(src) as u32;
+            src += 1;
+            for shift in (0..8).step_by(src_width as usize) {
+                let mut value = (byte >> shift) & src_mask;
+                if value != 0 || zero_data {
+                    value = value.wrapping_add(data_offset);
+                }
+                acc |= value << acc_bits;
+                acc_bits += dst_width;
+                if acc_bits == 32 {
+                    self.write_32(dst, acc);
+                    dst += 4;
+                    acc = 0;
+                    acc_bits = 0;
+                }
+            }
+        }
+    }
+
+    fn lz77_uncomp(&mut self, mut src: u32, dst: u32, vram: bool) {
+        let size = (self.read_32(src) >> 8) as usize;
+        src += 4;
+        let mut out = Vec::with_capacity(size);
+        while out.len() < size {
+            let flags = self.read_8(src);
+            src += 1;
+            for i in (0..8).rev() {
+                if out.len() >= size {
+                    break;
+                }
+                if flags.bit(i) {
+                    let b0 = self.read_8(src) as usize;
+                    let b1 = self.read_8(src + 1) as usize;
+                    src += 2;
+                    let len = (b0 >> 4) + 3;
+                    let disp = (((b0 & 0xf) << 8) | b1) + 1;
+                    for _ in 0..len {
+                        let value = if disp <= out.len() {
+                            out[out.len() - disp]
+                        } else {
+                            0
+                        };
+                        out.push(value);
+                    }
+                } else {
+                    out.push(self.read_8(src));
+                    src += 1;
+                }
+            }
+        }
+        out.truncate(size);
+        self.write_output(dst, &out, vram);
+    }
+
+    fn huff_uncomp(&mut self, src: u32, mut dst: u32) {
+        let header = self.read_32(src);
+        let data_bits = header & 0xf;
+        let size = header >> 8;
+        if data_bits != 4 && data_bits != 8 {
+            warn!("hle: invalid Huffman data size {}", data_bits);
+            return;
+        }
+        let tree_size = self.read_8(src + 4) as u32;
+        let root = src + 5;
+        let mut stream = src + 4 + (tree_size + 1) * 2;
+
+        let mut node_addr = root;
+        let mut node = self.read_8(root);
+        let mut acc = 0u32;
+        let mut acc_bits = 0;
+        let mut written = 0;
+        while written < size {
+            let word = self.read_32(stream);
+            stream += 4;
+            for bit in (0..32).rev() {
+                let right = word.bit(bit);
+                let child = (node_addr & !1) + (node as u32 & 0x3f) * 2 + 2 + right as u32;
+                let is_data = if right { node.bit(6) } else { node.bit(7) };
+                if is_data {
+                    let value = self.read_8(child) as u32 & ((1 << data_bits) - 1);
+                    acc |= value << acc_bits;
+                    acc_bits += data_bits;
+                    node_addr = root;
+                    node = self.read_8(root);
+                    if acc_bits == 32 {
+                        self.write_32(dst, acc);
+                        dst += 4;
+                        written += 4;
+                        acc = 0;
+                        acc_bits = 0;
+                        if written >= size {
+                            break;
+                        }
+                    }
+                } else {
+                    node_addr = child;
+                    node = self.read_8(child);
+                }
+            }
+        }
+    }
+
+    fn rl_uncomp(&mut self, mut src: u32, dst: u32, vram: bool) {
+        let size = (self.read_32(src) >> 8) as usize;
+        src += 4;
+        let mut out = Vec::with_capacity(size);
+        while out.len() < size {
+            let flag = self.read_8(src);
+            src += 1;
+            if flag.bit(7) {
+                let len = (flag & 0x7f) as usize + 3;
+                let value = self.read_8(src);
+                src += 1;
+                out.extend(std::iter::repeat(value).take(len));
+            } else {
+                let len = (flag & 0x7f) as usize + 1;
+                for _ in 0..len {
+                    out.push(self.read_8(src));
+                    src += 1;
+                }
+            }
+        }
+        out.truncate(size);
+        self.write_output(dst, &out, vram);
+    }
+
+    fn diff8_unfilter(&mut self, src: u32, dst: u32, vram: bool) {
+        let size = (self.read_32(src) >> 8) as usize;
+        let mut out = Vec::with_capacity(size);
+        let mut value = 0u8;
+        for i in 0..size {
+            value = value.wrapping_add(self.read_8(src + 4 + i as u32));
+            out.push(value);
+        }
+        self.write_output(dst, &out, vram);
+    }
+
+    fn diff16_unfilter(&mut self, src: u32, dst: u32) {
+        let size = self.read_32(src) >> 8;
+        let mut value = 0u16;
+        for ofs in (0..size).step_by(2) {
+            value = value.wrapping_add(self.read_16(src + 4 + ofs));
+            self.write_16(dst + ofs, value);
+        }
+    }
+
+    /// Runs a software interrupt. Returns where execution should continue,
+    /// or None if the function is not emulated.
+    fn swi(&mut self, function: u32, return_addr: u32) -> Option<u32> {
+        let (r0, r1, r2, r3) = (self.reg(0), self.reg(1), self.reg(2), self.reg(3));
+        let swi_addr = return_addr - self.cpu.word_size() as u32;
+        match function {
+            0x00 => return Some(self.soft_reset()),
+            0x01 => self.register_ram_reset(r0),
+            0x02 => self.sb.io.haltcnt = HaltState::Halt,
+            0x03 => self.sb.io.haltcnt = HaltState::Stop,
+            0x04 => {
+                if !self.intr_wait(r0 != 0, r1 as u16) {
+                    return Some(swi_addr);
+                }
+            }
+            0x05 => {
+                self.set_reg(0, 1);
+                self.set_reg(1, 1);
+                if !self.intr_wait(true, 1) {
+                    return Some(swi_addr);
+                }
+            }
+            0x06 => self.div(r0 as i32, r1 as i32),
+            0x07 => self.div(r1 as i32, r0 as i32),
+            0x08 => self.set_reg(0, (r0 as f64).sqrt() as u32),
+            0x09 => {
+                let result = self.arctan(r0 as i16 as i32);
+                self.set_reg(0, result as u32);
+            }
+            0x0A => {
+                let result = self.arctan2(r0 as i16 as i32, r1 as i16 as i32);
+                self.set_reg(0, result);
+            }
+            0x0B => self.cpu_set(r0, r1, r2),
+            0x0C => self.cpu_fast_set(r0, r1, r2),
+            0x0D => self.set_reg(0, BIOS_CHECKSUM),
+            0x0E => self.bg_affine_set(r0, r1, r2),
+            0x0F => self.obj_affine_set(r0, r1, r2, r3),
+            0x10 => self.bit_unpack(r0, r1, r2),
+            0x11 => self.lz77_uncomp(r0, r1, false),
+            0x12 => self.lz77_uncomp(r0, r1, true),
+            0x13 => self.huff_uncomp(r0, r1),
+            0x14 => self.rl_uncomp(r0, r1, false),
+            0x15 => self.rl_uncomp(r0, r1, true),
+            0x16 => self.diff8_unfilter(r0, r1, false),
+            0x17 => self.diff8_unfilter(r0, r1, true),
+            0x18 => self.diff16_unfilter(r0, r1),
+            0x19 => {
+                let bias = self.sb.read_16(REG_SOUNDBIAS) & !0x3ff;
+                let level = if r0 != 0 { 0x200 } else { 0 };
+                self.sb.write_16(REG_SOUNDBIAS, bias | level);
+            }
+            // MultiBoot, fail since there is no one to talk to
+            0x25 => self.set_reg(0, 1),
+            0x27 => {
+                self.sb.io.haltcnt = if r2.bit(7) {
+                    HaltState::Stop
+                } else {
+                    HaltState::Halt
+                }
+            }
+            _ => return None,
+        }
+        Some(return_addr)
+    }
+}
+
+/// Handles a software interrupt without going through the BIOS.
+/// Returns the address execution should continue at, or None if the exception should be taken as usual.
+pub fn hle_swi(cpu: &mut Core, sb: &mut SysBus, function: u32, return_addr: u32) -> Option<u32> {
+    trace!("hle: swi {:#04x}", function);
+    let next_pc = Hle { cpu, sb }.swi(function, return_addr);
+    match next_pc {
+        Some(_) => sb.set_bios_open_bus(BIOS_OPCODE_AFTER_SWI),
+        None => warn!("hle: swi {:#04x} is not implemented", function),
+    }
+    next_pc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cartridge::GamepakBuilder;
+    use crate::core::gpu::Gpu;
+    use crate::core::iodev::IoDevices;
+    use crate::core::sched::Scheduler;
+    use crate::core::sound::{ResamplerType, SoundController};
+
+    fn make_sysbus() -> Box<SysBus> {
+        let cartridge = GamepakBuilder::new()
+            .buffer(&[0; 0x200])
+            .with_sram()
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+        let mut scheduler = Scheduler::new();
+        let gpu = Box::new(Gpu::new(&mut scheduler));
+        let sound_controller = Box::new(SoundController::new(
+            &mut scheduler,
+            44100.0,
+            ResamplerType::default(),
+        ));
+        let io = IoDevices::new(scheduler, gpu, sound_controller);
+        Box::new(SysBus::new(io, hle_bios_rom(), cartridge))
+    }
+
+    #[test]
+    fn test_hle_div_and_lz77() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        cpu.gpr[0] = -7i32 as u32;
+        cpu.gpr[1] = 2;
+        assert_eq!(
+            hle_swi(&mut cpu, &mut sb, 0x06, 0x0800_0004),
+            Some(0x0800_0004)
+        );
+        assert_eq!(cpu.gpr[0] as i32, -3);
+        assert_eq!(cpu.gpr[1] as i32, -1);
+        assert_eq!(cpu.gpr[3], 3);
+
+        // "ABCABCABCD": 3 literals, a back-reference of 6 bytes 3 bytes back, and a literal
+        let compressed = [0x10, 10, 0, 0, 0x10, b'A', b'B', b'C', 0x30, 0x02, b'D'];
+        for (i, byte) in compressed.iter().enumerate() {
+            sb.write_8(0x0200_0000 + i as u32, *byte);
+        }
+        cpu.gpr[0] = 0x0200_0000;
+        cpu.gpr[1] = 0x0200_0100;
+        hle_swi(&mut cpu, &mut sb, 0x11, 0x0800_0004);
+        let output: Vec<u8> = (0..10).map(|i| sb.read_8(0x0200_0100 + i)).collect();
+        assert_eq!(&output, b"ABCABCABCD");
+    }
+
+    fn write_bytes(sb: &mut SysBus, addr: u32, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            sb.write_8(addr + i as u32, *byte);
+        }
+    }
+
+    fn read_bytes(sb: &mut SysBus, addr: u32, len: u32) -> Vec<u8> {
+        (0..len).map(|i| sb.read_8(addr + i)).collect()
+    }
+
+    /// Runs the swi with r0-r3 set to `args`, and returns r0, r1 and r3
+    fn call(cpu: &mut Core, sb: &mut SysBus, function: u32, args: [u32; 4]) -> (u32, u32, u32) {
+        cpu.gpr[..4].copy_from_slice(&args);
+        assert_eq!(hle_swi(cpu, sb, function, 0x0800_0004), Some(0x0800_0004));
+        (cpu.gpr[0], cpu.gpr[1], cpu.gpr[3])
+    }
+
+    #[test]
+    fn test_hle_arctan_large_input() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        // the intermediate products overflow 32 bits, so the angles are far off
+        assert_eq!(
+            call(&mut cpu, &mut sb, 0x09, [0x7fff, 0, 0, 0]),
+            (0x16d8, 0xffff_0004, 0x2_2db6)
+        );
+        assert_eq!(
+            call(&mut cpu, &mut sb, 0x09, [0xffff_8000, 0, 0, 0]),
+            (0xffff_e95d, 0xffff_0000, 0x2_2d45)
+        );
+        // tan 45 degrees
+        assert_eq!(
+            call(&mut cpu, &mut sb, 0x09, [0x4000, 0, 0, 0]),
+            (0x2000, 0xffff_c000, 0x8000)
+        );
+    }
+
+    #[test]
+    fn test_hle_arctan2_quadrants() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        let cases: &[(i32, i32, u32)] = &[
+            (0x100, 0, 0),
+            (-0x100, 0, 0x8000),
+            (0, 0x100, 0x4000),
+            (0, -0x100, 0xc000),
+            (0x100, 0x100, 0x2000),
+            (-0x100, 0x100, 0x6000),
+            (-0x100, -0x100, 0xa000),
+            (0x100, -0x100, 0xe000),
+            (0x100, 0x40, 0x9fb),
+            (-3, -0x100, 0xbf86),
+        ];
+        for &(x, y, angle) in cases {
+            let (r0, _, _) = call(&mut cpu, &mut sb, 0x0a, [x as u32, y as u32, 0, 0]);
+            assert_eq!(r0, angle, "ArcTan2({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn test_hle_sqrt() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        for &(value, root) in &[
+            (0, 0),
+            (1_000_000, 1000),
+            (1_000_001, 1000),
+            (0xffff_ffff, 0xffff),
+        ] {
+            assert_eq!(call(&mut cpu, &mut sb, 0x08, [value, 0, 0, 0]).0, root);
+        }
+    }
+
+    #[test]
+    fn test_hle_cpu_set_and_cpu_fast_set() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+        let src = 0x0200_0000;
+        let dst = 0x0200_0100;
+        let data: Vec<u8> = (1..=64).collect();
+        write_bytes(&mut sb, src, &data);
+
+        // copy 3 halfwords
+        call(&mut cpu, &mut sb, 0x0b, [src, dst, 3, 0]);
+        assert_eq!(read_bytes(&mut sb, dst, 8), [1, 2, 3, 4, 5, 6, 0, 0]);
+
+        // fill 2 words with the first one
+        call(
+            &mut cpu,
+            &mut sb,
+            0x0b,
+            [src, dst, 2 | 1 << 24 | 1 << 26, 0],
+        );
+        assert_eq!(read_bytes(&mut sb, dst, 8), [1, 2, 3, 4, 1, 2, 3, 4]);
+
+        // CpuFastSet copies whole blocks of 8 words
+        call(&mut cpu, &mut sb, 0x0c, [src, dst, 3, 0]);
+        assert_eq!(read_bytes(&mut sb, dst, 36), {
+            let mut expected = data[..32].to_vec();
+            expected.extend_from_slice(&[0; 4]);
+            expected
+        });
+
+        // and fills them too
+        call(&mut cpu, &mut sb, 0x0c, [src + 4, dst, 1 | 1 << 24, 0]);
+        assert_eq!(read_bytes(&mut sb, dst, 32), [5, 6, 7, 8].repeat(8));
+    }
+
+    #[test]
+    fn test_hle_affine_set() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+        let src = 0x0200_0000;
+        let dst = 0x0200_0100;
+
+        // origin (64, 32), center (10, 20), scale 1 and 1 and rotated by 0 and by 90 degrees
+        for (i, &theta) in [0u16, 0x4000].iter().enumerate() {
+            let entry = src + i as u32 * 20;
+            sb.write_32(entry, 64 << 8);
+            sb.write_32(entry + 4, 32 << 8);
+            sb.write_16(entry + 8, 10);
+            sb.write_16(entry + 10, 20);
+            sb.write_16(entry + 12, 0x100);
+            sb.write_16(entry + 14, 0x100);
+            sb.write_16(entry + 16, theta);
+        }
+        call(&mut cpu, &mut sb, 0x0e, [src, dst, 2, 0]);
+        let params = |sb: &mut SysBus, entry: u32| {
+            let pa_to_pd: Vec<i16> = (0..4).map(|i| sb.read_16(entry + i * 2) as i16).collect();
+            let x = sb.read_32(entry + 8) as i32;
+            let y = sb.read_32(entry + 12) as i32;
+            (pa_to_pd, x, y)
+        };
+        assert_eq!(
+            params(&mut sb, dst),
+            (vec![0x100, 0, 0, 0x100], 54 << 8, 12 << 8)
+        );
+        assert_eq!(
+            params(&mut sb, dst + 16),
+            (vec![0, -0x100, 0x100, 0], (64 + 20) << 8, (32 - 10) << 8)
+        );
+
+        // scale 2 horizontally and 1/2 vertically, written 8 bytes apart like in OAM
+        sb.write_16(src, 0x200);
+        sb.write_16(src + 2, 0x80);
+        sb.write_16(src + 4, 0);
+        call(&mut cpu, &mut sb, 0x0f, [src, dst, 1, 8]);
+        let pa_to_pd: Vec<u16> = (0..4).map(|i| sb.read_16(dst + i * 8)).collect();
+        assert_eq!(pa_to_pd, [0x200, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn test_hle_rl_uncomp_round_trip() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        fn flush_literals(out: &mut Vec<u8>, literals: &mut Vec<u8>) {
+            for chunk in literals.chunks(128) {
+                out.push(chunk.len() as u8 - 1);
+                out.extend_from_slice(chunk);
+            }
+            literals.clear();
+        }
+
+        fn rl_comp(data: &[u8]) -> Vec<u8> {
+            let mut out = vec![0x30, data.len() as u8, (data.len() >> 8) as u8, 0];
+            let mut literals = vec![];
+            let mut i = 0;
+            while i < data.len() {
+                let run = data[i..]
+                    .iter()
+                    .take(130)
+                    .take_while(|&&byte| byte == data[i])
+                    .count();
+                if run >= 3 {
+                    flush_literals(&mut out, &mut literals);
+                    out.extend_from_slice(&[0x80 | (run - 3) as u8, data[i]]);
+                    i += run;
+                } else {
+                    literals.push(data[i]);
+                    i += 1;
+                }
+            }
+            flush_literals(&mut out, &mut literals);
+            out
+        }
+
+        // runs and literal blocks longer than a single flag byte can hold
+        let mut data = vec![0xaa; 200];
+        data.extend((0..150).map(|i| i as u8));
+        data.extend_from_slice(b"xyzzz");
+        let compressed = rl_comp(&data);
+        write_bytes(&mut sb, 0x0200_0000, &compressed);
+
+        call(&mut cpu, &mut sb, 0x14, [0x0200_0000, 0x0200_1000, 0, 0]);
+        assert_eq!(read_bytes(&mut sb, 0x0200_1000, data.len() as u32), data);
+    }
+
+    #[test]
+    fn test_hle_huff_uncomp_round_trip() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+        let src = 0x0200_0000;
+
+        // A full tree 3 levels deep, where each symbol's code is its index. Laid out breadth first, node i is at
+        // src + 5 + i and its children are at src + 6 + 2 * i, so the offsets also depend on whether the node is
+        // at an odd address.
+        let symbols = b"ABCDEFGH";
+        let mut compressed = vec![0x28, 16, 0, 0, 7];
+        for i in 0..7 {
+            let node_addr = 5 + i;
+            let offset = (6 + 2 * i - (node_addr & !1) - 2) / 2;
+            let data_children = if i >= 3 { 0xc0 } else { 0 };
+            compressed.push(offset as u8 | data_children);
+        }
+        compressed.extend_from_slice(symbols);
+        assert_eq!(compressed.len(), 20);
+
+        let data = b"FACEBEADDEADBEEF";
+        let mut bits = 0u64;
+        for byte in data {
+            let code = symbols.iter().position(|s| s == byte).unwrap() as u64;
+            bits = bits << 3 | code;
+        }
+        // 16 symbols of 3 bits, in the high bits of two words
+        let bits = bits << 16;
+        compressed.extend_from_slice(&((bits >> 32) as u32).to_le_bytes());
+        compressed.extend_from_slice(&(bits as u32).to_le_bytes());
+        write_bytes(&mut sb, src, &compressed);
+
+        call(&mut cpu, &mut sb, 0x13, [src, 0x0200_1000, 0, 0]);
+        assert_eq!(read_bytes(&mut sb, 0x0200_1000, 16), &data[..]);
+    }
+
+    #[test]
+    fn test_hle_diff_unfilter() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+
+        write_bytes(&mut sb, 0x0200_0000, &[0x81, 5, 0, 0, 1, 1, 1, 0xff, 0x10]);
+        call(&mut cpu, &mut sb, 0x16, [0x0200_0000, 0x0200_1000, 0, 0]);
+        assert_eq!(read_bytes(&mut sb, 0x0200_1000, 5), [1, 2, 3, 2, 0x12]);
+
+        sb.write_32(0x0200_0000, 0x82 | 6 << 8);
+        for (i, &delta) in [0x100u16, 0x100, 0xffff].iter().enumerate() {
+            sb.write_16(0x0200_0004 + i as u32 * 2, delta);
+        }
+        call(&mut cpu, &mut sb, 0x18, [0x0200_0000, 0x0200_1000, 0, 0]);
+        let output: Vec<u16> = (0..3).map(|i| sb.read_16(0x0200_1000 + i * 2)).collect();
+        assert_eq!(output, [0x100, 0x200, 0x1ff]);
+    }
+
+    #[test]
+    fn test_hle_swi_leaves_bios_opcode_on_the_bus() {
+        let mut cpu = Core::new();
+        let mut sb = make_sysbus();
+        sb.set_fetch_addr(0x0800_0000);
+
+        cpu.gpr[0] = 4;
+        cpu.gpr[1] = 2;
+        hle_swi(&mut cpu, &mut sb, 0x06, 0x0800_0004);
+        assert_eq!(sb.read_32(0), BIOS_OPCODE_AFTER_SWI);
+    }
+}
@@ -20,7 +20,12 @@ pub struct BackupFile {
 
 impl Clone for BackupFile {
     fn clone(&self) -> Self {
-        BackupFile::new(self.size, self.path.clone())
+        BackupFile {
+            size: self.size,
+            path: self.path.clone(),
+            file: self.file.as_ref().and_then(|file| file.try_clone().ok()),
+            buffer: self.buffer.clone(),
+        }
     }
 }
 
@@ -29,9 +34,10 @@ impl Serialize for BackupFile {
     where
         S: Serializer,
     {
+        // the save file belongs to whoever runs the game, so its path is not part of the state
         let mut state = serializer.serialize_struct("BackupFile", 2)?;
         state.serialize_field("size", &self.size)?;
-        state.serialize_field("path", &self.path)?;
+        state.serialize_field("buffer", &self.buffer)?;
         state.end()
     }
 }
@@ -57,14 +63,26 @@ impl<'de> Deserialize<'de> for BackupFile {
                 let size = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let path: Option<PathBuf> = seq
+                let buffer: Vec<u8> = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                Ok(BackupFile::new(size, path))
+                if buffer.len() != size {
+                    return Err(de::Error::invalid_length(
+                        buffer.len(),
+                        &"a buffer of `size` bytes",
+                    ));
+                }
+                // The save file is left alone until the restore goes through, see `reopen`
+                Ok(BackupFile {
+                    size,
+                    path: None,
+                    file: None,
+                    buffer,
+                })
             }
         }
 
-        const FIELDS: &'static [&'static str] = &["size", "path"];
+        const FIELDS: &'static [&'static str] = &["size", "buffer"];
         deserializer.deserialize_struct("BackupFile", FIELDS, BackupFileVisitor)
     }
 }
@@ -103,6 +121,37 @@ impl BackupFile {
         }
     }
 
+    /// Opens the save file given for a backup restored from a savestate, and overwrites it with the restored contents
+    pub fn reopen(&mut self, path: Option<PathBuf>) {
+        self.file = path.as_ref().and_then(|path| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .map_err(|e| warn!("could not open save file {:?}: {}", path, e))
+                .ok()
+        });
+        self.path = path;
+        self.flush();
+    }
+
+    /// Takes over the save file of the backup being replaced by this restored one,
+    /// and brings the file up to date if the contents differ
+    pub fn take_file_from(&mut self, other: &mut BackupFile) {
+        self.path = other.path.take();
+        self.file = other.file.take();
+        if self.buffer != other.buffer {
+            if let Some(file) = &mut self.file {
+                // the contents are still written over the start of the file
+                if let Err(e) = file.set_len(self.size as u64) {
+                    warn!("could not resize save file {:?}: {}", self.path, e);
+                }
+            }
+            self.flush();
+        }
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.buffer
     }
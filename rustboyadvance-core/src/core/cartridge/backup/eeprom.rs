@@ -5,6 +5,7 @@ use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
 
 use std::cell::RefCell;
+use std::cmp;
 use std::fs;
 use std::path::PathBuf;
 
@@ -74,7 +75,7 @@ impl Default for SpiState {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EepromChip {
-    memory: BackupFile,
+    pub(in crate) memory: BackupFile,
     addr_bits: EepromAddressBits,
 
     state: SpiState,
@@ -319,6 +320,35 @@ impl EepromController {
         }
     }
 
+    pub fn size(&self) -> usize {
+        self.chip.borrow().memory.bytes().len()
+    }
+
+    /// The size the memory ends up with, it takes the larger size until the game shows which one it is
+    pub fn size_bound(&self) -> usize {
+        if self.detect {
+            EepromType::Eeprom8k.size()
+        } else {
+            self.size()
+        }
+    }
+
+    /// Fills the memory with save data kept by the frontend. Until the size is detected the memory
+    /// keeps all of it, so the data of a large EEPROM is still there once the game shows which one it is.
+    pub fn load(&mut self, data: &[u8]) {
+        let chip = self.chip.get_mut();
+        if self.detect {
+            let size = cmp::min(data.len(), EepromType::Eeprom8k.size());
+            if size > chip.memory.bytes().len() {
+                chip.memory.resize(size);
+            }
+        }
+        let memory = chip.memory.bytes_mut();
+        let len = cmp::min(memory.len(), data.len());
+        memory[..len].copy_from_slice(&data[..len]);
+        chip.memory.flush();
+    }
+
     pub fn write_half(&mut self, address: u32, value: u16) {
         assert!(!self.detect);
         self.chip.borrow_mut().clock_data_in(address, value as u8);
@@ -520,4 +550,23 @@ mod tests {
             assert_eq!(0, chip.tx_count);
         }
     }
+
+    #[test]
+    fn test_loaded_data_outlives_size_detection() {
+        let data: Vec<u8> = (0..0x2000).map(|i| i as u8).collect();
+
+        let mut spi = EepromController::new(None);
+        assert_eq!(spi.size_bound(), 0x2000);
+        spi.load(&data);
+        // Read(11) + 14bit address + stop bit
+        spi.on_dma3_transfer(0x0300_0000, EEPROM_BASE_ADDR, 17);
+        assert_eq!(spi.chip.borrow().memory.bytes(), &data[..]);
+
+        let mut spi = EepromController::new(None);
+        spi.load(&data);
+        // Read(11) + 6bit address + stop bit
+        spi.on_dma3_transfer(0x0300_0000, EEPROM_BASE_ADDR, 9);
+        assert_eq!(spi.size_bound(), 0x200);
+        assert_eq!(spi.chip.borrow().memory.bytes(), &data[..0x200]);
+    }
 }
@@ -56,7 +56,7 @@ pub struct Flash {
     mode: FlashMode,
     bank: usize,
 
-    memory: BackupFile,
+    pub(crate) memory: BackupFile,
 }
 
 const MACRONIX_64K_CHIP_ID: u16 = 0x1CC2;
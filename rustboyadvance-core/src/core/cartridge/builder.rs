@@ -10,9 +10,12 @@ use super::super::{GBAError, GBAResult};
 use super::backup::eeprom::*;
 use super::backup::flash::*;
 use super::backup::{BackupFile, BackupType};
+use super::gpio::Gpio;
 use super::header;
+use super::rtc::{Rtc, RtcTimeSource};
+use super::sensors::{Gyro, SolarSensor, TiltSensor};
 use super::BackupMedia;
-use super::Cartridge;
+use super::{rom_checksum, Cartridge, CartridgeHardware};
 
 use crate::util::read_bin_file;
 
@@ -23,6 +26,9 @@ pub struct GamepakBuilder {
     save_path: Option<PathBuf>,
     save_type: BackupType,
     create_backup_file: bool,
+    hardware: Option<CartridgeHardware>,
+    rtc: Option<bool>,
+    rtc_time_source: RtcTimeSource,
 }
 
 impl GamepakBuilder {
@@ -33,6 +39,9 @@ impl GamepakBuilder {
             save_path: None,
             bytes: None,
             create_backup_file: true,
+            hardware: None,
+            rtc: None,
+            rtc_time_source: RtcTimeSource::Host,
         }
     }
 
@@ -86,6 +95,28 @@ impl GamepakBuilder {
         self
     }
 
+    /// Overrides the extra hardware otherwise detected from the game code
+    pub fn hardware(mut self, hardware: CartridgeHardware) -> Self {
+        self.hardware = Some(hardware);
+        self
+    }
+
+    /// Forces the cartridge to have a real-time clock, regardless of the game code
+    pub fn with_rtc(mut self) -> Self {
+        self.rtc = Some(true);
+        self
+    }
+
+    pub fn without_rtc(mut self) -> Self {
+        self.rtc = Some(false);
+        self
+    }
+
+    pub fn rtc_time_source(mut self, source: RtcTimeSource) -> Self {
+        self.rtc_time_source = source;
+        self
+    }
+
     pub fn build(mut self) -> GBAResult<Cartridge> {
         let bytes = if let Some(bytes) = self.bytes {
             Ok(bytes)
@@ -120,14 +151,59 @@ impl GamepakBuilder {
             }
         }
 
+        let mut hardware = self
+            .hardware
+            .unwrap_or_else(|| detect_hardware(&header.game_code));
+        if let Some(rtc) = self.rtc {
+            hardware.set(CartridgeHardware::RTC, rtc);
+        }
+        if !hardware.is_empty() {
+            info!("Cartridge hardware: {:?}", hardware);
+        }
+
+        let rtc = if hardware.contains(CartridgeHardware::RTC) {
+            let rtc_path = self
+                .save_path
+                .as_ref()
+                .map(|path| path.with_extension(RTC_FILE_EXT));
+            Some(Rtc::new(self.rtc_time_source, rtc_path))
+        } else {
+            None
+        };
+        let solar_sensor = if hardware.contains(CartridgeHardware::SOLAR_SENSOR) {
+            Some(SolarSensor::default())
+        } else {
+            None
+        };
+        let gyro = if hardware.contains(CartridgeHardware::GYRO) {
+            Some(Gyro::default())
+        } else {
+            None
+        };
+        let rumble = hardware.contains(CartridgeHardware::RUMBLE);
+        let gpio = if rtc.is_some() || solar_sensor.is_some() || gyro.is_some() || rumble {
+            Some(Gpio::new(rtc, solar_sensor, gyro, rumble))
+        } else {
+            None
+        };
+        let tilt_sensor = if hardware.contains(CartridgeHardware::TILT_SENSOR) {
+            Some(TiltSensor::default())
+        } else {
+            None
+        };
+
         let backup = create_backup(self.save_type, self.save_path);
 
         let size = bytes.len();
+        let checksum = rom_checksum(&bytes);
         Ok(Cartridge {
             header: header,
             bytes: bytes,
             size: size,
+            checksum: checksum,
             backup: backup,
+            gpio: gpio,
+            tilt_sensor: tilt_sensor,
         })
     }
 }
@@ -150,6 +226,31 @@ fn create_backup(backup_type: BackupType, rom_path: Option<PathBuf>) -> BackupMe
     }
 }
 
+pub(super) const RTC_FILE_EXT: &'static str = "rtc";
+fn detect_hardware(game_code: &str) -> CartridgeHardware {
+    use CartridgeHardware as Hw;
+    let hardware_game_codes = [
+        ("AXV", Hw::RTC),                    // Pokemon Ruby
+        ("AXP", Hw::RTC),                    // Pokemon Sapphire
+        ("BPE", Hw::RTC),                    // Pokemon Emerald
+        ("BR4", Hw::RTC),                    // Rockman EXE 4.5
+        ("BKA", Hw::RTC),                    // Sennen Kazoku
+        ("U3I", Hw::RTC | Hw::SOLAR_SENSOR), // Boktai
+        ("U32", Hw::RTC | Hw::SOLAR_SENSOR), // Boktai 2
+        ("U33", Hw::RTC | Hw::SOLAR_SENSOR), // Boktai 3
+        ("KHP", Hw::TILT_SENSOR),            // Koro Koro Puzzle
+        ("KYG", Hw::TILT_SENSOR),            // Yoshi Topsy-Turvy
+        ("RZW", Hw::GYRO | Hw::RUMBLE),      // WarioWare Twisted
+        ("V49", Hw::RUMBLE),                 // Drill Dozer
+    ];
+    hardware_game_codes
+        .iter()
+        .filter(|(code, _)| game_code.starts_with(code))
+        .fold(CartridgeHardware::empty(), |acc, (_, hardware)| {
+            acc | *hardware
+        })
+}
+
 fn detect_backup_type(bytes: &[u8]) -> Option<BackupType> {
     const ID_STRINGS: &'static [&'static str] =
         &["EEPROM", "SRAM", "FLASH_", "FLASH512_", "FLASH1M_"];
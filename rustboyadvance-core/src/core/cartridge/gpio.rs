@@ -0,0 +1,177 @@
+//! The 4-bit general purpose I/O port found on some cartridges, mapped over the ROM at 0x080000C4-0x080000C9
+use std::sync::{Arc, Mutex};
+
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::super::interrupt::IrqBitmask;
+use super::rtc::Rtc;
+use super::sensors::{Gyro, SolarSensor};
+use crate::SensorInterface;
+
+pub const GPIO_PORT_DATA: u32 = 0x0800_00C4;
+pub const GPIO_PORT_DIRECTION: u32 = 0x0800_00C6;
+pub const GPIO_PORT_CONTROL: u32 = 0x0800_00C8;
+
+const GPIO_PIN_MASK: u8 = 0b1111;
+
+type SensorDevice = Arc<Mutex<dyn SensorInterface>>;
+
+#[derive(Serialize, Deserialize, Clone, DebugStub)]
+pub struct Gpio {
+    /// Pin state last written by the game
+    data: u8,
+    /// A set bit means the pin is an output of the GBA
+    direction: u8,
+    /// The port is write-only unless this is set, reads return the ROM contents instead
+    readable: bool,
+
+    rtc: Option<Rtc>,
+    solar_sensor: Option<SolarSensor>,
+    gyro: Option<Gyro>,
+    /// Pin 3 drives the rumble motor, when there is one
+    rumble: Option<bool>,
+    /// Told as soon as the game switches the motor, so short pulses aren't lost
+    #[serde(skip)]
+    #[debug_stub = "SensorInterface"]
+    rumble_device: Option<SensorDevice>,
+}
+
+impl Gpio {
+    pub fn new(
+        rtc: Option<Rtc>,
+        solar_sensor: Option<SolarSensor>,
+        gyro: Option<Gyro>,
+        rumble: bool,
+    ) -> Gpio {
+        Gpio {
+            data: 0,
+            direction: 0,
+            readable: false,
+            rtc: rtc,
+            solar_sensor: solar_sensor,
+            gyro: gyro,
+            rumble: if rumble { Some(false) } else { None },
+            rumble_device: None,
+        }
+    }
+
+    pub fn is_gpio_access(addr: u32) -> bool {
+        addr >= GPIO_PORT_DATA && addr <= GPIO_PORT_CONTROL + 1
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    fn pins(&self) -> u8 {
+        let mut input = 0;
+        if let Some(rtc) = &self.rtc {
+            input |= rtc.read_pins();
+        }
+        if let Some(solar_sensor) = &self.solar_sensor {
+            input |= solar_sensor.read_pins();
+        }
+        if let Some(gyro) = &self.gyro {
+            input |= gyro.read_pins();
+        }
+        ((self.data & self.direction) | (input & !self.direction)) & GPIO_PIN_MASK
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        match addr {
+            GPIO_PORT_DATA => self.pins(),
+            GPIO_PORT_DIRECTION => self.direction,
+            GPIO_PORT_CONTROL => self.readable as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) {
+        match addr {
+            GPIO_PORT_DATA => {
+                self.data = value & GPIO_PIN_MASK;
+                let pins = self.pins();
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_pins(pins);
+                }
+                if let Some(solar_sensor) = &mut self.solar_sensor {
+                    solar_sensor.write_pins(pins);
+                }
+                if let Some(gyro) = &mut self.gyro {
+                    gyro.write_pins(pins);
+                }
+                if let Some(rumble) = &mut self.rumble {
+                    let enabled = pins.bit(3);
+                    if enabled != *rumble {
+                        *rumble = enabled;
+                        if let Some(device) = &self.rumble_device {
+                            device.lock().unwrap().set_rumble(enabled);
+                        }
+                    }
+                }
+            }
+            GPIO_PORT_DIRECTION => self.direction = value & GPIO_PIN_MASK,
+            GPIO_PORT_CONTROL => self.readable = value & 1 != 0,
+            _ => {}
+        }
+    }
+
+    pub fn update(&mut self, cycles: usize, irqs: &mut IrqBitmask) {
+        if let Some(rtc) = &mut self.rtc {
+            if rtc.update(cycles) {
+                irqs.set_GamePak(true);
+            }
+        }
+    }
+
+    /// Connects the rumble motor to the host, which is told about its current state right away
+    pub fn set_rumble_device(&mut self, device: SensorDevice) {
+        if let Some(rumble) = self.rumble {
+            device.lock().unwrap().set_rumble(rumble);
+            self.rumble_device = Some(device);
+        }
+    }
+
+    /// Feeds the sensors with the latest host values
+    pub fn sync_sensors(&mut self, sensors: &mut dyn SensorInterface) {
+        if let Some(solar_sensor) = &mut self.solar_sensor {
+            solar_sensor.set_light_level(sensors.read_light_level());
+        }
+        if let Some(gyro) = &mut self.gyro {
+            gyro.set_rotation(sensors.read_gyro());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RumbleLog(Vec<bool>);
+
+    impl SensorInterface for RumbleLog {
+        fn set_rumble(&mut self, enabled: bool) {
+            self.0.push(enabled);
+        }
+    }
+
+    #[test]
+    fn rumble_is_forwarded_on_write() {
+        let log = Arc::new(Mutex::new(RumbleLog::default()));
+        let mut gpio = Gpio::new(None, None, None, true);
+        gpio.set_rumble_device(log.clone());
+        gpio.write(GPIO_PORT_DIRECTION, 0b1000);
+
+        // a pulse shorter than a frame
+        gpio.write(GPIO_PORT_DATA, 0b1000);
+        gpio.write(GPIO_PORT_DATA, 0b1000);
+        gpio.write(GPIO_PORT_DATA, 0b0000);
+        assert_eq!(log.lock().unwrap().0, vec![false, true, false]);
+    }
+}
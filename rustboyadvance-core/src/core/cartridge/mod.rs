@@ -1,6 +1,11 @@
+use std::cmp;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
 use serde::{Deserialize, Serialize};
 
-use super::{Addr, Bus};
+use super::interrupt::IrqBitmask;
+use super::{Addr, Bus, GBAError, GBAResult};
 
 pub mod header;
 use header::CartridgeHeader;
@@ -14,6 +19,33 @@ use backup::{BackupFile, BackupMemoryInterface};
 mod builder;
 pub use builder::GamepakBuilder;
 
+mod gpio;
+use gpio::Gpio;
+mod rtc;
+use rtc::Rtc;
+pub use rtc::RtcTimeSource;
+mod sensors;
+use sensors::TiltSensor;
+
+use crate::SensorInterface;
+
+pub(crate) fn rom_checksum(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+bitflags! {
+    /// Extra hardware found on the cartridge, besides the ROM and backup memory
+    pub struct CartridgeHardware: u8 {
+        const RTC = 1 << 0;
+        const SOLAR_SENSOR = 1 << 1;
+        const TILT_SENSOR = 1 << 2;
+        const GYRO = 1 << 3;
+        const RUMBLE = 1 << 4;
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum BackupMedia {
     Sram(BackupFile),
@@ -22,12 +54,167 @@ pub enum BackupMedia {
     Undetected,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl Default for BackupMedia {
+    fn default() -> BackupMedia {
+        BackupMedia::Undetected
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Cartridge {
     pub header: CartridgeHeader,
+    /// The ROM is left out of savestates, see `attach_rom`
+    #[serde(skip)]
     bytes: Box<[u8]>,
     size: usize,
+    /// CRC32 of the ROM, used to tell whether a savestate belongs to it
+    checksum: u32,
     pub(in crate) backup: BackupMedia,
+    gpio: Option<Gpio>,
+    tilt_sensor: Option<TiltSensor>,
+}
+
+impl Cartridge {
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Detaches the ROM image, leaving the cartridge unusable until it is attached again
+    pub(crate) fn take_rom(&mut self) -> Box<[u8]> {
+        std::mem::replace(&mut self.bytes, Box::default())
+    }
+
+    /// Puts back the ROM image of a cartridge restored from a savestate, which doesn't carry it
+    pub(crate) fn attach_rom(&mut self, bytes: Box<[u8]>) -> GBAResult<()> {
+        if bytes.len() != self.size || rom_checksum(&bytes) != self.checksum {
+            return Err(self.rom_mismatch());
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Moves the ROM image over from another instance of the same cartridge
+    pub(crate) fn take_rom_from(&mut self, other: &mut Cartridge) -> GBAResult<()> {
+        if self.size != other.size || self.checksum != other.checksum {
+            return Err(self.rom_mismatch());
+        }
+        self.bytes = other.take_rom();
+        Ok(())
+    }
+
+    fn rom_mismatch(&self) -> GBAError {
+        GBAError::SaveStateRomMismatch(
+            self.header.game_title.clone(),
+            self.header.game_code.clone(),
+        )
+    }
+
+    /// Points a cartridge restored from a savestate at the save file given by the caller,
+    /// and the .rtc file next to it
+    pub(crate) fn reopen_files(&mut self, save_path: Option<&Path>) {
+        if let Some(backup) = self.backup_file_mut() {
+            backup.reopen(save_path.map(Path::to_path_buf));
+        }
+        if let Some(rtc) = self.rtc_mut() {
+            rtc.reopen(save_path.map(|path| path.with_extension(builder::RTC_FILE_EXT)));
+        }
+    }
+
+    /// Keeps writing to the files of the running cartridge, whichever paths a restored state recorded
+    pub(crate) fn take_files_from(&mut self, other: &mut Cartridge) {
+        if let (Some(backup), Some(current)) = (self.backup_file_mut(), other.backup_file_mut()) {
+            backup.take_file_from(current);
+        }
+        if let (Some(rtc), Some(current)) = (self.rtc_mut(), other.rtc_mut()) {
+            rtc.take_file_from(current);
+        }
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.gpio.as_mut().and_then(|gpio| gpio.rtc_mut())
+    }
+
+    /// The file behind the backup memory, if the backup type is known
+    pub(crate) fn backup_file_mut(&mut self) -> Option<&mut BackupFile> {
+        match &mut self.backup {
+            BackupMedia::Sram(memory) => Some(memory),
+            BackupMedia::Flash(flash) => Some(&mut flash.memory),
+            BackupMedia::Eeprom(spi) => Some(&mut spi.chip.get_mut().memory),
+            BackupMedia::Undetected => None,
+        }
+    }
+
+    /// Fills the backup memory with save data kept by the frontend, which should hold
+    /// `backup_size_bound` bytes
+    pub fn load_backup(&mut self, data: &[u8]) {
+        if let BackupMedia::Eeprom(spi) = &mut self.backup {
+            spi.load(data);
+        } else if let Some(backup) = self.backup_file_mut() {
+            let memory = backup.bytes_mut();
+            let len = cmp::min(memory.len(), data.len());
+            memory[..len].copy_from_slice(&data[..len]);
+            backup.flush();
+        }
+    }
+
+    /// Copies the backup memory out to the start of `data`, for frontends that keep the save data themselves
+    pub fn store_backup(&self, data: &mut [u8]) {
+        let mut store = |memory: &[u8]| {
+            let len = cmp::min(memory.len(), data.len());
+            data[..len].copy_from_slice(&memory[..len]);
+        };
+        match &self.backup {
+            BackupMedia::Sram(memory) => store(memory.bytes()),
+            BackupMedia::Flash(flash) => store(flash.memory.bytes()),
+            BackupMedia::Eeprom(spi) => store(spi.chip.borrow().memory.bytes()),
+            BackupMedia::Undetected => {}
+        }
+    }
+
+    pub fn backup_size(&self) -> usize {
+        match &self.backup {
+            BackupMedia::Sram(memory) => memory.bytes().len(),
+            BackupMedia::Flash(flash) => flash.memory.bytes().len(),
+            BackupMedia::Eeprom(spi) => spi.size(),
+            BackupMedia::Undetected => 0,
+        }
+    }
+
+    /// The size the backup memory can grow to during the run
+    pub fn backup_size_bound(&self) -> usize {
+        match &self.backup {
+            BackupMedia::Eeprom(spi) => spi.size_bound(),
+            _ => self.backup_size(),
+        }
+    }
+
+    pub fn update(&mut self, cycles: usize, irqs: &mut IrqBitmask) {
+        if let Some(gpio) = &mut self.gpio {
+            gpio.update(cycles, irqs);
+        }
+    }
+
+    /// Makes the clock of the cartridge reproducible, see `Rtc::pin_time_source`.
+    /// Returns `None` for cartridges without a clock.
+    pub(crate) fn pin_rtc_time_source(&mut self) -> Option<RtcTimeSource> {
+        self.rtc_mut().map(|rtc| rtc.pin_time_source())
+    }
+
+    pub fn set_rumble_device(&mut self, device: Arc<Mutex<dyn SensorInterface>>) {
+        if let Some(gpio) = &mut self.gpio {
+            gpio.set_rumble_device(device);
+        }
+    }
+
+    pub fn sync_sensors(&mut self, sensors: &mut dyn SensorInterface) {
+        if let Some(gpio) = &mut self.gpio {
+            gpio.sync_sensors(sensors);
+        }
+        if let Some(tilt_sensor) = &mut self.tilt_sensor {
+            let (x, y) = sensors.read_tilt();
+            tilt_sensor.set_tilt(x, y);
+        }
+    }
 }
 
 use super::sysbus::consts::*;
@@ -39,13 +226,24 @@ impl Bus for Cartridge {
         let offset = (addr & 0x01ff_ffff) as usize;
         match addr & 0xff000000 {
             SRAM_LO | SRAM_HI => match &self.backup {
+                _ if self.tilt_sensor.is_some() && TiltSensor::is_tilt_access(addr) => {
+                    self.tilt_sensor.as_ref().unwrap().read(addr)
+                }
                 BackupMedia::Sram(memory) => memory.read((addr & 0x7FFF) as usize),
                 BackupMedia::Flash(flash) => flash.read(addr),
                 _ => 0,
             },
             _ => {
+                if let Some(gpio) = &self.gpio {
+                    if gpio.is_readable() && Gpio::is_gpio_access(addr) {
+                        return gpio.read(addr);
+                    }
+                }
                 if offset >= self.size {
-                    0xDD // TODO - open bus implementation
+                    // nothing drives the bus past the end of the rom, so what's read back is
+                    // the lower 16 bits of the halfword address that were latched by the rom chip
+                    let latched = (offset >> 1) as u16;
+                    (latched >> ((offset & 1) * 8)) as u8
                 } else {
                     unsafe { *self.bytes.get_unchecked(offset as usize) }
                 }
@@ -55,7 +253,7 @@ impl Bus for Cartridge {
 
     fn read_16(&self, addr: u32) -> u16 {
         if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
+            && (self.size <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
         {
             if let BackupMedia::Eeprom(spi) = &self.backup {
                 return spi.read_half(addr);
@@ -67,17 +265,27 @@ impl Bus for Cartridge {
     fn write_8(&mut self, addr: u32, value: u8) {
         match addr & 0xff000000 {
             SRAM_LO | SRAM_HI => match &mut self.backup {
+                _ if self.tilt_sensor.is_some() && TiltSensor::is_tilt_access(addr) => {
+                    self.tilt_sensor.as_mut().unwrap().write(addr, value)
+                }
                 BackupMedia::Flash(flash) => flash.write(addr, value),
                 BackupMedia::Sram(memory) => memory.write((addr & 0x7FFF) as usize, value),
                 _ => {}
             },
-            _ => {} // TODO allow the debugger to write
+            _ => {
+                if let Some(gpio) = &mut self.gpio {
+                    if Gpio::is_gpio_access(addr) {
+                        gpio.write(addr, value);
+                    }
+                }
+                // TODO allow the debugger to write
+            }
         };
     }
 
     fn write_16(&mut self, addr: u32, value: u16) {
         if addr & 0xff000000 == GAMEPAK_WS2_HI
-            && (self.bytes.len() <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
+            && (self.size <= 16 * 1024 * 1024 || addr >= EEPROM_BASE_ADDR)
         {
             if let BackupMedia::Eeprom(spi) = &mut self.backup {
                 return spi.write_half(addr, value);
@@ -0,0 +1,487 @@
+//! Seiko S-3511 real-time clock, connected to the cartridge GPIO port.
+//!
+//! The clock is driven through 3 pins: SCK (bit 0), SIO (bit 1) and CS (bit 2).
+//! After CS goes high, the game clocks in a command byte (MSB first) of the form `0110cccr`,
+//! followed by the parameter bytes (LSB first) that are either written by the game or shifted out by the clock.
+use std::path::PathBuf;
+
+use bincode;
+use bit::BitIndex;
+use num::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime};
+
+use super::super::sched::CLOCK_FREQ;
+use crate::util::{read_bin_file, write_bin_file};
+
+/// Unix timestamp of 2000-01-01 00:00:00, the date the clock is set to on reset
+const RTC_RESET_TIMESTAMP: i64 = 946_684_800;
+
+const STATUS_INTFE: u8 = 1 << 1;
+const STATUS_INTME: u8 = 1 << 3;
+const STATUS_INTAE: u8 = 1 << 5;
+const STATUS_24H: u8 = 1 << 6;
+const STATUS_WRITE_MASK: u8 = STATUS_INTFE | STATUS_INTME | STATUS_INTAE | STATUS_24H;
+
+/// Where the clock takes the current time from
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum RtcTimeSource {
+    /// The local time of the host
+    Host,
+    /// Starts at the given unix timestamp (as local time) and advances with the emulated cycles,
+    /// so runs are reproducible.
+    Fixed(i64),
+}
+
+impl Default for RtcTimeSource {
+    fn default() -> RtcTimeSource {
+        RtcTimeSource::Host
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Primitive, PartialEq, Copy, Clone)]
+enum RtcCommand {
+    Reset = 0,
+    Status = 1,
+    DateTime = 2,
+    Time = 3,
+    ForceIrq = 6,
+}
+
+impl RtcCommand {
+    fn num_bytes(&self) -> usize {
+        match self {
+            RtcCommand::Status => 1,
+            RtcCommand::DateTime => 7,
+            RtcCommand::Time => 3,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Copy, Clone)]
+enum RtcState {
+    Idle,
+    RxCommand,
+    RxData(RtcCommand),
+    TxData,
+}
+
+/// What gets persisted to the .rtc file
+#[derive(Serialize, Deserialize, Debug)]
+struct RtcBackup {
+    status: u8,
+    offset: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rtc {
+    source: RtcTimeSource,
+    /// Not part of savestates, the file is given by whoever restores one
+    #[serde(skip)]
+    path: Option<PathBuf>,
+
+    /// Seconds added to the time source, changes when the game sets the clock
+    offset: i64,
+    /// Emulated cycles since power on, used by `RtcTimeSource::Fixed`
+    cycles: u64,
+    status: u8,
+
+    state: RtcState,
+    sck: bool,
+    cs: bool,
+    sio_out: bool,
+    shift: u8,
+    bit_count: usize,
+    buffer: [u8; 7],
+    length: usize,
+    index: usize,
+
+    irq_pending: bool,
+    /// The per-minute interrupt is checked once every emulated second
+    minute_cycles: u64,
+    last_minute: u8,
+}
+
+fn bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0xf)
+}
+
+impl Rtc {
+    pub fn new(source: RtcTimeSource, path: Option<PathBuf>) -> Rtc {
+        let mut rtc = Rtc {
+            source: source,
+            path: None,
+            offset: 0,
+            cycles: 0,
+            status: STATUS_24H,
+            state: RtcState::Idle,
+            sck: false,
+            cs: false,
+            sio_out: false,
+            shift: 0,
+            bit_count: 0,
+            buffer: [0; 7],
+            length: 0,
+            index: 0,
+            irq_pending: false,
+            minute_cycles: 0,
+            last_minute: 0,
+        };
+        if let Some(path) = &path {
+            if path.is_file() {
+                match read_bin_file(path)
+                    .ok()
+                    .and_then(|bytes| bincode::deserialize::<RtcBackup>(&bytes).ok())
+                {
+                    Some(backup) => {
+                        rtc.status = backup.status;
+                        rtc.offset = backup.offset;
+                    }
+                    None => warn!("rtc: failed to load {}", path.display()),
+                }
+            }
+        }
+        rtc.path = path;
+        rtc.last_minute = rtc.date_time().minute();
+        rtc
+    }
+
+    /// Switches a host clock over to a fixed source showing the same time, so the rest of the run can be replayed.
+    /// Returns the time source in use from now on.
+    pub fn pin_time_source(&mut self) -> RtcTimeSource {
+        if self.source == RtcTimeSource::Host {
+            let elapsed = (self.cycles / CLOCK_FREQ as u64) as i64;
+            self.source = RtcTimeSource::Fixed(self.source_time() - elapsed);
+        }
+        self.source
+    }
+
+    /// Writes a clock restored from a savestate to the given .rtc file from now on
+    pub fn reopen(&mut self, path: Option<PathBuf>) {
+        self.path = path;
+        self.flush();
+    }
+
+    /// Takes over the .rtc file of the clock being replaced by this restored one
+    pub fn take_file_from(&mut self, other: &mut Rtc) {
+        self.path = other.path.take();
+        self.flush();
+    }
+
+    fn source_time(&self) -> i64 {
+        match self.source {
+            RtcTimeSource::Host => {
+                let now = OffsetDateTime::now_local();
+                now.timestamp() + now.offset().as_seconds() as i64
+            }
+            RtcTimeSource::Fixed(epoch) => epoch + (self.cycles / CLOCK_FREQ as u64) as i64,
+        }
+    }
+
+    /// The time the clock is showing, in the local time zone (stored as UTC)
+    fn date_time(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(self.source_time() + self.offset)
+    }
+
+    fn set_date_time(&mut self, timestamp: i64) {
+        self.offset = timestamp - self.source_time();
+        self.last_minute = self.date_time().minute();
+        self.flush();
+    }
+
+    fn flush(&self) {
+        if let Some(path) = &self.path {
+            let backup = RtcBackup {
+                status: self.status,
+                offset: self.offset,
+            };
+            let result = bincode::serialize(&backup)
+                .map_err(|e| e.to_string())
+                .and_then(|bytes| write_bin_file(path, &bytes).map_err(|e| e.to_string()));
+            if let Err(e) = result {
+                warn!("rtc: failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn encode_hour(&self, hour: u8) -> u8 {
+        let pm = (hour >= 12) as u8;
+        if self.status & STATUS_24H != 0 {
+            bcd(hour) | (pm << 7)
+        } else {
+            bcd(hour % 12) | (pm << 7)
+        }
+    }
+
+    fn decode_hour(&self, value: u8) -> u8 {
+        let hour = from_bcd(value & 0x3f);
+        if self.status & STATUS_24H != 0 {
+            hour
+        } else {
+            (hour % 12) + 12 * (value.bit(7) as u8)
+        }
+    }
+
+    fn latch_date_time(&mut self) {
+        let t = self.date_time();
+        self.buffer = [
+            bcd((t.year() % 100) as u8),
+            bcd(t.month()),
+            bcd(t.day()),
+            t.weekday().number_days_from_sunday(),
+            self.encode_hour(t.hour()),
+            bcd(t.minute()),
+            bcd(t.second()),
+        ];
+    }
+
+    fn process_command(&mut self, byte: u8) {
+        self.shift = 0;
+        self.bit_count = 0;
+        self.index = 0;
+
+        if byte >> 4 != 0b0110 {
+            warn!("rtc: invalid command byte {:#04x}", byte);
+            self.state = RtcState::Idle;
+            return;
+        }
+        let command = match RtcCommand::from_u8((byte >> 1) & 0b111) {
+            Some(command) => command,
+            None => {
+                warn!("rtc: unsupported command {:#04x}", byte);
+                self.state = RtcState::Idle;
+                return;
+            }
+        };
+        let read = byte.bit(0);
+        self.length = command.num_bytes();
+
+        trace!("rtc: command {:?} (read={})", command, read);
+
+        self.state = match command {
+            RtcCommand::Reset => {
+                self.status = 0;
+                self.set_date_time(RTC_RESET_TIMESTAMP);
+                RtcState::Idle
+            }
+            RtcCommand::ForceIrq => {
+                self.irq_pending = true;
+                RtcState::Idle
+            }
+            _ if !read => RtcState::RxData(command),
+            RtcCommand::Status => {
+                self.buffer[0] = self.status;
+                RtcState::TxData
+            }
+            RtcCommand::DateTime => {
+                self.latch_date_time();
+                RtcState::TxData
+            }
+            RtcCommand::Time => {
+                self.latch_date_time();
+                self.buffer.copy_within(4..7, 0);
+                RtcState::TxData
+            }
+        }
+    }
+
+    fn process_write(&mut self, command: RtcCommand) {
+        match command {
+            RtcCommand::Status => {
+                self.status = self.buffer[0] & STATUS_WRITE_MASK;
+                self.flush();
+            }
+            RtcCommand::DateTime | RtcCommand::Time => {
+                let (date, hms) = if command == RtcCommand::DateTime {
+                    let date = Date::try_from_ymd(
+                        2000 + from_bcd(self.buffer[0]) as i32,
+                        from_bcd(self.buffer[1]),
+                        from_bcd(self.buffer[2]),
+                    );
+                    (date, &self.buffer[4..7])
+                } else {
+                    (Ok(self.date_time().date()), &self.buffer[0..3])
+                };
+                let date_time = date.and_then(|date| {
+                    date.try_with_hms(self.decode_hour(hms[0]), from_bcd(hms[1]), from_bcd(hms[2]))
+                });
+                match date_time {
+                    Ok(date_time) => self.set_date_time(date_time.assume_utc().timestamp()),
+                    Err(e) => warn!("rtc: game wrote an invalid time {:x?} ({})", self.buffer, e),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// The pins driven by the clock, only SIO while a register is being read
+    pub fn read_pins(&self) -> u8 {
+        (self.sio_out as u8) << 1
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        let sck = pins.bit(0);
+        let sio = pins.bit(1) as u8;
+        let cs = pins.bit(2);
+
+        if !cs {
+            self.state = RtcState::Idle;
+            self.cs = false;
+            self.sck = sck;
+            return;
+        }
+        if !self.cs {
+            self.cs = true;
+            self.state = RtcState::RxCommand;
+            self.shift = 0;
+            self.bit_count = 0;
+        }
+
+        let rising = !self.sck && sck;
+        let falling = self.sck && !sck;
+        self.sck = sck;
+
+        match self.state {
+            RtcState::RxCommand if rising => {
+                self.shift = (self.shift << 1) | sio;
+                self.bit_count += 1;
+                if self.bit_count == 8 {
+                    self.process_command(self.shift);
+                }
+            }
+            RtcState::RxData(command) if rising => {
+                self.shift |= sio << self.bit_count;
+                self.bit_count += 1;
+                if self.bit_count == 8 {
+                    self.buffer[self.index] = self.shift;
+                    self.index += 1;
+                    self.shift = 0;
+                    self.bit_count = 0;
+                    if self.index == self.length {
+                        self.process_write(command);
+                        self.state = RtcState::Idle;
+                    }
+                }
+            }
+            // the next bit is put on SIO when the clock goes low, so it can be sampled after it goes back high
+            RtcState::TxData if falling => {
+                if self.index < self.length {
+                    self.sio_out = self.buffer[self.index].bit(self.bit_count);
+                    self.bit_count += 1;
+                    if self.bit_count == 8 {
+                        self.bit_count = 0;
+                        self.index += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances the clock, returns true when the interrupt line is asserted
+    pub fn update(&mut self, cycles: usize) -> bool {
+        self.cycles += cycles as u64;
+
+        if self.status & STATUS_INTME != 0 {
+            self.minute_cycles += cycles as u64;
+            if self.minute_cycles >= CLOCK_FREQ as u64 {
+                self.minute_cycles -= CLOCK_FREQ as u64;
+                let minute = self.date_time().minute();
+                if minute != self.last_minute {
+                    self.last_minute = minute;
+                    self.irq_pending = true;
+                }
+            }
+        }
+
+        let irq = self.irq_pending;
+        self.irq_pending = false;
+        irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CS: u8 = 0b100;
+    const SCK: u8 = 0b001;
+
+    fn send_command(rtc: &mut Rtc, command: u8) {
+        rtc.write_pins(SCK);
+        rtc.write_pins(CS | SCK);
+        for i in (0..8).rev() {
+            let sio = ((command >> i) & 1) << 1;
+            rtc.write_pins(CS | sio);
+            rtc.write_pins(CS | SCK | sio);
+        }
+    }
+
+    fn read_bytes(rtc: &mut Rtc, count: usize) -> Vec<u8> {
+        (0..count)
+            .map(|_| {
+                let mut byte = 0;
+                for i in 0..8 {
+                    rtc.write_pins(CS);
+                    rtc.write_pins(CS | SCK);
+                    byte |= ((rtc.read_pins() >> 1) & 1) << i;
+                }
+                byte
+            })
+            .collect()
+    }
+
+    fn write_bytes(rtc: &mut Rtc, bytes: &[u8]) {
+        for byte in bytes {
+            for i in 0..8 {
+                let sio = ((byte >> i) & 1) << 1;
+                rtc.write_pins(CS | sio);
+                rtc.write_pins(CS | SCK | sio);
+            }
+        }
+        rtc.write_pins(SCK);
+    }
+
+    #[test]
+    fn test_rtc_fixed_time_source() {
+        // 2004-09-08 23:59:58
+        let mut rtc = Rtc::new(RtcTimeSource::Fixed(1_094_687_998), None);
+
+        send_command(&mut rtc, 0x65);
+        assert_eq!(
+            read_bytes(&mut rtc, 7),
+            vec![0x04, 0x09, 0x08, 0x03, 0xa3, 0x59, 0x58]
+        );
+
+        rtc.update(2 * CLOCK_FREQ);
+        send_command(&mut rtc, 0x67);
+        assert_eq!(read_bytes(&mut rtc, 3), vec![0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_rtc_set_time() {
+        let mut rtc = Rtc::new(RtcTimeSource::Fixed(0), None);
+
+        send_command(&mut rtc, 0x64);
+        write_bytes(&mut rtc, &[0x19, 0x12, 0x31, 0x02, 0x23, 0x59, 0x30]);
+
+        send_command(&mut rtc, 0x65);
+        assert_eq!(
+            read_bytes(&mut rtc, 7),
+            vec![0x19, 0x12, 0x31, 0x02, 0xa3, 0x59, 0x30]
+        );
+    }
+
+    #[test]
+    fn test_rtc_file_is_not_part_of_savestates() {
+        let mut rtc = Rtc::new(RtcTimeSource::Fixed(0), None);
+        rtc.path = Some(PathBuf::from("elsewhere.rtc"));
+        let restored: Rtc = bincode::deserialize(&bincode::serialize(&rtc).unwrap()).unwrap();
+        assert_eq!(restored.path, None);
+    }
+}
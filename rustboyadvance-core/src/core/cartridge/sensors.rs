@@ -0,0 +1,146 @@
+//! Sensors built into some cartridges. The solar sensor and the gyro are wired to the GPIO port,
+//! while the tilt sensor has its own registers in the SRAM region.
+use serde::{Deserialize, Serialize};
+
+use bit::BitIndex;
+
+/// Boktai solar sensor.
+///
+/// The game resets a counter (pin 1) and clocks it (pin 0) until the sensor raises its flag (pin 3),
+/// the number of clocks it took tells how much light there is.
+/// Nothing happens while pin 2 is high, as it is the chip select of the RTC sharing the port.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SolarSensor {
+    light_level: u8,
+    counter: u8,
+    threshold: u8,
+    clock: bool,
+}
+
+impl SolarSensor {
+    /// `level` goes from 0 (darkness) to 255 (direct sunlight)
+    pub fn set_light_level(&mut self, level: u8) {
+        self.light_level = level;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        ((self.counter >= self.threshold) as u8) << 3
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        if pins.bit(2) {
+            return;
+        }
+        if pins.bit(1) {
+            self.counter = 0;
+            // the more light, the sooner the flag goes up
+            self.threshold = 0xff - self.light_level;
+        }
+        let clock = pins.bit(0);
+        if clock && !self.clock {
+            self.counter = self.counter.saturating_add(1);
+        }
+        self.clock = clock;
+    }
+}
+
+/// The gyro of WarioWare Twisted.
+///
+/// Pin 0 latches a new sample, which is then shifted out MSB first on pin 2 at every falling edge of pin 1.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Gyro {
+    rotation: i16,
+    sample: u16,
+    clock: bool,
+    data_out: bool,
+}
+
+/// The value read when the console is not rotating
+const GYRO_CENTER: i32 = 0x6c0;
+
+impl Gyro {
+    pub fn set_rotation(&mut self, rotation: i16) {
+        self.rotation = rotation;
+    }
+
+    pub fn read_pins(&self) -> u8 {
+        (self.data_out as u8) << 2
+    }
+
+    pub fn write_pins(&mut self, pins: u8) {
+        if pins.bit(0) {
+            self.sample = (GYRO_CENTER + (self.rotation as i32 >> 5)) as u16;
+        }
+        let clock = pins.bit(1);
+        if self.clock && !clock {
+            self.data_out = self.sample.bit(15);
+            self.sample <<= 1;
+        }
+        self.clock = clock;
+    }
+}
+
+pub const TILT_SENSOR_START: u32 = 0x0E00_8000;
+pub const TILT_SENSOR_SAMPLE: u32 = 0x0E00_8100;
+pub const TILT_SENSOR_X_LO: u32 = 0x0E00_8200;
+pub const TILT_SENSOR_X_HI: u32 = 0x0E00_8300;
+pub const TILT_SENSOR_Y_LO: u32 = 0x0E00_8400;
+pub const TILT_SENSOR_Y_HI: u32 = 0x0E00_8500;
+
+/// The value read when the console is held level
+const TILT_CENTER: i32 = 0x3a0;
+
+/// 2-axis accelerometer of Koro Koro Puzzle and Yoshi Topsy-Turvy.
+///
+/// The game writes 0x55 and then 0xAA to start a conversion, and reads back two 12-bit values.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TiltSensor {
+    tilt: (i16, i16),
+    x: u16,
+    y: u16,
+    started: bool,
+}
+
+impl TiltSensor {
+    pub fn is_tilt_access(addr: u32) -> bool {
+        addr >= TILT_SENSOR_START && addr <= TILT_SENSOR_Y_HI
+    }
+
+    pub fn set_tilt(&mut self, x: i16, y: i16) {
+        self.tilt = (x, y);
+    }
+
+    fn convert(value: i16) -> u16 {
+        let value = TILT_CENTER + (value as i32 >> 5);
+        if value < 0 {
+            0
+        } else if value > 0xfff {
+            0xfff
+        } else {
+            value as u16
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        match addr {
+            TILT_SENSOR_X_LO => self.x as u8,
+            // bit 7 tells the conversion is done, which for us is right away
+            TILT_SENSOR_X_HI => ((self.x >> 8) as u8) | 0x80,
+            TILT_SENSOR_Y_LO => self.y as u8,
+            TILT_SENSOR_Y_HI => (self.y >> 8) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) {
+        match (addr, value) {
+            (TILT_SENSOR_START, 0x55) => self.started = true,
+            (TILT_SENSOR_SAMPLE, 0xaa) if self.started => {
+                self.x = TiltSensor::convert(self.tilt.0);
+                self.y = TiltSensor::convert(self.tilt.1);
+                self.started = false;
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use super::cartridge::BackupMedia;
 use super::iodev::consts::{REG_FIFO_A, REG_FIFO_B};
+use super::sched::{EventType, Scheduler};
 use super::sysbus::SysBus;
 use super::{Bus, Interrupt, IrqBitmask};
 
@@ -113,77 +114,88 @@ impl DmaChannel {
         return start_immediately;
     }
 
+    /// Wraps up a transfer that ended with the latched registers in `internal`
+    fn xfer_done(&mut self, internal: DmaInternalRegs, irqs: &mut IrqBitmask) {
+        self.internal = internal;
+        if self.ctrl.is_triggering_irq() {
+            irqs.add_irq(self.irq);
+        }
+        if self.ctrl.repeat() {
+            self.start_cycles = self.cycles;
+            /* reload */
+            if 3 == self.ctrl.dst_adj() {
+                self.internal.dst_addr = self.dst;
+            }
+        } else {
+            self.running = false;
+            self.ctrl.set_enabled(false);
+        }
+    }
+}
+
+impl DmaInternalRegs {
     #[inline]
-    fn xfer_adj_addrs(&mut self, word_size: u32) {
-        match self.ctrl.src_adj() {
-            /* Increment */ 0 => self.internal.src_addr += word_size,
-            /* Decrement */ 1 => self.internal.src_addr -= word_size,
+    fn xfer_adj_addrs(&mut self, ctrl: &DmaChannelCtrl, word_size: u32) {
+        match ctrl.src_adj() {
+            /* Increment */ 0 => self.src_addr += word_size,
+            /* Decrement */ 1 => self.src_addr -= word_size,
             /* Fixed */ 2 => {}
             _ => panic!("forbidden DMA source address adjustment"),
         }
-        match self.ctrl.dst_adj() {
-            /* Increment[+Reload] */ 0 | 3 => self.internal.dst_addr += word_size,
-            /* Decrement */ 1 => self.internal.dst_addr -= word_size,
+        match ctrl.dst_adj() {
+            /* Increment[+Reload] */ 0 | 3 => self.dst_addr += word_size,
+            /* Decrement */ 1 => self.dst_addr -= word_size,
             /* Fixed */ 2 => {}
             _ => panic!("forbidden DMA dest address adjustment"),
         }
     }
+}
 
-    fn xfer(&mut self, sb: &mut SysBus, irqs: &mut IrqBitmask) {
-        let word_size = if self.ctrl.is_32bit() { 4 } else { 2 };
-        let count = match self.internal.count {
-            0 => match self.id {
-                3 => 0x1_0000,
-                _ => 0x0_4000,
-            },
-            _ => self.internal.count,
-        };
+/// Runs the transfer of channel `id` over `sb`.
+/// The channel lives on the very bus it copies over, so the transfer works on a copy of the
+/// latched registers and the channel is only borrowed again once it is done.
+fn xfer(id: usize, sb: &mut SysBus, irqs: &mut IrqBitmask) {
+    let channel = &sb.io.dmac.channels[id];
+    let ctrl = channel.ctrl.clone();
+    let fifo_mode = channel.fifo_mode;
+    let mut internal = channel.internal.clone();
 
-        if self.id == 3 && word_size == 2 {
-            if let BackupMedia::Eeprom(eeprom) = &mut sb.cartridge.backup {
-                eeprom.on_dma3_transfer(
-                    self.internal.src_addr,
-                    self.internal.dst_addr,
-                    count as usize,
-                )
-            }
-        }
+    let word_size = if ctrl.is_32bit() { 4 } else { 2 };
+    let count = match internal.count {
+        0 => match id {
+            3 => 0x1_0000,
+            _ => 0x0_4000,
+        },
+        _ => internal.count,
+    };
 
-        let fifo_mode = self.fifo_mode;
+    if id == 3 && word_size == 2 {
+        if let BackupMedia::Eeprom(eeprom) = &mut sb.cartridge.backup {
+            eeprom.on_dma3_transfer(internal.src_addr, internal.dst_addr, count as usize)
+        }
+    }
 
-        if fifo_mode {
-            for _ in 0..4 {
-                let v = sb.read_32(self.internal.src_addr & !3);
-                sb.write_32(self.internal.dst_addr & !3, v);
-                self.internal.src_addr += 4;
-            }
-        } else if word_size == 4 {
-            for _ in 0..count {
-                let w = sb.read_32(self.internal.src_addr & !3);
-                sb.write_32(self.internal.dst_addr & !3, w);
-                self.xfer_adj_addrs(word_size);
-            }
-        } else {
-            for _ in 0..count {
-                let hw = sb.read_16(self.internal.src_addr & !1);
-                sb.write_16(self.internal.dst_addr & !1, hw);
-                self.xfer_adj_addrs(word_size)
-            }
+    if fifo_mode {
+        for _ in 0..4 {
+            let v = sb.read_32(internal.src_addr & !3);
+            sb.write_32(internal.dst_addr & !3, v);
+            internal.src_addr += 4;
         }
-        if self.ctrl.is_triggering_irq() {
-            irqs.add_irq(self.irq);
+    } else if word_size == 4 {
+        for _ in 0..count {
+            let w = sb.read_32(internal.src_addr & !3);
+            sb.write_32(internal.dst_addr & !3, w);
+            internal.xfer_adj_addrs(&ctrl, word_size);
         }
-        if self.ctrl.repeat() {
-            self.start_cycles = self.cycles;
-            /* reload */
-            if 3 == self.ctrl.dst_adj() {
-                self.internal.dst_addr = self.dst;
-            }
-        } else {
-            self.running = false;
-            self.ctrl.set_enabled(false);
+    } else {
+        for _ in 0..count {
+            let hw = sb.read_16(internal.src_addr & !1);
+            sb.write_16(internal.dst_addr & !1, hw);
+            internal.xfer_adj_addrs(&ctrl, word_size)
         }
     }
+
+    sb.io.dmac.channels[id].xfer_done(internal, irqs);
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -211,16 +223,17 @@ impl DmaController {
         self.pending_set != 0
     }
 
-    pub fn perform_work(&mut self, sb: &mut SysBus, irqs: &mut IrqBitmask) {
+    /// Runs the pending transfers of the controller of `sb`
+    pub fn perform_work(sb: &mut SysBus, irqs: &mut IrqBitmask) {
         for id in 0..4 {
-            if self.pending_set & (1 << id) != 0 {
-                self.channels[id].xfer(sb, irqs);
+            if sb.io.dmac.pending_set & (1 << id) != 0 {
+                xfer(id, sb, irqs);
             }
         }
-        self.pending_set = 0;
+        sb.io.dmac.pending_set = 0;
     }
 
-    pub fn write_16(&mut self, channel_id: usize, ofs: u32, value: u16) {
+    pub fn write_16(&mut self, channel_id: usize, ofs: u32, value: u16, scheduler: &mut Scheduler) {
         match ofs {
             0 => self.channels[channel_id].write_src_low(value),
             2 => self.channels[channel_id].write_src_high(value),
@@ -229,8 +242,10 @@ impl DmaController {
             8 => self.channels[channel_id].write_word_count(value),
             10 => {
                 if self.channels[channel_id].write_dma_ctrl(value) {
-                    self.pending_set |= 1 << channel_id;
+                    // immediate transfers start 2 cycles after being enabled
+                    scheduler.schedule(EventType::DmaActivateChannel(channel_id), 2);
                 } else {
+                    scheduler.cancel(EventType::DmaActivateChannel(channel_id));
                     self.pending_set &= !(1 << channel_id);
                 }
             }
@@ -238,6 +253,12 @@ impl DmaController {
         }
     }
 
+    pub fn activate_channel(&mut self, channel_id: usize) {
+        if self.channels[channel_id].ctrl.is_enabled() {
+            self.pending_set |= 1 << channel_id;
+        }
+    }
+
     pub fn notify_vblank(&mut self) {
         for i in 0..4 {
             if self.channels[i].ctrl.is_enabled() && self.channels[i].ctrl.timing() == 1 {
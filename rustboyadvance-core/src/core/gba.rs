@@ -1,116 +1,471 @@
 /// Struct containing everything
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 use bincode;
 use serde::{Deserialize, Serialize};
 
 use super::arm7tdmi;
+use super::bios;
 use super::cartridge::Cartridge;
+use super::dma::DmaController;
 use super::gpu::*;
 use super::interrupt::*;
 use super::iodev::*;
-use super::sound::SoundController;
+use super::movie::{Movie, MovieHeader, MoviePlayer, MovieRecorder, DEFAULT_HASH_INTERVAL};
+use super::recording::{AvRecorder, RecordingAudio, RecordingVideo};
+use super::rewind::RewindBuffer;
+use super::savestate::{deserialize_bounded, SaveStateFile, SaveStateInfo};
+use super::sched::{EventType, Scheduler};
+use super::sio::LinkTransport;
+use super::sound::{ResamplerType, SoundController, SoundOutput};
 use super::sysbus::SysBus;
+use super::{GBAError, GBAResult};
 
-use super::super::{AudioInterface, InputInterface, VideoInterface};
+use super::super::{AudioInterface, InputInterface, SensorInterface, VideoInterface};
 
-pub struct GameBoyAdvance {
+/// The emulator, generic over its video, audio and input devices so the calls made to them
+/// on every sample and frame can be statically dispatched and inlined.
+/// Movie playback and audio/video recording put their own devices in place of the ones given,
+/// so they are only available on `GameBoyAdvance`, which holds trait objects.
+///
+/// The devices stay behind `Arc<Mutex<..>>`: frontends often give the same object for all three
+/// and keep a handle to it to read what the emulator produced, and the movie and recording devices
+/// wrap the live ones. The devices are only locked about once a frame, so the lock costs next to nothing.
+pub struct GenericGameBoyAdvance<V: ?Sized, A: ?Sized, I: ?Sized> {
     pub sysbus: Box<SysBus>,
     pub cpu: arm7tdmi::Core,
 
-    pub video_device: Rc<RefCell<dyn VideoInterface>>,
-    pub audio_device: Rc<RefCell<dyn AudioInterface>>,
-    pub input_device: Rc<RefCell<dyn InputInterface>>,
+    pub video_device: Arc<Mutex<V>>,
+    pub audio_device: Arc<Mutex<A>>,
+    pub input_device: Arc<Mutex<I>>,
+    sensor_device: Option<Arc<Mutex<dyn SensorInterface>>>,
 
-    pub cycles_to_next_event: usize,
+    link: Option<Box<dyn LinkTransport>>,
+    rewind_buffer: Option<RewindBuffer>,
+    movie_recorder: Option<MovieRecorder>,
+    movie_player: Option<Arc<Mutex<MoviePlayer>>>,
+    /// The input device put aside while a movie plays
+    live_input_device: Option<Arc<Mutex<I>>>,
+    av_recorder: Option<Arc<Mutex<AvRecorder>>>,
+    /// The video and audio devices put aside while recording
+    live_av_devices: Option<(Arc<Mutex<V>>, Arc<Mutex<A>>)>,
 
     overshoot_cycles: usize,
 }
 
-#[derive(Serialize, Deserialize)]
+pub type GameBoyAdvance =
+    GenericGameBoyAdvance<dyn VideoInterface, dyn AudioInterface, dyn InputInterface>;
+
+/// Savestates leave out the BIOS and the cartridge ROM, they are attached again when restoring
+#[derive(Deserialize)]
 struct SaveState {
     sysbus: Box<SysBus>,
     cpu: arm7tdmi::Core,
 }
 
-impl GameBoyAdvance {
+/// Borrowing counterpart of `SaveState`, so saving doesn't need to clone the whole system
+#[derive(Serialize)]
+struct SaveStateRef<'a> {
+    sysbus: &'a SysBus,
+    cpu: &'a arm7tdmi::Core,
+}
+
+fn decode_state(payload: &[u8]) -> GBAResult<Box<SaveState>> {
+    deserialize_bounded(payload)
+}
+
+fn create_io_devices<A: AudioInterface + ?Sized>(audio_device: &Arc<Mutex<A>>) -> IoDevices {
+    let mut scheduler = Scheduler::new();
+    let gpu = Box::new(Gpu::new(&mut scheduler));
+    let sound_controller = Box::new(SoundController::new(
+        &mut scheduler,
+        audio_device.lock().unwrap().get_sample_rate() as f32,
+        ResamplerType::default(),
+    ));
+    IoDevices::new(scheduler, gpu, sound_controller)
+}
+
+impl<V, A, I> GenericGameBoyAdvance<V, A, I>
+where
+    V: VideoInterface + ?Sized,
+    A: AudioInterface + ?Sized,
+    I: InputInterface + ?Sized,
+{
     pub fn new(
         bios_rom: Box<[u8]>,
         gamepak: Cartridge,
-        video_device: Rc<RefCell<dyn VideoInterface>>,
-        audio_device: Rc<RefCell<dyn AudioInterface>>,
-        input_device: Rc<RefCell<dyn InputInterface>>,
-    ) -> GameBoyAdvance {
-        let gpu = Box::new(Gpu::new());
-        let sound_controller = Box::new(SoundController::new(
-            audio_device.borrow().get_sample_rate() as f32,
-        ));
-        let io = IoDevices::new(gpu, sound_controller);
+        video_device: Arc<Mutex<V>>,
+        audio_device: Arc<Mutex<A>>,
+        input_device: Arc<Mutex<I>>,
+    ) -> Self {
+        let io = create_io_devices(&audio_device);
         let sysbus = Box::new(SysBus::new(io, bios_rom, gamepak));
 
         let cpu = arm7tdmi::Core::new();
 
-        let mut gba = GameBoyAdvance {
+        GenericGameBoyAdvance {
             cpu: cpu,
             sysbus: sysbus,
 
             video_device: video_device,
             audio_device: audio_device,
             input_device: input_device,
+            sensor_device: None,
 
-            cycles_to_next_event: 1,
-            overshoot_cycles: 0,
-        };
+            link: None,
+            rewind_buffer: None,
+            movie_recorder: None,
+            movie_player: None,
+            live_input_device: None,
+            av_recorder: None,
+            live_av_devices: None,
 
-        gba.sysbus.created();
+            overshoot_cycles: 0,
+        }
+    }
 
+    /// Creates a GameBoyAdvance that doesn't need a BIOS dump.
+    /// The software interrupts are emulated and the boot sequence is skipped.
+    pub fn new_with_hle_bios(
+        gamepak: Cartridge,
+        video_device: Arc<Mutex<V>>,
+        audio_device: Arc<Mutex<A>>,
+        input_device: Arc<Mutex<I>>,
+    ) -> Self {
+        let mut gba = Self::new(
+            bios::hle_bios_rom(),
+            gamepak,
+            video_device,
+            audio_device,
+            input_device,
+        );
+        gba.cpu.hle_bios = true;
+        gba.skip_bios();
         gba
     }
 
+    /// Creates a GameBoyAdvance from a savestate, along with the BIOS and ROM it was made with.
+    /// `bios_rom` is not used when the savestate was made with the HLE BIOS.
+    /// The backup memory of the state is written to `save_path`, if there is one.
     pub fn from_saved_state(
         savestate: &[u8],
-        video_device: Rc<RefCell<dyn VideoInterface>>,
-        audio_device: Rc<RefCell<dyn AudioInterface>>,
-        input_device: Rc<RefCell<dyn InputInterface>>,
-    ) -> bincode::Result<GameBoyAdvance> {
-        let decoded: Box<SaveState> = bincode::deserialize_from(savestate)?;
+        bios_rom: Box<[u8]>,
+        rom: Box<[u8]>,
+        save_path: Option<&Path>,
+        video_device: Arc<Mutex<V>>,
+        audio_device: Arc<Mutex<A>>,
+        input_device: Arc<Mutex<I>>,
+    ) -> GBAResult<Self> {
+        let file = SaveStateFile::parse(savestate)?;
+        let mut decoded = decode_state(&file.payload()?)?;
+
+        decoded.sysbus.cartridge.attach_rom(rom)?;
+        if decoded.cpu.hle_bios {
+            decoded.sysbus.attach_bios(bios::hle_bios_rom());
+        } else {
+            decoded.sysbus.attach_bios(bios_rom);
+        }
+        decoded.sysbus.cartridge.reopen_files(save_path);
+        let sample_rate = audio_device.lock().unwrap().get_sample_rate() as f32;
+        decoded
+            .sysbus
+            .io
+            .sound
+            .set_output(SoundOutput::new(sample_rate, ResamplerType::default()));
 
-        Ok(GameBoyAdvance {
+        Ok(GenericGameBoyAdvance {
             cpu: decoded.cpu,
             sysbus: decoded.sysbus,
 
             video_device: video_device,
             audio_device: audio_device,
             input_device: input_device,
+            sensor_device: None,
 
-            cycles_to_next_event: 1,
+            link: None,
+            rewind_buffer: None,
+            movie_recorder: None,
+            movie_player: None,
+            live_input_device: None,
+            av_recorder: None,
+            live_av_devices: None,
 
             overshoot_cycles: 0,
         })
     }
 
-    pub fn save_state(&self) -> bincode::Result<Vec<u8>> {
-        let s = SaveState {
-            cpu: self.cpu.clone(),
-            sysbus: self.sysbus.clone(),
+    /// The bare emulator state, as saved in savestates and rewind snapshots
+    fn snapshot(&self) -> Vec<u8> {
+        let s = SaveStateRef {
+            cpu: &self.cpu,
+            sysbus: &self.sysbus,
         };
-
-        bincode::serialize(&s)
+        // serializing plain data can't fail
+        bincode::serialize(&s).unwrap()
     }
 
-    pub fn restore_state(&mut self, bytes: &[u8]) -> bincode::Result<()> {
-        let decoded: Box<SaveState> = bincode::deserialize_from(bytes)?;
+    /// Switches over to a decoded state of the running game, which takes over the ROM, BIOS and save files
+    fn load_state(&mut self, mut decoded: Box<SaveState>) -> GBAResult<()> {
+        decoded
+            .sysbus
+            .cartridge
+            .take_rom_from(&mut self.sysbus.cartridge)?;
+        decoded.sysbus.attach_bios(self.sysbus.take_bios());
+        decoded
+            .sysbus
+            .cartridge
+            .take_files_from(&mut self.sysbus.cartridge);
+        decoded
+            .sysbus
+            .io
+            .sound
+            .set_output(self.sysbus.io.sound.take_output());
+        decoded.sysbus.prefetch_emulation = self.sysbus.prefetch_emulation;
 
         self.cpu = decoded.cpu;
         self.sysbus = decoded.sysbus;
-        self.cycles_to_next_event = 1;
+        if let Some(sensor_device) = &self.sensor_device {
+            self.sysbus
+                .cartridge
+                .set_rumble_device(sensor_device.clone());
+        }
 
-        self.sysbus.created();
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> GBAResult<Vec<u8>> {
+        let info = SaveStateInfo::new(&self.sysbus.cartridge, self.cpu.hle_bios);
+        self.encode_state(info)
+    }
 
+    /// Same as `save_state` but without the wall-clock timestamp, so the same machine state always
+    /// gives the same bytes. Frontends that compare states, e.g. for netplay, need this.
+    pub fn save_state_untimed(&self) -> GBAResult<Vec<u8>> {
+        let mut info = SaveStateInfo::new(&self.sysbus.cartridge, self.cpu.hle_bios);
+        info.timestamp = 0;
+        self.encode_state(info)
+    }
+
+    fn encode_state(&self, info: SaveStateInfo) -> GBAResult<Vec<u8>> {
+        let file = SaveStateFile::new(
+            info,
+            self.sysbus.io.gpu.get_frame_buffer(),
+            &self.snapshot(),
+        );
+        Ok(file.to_bytes())
+    }
+
+    /// Upper bound of the size of `save_state`, for frontends that keep states in fixed size buffers.
+    /// It holds for the rest of the run, whichever events are pending and whatever the serial port does.
+    pub fn save_state_size_bound(&self) -> usize {
+        let info = SaveStateInfo::new(&self.sysbus.cartridge, self.cpu.hle_bios);
+        let io = &self.sysbus.io;
+        let cartridge = &self.sysbus.cartridge;
+        let payload_len = self.snapshot().len()
+            - bincode::serialized_size(&io.scheduler).unwrap() as usize
+            + Scheduler::serialized_size_bound()
+            - bincode::serialized_size(&io.sio).unwrap() as usize
+            + io.sio.serialized_size_bound()
+            - cartridge.backup_size()
+            + cartridge.backup_size_bound();
+        SaveStateFile::size_bound(&info, payload_len)
+    }
+
+    /// Restores a savestate made with the same ROM, the save data of the cartridge is restored too
+    pub fn restore_state(&mut self, bytes: &[u8]) -> GBAResult<()> {
+        if self.movie_recorder.is_some() {
+            return Err(GBAError::MovieRecordingInProgress);
+        }
+        let file = SaveStateFile::parse(bytes)?;
+        let info = file.info();
+        if info.rom_checksum != self.sysbus.cartridge.checksum() {
+            return Err(GBAError::SaveStateRomMismatch(
+                info.game_title.clone(),
+                info.game_code.clone(),
+            ));
+        }
+        if info.hle_bios != self.cpu.hle_bios {
+            return Err(GBAError::SaveStateBiosMismatch);
+        }
+        let decoded = decode_state(&file.payload()?)?;
+        self.load_state(decoded)
+    }
+
+    /// Power cycles the system, the BIOS and the cartridge along with its save data stay in
+    pub fn reset(&mut self) {
+        let bios_rom = self.sysbus.take_bios();
+        let cartridge = std::mem::replace(&mut self.sysbus.cartridge, Cartridge::default());
+        let io = create_io_devices(&self.audio_device);
+        let mut sysbus = Box::new(SysBus::new(io, bios_rom, cartridge));
+        sysbus
+            .io
+            .sound
+            .set_output(self.sysbus.io.sound.take_output());
+        sysbus.prefetch_emulation = self.sysbus.prefetch_emulation;
+        sysbus.trace_access = self.sysbus.trace_access;
+        self.sysbus = sysbus;
+
+        let mut cpu = arm7tdmi::Core::new();
+        cpu.hle_bios = self.cpu.hle_bios;
+        cpu.breakpoints = std::mem::replace(&mut self.cpu.breakpoints, Vec::new());
+        self.cpu = cpu;
+
+        self.overshoot_cycles = 0;
+        if self.cpu.hle_bios {
+            self.skip_bios();
+        }
+
+        if let Some(recorder) = &mut self.movie_recorder {
+            recorder.on_reset();
+        }
+    }
+
+    /// CRC32 of the registers, work RAM, screen and cycle count, used to tell when a movie desyncs
+    fn state_hash(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for reg in self.cpu.gpr.iter() {
+            hasher.update(&reg.to_le_bytes());
+        }
+        hasher.update(&self.cpu.pc.to_le_bytes());
+        hasher.update(&self.cpu.cpsr.get().to_le_bytes());
+        self.sysbus.hash_work_ram(&mut hasher);
+        for pixel in self.get_frame_buffer() {
+            hasher.update(&pixel.to_le_bytes());
+        }
+        hasher.update(&self.sysbus.io.scheduler.timestamp().to_le_bytes());
+        hasher.finalize()
+    }
+
+    /// Starts recording the keys of every frame into a movie, either from power on, which resets the system,
+    /// or from the current state. A cartridge clock running on the host time is switched to a fixed source.
+    /// Sensors are not recorded, and savestates can't be restored or rewound to until the recording stops.
+    pub fn start_movie_recording(&mut self, power_on: bool) -> GBAResult<()> {
+        self.movie_recorder = None;
+        if power_on {
+            self.reset();
+        }
+        let rtc_time_source = self.sysbus.cartridge.pin_rtc_time_source();
+        let start_state = self.save_state()?;
+        let cartridge = &self.sysbus.cartridge;
+        let header = MovieHeader {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_title: cartridge.header.game_title.clone(),
+            game_code: cartridge.header.game_code.clone(),
+            rom_checksum: cartridge.checksum(),
+            bios_checksum: self.sysbus.bios_checksum(),
+            rtc_time_source: rtc_time_source,
+            power_on: power_on,
+            start_state: start_state,
+            hash_interval: DEFAULT_HASH_INTERVAL,
+        };
+        self.movie_recorder = Some(MovieRecorder::new(header));
         Ok(())
     }
 
+    pub fn is_recording_movie(&self) -> bool {
+        self.movie_recorder.is_some()
+    }
+
+    /// Ends the recording, returning the movie
+    pub fn stop_movie_recording(&mut self) -> Option<Movie> {
+        self.movie_recorder.take().map(|recorder| recorder.finish())
+    }
+
+    /// The movie being played, or the last one played, to check how it went
+    pub fn movie_player(&self) -> Option<Arc<Mutex<MoviePlayer>>> {
+        self.movie_player.clone()
+    }
+
+    pub fn is_playing_movie(&self) -> bool {
+        self.live_input_device.is_some()
+    }
+
+    /// Gives the input back to the input device
+    pub fn stop_movie_playback(&mut self) {
+        if let Some(live_input_device) = self.live_input_device.take() {
+            self.input_device = live_input_device;
+        }
+    }
+
+    pub fn av_recorder(&self) -> Option<Arc<Mutex<AvRecorder>>> {
+        self.av_recorder.clone()
+    }
+
+    /// Completes the recorded files, and gives the video and audio back to their devices
+    pub fn stop_av_recording(&mut self) -> GBAResult<()> {
+        if let Some((video_device, audio_device)) = self.live_av_devices.take() {
+            self.video_device = video_device;
+            self.audio_device = audio_device;
+        }
+        match self.av_recorder.take() {
+            Some(recorder) => Ok(recorder.lock().unwrap().finish()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps a snapshot every `interval` frames to rewind to, using about `budget` bytes at most
+    pub fn enable_rewind(&mut self, interval: usize, budget: usize) {
+        self.rewind_buffer = Some(RewindBuffer::new(interval, budget));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind_buffer = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind_buffer.as_ref()
+    }
+
+    /// Goes back to the newest snapshot at least `frames` old, or the oldest one if the history is shorter.
+    /// Returns how many frames were rewound, the restored screen is sent to the video device.
+    pub fn rewind(&mut self, frames: usize) -> GBAResult<usize> {
+        if self.movie_recorder.is_some() {
+            return Err(GBAError::MovieRecordingInProgress);
+        }
+        let mut rewind_buffer = match self.rewind_buffer.take() {
+            Some(rewind_buffer) => rewind_buffer,
+            None => return Ok(0),
+        };
+        let result = match rewind_buffer.go_back(frames) {
+            Ok(Some((snapshot, rewound))) => decode_state(snapshot)
+                .and_then(|decoded| self.load_state(decoded))
+                .map(|_| rewound),
+            Ok(None) => Ok(0),
+            Err(e) => Err(e),
+        };
+        self.rewind_buffer = Some(rewind_buffer);
+
+        if let Ok(rewound) = result {
+            if rewound > 0 {
+                self.overshoot_cycles = 0;
+                self.video_device
+                    .lock()
+                    .unwrap()
+                    .render(self.sysbus.io.gpu.get_frame_buffer());
+            }
+        }
+        result
+    }
+
+    /// Connects the host sensors to the cartridge, for games that have any
+    pub fn set_sensor_device(&mut self, sensor_device: Arc<Mutex<dyn SensorInterface>>) {
+        self.sysbus
+            .cartridge
+            .set_rumble_device(sensor_device.clone());
+        self.sensor_device = Some(sensor_device);
+    }
+
+    /// Plugs a link cable into the serial port
+    pub fn set_link_transport(&mut self, link: Box<dyn LinkTransport>) {
+        self.link = Some(link);
+    }
+
+    /// Unplugs the link cable, returning it
+    pub fn take_link_transport(&mut self) -> Option<Box<dyn LinkTransport>> {
+        self.link.take()
+    }
+
     pub fn get_game_title(&self) -> String {
         self.sysbus.cartridge.header.game_title.clone()
     }
@@ -121,25 +476,123 @@ impl GameBoyAdvance {
 
     #[inline]
     pub fn key_poll(&mut self) {
-        self.sysbus.io.keyinput = self.input_device.borrow_mut().poll();
+        self.sysbus.io.keyinput = self.input_device.lock().unwrap().poll();
+        self.sysbus.io.check_keypad_irq();
+    }
+
+    pub fn sensor_poll(&mut self) {
+        if let Some(sensor_device) = &self.sensor_device {
+            self.sysbus
+                .cartridge
+                .sync_sensors(&mut *sensor_device.lock().unwrap());
+        }
     }
 
     pub fn frame(&mut self) {
+        let reset_due = self.is_playing_movie()
+            && self
+                .movie_player
+                .as_ref()
+                .map_or(false, |player| player.lock().unwrap().reset_due());
+        if reset_due {
+            self.reset();
+        }
+
         self.key_poll();
+        if let Some(recorder) = &mut self.movie_recorder {
+            recorder.on_key_poll(self.sysbus.io.keyinput);
+        }
+        self.sensor_poll();
 
-        let mut remaining_cycles = 280896 - self.overshoot_cycles;
+        self.run_frame();
+        self.flush_audio();
+        if let Some(recorder) = &self.av_recorder {
+            recorder.lock().unwrap().end_frame();
+        }
 
-        while remaining_cycles > 0 {
-            let cycles = self.step();
-            if remaining_cycles >= cycles {
-                remaining_cycles -= cycles;
-            } else {
-                self.overshoot_cycles = cycles - remaining_cycles;
+        self.end_movie_frame();
+
+        let snapshot_due = self
+            .rewind_buffer
+            .as_mut()
+            .map_or(false, |rewind_buffer| rewind_buffer.on_frame());
+        if snapshot_due {
+            let snapshot = self.snapshot();
+            self.rewind_buffer.as_mut().unwrap().push(snapshot);
+        }
+    }
+
+    /// Hashes the state for the movie being recorded or played when it is due,
+    /// and gives the input back once the movie is over
+    fn end_movie_frame(&mut self) {
+        let record_hash = self
+            .movie_recorder
+            .as_ref()
+            .map_or(false, |recorder| recorder.hash_due());
+        if record_hash {
+            let hash = self.state_hash();
+            self.movie_recorder.as_mut().unwrap().on_hash(hash);
+        }
+
+        if !self.is_playing_movie() {
+            return;
+        }
+        let player = self.movie_player.clone().unwrap();
+        if player.lock().unwrap().hash_due() {
+            let hash = self.state_hash();
+            player.lock().unwrap().check_hash(hash);
+        }
+        if player.lock().unwrap().is_finished() {
+            self.stop_movie_playback();
+        }
+    }
+
+    /// Pushes the sound of the frame to the audio device, in one batch.
+    /// `step` does it too once the samples pile up.
+    pub fn flush_audio(&mut self) {
+        self.sysbus.io.sound.flush_samples(&self.audio_device);
+    }
+
+    /// Switches the resampler that brings the sound to the rate of the audio device
+    pub fn set_resampler(&mut self, resampler_type: ResamplerType) {
+        self.sysbus.io.sound.set_resampler(resampler_type);
+    }
+
+    /// Dynamic rate control, see `SoundController::set_rate_adjustment`.
+    /// Held at 0 while recording, so the WAV plays at the rate in its header and keeps up with the video.
+    pub fn set_audio_rate_adjustment(&mut self, adjustment: f32) {
+        let adjustment = if self.av_recorder.is_some() {
+            0.0
+        } else {
+            adjustment
+        };
+        self.sysbus.io.sound.set_rate_adjustment(adjustment);
+    }
+
+    /// Runs the system until the end of the frame, or until it is stopped
+    fn run_frame(&mut self) {
+        if self.sysbus.io.haltcnt == HaltState::Stop {
+            // everything is frozen until an interrupt wakes the system up
+            return;
+        }
+
+        let frame_end = self.sysbus.io.scheduler.timestamp() + CYCLES_FULL_REFRESH as u64
+            - self.overshoot_cycles as u64;
+        self.sysbus
+            .io
+            .scheduler
+            .schedule_at(EventType::RunLimitReached, frame_end);
+
+        while self.sysbus.io.scheduler.timestamp() < frame_end {
+            if self.sysbus.io.haltcnt == HaltState::Stop {
+                self.sysbus.io.scheduler.cancel(EventType::RunLimitReached);
+                self.overshoot_cycles = 0;
                 return;
             }
+            self.step();
         }
 
-        self.overshoot_cycles = 0;
+        self.overshoot_cycles = (self.sysbus.io.scheduler.timestamp() - frame_end) as usize;
     }
 
     pub fn add_breakpoint(&mut self, addr: u32) -> Option<usize> {
@@ -166,66 +619,104 @@ impl GameBoyAdvance {
     pub fn skip_bios(&mut self) {
         self.cpu.skip_bios();
         self.sysbus.io.gpu.skip_bios();
+        self.sysbus.io.post_boot_flag = true;
     }
 
-    pub fn step_cpu(&mut self, io: &mut IoDevices) -> usize {
-        if io.intc.irq_pending() {
+    pub fn step_cpu(&mut self) -> usize {
+        if self.sysbus.io.intc.irq_line() {
             self.cpu.irq(&mut self.sysbus);
-            io.haltcnt = HaltState::Running;
+            self.sysbus.io.haltcnt = HaltState::Running;
         }
         let previous_cycles = self.cpu.cycles;
         self.cpu.step(&mut self.sysbus);
-        self.cpu.cycles - previous_cycles
+        (self.cpu.cycles - previous_cycles) as usize
     }
 
-    pub fn step(&mut self) -> usize {
-        // I hate myself for doing this, but rust left me no choice.
-        let io = unsafe {
-            let ptr = &mut *self.sysbus as *mut SysBus;
-            &mut (*ptr).io as &mut IoDevices
-        };
-
+    /// Runs the pending dma transfers
+    pub(crate) fn step_dma(&mut self) {
         let mut irqs = IrqBitmask(0);
+        DmaController::perform_work(&mut self.sysbus, &mut irqs);
+        let io = &mut self.sysbus.io;
+        io.intc.request_irqs(irqs, &mut io.scheduler);
+    }
 
-        let mut cycles_left = self.cycles_to_next_event;
-        let mut cycles_to_next_event = std::usize::MAX;
-        let mut cycles = 0;
-
-        while cycles_left > 0 {
-            let mut irqs = IrqBitmask(0);
-            let _cycles = if !io.dmac.is_active() {
-                if HaltState::Running == io.haltcnt {
-                    self.step_cpu(io)
-                } else {
-                    cycles = cycles_left;
-                    break;
-                }
-            } else {
-                io.dmac.perform_work(&mut self.sysbus, &mut irqs);
-                io.intc.request_irqs(irqs);
-                return cycles;
-            };
+    /// Handles the events that are due
+    pub(crate) fn handle_events(&mut self, irqs: &mut IrqBitmask) {
+        let io = &mut self.sysbus.io;
+        while let Some((event, extra_cycles)) = io.scheduler.pop_pending_event() {
+            match event {
+                // only there to stop the cpu
+                EventType::RunLimitReached => {}
+                EventType::Gpu(state) => io.gpu.on_state_completed(
+                    state,
+                    extra_cycles,
+                    &mut io.dmac,
+                    &mut io.scheduler,
+                    irqs,
+                    &self.video_device,
+                ),
+                EventType::TimerOverflow(id) => io.timers.handle_overflow_event(
+                    id,
+                    extra_cycles,
+                    &mut io.sound,
+                    &mut io.dmac,
+                    &mut io.scheduler,
+                    irqs,
+                ),
+                EventType::SoundSample => io.sound.on_sample(extra_cycles, &mut io.scheduler),
+                EventType::DmaActivateChannel(id) => io.dmac.activate_channel(id),
+                EventType::Irq => io.intc.on_irq_event(),
+            }
+        }
+    }
 
-            cycles += _cycles;
-            if cycles_left < _cycles {
+    /// Runs the cpu until the next event, and handles it.
+    /// Returns the number of cycles that passed.
+    pub fn step(&mut self) -> usize {
+        let start_time = self.sysbus.io.scheduler.timestamp();
+
+        while self.sysbus.io.scheduler.cycles_to_next_event() > 0 {
+            if self.sysbus.io.dmac.is_active() {
+                self.step_dma();
+            } else if HaltState::Running == self.sysbus.io.haltcnt {
+                let cycles = self.step_cpu();
+                self.sysbus.io.scheduler.update(cycles);
+            } else if HaltState::Halt == self.sysbus.io.haltcnt {
+                // nothing happens until the next event
+                let scheduler = &mut self.sysbus.io.scheduler;
+                let cycles = scheduler.cycles_to_next_event();
+                scheduler.update(cycles);
+            } else {
+                // stopped, time doesn't pass for the gpu, sound and timers
                 break;
             }
-            cycles_left -= _cycles;
         }
 
-        // update gpu & sound
-        io.timers.update(cycles, &mut self.sysbus, &mut irqs);
-        io.gpu.update(
+        let cycles = (self.sysbus.io.scheduler.timestamp() - start_time) as usize;
+        let mut irqs = IrqBitmask(0);
+
+        self.sysbus.cartridge.update(cycles, &mut irqs);
+        self.sysbus.io.sio.update(
             cycles,
-            &mut self.sysbus,
             &mut irqs,
-            &mut cycles_to_next_event,
-            &self.video_device,
+            self.link
+                .as_mut()
+                .map(|link| link.as_mut() as &mut dyn LinkTransport),
         );
-        io.sound
-            .update(cycles, &mut cycles_to_next_event, &self.audio_device);
-        self.cycles_to_next_event = cycles_to_next_event;
-        io.intc.request_irqs(irqs);
+        self.handle_events(&mut irqs);
+        if self.sysbus.io.sound.needs_flush() {
+            self.flush_audio();
+        }
+
+        let io = &mut self.sysbus.io;
+        io.intc.request_irqs(irqs, &mut io.scheduler);
+
+        // Halt is left once an enabled interrupt is flagged, regardless of IME
+        if io.haltcnt == HaltState::Halt
+            && io.intc.interrupt_enable.0 & io.intc.interrupt_flags.0 != 0
+        {
+            io.haltcnt = HaltState::Running;
+        }
 
         cycles
     }
@@ -237,11 +728,59 @@ impl GameBoyAdvance {
     }
 }
 
+impl GameBoyAdvance {
+    /// Restores the start state of a movie, then takes the keys from it instead of the input device until it ends
+    pub fn play_movie(&mut self, movie: Movie) -> GBAResult<()> {
+        let header = &movie.header;
+        if header.rom_checksum != self.sysbus.cartridge.checksum() {
+            return Err(GBAError::MovieRomMismatch(
+                header.game_title.clone(),
+                header.game_code.clone(),
+            ));
+        }
+        if header.bios_checksum != self.sysbus.bios_checksum() {
+            return Err(GBAError::MovieBiosMismatch);
+        }
+        if header.hash_interval == 0 {
+            return Err(GBAError::InvalidMovie("hash interval is zero".to_string()));
+        }
+        self.restore_state(&header.start_state)?;
+        self.overshoot_cycles = 0;
+
+        self.stop_movie_playback();
+        let player = Arc::new(Mutex::new(MoviePlayer::new(movie)));
+        let live_input_device = std::mem::replace(&mut self.input_device, player.clone());
+        self.live_input_device = Some(live_input_device);
+        self.movie_player = Some(player);
+        Ok(())
+    }
+
+    /// Starts capturing the frames and the sound into a Y4M video file and a WAV file, see `AvRecorder`
+    pub fn start_av_recording(&mut self, video_path: &Path, audio_path: &Path) -> GBAResult<()> {
+        self.stop_av_recording()?;
+        let sample_rate = self.audio_device.lock().unwrap().get_sample_rate();
+        let recorder = Arc::new(Mutex::new(AvRecorder::create(
+            video_path,
+            audio_path,
+            sample_rate,
+        )?));
+        let video = RecordingVideo::new(self.video_device.clone(), recorder.clone());
+        let audio = RecordingAudio::new(self.audio_device.clone(), recorder.clone());
+        let live_video_device =
+            std::mem::replace(&mut self.video_device, Arc::new(Mutex::new(video)));
+        let live_audio_device =
+            std::mem::replace(&mut self.audio_device, Arc::new(Mutex::new(audio)));
+        self.live_av_devices = Some((live_video_device, live_audio_device));
+        self.av_recorder = Some(recorder);
+        self.set_audio_rate_adjustment(0.0);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::cell::RefCell;
-    use std::rc::Rc;
+    use std::thread;
 
     use super::super::bus::Bus;
     use super::super::cartridge::GamepakBuilder;
@@ -258,6 +797,52 @@ mod tests {
     impl AudioInterface for DummyInterface {}
     impl InputInterface for DummyInterface {}
 
+    #[derive(Default)]
+    struct CountingAudio {
+        samples: usize,
+    }
+
+    impl AudioInterface for CountingAudio {
+        fn push_sample(&mut self, _sample: crate::StereoSample<i16>) {
+            self.samples += 1;
+        }
+    }
+
+    /// Keys that stay as the test sets them
+    struct HeldKeys {
+        keyinput: u16,
+    }
+
+    impl InputInterface for HeldKeys {
+        fn poll(&mut self) -> u16 {
+            self.keyinput
+        }
+    }
+
+    /// A game that spins forever
+    fn make_spinning_cartridge() -> Cartridge {
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        GamepakBuilder::new()
+            .buffer(&rom)
+            .with_sram()
+            .without_backup_to_file()
+            .build()
+            .unwrap()
+    }
+
+    /// A spinning game on concrete devices, counting the samples it plays
+    fn make_counting_gba() -> GenericGameBoyAdvance<DummyInterface, CountingAudio, DummyInterface> {
+        let dummy = Arc::new(Mutex::new(DummyInterface::new()));
+        let audio = Arc::new(Mutex::new(CountingAudio::default()));
+        GenericGameBoyAdvance::new_with_hle_bios(
+            make_spinning_cartridge(),
+            dummy.clone(),
+            audio,
+            dummy,
+        )
+    }
+
     fn make_mock_gba(rom: &[u8]) -> GameBoyAdvance {
         let bios = vec![0; 0x4000].into_boxed_slice();
         let cartridge = GamepakBuilder::new()
@@ -266,7 +851,7 @@ mod tests {
             .without_backup_to_file()
             .build()
             .unwrap();
-        let dummy = Rc::new(RefCell::new(DummyInterface::new()));
+        let dummy = Arc::new(Mutex::new(DummyInterface::new()));
         let mut gba =
             GameBoyAdvance::new(bios, cartridge, dummy.clone(), dummy.clone(), dummy.clone());
         gba.skip_bios();
@@ -274,6 +859,290 @@ mod tests {
         gba
     }
 
+    #[test]
+    fn test_gba_runs_on_another_thread() {
+        let mut rom = vec![0; 0x200];
+        // b .
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+        gba.frame();
+
+        let gba = thread::spawn(move || {
+            let mut gba = gba;
+            gba.frame();
+            gba
+        })
+        .join()
+        .unwrap();
+        assert_eq!(gba.sysbus.read_32(gba.cpu.pc - 8), 0xeafffffe);
+    }
+
+    #[test]
+    fn test_concrete_devices() {
+        let mut gba = make_counting_gba();
+        gba.frame();
+        // about 738 at 44100Hz and 59.73 frames per second, depending on where the resampler stands
+        let samples = gba.audio_device.lock().unwrap().samples;
+        assert!(samples > 700 && samples < 780, "{}", samples);
+    }
+
+    #[test]
+    fn test_stop_wakes_on_keypad_irq() {
+        use super::super::iodev::consts::*;
+        use super::super::keypad::{Keys, KEYINPUT_ALL_RELEASED};
+
+        let dummy = Arc::new(Mutex::new(DummyInterface::new()));
+        let keys = Arc::new(Mutex::new(HeldKeys {
+            keyinput: KEYINPUT_ALL_RELEASED,
+        }));
+        let mut gba = GenericGameBoyAdvance::new_with_hle_bios(
+            make_spinning_cartridge(),
+            dummy.clone(),
+            dummy,
+            keys.clone(),
+        );
+        let hold = |pressed: u16| keys.lock().unwrap().keyinput = KEYINPUT_ALL_RELEASED & !pressed;
+        let key = |key: Keys| 1 << key as u16;
+        gba.sysbus.write_16(REG_IE, 1 << 12);
+
+        // KEYCNT with A and B selected, keys that don't wake, keys that do
+        let cases = [
+            (0x4003, key(Keys::Start), key(Keys::ButtonB)),
+            (
+                0xc003,
+                key(Keys::ButtonB),
+                key(Keys::ButtonA) | key(Keys::ButtonB),
+            ),
+        ];
+        for &(keycnt, stays, wakes) in cases.iter() {
+            hold(0);
+            gba.sysbus.write_16(REG_IF, 0xffff);
+            gba.sysbus.write_16(REG_KEYCNT, keycnt);
+            // POSTFLG in the low byte and HALTCNT in the high byte
+            gba.sysbus.write_16(REG_POSTFLG, 0x8001);
+            assert!(gba.sysbus.io.post_boot_flag);
+            assert_eq!(gba.sysbus.io.haltcnt, HaltState::Stop);
+
+            gba.frame();
+            assert_eq!(gba.sysbus.io.haltcnt, HaltState::Stop);
+            hold(stays);
+            gba.frame();
+            assert_eq!(
+                gba.sysbus.io.haltcnt,
+                HaltState::Stop,
+                "KEYCNT {:#x}",
+                keycnt
+            );
+            hold(wakes);
+            gba.frame();
+            assert_eq!(
+                gba.sysbus.io.haltcnt,
+                HaltState::Running,
+                "KEYCNT {:#x}",
+                keycnt
+            );
+            assert!(gba.sysbus.io.intc.interrupt_flags.Keypad());
+        }
+
+        gba.sysbus.write_16(REG_POSTFLG, 0x0002);
+        assert!(!gba.sysbus.io.post_boot_flag);
+        assert_eq!(gba.sysbus.io.haltcnt, HaltState::Halt);
+    }
+
+    #[test]
+    fn test_step_alone_flushes_the_sound() {
+        let mut gba = make_counting_gba();
+        let end = gba.sysbus.io.scheduler.timestamp() + 3 * CYCLES_FULL_REFRESH as u64;
+        while gba.sysbus.io.scheduler.timestamp() < end {
+            gba.step();
+        }
+        // three frames worth, less what is still waiting for the next flush
+        let samples = gba.audio_device.lock().unwrap().samples;
+        assert!(samples > 1000, "{}", samples);
+        assert!(!gba.sysbus.io.sound.needs_flush());
+    }
+
+    #[test]
+    fn test_no_rate_adjustment_while_recording() {
+        let mut gba = make_mock_gba(&[0; 0x200]);
+        gba.set_audio_rate_adjustment(0.01);
+        let temp_path =
+            |name| std::env::temp_dir().join(format!("rba-test-{}-{}", std::process::id(), name));
+        let video_path = temp_path("rate.y4m");
+        let audio_path = temp_path("rate.wav");
+        gba.start_av_recording(&video_path, &audio_path).unwrap();
+
+        gba.frame();
+        let recorder = gba.av_recorder().unwrap();
+        let start = recorder.lock().unwrap().samples();
+        for _ in 0..8 {
+            gba.set_audio_rate_adjustment(0.005);
+            gba.frame();
+        }
+        // 8 frames at 44100Hz and 59.73 frames per second, the adjustment would make 30 more
+        let samples = recorder.lock().unwrap().samples() - start;
+        assert!(samples >= 5905 && samples <= 5909, "{}", samples);
+
+        gba.stop_av_recording().unwrap();
+        std::fs::remove_file(video_path).unwrap();
+        std::fs::remove_file(audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_waitcnt_reaches_the_restored_bus() {
+        use super::super::sysbus::{MemoryAccessType, MemoryAccessWidth};
+
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+        gba.frame();
+
+        let dummy = Arc::new(Mutex::new(DummyInterface::new()));
+        let mut restored = GameBoyAdvance::from_saved_state(
+            &gba.save_state().unwrap(),
+            vec![0; 0x4000].into_boxed_slice(),
+            rom.into_boxed_slice(),
+            None,
+            dummy.clone(),
+            dummy.clone(),
+            dummy.clone(),
+        )
+        .unwrap();
+
+        let rom_cycles = |gba: &GameBoyAdvance| {
+            gba.sysbus.get_cycles(
+                0x0800_0000,
+                MemoryAccessType::NonSeq,
+                MemoryAccessWidth::MemoryAccess16,
+            )
+        };
+        let before = rom_cycles(&gba);
+        // WS0 first access down to 2 cycles
+        restored.sysbus.write_16(0x0400_0204, 0b1000);
+        assert_ne!(rom_cycles(&restored), before);
+        assert_eq!(rom_cycles(&gba), before);
+    }
+
+    #[test]
+    fn test_savestate_keeps_the_save_file_of_the_caller() {
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+        let original_path = dir.join(format!("rba-test-{}-original.sav", pid));
+        let restored_path = dir.join(format!("rba-test-{}-restored.sav", pid));
+
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let cartridge = GamepakBuilder::new()
+            .buffer(&rom)
+            .with_sram()
+            .save_path(&original_path)
+            .build()
+            .unwrap();
+        let dummy = Arc::new(Mutex::new(DummyInterface::new()));
+        let mut gba = GameBoyAdvance::new_with_hle_bios(
+            cartridge,
+            dummy.clone(),
+            dummy.clone(),
+            dummy.clone(),
+        );
+        gba.sysbus.write_8(0x0E00_0000, 0x12);
+        gba.sysbus.write_8(0x0E00_0001, 0x34);
+        let state = gba.save_state().unwrap();
+        gba.sysbus.write_8(0x0E00_0000, 0x56);
+
+        let mut restored = GameBoyAdvance::from_saved_state(
+            &state,
+            vec![].into_boxed_slice(),
+            rom.clone().into_boxed_slice(),
+            Some(&restored_path),
+            dummy.clone(),
+            dummy.clone(),
+            dummy.clone(),
+        )
+        .unwrap();
+        // the file of the running game is left alone
+        assert_eq!(std::fs::read(&original_path).unwrap()[0..2], [0x56, 0x34]);
+        assert_eq!(std::fs::read(&restored_path).unwrap()[0..2], [0x12, 0x34]);
+
+        restored.sysbus.write_8(0x0E00_0001, 0x78);
+        let state = restored.save_state().unwrap();
+        let again = GameBoyAdvance::from_saved_state(
+            &state,
+            vec![].into_boxed_slice(),
+            rom.into_boxed_slice(),
+            None,
+            dummy.clone(),
+            dummy.clone(),
+            dummy,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(&restored_path).unwrap()[0..2], [0x12, 0x78]);
+        assert_eq!(again.sysbus.read_8(0x0E00_0000), 0x12);
+        assert_eq!(again.sysbus.read_8(0x0E00_0001), 0x78);
+
+        let _ = std::fs::remove_file(&original_path);
+        let _ = std::fs::remove_file(&restored_path);
+    }
+
+    #[test]
+    fn test_rewind_restores_cpu_and_memory() {
+        let mut rom = vec![0; 0x200];
+        // mov r1, #0x3000000
+        rom[0..4].copy_from_slice(&0xe3a0_1403u32.to_le_bytes());
+        // loop: add r0, r0, #1
+        rom[4..8].copy_from_slice(&0xe280_0001u32.to_le_bytes());
+        // str r0, [r1]
+        rom[8..12].copy_from_slice(&0xe581_0000u32.to_le_bytes());
+        // b loop
+        rom[12..16].copy_from_slice(&0xeaff_fffcu32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+        gba.enable_rewind(1, 1 << 20);
+
+        let mut states = vec![];
+        for _ in 0..5 {
+            gba.frame();
+            states.push((gba.cpu.get_registers(), gba.sysbus.read_32(0x0300_0000)));
+        }
+        assert_ne!(states[2], states[4]);
+
+        assert_eq!(gba.rewind(2).unwrap(), 2);
+        assert_eq!(
+            (gba.cpu.get_registers(), gba.sysbus.read_32(0x0300_0000)),
+            states[2]
+        );
+        // going back further than the history stops at the oldest snapshot
+        assert_eq!(gba.rewind(10).unwrap(), 2);
+        assert_eq!(
+            (gba.cpu.get_registers(), gba.sysbus.read_32(0x0300_0000)),
+            states[0]
+        );
+    }
+
+    #[test]
+    fn test_no_restore_while_recording_a_movie() {
+        let mut rom = vec![0; 0x200];
+        rom[0..4].copy_from_slice(&0xeaff_fffeu32.to_le_bytes());
+        let mut gba = make_mock_gba(&rom);
+        gba.enable_rewind(1, 1 << 20);
+        gba.frame();
+        let state = gba.save_state().unwrap();
+
+        gba.start_movie_recording(false).unwrap();
+        gba.frame();
+        match gba.restore_state(&state) {
+            Err(GBAError::MovieRecordingInProgress) => {}
+            _ => panic!("expected the restore to be refused"),
+        }
+        match gba.rewind(1) {
+            Err(GBAError::MovieRecordingInProgress) => {}
+            _ => panic!("expected the rewind to be refused"),
+        }
+
+        assert_eq!(gba.stop_movie_recording().unwrap().len(), 1);
+        gba.restore_state(&state).unwrap();
+        assert_eq!(gba.rewind(1).unwrap(), 1);
+    }
+
     #[test]
     fn test_arm7tdmi_arm_eggvance() {
         let mut gba = make_mock_gba(include_bytes!("../../../external/gba-suite/arm/arm.gba"));
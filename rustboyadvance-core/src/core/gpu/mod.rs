@@ -1,12 +1,13 @@
-use std::cell::RefCell;
 use std::fmt;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use serde::{Deserialize, Serialize};
 
 use super::super::VideoInterface;
+use super::dma::DmaController;
 use super::interrupt::IrqBitmask;
-use super::sysbus::{BoxedMemory, SysBus};
+use super::sched::{EventType, Scheduler};
+use super::sysbus::BoxedMemory;
 use super::Bus;
 
 use crate::bitfield::Bit;
@@ -14,6 +15,7 @@ use crate::num::FromPrimitive;
 
 mod render;
 
+use mosaic::MosaicCounter;
 use render::Point;
 
 mod layer;
@@ -45,6 +47,7 @@ pub mod consts {
     pub(super) const CYCLES_SCANLINE: usize = 1232;
     pub(super) const CYCLES_VDRAW: usize = 197120;
     pub(super) const CYCLES_VBLANK: usize = 83776;
+    pub const CYCLES_FULL_REFRESH: usize = CYCLES_VDRAW + CYCLES_VBLANK;
 
     pub const TILE_SIZE: u32 = 0x20;
 }
@@ -121,9 +124,6 @@ pub struct Background {
     pub bghofs: u16,
 
     line: Scanline,
-
-    // for mosaic
-    mosaic_first_row: Scanline,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -165,15 +165,10 @@ impl Default for ObjBufferEntry {
     }
 }
 
-type VideoDeviceRcRefCell = Rc<RefCell<dyn VideoInterface>>;
-
 #[derive(Serialize, Deserialize, Clone, DebugStub)]
 pub struct Gpu {
     pub state: GpuState,
 
-    /// how many cycles left until next gpu state ?
-    cycles_left_for_current_state: usize,
-
     // registers
     pub vcount: usize, // VCOUNT
     pub dispcnt: DisplayControl,
@@ -188,6 +183,8 @@ pub struct Gpu {
     pub winobj_flags: WindowFlags,
 
     pub mosaic: RegMosaic,
+    bg_mosaic: MosaicCounter,
+    obj_mosaic: MosaicCounter,
     pub bldcnt: BlendControl,
     pub bldalpha: BlendAlpha,
     pub bldy: u16,
@@ -204,7 +201,8 @@ pub struct Gpu {
 }
 
 impl Gpu {
-    pub fn new() -> Gpu {
+    pub fn new(scheduler: &mut Scheduler) -> Gpu {
+        scheduler.schedule(EventType::Gpu(HDraw), CYCLES_HDRAW);
         Gpu {
             dispcnt: DisplayControl(0x80),
             dispstat: DisplayStatus(0),
@@ -220,13 +218,14 @@ impl Gpu {
             winout_flags: WindowFlags::from(0),
             winobj_flags: WindowFlags::from(0),
             mosaic: RegMosaic(0),
+            bg_mosaic: MosaicCounter::default(),
+            obj_mosaic: MosaicCounter::default(),
             bldcnt: BlendControl(0),
             bldalpha: BlendAlpha(0),
             bldy: 0,
 
             state: HDraw,
             vcount: 0,
-            cycles_left_for_current_state: CYCLES_HDRAW,
 
             palette_ram: BoxedMemory::new(vec![0; PALETTE_RAM_SIZE].into_boxed_slice()),
             vram: BoxedMemory::new(vec![0; VIDEO_RAM_SIZE].into_boxed_slice()),
@@ -296,24 +295,31 @@ impl Gpu {
 
     pub fn get_ref_point(&self, bg: usize) -> Point {
         assert!(bg == 2 || bg == 3);
-        (
-            self.bg_aff[bg - 2].internal_x,
-            self.bg_aff[bg - 2].internal_y,
-        )
+        let bg_aff = &self.bg_aff[bg - 2];
+        if self.backgrounds[bg].bgcnt.mosaic() {
+            // roll back the reference point to the scanline currently latched by the vertical mosaic
+            let dy = (self.vcount - self.bg_mosaic.y) as i32;
+            (
+                bg_aff.internal_x - dy * (bg_aff.pb as i32),
+                bg_aff.internal_y - dy * (bg_aff.pd as i32),
+            )
+        } else {
+            (bg_aff.internal_x, bg_aff.internal_y)
+        }
     }
 
     pub fn render_scanline(&mut self) {
         if self.dispcnt.enable_obj() {
             self.render_objs();
         }
-        match self.dispcnt.mode() {
+        let (bg_start, bg_end) = match self.dispcnt.mode() {
             0 => {
                 for bg in 0..=3 {
                     if self.dispcnt.enable_bg(bg) {
                         self.render_reg_bg(bg);
                     }
                 }
-                self.finalize_scanline(0, 3);
+                (0, 3)
             }
             1 => {
                 if self.dispcnt.enable_bg(2) {
@@ -325,7 +331,7 @@ impl Gpu {
                 if self.dispcnt.enable_bg(0) {
                     self.render_reg_bg(0);
                 }
-                self.finalize_scanline(0, 2);
+                (0, 2)
             }
             2 => {
                 if self.dispcnt.enable_bg(3) {
@@ -334,19 +340,31 @@ impl Gpu {
                 if self.dispcnt.enable_bg(2) {
                     self.render_aff_bg(2);
                 }
-                self.finalize_scanline(2, 3);
+                (2, 3)
             }
             3 => {
                 self.render_mode3(2);
-                self.finalize_scanline(2, 2);
+                (2, 2)
             }
             4 => {
                 self.render_mode4(2);
-                self.finalize_scanline(2, 2);
+                (2, 2)
             }
-            _ => panic!("{:?} not supported", self.dispcnt.mode()),
-        }
-        // self.mosaic_sfx();
+            5 => {
+                self.render_mode5(2);
+                (2, 2)
+            }
+            _ => {
+                // modes 6 and 7 are prohibited, there is no valid background data to show,
+                // so only the backdrop and the sprites are drawn.
+                for bg in 0..=3 {
+                    self.backgrounds[bg].line = Scanline::default();
+                }
+                (0, 3)
+            }
+        };
+        self.mosaic_sfx(bg_start, bg_end);
+        self.finalize_scanline(bg_start, bg_end);
     }
 
     fn update_vcount(&mut self, value: usize, irqs: &mut IrqBitmask) {
@@ -371,24 +389,27 @@ impl Gpu {
         &self.frame_buffer
     }
 
-    pub fn on_state_completed(
+    /// Moves on to the next state once the current one is completed, `extra_cycles` late
+    pub fn on_state_completed<V: VideoInterface + ?Sized>(
         &mut self,
         completed: GpuState,
-        sb: &mut SysBus,
+        extra_cycles: usize,
+        dmac: &mut DmaController,
+        scheduler: &mut Scheduler,
         irqs: &mut IrqBitmask,
-        video_device: &VideoDeviceRcRefCell,
+        video_device: &Arc<Mutex<V>>,
     ) {
-        match completed {
+        let cycles_for_next_state = match completed {
             HDraw => {
                 // Transition to HBlank
                 self.state = HBlank;
-                self.cycles_left_for_current_state = CYCLES_HBLANK;
                 self.dispstat.set_hblank_flag(true);
 
                 if self.dispstat.hblank_irq_enable() {
                     irqs.set_LCD_HBlank(true);
                 };
-                sb.io.dmac.notify_hblank();
+                dmac.notify_hblank();
+                CYCLES_HBLANK
             }
             HBlank => {
                 self.update_vcount(self.vcount + 1, irqs);
@@ -396,13 +417,13 @@ impl Gpu {
                 if self.vcount < DISPLAY_HEIGHT {
                     self.state = HDraw;
                     self.dispstat.set_hblank_flag(false);
+                    self.mosaic_next_line();
                     self.render_scanline();
                     // update BG2/3 reference points on the end of a scanline
                     for i in 0..2 {
                         self.bg_aff[i].internal_x += self.bg_aff[i].pb as i16 as i32;
                         self.bg_aff[i].internal_y += self.bg_aff[i].pd as i16 as i32;
                     }
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                 } else {
                     // latch BG2/3 reference points on vblank
                     for i in 0..2 {
@@ -416,66 +437,44 @@ impl Gpu {
                         irqs.set_LCD_VBlank(true);
                     };
 
-                    sb.io.dmac.notify_vblank();
-                    video_device.borrow_mut().render(&self.frame_buffer);
+                    dmac.notify_vblank();
+                    video_device.lock().unwrap().render(&self.frame_buffer);
                     self.obj_buffer_reset();
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = VBlankHDraw;
                 }
+                CYCLES_HDRAW
             }
             VBlankHDraw => {
-                self.cycles_left_for_current_state = CYCLES_HBLANK;
                 self.state = VBlankHBlank;
 
                 self.dispstat.set_hblank_flag(true);
                 if self.dispstat.hblank_irq_enable() {
                     irqs.set_LCD_HBlank(true);
                 };
+                CYCLES_HBLANK
             }
             VBlankHBlank => {
                 self.update_vcount(self.vcount + 1, irqs);
 
                 if self.vcount < DISPLAY_HEIGHT + VBLANK_LINES - 1 {
                     self.dispstat.set_hblank_flag(false);
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = VBlankHDraw;
                 } else {
                     self.update_vcount(0, irqs);
                     self.dispstat.set_vblank_flag(false);
+                    self.mosaic_reset();
                     self.render_scanline();
-                    self.cycles_left_for_current_state = CYCLES_HDRAW;
                     self.state = HDraw;
                 }
+                CYCLES_HDRAW
             }
         };
-    }
-
-    // Returns the new gpu state
-    pub fn update(
-        &mut self,
-        cycles: usize,
-        sb: &mut SysBus,
-        irqs: &mut IrqBitmask,
-        cycles_to_next_event: &mut usize,
-        video_device: &VideoDeviceRcRefCell,
-    ) {
-        if self.cycles_left_for_current_state <= cycles {
-            let overshoot = cycles - self.cycles_left_for_current_state;
-
-            self.on_state_completed(self.state, sb, irqs, video_device);
-
-            // handle the overshoot
-            if overshoot < self.cycles_left_for_current_state {
-                self.cycles_left_for_current_state -= overshoot;
-            } else {
-                panic!("OH SHIT");
-            }
-        } else {
-            self.cycles_left_for_current_state -= cycles;
-        }
-
-        if self.cycles_left_for_current_state < *cycles_to_next_event {
-            *cycles_to_next_event = self.cycles_left_for_current_state;
-        }
+        // scheduled from when the state actually ended, if the event was handled late the next
+        // state might already be completed too and is handled right away
+        let completed_time = scheduler.timestamp() - extra_cycles as u64;
+        scheduler.schedule_at(
+            EventType::Gpu(self.state),
+            completed_time + cycles_for_next_state as u64,
+        );
     }
 }
@@ -7,29 +7,107 @@ impl RegMosaic {
     }
 }
 
+/// Tracks the vertical mosaic, the scanline being sampled is latched once every `vsize + 1` lines.
+#[derive(Serialize, Deserialize, Debug, Default, Copy, Clone)]
+pub struct MosaicCounter {
+    pub(super) y: usize,
+    counter: usize,
+}
+
+impl MosaicCounter {
+    fn reset(&mut self) {
+        self.y = 0;
+        self.counter = 0;
+    }
+
+    fn next_line(&mut self, vcount: usize, vsize: usize) {
+        self.counter += 1;
+        if self.counter > vsize {
+            self.counter = 0;
+            self.y = vcount;
+        }
+    }
+}
+
 impl Gpu {
-    fn mosaic_bg(&mut self) {
+    /// Should be called at the start of each frame
+    pub(super) fn mosaic_reset(&mut self) {
+        self.bg_mosaic.reset();
+        self.obj_mosaic.reset();
+    }
+
+    /// Should be called every time vcount advances during VDraw
+    pub(super) fn mosaic_next_line(&mut self) {
+        let vcount = self.vcount;
+        self.bg_mosaic
+            .next_line(vcount, self.mosaic.bg_vsize() as usize);
+        self.obj_mosaic
+            .next_line(vcount, self.mosaic.obj_vsize() as usize);
+    }
+
+    fn mosaic_bg(&mut self, bg_start: usize, bg_end: usize) {
         let hsize = (self.mosaic.bg_hsize() + 1) as usize;
-        let vsize = (self.mosaic.bg_vsize() + 1) as usize;
+        if hsize == 1 {
+            return;
+        }
 
-        for bg in 0..4 {
+        for bg in bg_start..=bg_end {
             if self.dispcnt.enable_bg(bg) && self.backgrounds[bg].bgcnt.mosaic() {
-                let y = self.vcount as usize;
-                if y % vsize == 0 {
-                    self.backgrounds[bg].mosaic_first_row = self.backgrounds[bg].line.clone();
-                }
                 for x in 0..DISPLAY_WIDTH {
-                    let color = self.backgrounds[bg].mosaic_first_row[(x / hsize) * hsize];
+                    let color = self.backgrounds[bg].line[(x / hsize) * hsize];
                     self.backgrounds[bg].line[x] = color;
                 }
             }
         }
     }
 
-    pub fn mosaic_sfx(&mut self) {
+    /// Applies the horizontal BG mosaic to the rendered background lines.
+    /// Vertical mosaic is handled by the renderers themselves, and OBJ mosaic is done while rendering the sprites.
+    pub fn mosaic_sfx(&mut self, bg_start: usize, bg_end: usize) {
         if self.mosaic.is_enabled_for_bg() {
-            self.mosaic_bg();
+            self.mosaic_bg(bg_start, bg_end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The latched line of every scanline in a frame
+    fn latched_lines(vsize: usize) -> Vec<usize> {
+        let mut counter = MosaicCounter::default();
+        counter.reset();
+        let mut lines = vec![counter.y];
+        for vcount in 1..DISPLAY_HEIGHT {
+            counter.next_line(vcount, vsize);
+            lines.push(counter.y);
+        }
+        lines
+    }
+
+    #[test]
+    fn test_mosaic_counter_next_line() {
+        assert_eq!(latched_lines(1)[..7], [0, 0, 2, 2, 4, 4, 6]);
+        assert_eq!(latched_lines(2)[..7], [0, 0, 0, 3, 3, 3, 6]);
+        for &vsize in &[0, 3, 7, 15] {
+            let expected: Vec<usize> = (0..DISPLAY_HEIGHT)
+                .map(|vcount| vcount - vcount % (vsize + 1))
+                .collect();
+            assert_eq!(latched_lines(vsize), expected, "vsize {}", vsize);
+        }
+    }
+
+    #[test]
+    fn test_mosaic_counter_restarts_each_frame() {
+        let mut counter = MosaicCounter::default();
+        for vcount in 1..10 {
+            counter.next_line(vcount, 3);
         }
-        // TODO obj mosaic
+        assert_eq!(counter.y, 8);
+        counter.reset();
+        assert_eq!(counter.y, 0);
+        counter.next_line(1, 3);
+        assert_eq!(counter.y, 0);
     }
 }
@@ -1,14 +1,29 @@
-//! Rendering for modes 4-5
+//! Rendering for modes 3-5
 
 use super::super::consts::*;
 use super::super::Gpu;
 use super::super::Rgb15;
 
-use super::{utils, SCREEN_VIEWPORT};
+use super::{utils, ViewPort, SCREEN_VIEWPORT};
 
 use crate::core::Bus;
 
+const MODE5_VIEWPORT: ViewPort = ViewPort {
+    origin: (0, 0),
+    w: 160,
+    h: 128,
+};
+
 impl Gpu {
+    /// Offset into vram of the bitmap currently selected for display in modes 4-5
+    fn bitmap_page_ofs(&self) -> u32 {
+        match self.dispcnt.display_frame() {
+            0 => 0x0600_0000 - VRAM_ADDR,
+            1 => 0x0600_a000 - VRAM_ADDR,
+            _ => unreachable!(),
+        }
+    }
+
     pub(in super::super) fn render_mode3(&mut self, bg: usize) {
         let _y = self.vcount;
 
@@ -31,11 +46,7 @@ impl Gpu {
     }
 
     pub(in super::super) fn render_mode4(&mut self, bg: usize) {
-        let page_ofs: u32 = match self.dispcnt.display_frame() {
-            0 => 0x0600_0000 - VRAM_ADDR,
-            1 => 0x0600_a000 - VRAM_ADDR,
-            _ => unreachable!(),
-        };
+        let page_ofs = self.bitmap_page_ofs();
 
         let _y = self.vcount;
 
@@ -56,4 +67,97 @@ impl Gpu {
             self.backgrounds[bg].line[x] = color;
         }
     }
+
+    pub(in super::super) fn render_mode5(&mut self, bg: usize) {
+        let page_ofs = self.bitmap_page_ofs();
+
+        let _y = self.vcount;
+
+        let pa = self.bg_aff[bg - 2].pa as i32;
+        let pc = self.bg_aff[bg - 2].pc as i32;
+        let ref_point = self.get_ref_point(bg);
+
+        for x in 0..DISPLAY_WIDTH {
+            let t = utils::transform_bg_point(ref_point, x as i32, pa, pc);
+            if !MODE5_VIEWPORT.contains_point(t) {
+                self.backgrounds[bg].line[x] = Rgb15::TRANSPARENT;
+                continue;
+            }
+            let pixel_index = index2d!(u32, t.0, t.1, MODE5_VIEWPORT.w);
+            let pixel_ofs = page_ofs + 2 * pixel_index;
+            let color = Rgb15(self.vram.read_16(pixel_ofs));
+            self.backgrounds[bg].line[x] = color;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::sched::Scheduler;
+
+    const TRANSPARENT: u16 = Rgb15::TRANSPARENT.0;
+
+    /// Renders BG2 in mode 5 with the reference point at (x, y), returns the colors of the line
+    fn render_line(gpu: &mut Gpu, x: i32, y: i32) -> Vec<u16> {
+        gpu.bg_aff[0].internal_x = x << 8;
+        gpu.bg_aff[0].internal_y = y << 8;
+        gpu.render_mode5(2);
+        (0..DISPLAY_WIDTH)
+            .map(|x| gpu.backgrounds[2].line[x].0)
+            .collect()
+    }
+
+    #[test]
+    fn test_render_mode5() {
+        let mut gpu = Gpu::new(&mut Scheduler::new());
+        gpu.skip_bios();
+        // the front page has the coordinates of each pixel as its color, the back page their complement
+        for y in 0..128 {
+            for x in 0..160 {
+                let ofs = 2 * (y * 160 + x);
+                let color = (x | y << 8) as u16;
+                gpu.vram.write_16(ofs, color);
+                gpu.vram.write_16(0xa000 + ofs, !color & 0x7fff);
+            }
+        }
+
+        // only the left 160 pixels are in the bitmap
+        let line = render_line(&mut gpu, 0, 5);
+        let expected: Vec<u16> = (0..240)
+            .map(|x| if x < 160 { x | 5 << 8 } else { TRANSPARENT })
+            .collect();
+        assert_eq!(line, expected);
+
+        gpu.dispcnt.set_display_frame(1);
+        let line = render_line(&mut gpu, 0, 127);
+        let expected: Vec<u16> = (0..240)
+            .map(|x| {
+                if x < 160 {
+                    !(x | 127 << 8) & 0x7fff
+                } else {
+                    TRANSPARENT
+                }
+            })
+            .collect();
+        assert_eq!(line, expected);
+        gpu.dispcnt.set_display_frame(0);
+
+        // scrolled, the bitmap starts 8 pixels in
+        let line = render_line(&mut gpu, -8, 0);
+        let expected: Vec<u16> = (0..240)
+            .map(|x| {
+                if x >= 8 && x < 168 {
+                    x - 8
+                } else {
+                    TRANSPARENT
+                }
+            })
+            .collect();
+        assert_eq!(line, expected);
+
+        // above and below the 128 lines of the bitmap
+        assert_eq!(render_line(&mut gpu, 0, -1), vec![TRANSPARENT; 240]);
+        assert_eq!(render_line(&mut gpu, 0, 128), vec![TRANSPARENT; 240]);
+    }
 }
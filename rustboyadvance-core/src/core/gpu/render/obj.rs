@@ -54,6 +54,20 @@ impl ObjAttrs {
     }
 }
 
+/// Returns the screen coordinate a mosaic sprite pixel should be sampled from,
+/// without going past the top-left edge of the sprite.
+#[inline]
+fn obj_mosaic_point(
+    screen_x: i32,
+    mosaic_y: i32,
+    hsize: i32,
+    obj_x: i32,
+    obj_y: i32,
+) -> (i32, i32) {
+    let x = screen_x - (screen_x % hsize);
+    (std::cmp::max(x, obj_x), std::cmp::max(mosaic_y, obj_y))
+}
+
 impl Gpu {
     fn get_affine_matrix(&self, affine_index: u32) -> AffineMatrix {
         let mut offset = AFFINE_FILL + affine_index * 16 * 2;
@@ -68,6 +82,18 @@ impl Gpu {
         AffineMatrix { pa, pb, pc, pd }
     }
 
+    /// Returns the horizontal mosaic block size and the sampled scanline for a sprite
+    fn obj_mosaic_params(&self, attrs: &ObjAttrs) -> (i32, i32) {
+        if attrs.0.mosaic() {
+            (
+                (self.mosaic.obj_hsize() + 1) as i32,
+                self.obj_mosaic.y as i32,
+            )
+        } else {
+            (1, self.vcount as i32)
+        }
+    }
+
     fn read_obj_attrs(&self, obj: usize) -> ObjAttrs {
         let addr = ATTRS_SIZE * (obj as u32);
         let attr0 = Attribute0(self.oam.read_16(addr + 0));
@@ -117,7 +143,7 @@ impl Gpu {
         let half_width = bbox_w / 2;
         let half_height = bbox_h / 2;
         let screen_width = DISPLAY_WIDTH as i32;
-        let iy = screen_y - (ref_y + half_height);
+        let (mosaic_hsize, mosaic_y) = self.obj_mosaic_params(&attrs);
 
         macro_rules! render_loop {
             ($read_pixel_index_fn:ident) => {
@@ -138,6 +164,10 @@ impl Gpu {
                         continue;
                     }
 
+                    let (sample_x, sample_y) =
+                        obj_mosaic_point(screen_x, mosaic_y, mosaic_hsize, ref_x, ref_y);
+                    let ix = sample_x - (ref_x + half_width);
+                    let iy = sample_y - (ref_y + half_height);
                     let transformed_x = (affine_matrix.pa * ix + affine_matrix.pb * iy) >> 8;
                     let transformed_y = (affine_matrix.pc * ix + affine_matrix.pd * iy) >> 8;
                     let texture_x = transformed_x + obj_w / 2;
@@ -204,6 +234,7 @@ impl Gpu {
         // render the pixels
         let screen_width = DISPLAY_WIDTH as i32;
         let end_x = ref_x + obj_w;
+        let (mosaic_hsize, mosaic_y) = self.obj_mosaic_params(&attrs);
 
         macro_rules! render_loop {
             ($read_pixel_index_fn:ident) => {
@@ -222,8 +253,10 @@ impl Gpu {
                     {
                         continue;
                     }
-                    let mut sprite_y = screen_y - ref_y;
-                    let mut sprite_x = screen_x - ref_x;
+                    let (sample_x, sample_y) =
+                        obj_mosaic_point(screen_x, mosaic_y, mosaic_hsize, ref_x, ref_y);
+                    let mut sprite_y = sample_y - ref_y;
+                    let mut sprite_x = sample_x - ref_x;
                     sprite_y = if attrs.1.v_flip() {
                         obj_h - sprite_y - 1
                     } else {
@@ -346,3 +379,28 @@ bitfield! {
     priority, _: 11, 10;
     into u32, palette, _: 15, 12;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_obj_mosaic_point_clamps_to_the_sprite() {
+        // a sprite at (13, 10), with 4 pixel wide blocks
+        let block_xs: Vec<i32> = (13..21)
+            .map(|x| obj_mosaic_point(x, 10, 4, 13, 10).0)
+            .collect();
+        assert_eq!(block_xs, [13, 13, 13, 16, 16, 16, 16, 20]);
+
+        // the latched line is above the sprite's top edge until the counter catches up
+        assert_eq!(obj_mosaic_point(13, 8, 4, 13, 10), (13, 10));
+        assert_eq!(obj_mosaic_point(17, 12, 4, 13, 10), (16, 12));
+
+        // partly off the left of the screen
+        assert_eq!(obj_mosaic_point(0, 0, 4, -5, -3), (0, 0));
+        assert_eq!(obj_mosaic_point(6, 0, 4, -5, -3), (4, 0));
+
+        // blocks of 1 pixel sample every pixel
+        assert_eq!(obj_mosaic_point(15, 11, 1, 13, 10), (15, 11));
+    }
+}
@@ -19,7 +19,11 @@ impl Gpu {
 
         let (bg_width, bg_height) = self.backgrounds[bg].bgcnt.size_regular();
 
-        let screen_y = self.vcount as u32;
+        let screen_y = if self.backgrounds[bg].bgcnt.mosaic() {
+            self.bg_mosaic.y as u32
+        } else {
+            self.vcount as u32
+        };
         let mut screen_x = 0;
 
         // calculate the bg coords at the top-left corner, including wraparound
@@ -1,5 +1,10 @@
 use serde::{Deserialize, Serialize};
 
+use super::sched::{EventType, Scheduler};
+
+/// The cpu sees changes to IME, IE and IF a few cycles late, as the IRQ signal goes through a synchronizer
+const IRQ_SYNC_CYCLES: usize = 3;
+
 #[derive(Serialize, Deserialize, Debug, Primitive, Copy, Clone, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Interrupt {
@@ -24,6 +29,10 @@ pub struct InterruptController {
     pub interrupt_master_enable: bool,
     pub interrupt_enable: IrqBitmask,
     pub interrupt_flags: IrqBitmask,
+    /// The IRQ signal as the cpu sees it, it follows `irq_pending` through `EventType::Irq`
+    irq_line: bool,
+    /// Set while an `EventType::Irq` is on its way
+    irq_sync_scheduled: bool,
 }
 
 impl InterruptController {
@@ -34,13 +43,32 @@ impl InterruptController {
         }
     }
 
-    pub fn request_irqs(&mut self, flags: IrqBitmask) {
+    pub fn request_irqs(&mut self, flags: IrqBitmask, scheduler: &mut Scheduler) {
         self.interrupt_flags.0 |= flags.0;
+        self.sync_irq_line(scheduler);
     }
 
     pub fn irq_pending(&self) -> bool {
         self.interrupt_master_enable & ((self.interrupt_flags.0 & self.interrupt_enable.0) != 0)
     }
+
+    /// Whether the cpu sees an interrupt request
+    pub fn irq_line(&self) -> bool {
+        self.irq_line
+    }
+
+    /// Must be called after IME, IE or IF change, the IRQ line follows them `IRQ_SYNC_CYCLES` later
+    pub fn sync_irq_line(&mut self, scheduler: &mut Scheduler) {
+        if self.irq_pending() != self.irq_line && !self.irq_sync_scheduled {
+            scheduler.schedule(EventType::Irq, IRQ_SYNC_CYCLES);
+            self.irq_sync_scheduled = true;
+        }
+    }
+
+    pub fn on_irq_event(&mut self) {
+        self.irq_sync_scheduled = false;
+        self.irq_line = self.irq_pending();
+    }
 }
 
 impl IrqBitmask {
@@ -83,3 +111,40 @@ bitfield! {
     #[allow(non_snake_case)]
     pub GamePak, set_GamePak: 13;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_irq_line_follows_after_the_sync_delay() {
+        let mut scheduler = Scheduler::new();
+        let mut intc = InterruptController::new();
+        intc.interrupt_master_enable = true;
+        intc.interrupt_enable.set_LCD_VBlank(true);
+
+        let mut irqs = IrqBitmask(0);
+        irqs.set_LCD_VBlank(true);
+        intc.request_irqs(irqs, &mut scheduler);
+        assert!(intc.irq_pending());
+        assert!(!intc.irq_line());
+        assert_eq!(scheduler.cycles_to_next_event(), IRQ_SYNC_CYCLES);
+
+        // another request before the line catches up doesn't schedule a second event
+        intc.request_irqs(irqs, &mut scheduler);
+        scheduler.update(IRQ_SYNC_CYCLES);
+        assert_eq!(scheduler.pop_pending_event(), Some((EventType::Irq, 0)));
+        assert_eq!(scheduler.pop_pending_event(), None);
+        intc.on_irq_event();
+        assert!(intc.irq_line());
+
+        // acknowledging the irq drops the line just as late
+        intc.interrupt_flags.0 = 0;
+        intc.sync_irq_line(&mut scheduler);
+        assert!(intc.irq_line());
+        scheduler.update(IRQ_SYNC_CYCLES);
+        assert_eq!(scheduler.pop_pending_event(), Some((EventType::Irq, 0)));
+        intc.on_irq_event();
+        assert!(!intc.irq_line());
+    }
+}
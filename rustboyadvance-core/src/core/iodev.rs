@@ -2,9 +2,10 @@ use super::dma::DmaController;
 use super::gpu::regs::WindowFlags;
 use super::gpu::*;
 use super::interrupt::InterruptController;
-use super::keypad;
+use super::keypad::{self, KeyControl};
+use super::sched::Scheduler;
+use super::sio::SerialController;
 use super::sound::SoundController;
-use super::sysbus::SysBusPtr;
 use super::timer::Timers;
 use super::{Addr, Bus};
 
@@ -21,42 +22,70 @@ pub enum HaltState {
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct IoDevices {
+    pub scheduler: Scheduler,
     pub intc: InterruptController,
     pub gpu: Box<Gpu>,
     pub sound: Box<SoundController>,
     pub timers: Timers,
     pub dmac: DmaController,
+    pub sio: SerialController,
     pub keyinput: u16,
+    pub keycnt: KeyControl,
     pub post_boot_flag: bool,
     pub waitcnt: WaitControl, // TODO also implement 4000800
     pub haltcnt: HaltState,
-
-    // HACK
-    // my ownership design sucks
-    #[serde(skip)]
-    #[serde(default = "SysBusPtr::default")]
-    sysbus_ptr: SysBusPtr,
 }
 
 impl IoDevices {
-    pub fn new(gpu: Box<Gpu>, sound_controller: Box<SoundController>) -> IoDevices {
+    pub fn new(
+        scheduler: Scheduler,
+        gpu: Box<Gpu>,
+        sound_controller: Box<SoundController>,
+    ) -> IoDevices {
         IoDevices {
+            scheduler: scheduler,
             gpu: gpu,
             sound: sound_controller,
             timers: Timers::new(),
             dmac: DmaController::new(),
+            sio: SerialController::new(),
             intc: InterruptController::new(),
             post_boot_flag: false,
             haltcnt: HaltState::Running,
             keyinput: keypad::KEYINPUT_ALL_RELEASED,
+            keycnt: KeyControl(0),
             waitcnt: WaitControl(0),
+        }
+    }
 
-            sysbus_ptr: Default::default(),
+    /// Requests the keypad interrupt if the keys selected in KEYCNT are pressed.
+    /// Leaves STOP mode once an enabled interrupt is flagged, which is usually this one.
+    pub fn check_keypad_irq(&mut self) {
+        if self.keycnt.irq_condition(self.keyinput) {
+            self.intc.interrupt_flags.set_Keypad(true);
+            self.intc.sync_irq_line(&mut self.scheduler);
+        }
+        if self.haltcnt == HaltState::Stop
+            && self.intc.interrupt_enable.0 & self.intc.interrupt_flags.0 != 0
+        {
+            self.haltcnt = HaltState::Running;
         }
     }
 
-    pub fn set_sysbus_ptr(&mut self, ptr: SysBusPtr) {
-        self.sysbus_ptr = ptr;
+    fn write_haltcnt(&mut self, value: u8) {
+        self.haltcnt = if value & 0x80 != 0 {
+            HaltState::Stop
+        } else {
+            HaltState::Halt
+        }
+    }
+
+    /// Registers that change when the cpu reads them, see `SysBus::on_cpu_read`
+    pub fn on_cpu_read(&mut self, addr: Addr, len: u32) {
+        let io_addr = addr + IO_BASE;
+        if (io_addr..io_addr + len).contains(&REG_SIODATA8) {
+            self.sio.on_data8_read();
+        }
     }
 }
 
@@ -92,7 +121,7 @@ impl Bus for IoDevices {
             REG_IE => io.intc.interrupt_enable.0 as u16,
             REG_IF => io.intc.interrupt_flags.0 as u16,
 
-            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_read(io_addr),
+            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_read(io_addr, io.scheduler.timestamp()),
 
             SOUND_BASE..=SOUND_END => io.sound.handle_read(io_addr),
             REG_DMA0CNT_H => io.dmac.channels[0].ctrl.0,
@@ -100,11 +129,14 @@ impl Bus for IoDevices {
             REG_DMA2CNT_H => io.dmac.channels[2].ctrl.0,
             REG_DMA3CNT_H => io.dmac.channels[3].ctrl.0,
 
+            REG_SIOMULTI0..=REG_SIODATA8 | REG_RCNT => io.sio.handle_read(io_addr),
+
             REG_WAITCNT => io.waitcnt.0,
 
             REG_POSTFLG => io.post_boot_flag as u16,
             REG_HALTCNT => 0,
             REG_KEYINPUT => io.keyinput as u16,
+            REG_KEYCNT => io.keycnt.0,
 
             _ => {
                 trace!(
@@ -127,7 +159,7 @@ impl Bus for IoDevices {
     }
 
     fn write_16(&mut self, addr: Addr, value: u16) {
-        let mut io = self;
+        let io = self;
         if addr > 0x0800 {
             return;
         }
@@ -228,11 +260,27 @@ impl Bus for IoDevices {
             REG_BLDALPHA => io.gpu.bldalpha.0 = value,
             REG_BLDY => io.gpu.bldy = value & 0b11111,
 
-            REG_IME => io.intc.interrupt_master_enable = value != 0,
-            REG_IE => io.intc.interrupt_enable.0 = value,
-            REG_IF => io.intc.interrupt_flags.0 &= !value,
+            REG_KEYCNT => {
+                io.keycnt.0 = value & 0xc3ff;
+                io.check_keypad_irq();
+            }
 
-            REG_TM0CNT_L..=REG_TM3CNT_H => io.timers.handle_write(io_addr, value),
+            REG_IME => {
+                io.intc.interrupt_master_enable = value != 0;
+                io.intc.sync_irq_line(&mut io.scheduler);
+            }
+            REG_IE => {
+                io.intc.interrupt_enable.0 = value;
+                io.intc.sync_irq_line(&mut io.scheduler);
+            }
+            REG_IF => {
+                io.intc.interrupt_flags.0 &= !value;
+                io.intc.sync_irq_line(&mut io.scheduler);
+            }
+
+            REG_TM0CNT_L..=REG_TM3CNT_H => {
+                io.timers.handle_write(io_addr, value, &mut io.scheduler)
+            }
 
             SOUND_BASE..=SOUND_END => {
                 io.sound.handle_write(io_addr, value);
@@ -241,22 +289,18 @@ impl Bus for IoDevices {
             DMA_BASE..=REG_DMA3CNT_H => {
                 let ofs = io_addr - DMA_BASE;
                 let channel_id = (ofs / 12) as usize;
-                io.dmac.write_16(channel_id, ofs % 12, value)
+                io.dmac
+                    .write_16(channel_id, ofs % 12, value, &mut io.scheduler)
             }
 
-            REG_WAITCNT => {
-                io.waitcnt.0 = value;
-                (*io.sysbus_ptr).on_waitcnt_written(io.waitcnt);
-            }
+            REG_SIOMULTI0..=REG_SIODATA8 | REG_RCNT => io.sio.handle_write(io_addr, value),
 
-            REG_POSTFLG => io.post_boot_flag = value != 0,
-            REG_HALTCNT => {
-                if value & 0x80 != 0 {
-                    io.haltcnt = HaltState::Stop;
-                    panic!("Can't handle HaltCtrl == Stop yet");
-                } else {
-                    io.haltcnt = HaltState::Halt;
-                }
+            REG_WAITCNT => io.waitcnt.0 = value,
+
+            // a halfword write sets HALTCNT along with POSTFLG
+            REG_POSTFLG => {
+                io.post_boot_flag = value & 1 != 0;
+                io.write_haltcnt((value >> 8) as u8);
             }
 
             _ => {
@@ -279,6 +323,9 @@ impl Bus for IoDevices {
             0x0400_00A4 | 0x0400_00A5 | 0x0400_00A6 | 0x0400_00A7 => {
                 self.sound.write_fifo(1, value as i8)
             }
+            io_addr @ SOUND_BASE..=SOUND_END => self.sound.handle_write_8(io_addr, value),
+            REG_POSTFLG => self.post_boot_flag = value & 1 != 0,
+            REG_HALTCNT => self.write_haltcnt(value),
             _ => {
                 let t = self.read_16(addr & !1);
                 let t = if addr & 1 != 0 {
@@ -306,7 +353,7 @@ bitfield! {
     pub ws2_second_access, _:      10, 10;
     #[allow(non_snake_case)]
     PHI_terminal_output, _:    12, 11;
-    prefetch, _:               14;
+    pub prefetch, _:           14;
 }
 
 #[rustfmt::skip]
@@ -401,14 +448,14 @@ pub mod consts {
     pub const REG_TM2CNT_H: Addr = 0x0400_010A;     //  2    R/W    Timer 2 Control
     pub const REG_TM3CNT_L: Addr = 0x0400_010C;     //  2    R/W    Timer 3 Counter/Reload
     pub const REG_TM3CNT_H: Addr = 0x0400_010E;     //  2    R/W    Timer 3 Control
-    // pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
-    // pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
-    // pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
-    // pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
-    // pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
-    // pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
-    // pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
+    pub const REG_SIODATA32: Addr = 0x0400_0120;    //  4    R/W    SIO Data (Normal-32bit Mode; shared with below)
+    pub const REG_SIOMULTI0: Addr = 0x0400_0120;    //  2    R/W    SIO Data 0 (Parent)    (Multi-Player Mode)
+    pub const REG_SIOMULTI1: Addr = 0x0400_0122;    //  2    R/W    SIO Data 1 (1st Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI2: Addr = 0x0400_0124;    //  2    R/W    SIO Data 2 (2nd Child) (Multi-Player Mode)
+    pub const REG_SIOMULTI3: Addr = 0x0400_0126;    //  2    R/W    SIO Data 3 (3rd Child) (Multi-Player Mode)
+    pub const REG_SIOCNT: Addr = 0x0400_0128;       //  2    R/W    SIO Control Register
+    pub const REG_SIOMLT_SEND: Addr = 0x0400_012A;  //  2    R/W    SIO Data (Local of MultiPlayer; shared below)
+    pub const REG_SIODATA8: Addr = 0x0400_012A;     //  2    R/W    SIO Data (Normal-8bit and UART Mode)
     pub const REG_KEYINPUT: Addr = 0x0400_0130;     //  2    R      Key Status
     pub const REG_KEYCNT: Addr = 0x0400_0132;       //  2    R/W    Key Interrupt Control
     pub const REG_RCNT: Addr = 0x0400_0134;         //  2    R/W    SIO Mode Select/General Purpose Data
@@ -510,12 +557,12 @@ pub fn io_reg_string(addr: u32) -> &'static str {
         REG_TM3CNT_L => "REG_TM3CNT_L",
         REG_TM3CNT_H => "REG_TM3CNT_H",
         // REG_SIODATA32 => "REG_SIODATA32",
-        // REG_SIOMULTI0 => "REG_SIOMULTI0",
-        // REG_SIOMULTI1 => "REG_SIOMULTI1",
-        // REG_SIOMULTI2 => "REG_SIOMULTI2",
-        // REG_SIOMULTI3 => "REG_SIOMULTI3",
-        // REG_SIOCNT => "REG_SIOCNT",
-        // REG_SIOMLT_SEND => "REG_SIOMLT_SEND",
+        REG_SIOMULTI0 => "REG_SIOMULTI0",
+        REG_SIOMULTI1 => "REG_SIOMULTI1",
+        REG_SIOMULTI2 => "REG_SIOMULTI2",
+        REG_SIOMULTI3 => "REG_SIOMULTI3",
+        REG_SIOCNT => "REG_SIOCNT",
+        REG_SIOMLT_SEND => "REG_SIOMLT_SEND",
         // REG_SIODATA8 => "REG_SIODATA8",
         REG_KEYINPUT => "REG_KEYINPUT",
         REG_KEYCNT => "REG_KEYCNT",
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Primitive, PartialEq)]
 #[repr(u8)]
 pub enum Keys {
@@ -31,3 +33,29 @@ impl Into<bool> for KeyState {
         }
     }
 }
+
+bitfield! {
+    #[derive(Serialize, Deserialize, Default, Copy, Clone, PartialEq)]
+    pub struct KeyControl(u16);
+    impl Debug;
+    u16;
+    pub keys, _: 9, 0;
+    pub irq_enabled, _: 14;
+    /// When set, all the selected keys must be pressed for the irq, otherwise any of them
+    pub irq_logical_and, _: 15;
+}
+
+impl KeyControl {
+    pub fn irq_condition(&self, keyinput: u16) -> bool {
+        if !self.irq_enabled() {
+            return false;
+        }
+        let selected = self.keys();
+        let pressed = !keyinput & selected;
+        if self.irq_logical_and() {
+            selected != 0 && pressed == selected
+        } else {
+            pressed != 0
+        }
+    }
+}
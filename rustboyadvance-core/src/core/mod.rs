@@ -1,4 +1,5 @@
 pub mod arm7tdmi;
+pub mod bios;
 pub mod cartridge;
 pub mod gpu;
 pub mod sound;
@@ -9,10 +10,16 @@ pub mod iodev;
 pub use interrupt::Interrupt;
 pub use interrupt::IrqBitmask;
 pub mod gba;
-pub use gba::GameBoyAdvance;
+pub use gba::{GameBoyAdvance, GenericGameBoyAdvance};
 pub mod bus;
 pub mod dma;
 pub mod keypad;
+pub mod movie;
+pub mod recording;
+pub mod rewind;
+pub mod savestate;
+pub mod sched;
+pub mod sio;
 pub mod timer;
 pub use bus::*;
 
@@ -30,6 +37,24 @@ use std::fmt;
 pub enum GBAError {
     IO(::std::io::Error),
     CartridgeLoadError(String),
+    /// The data doesn't start with the savestate magic
+    NotASaveState,
+    /// The savestate format is newer than this build, or too old to be migrated
+    UnsupportedSaveStateVersion(u32),
+    /// The savestate was made with another ROM, whose title and game code are given
+    SaveStateRomMismatch(String, String),
+    /// The savestate was made with the HLE BIOS and the emulator runs a BIOS dump, or the other way around
+    SaveStateBiosMismatch,
+    /// The savestate is damaged, or was made by an incompatible build with the same format version
+    CorruptSaveState(String),
+    /// The movie file is damaged or from an unsupported format version
+    InvalidMovie(String),
+    /// The movie was recorded with another ROM, whose title and game code are given
+    MovieRomMismatch(String, String),
+    /// The movie was recorded with another BIOS
+    MovieBiosMismatch,
+    /// Savestates can't be restored, nor rewound to, while a movie is being recorded
+    MovieRecordingInProgress,
     #[cfg(feature = "debugger")]
     DebuggerError(debugger::DebuggerError),
 }
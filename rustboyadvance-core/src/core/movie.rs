@@ -0,0 +1,293 @@
+//! Input movies, recordings of the keys pressed on every frame that replay bit-exactly.
+//!
+//! A movie starts from a savestate, taken right after a reset when recording from power on,
+//! so the save data and the clock are part of the recording too.
+//! The state is hashed every `hash_interval` frames, playback checks the hashes to tell when it desyncs.
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+
+use super::cartridge::RtcTimeSource;
+use super::keypad::KEYINPUT_ALL_RELEASED;
+use super::{GBAError, GBAResult};
+use crate::InputInterface;
+
+pub const MOVIE_MAGIC: &[u8; 8] = b"RBAMOVIE";
+pub const MOVIE_FORMAT_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 12;
+
+/// Frames between state hashes, unless told otherwise
+pub const DEFAULT_HASH_INTERVAL: u32 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MovieHeader {
+    pub emulator_version: String,
+    pub game_title: String,
+    pub game_code: String,
+    pub rom_checksum: u32,
+    /// CRC32 of the BIOS image, the HLE BIOS has its own
+    pub bios_checksum: u32,
+    /// The clock of the cartridge, always a fixed source so it replays the same
+    pub rtc_time_source: Option<RtcTimeSource>,
+    /// Whether the recording started with a reset, rather than from wherever the game was
+    pub power_on: bool,
+    pub start_state: Vec<u8>,
+    pub hash_interval: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    /// KEYINPUT as polled at the start of the frame
+    pub keys: u16,
+    /// The system was reset right before this frame
+    pub reset: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Movie {
+    pub header: MovieHeader,
+    pub frames: Vec<MovieFrame>,
+    /// `hashes[i]` is the state hash after frame `(i + 1) * hash_interval`
+    pub hashes: Vec<u32>,
+}
+
+impl Movie {
+    pub fn from_bytes(bytes: &[u8]) -> GBAResult<Movie> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..8] != MOVIE_MAGIC {
+            return Err(GBAError::InvalidMovie("not a movie file".to_string()));
+        }
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        if version != MOVIE_FORMAT_VERSION {
+            return Err(GBAError::InvalidMovie(format!(
+                "unsupported movie format version {}",
+                version
+            )));
+        }
+        let body = &bytes[HEADER_SIZE..];
+        let movie: Movie = bincode::config()
+            .limit(body.len() as u64)
+            .deserialize(body)
+            .map_err(|e| GBAError::InvalidMovie(e.to_string()))?;
+        if movie.header.hash_interval == 0 {
+            return Err(GBAError::InvalidMovie("hash interval of 0".to_string()));
+        }
+        Ok(movie)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MOVIE_MAGIC);
+        bytes.extend_from_slice(&MOVIE_FORMAT_VERSION.to_le_bytes());
+        // serializing plain data can't fail
+        bincode::serialize_into(&mut bytes, self).unwrap();
+        bytes
+    }
+
+    /// Number of frames recorded
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+}
+
+pub(crate) struct MovieRecorder {
+    movie: Movie,
+    reset_pending: bool,
+}
+
+impl MovieRecorder {
+    pub fn new(header: MovieHeader) -> MovieRecorder {
+        MovieRecorder {
+            movie: Movie {
+                header: header,
+                frames: Vec::new(),
+                hashes: Vec::new(),
+            },
+            reset_pending: false,
+        }
+    }
+
+    pub fn on_reset(&mut self) {
+        self.reset_pending = true;
+    }
+
+    pub fn on_key_poll(&mut self, keys: u16) {
+        self.movie.frames.push(MovieFrame {
+            keys: keys,
+            reset: self.reset_pending,
+        });
+        self.reset_pending = false;
+    }
+
+    /// Whether the state is due for hashing, after the frame that was just polled
+    pub fn hash_due(&self) -> bool {
+        self.movie.frames.len() % self.movie.header.hash_interval as usize == 0
+    }
+
+    pub fn on_hash(&mut self, hash: u32) {
+        self.movie.hashes.push(hash);
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Replays the keys of a movie, one frame per poll
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+    desynced_at: Option<usize>,
+}
+
+impl MoviePlayer {
+    pub(crate) fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer {
+            movie: movie,
+            frame: 0,
+            desynced_at: None,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    /// Frames played so far
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    /// The first frame after which the state didn't match the recording
+    pub fn desynced_at(&self) -> Option<usize> {
+        self.desynced_at
+    }
+
+    /// Whether the system has to be reset before the next frame
+    pub(crate) fn reset_due(&self) -> bool {
+        self.movie
+            .frames
+            .get(self.frame)
+            .map_or(false, |frame| frame.reset)
+    }
+
+    pub(crate) fn hash_due(&self) -> bool {
+        self.frame % self.movie.header.hash_interval as usize == 0
+    }
+
+    /// Checks the state hash after the frame that was just played
+    pub(crate) fn check_hash(&mut self, hash: u32) {
+        // nothing was played yet, when the movie is started at frame 0
+        let index = match (self.frame / self.movie.header.hash_interval as usize).checked_sub(1) {
+            Some(index) => index,
+            None => return,
+        };
+        match self.movie.hashes.get(index) {
+            Some(&expected) if expected != hash && self.desynced_at.is_none() => {
+                warn!("movie desynced at frame {}", self.frame);
+                self.desynced_at = Some(self.frame);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl InputInterface for MoviePlayer {
+    fn poll(&mut self) -> u16 {
+        let keys = self
+            .movie
+            .frames
+            .get(self.frame)
+            .map_or(KEYINPUT_ALL_RELEASED, |frame| frame.keys);
+        self.frame += 1;
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_header() -> MovieHeader {
+        MovieHeader {
+            emulator_version: "0.1.0".to_string(),
+            game_title: "TEST".to_string(),
+            game_code: "ABCE".to_string(),
+            rom_checksum: 0x1234_5678,
+            bios_checksum: 0x9abc_def0,
+            rtc_time_source: Some(RtcTimeSource::Fixed(0)),
+            power_on: true,
+            start_state: vec![0xaa; 16],
+            hash_interval: 2,
+        }
+    }
+
+    #[test]
+    fn test_movie_record_and_play() {
+        let mut recorder = MovieRecorder::new(make_header());
+        for keys in 0..5 {
+            if keys == 3 {
+                recorder.on_reset();
+            }
+            recorder.on_key_poll(keys);
+            if recorder.hash_due() {
+                recorder.on_hash(keys as u32);
+            }
+        }
+        let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+        assert_eq!(movie.len(), 5);
+        assert_eq!(movie.hashes, vec![1, 3]);
+
+        let mut player = MoviePlayer::new(movie);
+        for keys in 0..5 {
+            assert_eq!(player.reset_due(), keys == 3);
+            assert_eq!(player.poll(), keys);
+            if player.hash_due() {
+                player.check_hash(if keys == 3 { 0 } else { keys as u32 });
+            }
+        }
+        assert!(player.is_finished());
+        assert_eq!(player.desynced_at(), Some(4));
+        assert_eq!(player.poll(), KEYINPUT_ALL_RELEASED);
+
+        match Movie::from_bytes(b"RBASTATE\x01\x00\x00\x00") {
+            Err(GBAError::InvalidMovie(_)) => {}
+            _ => panic!("expected an invalid movie error"),
+        }
+    }
+
+    #[test]
+    fn test_malformed_movies() {
+        let mut player = MoviePlayer::new(
+            Movie::from_bytes(&MovieRecorder::new(make_header()).finish().to_bytes()).unwrap(),
+        );
+        // checked before a single frame was played
+        assert!(player.hash_due());
+        player.check_hash(0);
+        assert_eq!(player.desynced_at(), None);
+
+        let mut header = make_header();
+        header.hash_interval = 0;
+        let bytes = MovieRecorder::new(header).finish().to_bytes();
+        match Movie::from_bytes(&bytes) {
+            Err(GBAError::InvalidMovie(_)) => {}
+            _ => panic!("expected an invalid movie error"),
+        }
+
+        // an emulator version claiming to be far longer than the file
+        let mut bytes = MOVIE_MAGIC.to_vec();
+        bytes.extend_from_slice(&MOVIE_FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&u64::max_value().to_le_bytes());
+        match Movie::from_bytes(&bytes) {
+            Err(GBAError::InvalidMovie(_)) => {}
+            _ => panic!("expected an invalid movie error"),
+        }
+    }
+}
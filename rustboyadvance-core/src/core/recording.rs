@@ -0,0 +1,366 @@
+//! Captures what the emulator shows and plays, into a Y4M video file and a WAV audio file.
+//!
+//! The frame rate of the video is the exact refresh rate of the GBA, one frame every `CYCLES_FULL_REFRESH` cycles,
+//! and the audio comes out of the sound controller at the sample rate of the audio device.
+//! Both start at the first vblank after recording begins, so they stay in sync with each other.
+//! While the system is stopped the last frame is repeated, along with silence.
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::gpu::{CYCLES_FULL_REFRESH, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::sched::CLOCK_FREQ;
+use crate::{AudioInterface, StereoSample, VideoInterface};
+
+const WAV_HEADER_SIZE: u32 = 44;
+/// The RIFF chunk size is a u32 that counts the header after it along with the samples
+const WAV_MAX_SAMPLES: usize = ((std::u32::MAX - (WAV_HEADER_SIZE - 8)) / 4) as usize;
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// BT.601 limited range, pixels are 0x00RRGGBB
+fn rgb_to_ycbcr(pixel: u32) -> (u8, u8, u8) {
+    let r = ((pixel >> 16) & 0xff) as i32;
+    let g = ((pixel >> 8) & 0xff) as i32;
+    let b = (pixel & 0xff) as i32;
+    let y = 16 + ((66 * r + 129 * g + 25 * b + 128) >> 8);
+    let cb = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let cr = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+    (y as u8, cb as u8, cr as u8)
+}
+
+fn write_wav_header<W: Write>(writer: &mut W, sample_rate: u32, data_size: u32) -> io::Result<()> {
+    let channels = 2u16;
+    let bits_per_sample = 16u16;
+    let block_align = channels * bits_per_sample / 8;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&1u16.to_le_bytes())?; // PCM
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&bits_per_sample.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_size.to_le_bytes())
+}
+
+pub struct AvRecorder {
+    video: BufWriter<File>,
+    audio: BufWriter<File>,
+    sample_rate: u32,
+    /// Nothing is written before the first frame, so the audio starts along with it
+    started: bool,
+    frames: usize,
+    samples: usize,
+    /// Whether the current frame of the emulator reached vblank
+    rendered: bool,
+    /// Sample rate times the cycles of silence owed to the audio, short of a whole sample
+    silence_remainder: u64,
+    /// Set once a write failed, the recording stops there
+    error: Option<io::Error>,
+    frame_data: Vec<u8>,
+}
+
+impl AvRecorder {
+    pub fn create(
+        video_path: &Path,
+        audio_path: &Path,
+        sample_rate: i32,
+    ) -> io::Result<AvRecorder> {
+        let mut video = BufWriter::new(File::create(video_path)?);
+        let divisor = gcd(CLOCK_FREQ, CYCLES_FULL_REFRESH);
+        write!(
+            video,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+            DISPLAY_WIDTH,
+            DISPLAY_HEIGHT,
+            CLOCK_FREQ / divisor,
+            CYCLES_FULL_REFRESH / divisor
+        )?;
+        let mut audio = BufWriter::new(File::create(audio_path)?);
+        // the sizes are filled in by `finish`
+        write_wav_header(&mut audio, sample_rate as u32, 0)?;
+        Ok(AvRecorder {
+            video: video,
+            audio: audio,
+            sample_rate: sample_rate as u32,
+            started: false,
+            frames: 0,
+            samples: 0,
+            rendered: false,
+            silence_remainder: 0,
+            error: None,
+            frame_data: vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT * 3],
+        })
+    }
+
+    /// Frames written so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Stereo samples written so far
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    fn write_frame(&mut self, buffer: &[u32]) -> io::Result<()> {
+        let plane_size = DISPLAY_WIDTH * DISPLAY_HEIGHT;
+        for (i, pixel) in buffer.iter().enumerate() {
+            let (y, cb, cr) = rgb_to_ycbcr(*pixel);
+            self.frame_data[i] = y;
+            self.frame_data[plane_size + i] = cb;
+            self.frame_data[2 * plane_size + i] = cr;
+        }
+        self.video.write_all(b"FRAME\n")?;
+        self.video.write_all(&self.frame_data)
+    }
+
+    fn write_sample(&mut self, (left, right): StereoSample<i16>) -> io::Result<()> {
+        if self.samples == WAV_MAX_SAMPLES {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the WAV file is full, it can't hold 4 GiB of samples",
+            ));
+        }
+        self.audio.write_all(&left.to_le_bytes())?;
+        self.audio.write_all(&right.to_le_bytes())
+    }
+
+    fn on_frame(&mut self, buffer: &[u32]) {
+        if self.error.is_some() {
+            return;
+        }
+        self.started = true;
+        self.rendered = true;
+        match self.write_frame(buffer) {
+            Ok(_) => self.frames += 1,
+            Err(e) => {
+                error!("video recording failed: {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Writes the last frame again, along with a frame of silence
+    fn repeat_frame(&mut self) -> io::Result<()> {
+        self.video.write_all(b"FRAME\n")?;
+        self.video.write_all(&self.frame_data)?;
+        self.frames += 1;
+
+        let owed = self.silence_remainder + self.sample_rate as u64 * CYCLES_FULL_REFRESH as u64;
+        self.silence_remainder = owed % CLOCK_FREQ as u64;
+        for _ in 0..owed / CLOCK_FREQ as u64 {
+            self.write_sample((0, 0))?;
+            self.samples += 1;
+        }
+        Ok(())
+    }
+
+    /// Called after every frame of the emulator, one that didn't reach vblank because the system
+    /// was stopped still takes a frame of the recording
+    pub(crate) fn end_frame(&mut self) {
+        if !self.started || self.error.is_some() {
+            return;
+        }
+        if !std::mem::replace(&mut self.rendered, false) {
+            if let Err(e) = self.repeat_frame() {
+                error!("recording failed: {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn on_sample(&mut self, sample: StereoSample<i16>) {
+        if !self.started || self.error.is_some() {
+            return;
+        }
+        match self.write_sample(sample) {
+            Ok(_) => self.samples += 1,
+            Err(e) => {
+                error!("audio recording failed: {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Completes the files, returns the error that stopped the recording if there was one
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.video.flush()?;
+        // can't overflow, the samples stop at `WAV_MAX_SAMPLES`
+        let data_size = (self.samples * 4) as u32;
+        self.audio.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.audio, self.sample_rate, data_size)?;
+        self.audio.flush()
+    }
+}
+
+/// Tees the frames into an `AvRecorder` on their way to the video device
+pub struct RecordingVideo {
+    device: Arc<Mutex<dyn VideoInterface>>,
+    recorder: Arc<Mutex<AvRecorder>>,
+}
+
+impl RecordingVideo {
+    pub fn new(
+        device: Arc<Mutex<dyn VideoInterface>>,
+        recorder: Arc<Mutex<AvRecorder>>,
+    ) -> RecordingVideo {
+        RecordingVideo {
+            device: device,
+            recorder: recorder,
+        }
+    }
+}
+
+impl VideoInterface for RecordingVideo {
+    fn render(&mut self, buffer: &[u32]) {
+        self.recorder.lock().unwrap().on_frame(buffer);
+        self.device.lock().unwrap().render(buffer);
+    }
+}
+
+/// Tees the samples into an `AvRecorder` on their way to the audio device
+pub struct RecordingAudio {
+    device: Arc<Mutex<dyn AudioInterface>>,
+    recorder: Arc<Mutex<AvRecorder>>,
+}
+
+impl RecordingAudio {
+    pub fn new(
+        device: Arc<Mutex<dyn AudioInterface>>,
+        recorder: Arc<Mutex<AvRecorder>>,
+    ) -> RecordingAudio {
+        RecordingAudio {
+            device: device,
+            recorder: recorder,
+        }
+    }
+}
+
+impl AudioInterface for RecordingAudio {
+    fn get_sample_rate(&self) -> i32 {
+        self.device.lock().unwrap().get_sample_rate()
+    }
+
+    fn push_sample(&mut self, sample: StereoSample<i16>) {
+        self.recorder.lock().unwrap().on_sample(sample);
+        self.device.lock().unwrap().push_sample(sample);
+    }
+
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        let mut recorder = self.recorder.lock().unwrap();
+        for &sample in samples {
+            recorder.on_sample(sample);
+        }
+        self.device.lock().unwrap().push_samples(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rba-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_av_recorder_files() {
+        let video_path = temp_path("av_recorder.y4m");
+        let audio_path = temp_path("av_recorder.wav");
+        let recorder = Arc::new(Mutex::new(
+            AvRecorder::create(&video_path, &audio_path, 44100).unwrap(),
+        ));
+        let mut video = RecordingVideo::new(Arc::new(Mutex::new(NullDevice)), recorder.clone());
+        let mut audio = RecordingAudio::new(Arc::new(Mutex::new(NullDevice)), recorder.clone());
+
+        // samples before the first frame are left out
+        audio.push_sample((1, 1));
+        video.render(&[0x00ff_ffff; DISPLAY_WIDTH * DISPLAY_HEIGHT]);
+        for _ in 0..10 {
+            audio.push_sample((-1, 1));
+        }
+        recorder.lock().unwrap().finish().unwrap();
+
+        let y4m = fs::read(&video_path).unwrap();
+        let header = b"YUV4MPEG2 W240 H160 F262144:4389 Ip A1:1 C444\nFRAME\n";
+        assert_eq!(&y4m[..header.len()], &header[..]);
+        assert_eq!(y4m.len(), header.len() + DISPLAY_WIDTH * DISPLAY_HEIGHT * 3);
+        assert_eq!(y4m[header.len()], 235);
+
+        let wav = fs::read(&audio_path).unwrap();
+        assert_eq!(wav.len(), 44 + 10 * 4);
+        assert_eq!(&wav[40..44], &40u32.to_le_bytes());
+
+        fs::remove_file(video_path).unwrap();
+        fs::remove_file(audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_av_recorder_repeats_stopped_frames() {
+        let video_path = temp_path("stopped.y4m");
+        let audio_path = temp_path("stopped.wav");
+        let mut recorder = AvRecorder::create(&video_path, &audio_path, 44100).unwrap();
+
+        // nothing to repeat before the first frame
+        recorder.end_frame();
+        recorder.on_frame(&[0; DISPLAY_WIDTH * DISPLAY_HEIGHT]);
+        recorder.end_frame();
+        assert_eq!((recorder.frames(), recorder.samples()), (1, 0));
+        // 44100Hz at 59.73 frames per second
+        recorder.end_frame();
+        recorder.end_frame();
+        assert_eq!((recorder.frames(), recorder.samples()), (3, 1476));
+        recorder.end_frame();
+        assert_eq!((recorder.frames(), recorder.samples()), (4, 2215));
+        recorder.finish().unwrap();
+
+        let frame_size = b"FRAME\n".len() + DISPLAY_WIDTH * DISPLAY_HEIGHT * 3;
+        let y4m = fs::read(&video_path).unwrap();
+        assert_eq!(
+            &y4m[y4m.len() - frame_size..],
+            &y4m[y4m.len() - 2 * frame_size..y4m.len() - frame_size]
+        );
+
+        fs::remove_file(video_path).unwrap();
+        fs::remove_file(audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_av_recorder_stops_before_the_wav_overflows() {
+        let video_path = temp_path("full.y4m");
+        let audio_path = temp_path("full.wav");
+        let mut recorder = AvRecorder::create(&video_path, &audio_path, 44100).unwrap();
+        recorder.on_frame(&[0; DISPLAY_WIDTH * DISPLAY_HEIGHT]);
+        recorder.samples = WAV_MAX_SAMPLES - 1;
+
+        recorder.on_sample((0, 0));
+        assert_eq!(recorder.samples(), WAV_MAX_SAMPLES);
+        recorder.on_sample((0, 0));
+        assert_eq!(recorder.samples(), WAV_MAX_SAMPLES);
+        assert!(recorder.finish().is_err());
+
+        fs::remove_file(video_path).unwrap();
+        fs::remove_file(audio_path).unwrap();
+    }
+
+    struct NullDevice;
+
+    impl VideoInterface for NullDevice {}
+    impl AudioInterface for NullDevice {}
+}
@@ -0,0 +1,160 @@
+//! Rewinding keeps a snapshot of the emulator every few frames, within a memory budget.
+//!
+//! Only the newest snapshot is kept whole, every older one is stored as the compressed XOR
+//! of itself and the snapshot that came after it, which is mostly zeros.
+//! Going back a snapshot undoes the newest delta, and the oldest deltas are dropped to stay within the budget.
+use std::collections::VecDeque;
+use std::io::prelude::*;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::{GBAError, GBAResult};
+
+struct Delta {
+    /// Length of the older snapshot, which may differ from the one it is diffed against
+    len: usize,
+    compressed: Vec<u8>,
+}
+
+/// XORs `older` against `newer`, bytes past the end of `newer` are taken as zeros
+fn xor_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    older
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ newer.get(i).unwrap_or(&0))
+        .collect()
+}
+
+pub struct RewindBuffer {
+    interval: usize,
+    budget: usize,
+    frames_since_snapshot: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+    size: usize,
+}
+
+impl RewindBuffer {
+    /// Snapshots every `interval` frames, keeping at most about `budget` bytes of history
+    pub fn new(interval: usize, budget: usize) -> RewindBuffer {
+        RewindBuffer {
+            interval: interval.max(1),
+            budget: budget,
+            frames_since_snapshot: 0,
+            newest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    pub fn interval(&self) -> usize {
+        self.interval
+    }
+
+    /// How many frames back the history goes
+    pub fn available_frames(&self) -> usize {
+        match self.newest {
+            Some(_) => self.frames_since_snapshot + self.deltas.len() * self.interval,
+            None => 0,
+        }
+    }
+
+    /// Memory taken by the history, in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn clear(&mut self) {
+        self.frames_since_snapshot = 0;
+        self.newest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+
+    /// Counts a frame, returns true when a snapshot is due
+    pub(crate) fn on_frame(&mut self) -> bool {
+        self.frames_since_snapshot += 1;
+        self.newest.is_none() || self.frames_since_snapshot >= self.interval
+    }
+
+    pub(crate) fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+            // writing into a vec can't fail
+            encoder.write_all(&xor_delta(&newest, &snapshot)).unwrap();
+            let delta = Delta {
+                len: newest.len(),
+                compressed: encoder.finish().unwrap(),
+            };
+            self.size = self.size - newest.len() + delta.compressed.len();
+            self.deltas.push_back(delta);
+        }
+        self.size += snapshot.len();
+        self.newest = Some(snapshot);
+        self.frames_since_snapshot = 0;
+
+        while self.size > self.budget && !self.deltas.is_empty() {
+            let oldest = self.deltas.pop_front().unwrap();
+            self.size -= oldest.compressed.len();
+        }
+    }
+
+    /// Drops the snapshots newer than `frames` ago, and returns the one left on top,
+    /// along with how many frames back it actually is
+    pub(crate) fn go_back(&mut self, frames: usize) -> GBAResult<Option<(&[u8], usize)>> {
+        let mut newest = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(None),
+        };
+
+        let mut rewound = self.frames_since_snapshot;
+        while rewound < frames {
+            let delta = match self.deltas.pop_back() {
+                Some(delta) => delta,
+                None => break,
+            };
+            let mut xored = Vec::with_capacity(delta.len);
+            DeflateDecoder::new(&delta.compressed[..])
+                .read_to_end(&mut xored)
+                .map_err(|e| GBAError::CorruptSaveState(e.to_string()))?;
+            let older = xor_delta(&xored, &newest);
+            self.size = self.size + older.len() - newest.len() - delta.compressed.len();
+            newest = older;
+            rewound += self.interval;
+        }
+
+        self.frames_since_snapshot = 0;
+        self.newest = Some(newest);
+        Ok(self.newest.as_ref().map(|newest| (&newest[..], rewound)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewind_buffer_deltas() {
+        let mut rewind = RewindBuffer::new(2, 1 << 20);
+        let snapshots: Vec<Vec<u8>> = (0..5u8)
+            .map(|i| (0..1000 + i as usize).map(|j| (j as u8) ^ i).collect())
+            .collect();
+        for snapshot in &snapshots {
+            rewind.on_frame();
+            assert!(rewind.on_frame());
+            rewind.push(snapshot.clone());
+        }
+        assert_eq!(rewind.available_frames(), 8);
+
+        let (snapshot, rewound) = rewind.go_back(3).unwrap().unwrap();
+        assert_eq!(rewound, 4);
+        assert_eq!(snapshot, &snapshots[2][..]);
+
+        let (snapshot, rewound) = rewind.go_back(100).unwrap().unwrap();
+        assert_eq!(rewound, 4);
+        assert_eq!(snapshot, &snapshots[0][..]);
+        assert_eq!(rewind.size(), snapshots[0].len());
+    }
+}
@@ -0,0 +1,253 @@
+//! The savestate file format.
+//!
+//! ```text
+//! magic            8 bytes, "RBASTATE"
+//! format version   u32, little endian
+//! info             bincode encoded `SaveStateInfo`
+//! thumbnail        deflated 240x160 frame buffer, one u32 per pixel
+//! payload          deflated bincode of the emulator state
+//! ```
+//!
+//! Only the magic and the version are fixed, everything after them belongs to the format version.
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode;
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+
+use super::cartridge::Cartridge;
+use super::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use super::{GBAError, GBAResult};
+
+pub const SAVESTATE_MAGIC: &[u8; 8] = b"RBASTATE";
+/// Bump whenever anything serialized into savestates changes, and add a migration from the previous version
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADER_SIZE: usize = 12;
+
+/// Upgrades the body of a savestate, everything after the version, from one format version to the next.
+type Migration = fn(&[u8]) -> GBAResult<Vec<u8>>;
+
+/// `MIGRATIONS[i]` upgrades version `i + 1` to version `i + 2`
+const MIGRATIONS: &[Migration] = &[];
+
+const _: () = assert!(MIGRATIONS.len() == FORMAT_VERSION as usize - 1);
+
+/// The emulator state is well under a megabyte, anything inflating past this is not a savestate
+const MAX_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+const THUMBNAIL_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT * 4;
+
+fn corrupt(e: bincode::Error) -> GBAError {
+    GBAError::CorruptSaveState(e.to_string())
+}
+
+/// Decodes untrusted bytes, which can't make bincode allocate more than their own size
+pub(crate) fn deserialize_bounded<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> GBAResult<T> {
+    bincode::config()
+        .limit(bytes.len() as u64)
+        .deserialize(bytes)
+        .map_err(corrupt)
+}
+
+/// Describes a savestate, can be read without restoring it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SaveStateInfo {
+    pub emulator_version: String,
+    pub game_title: String,
+    pub game_code: String,
+    /// CRC32 of the ROM the state was made with
+    pub rom_checksum: u32,
+    pub hle_bios: bool,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl SaveStateInfo {
+    pub(crate) fn new(cartridge: &Cartridge, hle_bios: bool) -> SaveStateInfo {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        SaveStateInfo {
+            emulator_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_title: cartridge.header.game_title.clone(),
+            game_code: cartridge.header.game_code.clone(),
+            rom_checksum: cartridge.checksum(),
+            hle_bios: hle_bios,
+            timestamp: timestamp,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SaveStateBody {
+    info: SaveStateInfo,
+    thumbnail: Vec<u8>,
+    payload: Vec<u8>,
+}
+
+/// A parsed savestate file, brought up to the current format version
+pub struct SaveStateFile {
+    body: SaveStateBody,
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    // writing into a vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Deflate falls back to stored blocks for incompressible data, which adds 5 bytes every 64 KiB
+fn deflate_bound(len: usize) -> usize {
+    len + (len / 0xffff + 1) * 5 + 16
+}
+
+/// Inflates `data`, failing if it would grow past `max_size` bytes
+fn decompress(data: &[u8], max_size: usize) -> GBAResult<Vec<u8>> {
+    let mut decompressed = Vec::new();
+    DeflateDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| GBAError::CorruptSaveState(e.to_string()))?;
+    if decompressed.len() > max_size {
+        return Err(GBAError::CorruptSaveState(format!(
+            "inflates past {} bytes",
+            max_size
+        )));
+    }
+    Ok(decompressed)
+}
+
+impl SaveStateFile {
+    pub(crate) fn new(info: SaveStateInfo, frame_buffer: &[u32], payload: &[u8]) -> SaveStateFile {
+        let mut thumbnail = vec![0; frame_buffer.len() * 4];
+        LittleEndian::write_u32_into(frame_buffer, &mut thumbnail);
+        SaveStateFile {
+            body: SaveStateBody {
+                info: info,
+                thumbnail: compress(&thumbnail),
+                payload: compress(payload),
+            },
+        }
+    }
+
+    /// Upper bound of the size of a file made by `new`, given the size of the payload
+    pub(crate) fn size_bound(info: &SaveStateInfo, payload_len: usize) -> usize {
+        let info_len = bincode::serialized_size(info).unwrap() as usize;
+        // along with the lengths of the two vecs
+        HEADER_SIZE + info_len + 16 + deflate_bound(THUMBNAIL_SIZE) + deflate_bound(payload_len)
+    }
+
+    pub fn parse(bytes: &[u8]) -> GBAResult<SaveStateFile> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..8] != SAVESTATE_MAGIC {
+            return Err(GBAError::NotASaveState);
+        }
+        let version = LittleEndian::read_u32(&bytes[8..12]);
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(GBAError::UnsupportedSaveStateVersion(version));
+        }
+
+        let mut body = bytes[HEADER_SIZE..].to_vec();
+        for migration in &MIGRATIONS[(version - 1) as usize..] {
+            body = migration(&body)?;
+        }
+
+        let body = deserialize_bounded(&body)?;
+        Ok(SaveStateFile { body })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.body.payload.len());
+        bytes.extend_from_slice(SAVESTATE_MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        // serializing plain data can't fail
+        bincode::serialize_into(&mut bytes, &self.body).unwrap();
+        bytes
+    }
+
+    pub fn info(&self) -> &SaveStateInfo {
+        &self.body.info
+    }
+
+    /// The screen at the time the state was made, `DISPLAY_WIDTH` x `DISPLAY_HEIGHT` pixels
+    pub fn thumbnail(&self) -> GBAResult<Vec<u32>> {
+        let bytes = decompress(&self.body.thumbnail, THUMBNAIL_SIZE)?;
+        if bytes.len() != THUMBNAIL_SIZE {
+            return Err(GBAError::CorruptSaveState(
+                "thumbnail has the wrong size".to_string(),
+            ));
+        }
+        let mut pixels = vec![0; DISPLAY_WIDTH * DISPLAY_HEIGHT];
+        LittleEndian::read_u32_into(&bytes, &mut pixels);
+        Ok(pixels)
+    }
+
+    pub(crate) fn payload(&self) -> GBAResult<Vec<u8>> {
+        decompress(&self.body.payload, MAX_PAYLOAD_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_info() -> SaveStateInfo {
+        SaveStateInfo {
+            emulator_version: "0.1.0".to_string(),
+            game_title: "TEST".to_string(),
+            game_code: "ABCE".to_string(),
+            rom_checksum: 0x1234_5678,
+            hle_bios: false,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_savestate_file_roundtrip() {
+        let frame_buffer: Vec<u32> = (0..(DISPLAY_WIDTH * DISPLAY_HEIGHT) as u32).collect();
+        let payload = vec![0xaa; 0x1000];
+        let bytes = SaveStateFile::new(make_info(), &frame_buffer, &payload).to_bytes();
+
+        let file = SaveStateFile::parse(&bytes).unwrap();
+        assert_eq!(file.info().game_code, "ABCE");
+        assert_eq!(file.thumbnail().unwrap(), frame_buffer);
+        assert_eq!(file.payload().unwrap(), payload);
+
+        let mut newer = bytes.clone();
+        newer[8] = (FORMAT_VERSION + 1) as u8;
+        match SaveStateFile::parse(&newer) {
+            Err(GBAError::UnsupportedSaveStateVersion(v)) => assert_eq!(v, FORMAT_VERSION + 1),
+            _ => panic!("expected an unsupported version error"),
+        }
+        match SaveStateFile::parse(&payload) {
+            Err(GBAError::NotASaveState) => {}
+            _ => panic!("expected a bad magic error"),
+        }
+    }
+
+    #[test]
+    fn test_untrusted_sizes_are_bounded() {
+        let zeros = compress(&vec![0; 0x10000]);
+        assert_eq!(decompress(&zeros, 0x10000).unwrap().len(), 0x10000);
+        match decompress(&zeros, 0xffff) {
+            Err(GBAError::CorruptSaveState(_)) => {}
+            _ => panic!("expected the inflated size to be capped"),
+        }
+
+        // a thumbnail claiming to be far longer than the file
+        let mut bytes = SAVESTATE_MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut bytes, &make_info()).unwrap();
+        bytes.extend_from_slice(&u64::max_value().to_le_bytes());
+        match SaveStateFile::parse(&bytes) {
+            Err(GBAError::CorruptSaveState(_)) => {}
+            _ => panic!("expected a corrupt savestate error"),
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! The scheduler keeps the global cycle count, and the events the devices are waiting for.
+//! Instead of updating every device after each cpu step, the cpu runs until the next event is due.
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+
+use serde::{Deserialize, Serialize};
+
+use super::gpu::GpuState;
+
+/// The cpu runs at 2^24 Hz, all the cycle counts are in cpu cycles
+pub const CLOCK_FREQ: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    /// Ends the current run of the cpu, used to return from `GameBoyAdvance::frame` in time
+    RunLimitReached,
+    /// The gpu is done with the given state
+    Gpu(GpuState),
+    TimerOverflow(usize),
+    /// The sound controller is due for its next sample
+    SoundSample,
+    /// A dma channel starts after it was enabled with immediate timing
+    DmaActivateChannel(usize),
+    /// The IRQ line seen by the cpu catches up with IME, IE and IF
+    Irq,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Event {
+    typ: EventType,
+    time: u64,
+}
+
+// The event heap is ordered by time, soonest first
+
+impl Ord for Event {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.time.cmp(&self.time)
+    }
+}
+
+impl PartialOrd for Event {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Event {
+    fn eq(&self, other: &Self) -> bool {
+        self.time == other.time
+    }
+}
+
+impl Eq for Event {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Scheduler {
+    // 64 bits even on 32-bit targets, where a usize wraps after a few minutes of emulation
+    timestamp: u64,
+    events: BinaryHeap<Event>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            timestamp: 0,
+            events: BinaryHeap::with_capacity(16),
+        }
+    }
+
+    /// The number of cycles since power on
+    #[inline]
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline]
+    pub fn update(&mut self, cycles: usize) {
+        self.timestamp += cycles as u64;
+    }
+
+    /// Schedules an event to happen `cycles` from now
+    pub fn schedule(&mut self, typ: EventType, cycles: usize) {
+        self.schedule_at(typ, self.timestamp + cycles as u64);
+    }
+
+    /// Schedules an event to happen at the given timestamp, which may already have passed
+    pub fn schedule_at(&mut self, typ: EventType, time: u64) {
+        self.events.push(Event { typ, time });
+    }
+
+    /// Removes every pending event of this type
+    pub fn cancel(&mut self, typ: EventType) {
+        let events = std::mem::replace(&mut self.events, BinaryHeap::new());
+        self.events = events.into_iter().filter(|e| e.typ != typ).collect();
+    }
+
+    /// How many cycles can run before the next event is due
+    #[inline]
+    pub fn cycles_to_next_event(&self) -> usize {
+        match self.events.peek() {
+            Some(event) => {
+                let cycles = event.time.saturating_sub(self.timestamp);
+                cmp::min(cycles, std::usize::MAX as u64) as usize
+            }
+            None => std::usize::MAX,
+        }
+    }
+
+    /// Upper bound of the serialized size, every event type is pending once at most
+    pub(crate) fn serialized_size_bound() -> usize {
+        // one of each, and the largest of the events stands for all of them
+        const MAX_PENDING_EVENTS: usize = 1 + 1 + 4 + 1 + 4 + 1;
+        let largest_event = [
+            EventType::RunLimitReached,
+            EventType::Gpu(GpuState::HDraw),
+            EventType::TimerOverflow(0),
+            EventType::SoundSample,
+            EventType::DmaActivateChannel(0),
+            EventType::Irq,
+        ]
+        .iter()
+        .map(|&typ| bincode::serialized_size(&Event { typ, time: 0 }).unwrap() as usize)
+        .max()
+        .unwrap();
+        let empty = bincode::serialized_size(&Scheduler::new()).unwrap() as usize;
+        empty + MAX_PENDING_EVENTS * largest_event
+    }
+
+    /// Pops the next event if it is due, along with how many cycles late it is handled
+    #[inline]
+    pub fn pop_pending_event(&mut self) -> Option<(EventType, usize)> {
+        match self.events.peek() {
+            Some(event) if event.time <= self.timestamp => {
+                let event = self.events.pop().unwrap();
+                Some((event.typ, (self.timestamp - event.time) as usize))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_are_popped_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(EventType::TimerOverflow(1), 100);
+        scheduler.schedule(EventType::SoundSample, 50);
+        scheduler.schedule(EventType::TimerOverflow(0), 200);
+        scheduler.cancel(EventType::TimerOverflow(0));
+        assert_eq!(scheduler.cycles_to_next_event(), 50);
+
+        scheduler.update(120);
+        assert_eq!(
+            scheduler.pop_pending_event(),
+            Some((EventType::SoundSample, 70))
+        );
+        assert_eq!(
+            scheduler.pop_pending_event(),
+            Some((EventType::TimerOverflow(1), 20))
+        );
+        assert_eq!(scheduler.pop_pending_event(), None);
+        assert_eq!(scheduler.cycles_to_next_event(), std::usize::MAX);
+    }
+
+    #[test]
+    fn test_serialized_size_bound_covers_every_pending_event() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(EventType::RunLimitReached, 1);
+        scheduler.schedule(EventType::Gpu(GpuState::VBlankHBlank), 1);
+        scheduler.schedule(EventType::SoundSample, 1);
+        for i in 0..4 {
+            scheduler.schedule(EventType::TimerOverflow(i), 1);
+            scheduler.schedule(EventType::DmaActivateChannel(i), 1);
+        }
+        scheduler.schedule(EventType::Irq, 1);
+        let size = bincode::serialized_size(&scheduler).unwrap() as usize;
+        assert!(size <= Scheduler::serialized_size_bound());
+    }
+}
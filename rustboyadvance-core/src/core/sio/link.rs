@@ -0,0 +1,277 @@
+//! Link cable transports, used by the serial controller to talk to other emulator instances
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+
+use bincode;
+use byteorder::{ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+
+/// A single message sent over the link cable
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum LinkPacket {
+    /// Normal mode transfer started by the side that provides the clock
+    Normal(u32),
+    /// The data shifted back by the side using the external clock
+    NormalReply(u32),
+    /// Multi-player transfer started by the parent
+    Multi(u16),
+    /// The data of the child in a multi-player transfer
+    MultiReply(u16),
+    /// A single UART frame
+    Uart(u8),
+}
+
+/// Connects the serial port of a `GameBoyAdvance` to other instances.
+///
+/// Both operations must never block, since the emulation thread is the one polling the transport.
+pub trait LinkTransport: Send {
+    /// 0 for the parent side of the cable, 1-3 for the children
+    fn player_id(&self) -> usize;
+
+    fn send(&mut self, packet: LinkPacket) -> io::Result<()>;
+
+    /// Returns the next packet received, if any
+    fn recv(&mut self) -> Option<LinkPacket>;
+}
+
+/// Links two emulator instances living in the same process
+pub struct InProcessLink {
+    player_id: usize,
+    tx: Sender<LinkPacket>,
+    rx: Receiver<LinkPacket>,
+}
+
+impl InProcessLink {
+    /// Creates both ends of the cable, the first one being the parent
+    pub fn pair() -> (InProcessLink, InProcessLink) {
+        let (tx0, rx1) = channel();
+        let (tx1, rx0) = channel();
+        (
+            InProcessLink {
+                player_id: 0,
+                tx: tx0,
+                rx: rx0,
+            },
+            InProcessLink {
+                player_id: 1,
+                tx: tx1,
+                rx: rx1,
+            },
+        )
+    }
+}
+
+impl LinkTransport for InProcessLink {
+    fn player_id(&self) -> usize {
+        self.player_id
+    }
+
+    fn send(&mut self, packet: LinkPacket) -> io::Result<()> {
+        self.tx
+            .send(packet)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn recv(&mut self) -> Option<LinkPacket> {
+        self.rx.try_recv().ok()
+    }
+}
+
+/// Links two emulator processes over a local socket.
+/// The side that listens is the parent.
+pub struct StreamLink<S: Read + Write> {
+    player_id: usize,
+    stream: S,
+    rx_buffer: Vec<u8>,
+    /// Frames the stream wasn't ready to take yet
+    tx_buffer: Vec<u8>,
+}
+
+const FRAME_HEADER_SIZE: usize = 4;
+
+impl<S: Read + Write> StreamLink<S> {
+    fn new(stream: S, player_id: usize) -> StreamLink<S> {
+        StreamLink {
+            player_id,
+            stream,
+            rx_buffer: Vec::new(),
+            tx_buffer: Vec::new(),
+        }
+    }
+
+    /// Writes as much of the transmit buffer as the (non-blocking) stream takes
+    fn flush_tx_buffer(&mut self) -> io::Result<()> {
+        while !self.tx_buffer.is_empty() {
+            match self.stream.write(&self.tx_buffer) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    self.tx_buffer.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pulls everything available from the (non-blocking) stream into the receive buffer
+    fn fill_rx_buffer(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => self.rx_buffer.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<S: Read + Write + Send> LinkTransport for StreamLink<S> {
+    fn player_id(&self) -> usize {
+        self.player_id
+    }
+
+    fn send(&mut self, packet: LinkPacket) -> io::Result<()> {
+        let payload = bincode::serialize(&packet)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        LittleEndian::write_u32(&mut header, payload.len() as u32);
+        self.tx_buffer.extend_from_slice(&header);
+        self.tx_buffer.extend_from_slice(&payload);
+        // whatever doesn't fit now goes out on the next send or recv
+        self.flush_tx_buffer()
+    }
+
+    fn recv(&mut self) -> Option<LinkPacket> {
+        if let Err(e) = self.flush_tx_buffer() {
+            trace!("link: {}", e);
+        }
+        if let Err(e) = self.fill_rx_buffer() {
+            trace!("link: {}", e);
+        }
+        if self.rx_buffer.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+        let len = LittleEndian::read_u32(&self.rx_buffer) as usize;
+        if self.rx_buffer.len() < FRAME_HEADER_SIZE + len {
+            return None;
+        }
+        let frame: Vec<u8> = self
+            .rx_buffer
+            .drain(..FRAME_HEADER_SIZE + len)
+            .skip(FRAME_HEADER_SIZE)
+            .collect();
+        match bincode::deserialize(&frame) {
+            Ok(packet) => Some(packet),
+            Err(e) => {
+                warn!("link: dropping malformed packet ({})", e);
+                None
+            }
+        }
+    }
+}
+
+impl StreamLink<TcpStream> {
+    /// Waits for another emulator to connect, becoming the parent
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<StreamLink<TcpStream>> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, peer) = listener.accept()?;
+        info!("link: {} connected", peer);
+        StreamLink::from_tcp_stream(stream, 0)
+    }
+
+    /// Connects to an emulator waiting in `listen`, becoming a child
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<StreamLink<TcpStream>> {
+        let stream = TcpStream::connect(addr)?;
+        StreamLink::from_tcp_stream(stream, 1)
+    }
+
+    fn from_tcp_stream(stream: TcpStream, player_id: usize) -> io::Result<StreamLink<TcpStream>> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(StreamLink::new(stream, player_id))
+    }
+}
+
+#[cfg(unix)]
+impl StreamLink<UnixStream> {
+    /// Waits for another emulator to connect to the socket at `path`, becoming the parent
+    pub fn listen_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamLink<UnixStream>> {
+        let listener = UnixListener::bind(path)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(true)?;
+        Ok(StreamLink::new(stream, 0))
+    }
+
+    /// Connects to an emulator waiting in `listen_unix`, becoming a child
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<StreamLink<UnixStream>> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        Ok(StreamLink::new(stream, 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Takes at most `capacity` bytes before it would block
+    struct SmallPipe {
+        capacity: usize,
+        data: Vec<u8>,
+    }
+
+    impl Read for SmallPipe {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+    }
+
+    impl Write for SmallPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.capacity - self.data.len());
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stream_link_send_does_not_block() {
+        let pipe = SmallPipe {
+            capacity: 3,
+            data: Vec::new(),
+        };
+        let mut link = StreamLink::new(pipe, 0);
+        link.send(LinkPacket::Uart(0x41)).unwrap();
+        assert_eq!(link.stream.data.len(), 3);
+        assert!(!link.tx_buffer.is_empty());
+
+        // once the other side catches up, the rest goes out
+        link.stream.capacity = 1024;
+        assert_eq!(link.recv(), None);
+        assert!(link.tx_buffer.is_empty());
+
+        let mut receiver = StreamLink::new(
+            io::Cursor::new(std::mem::replace(&mut link.stream.data, Vec::new())),
+            1,
+        );
+        assert_eq!(receiver.recv(), Some(LinkPacket::Uart(0x41)));
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::VecDeque;
+
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::interrupt::IrqBitmask;
+use super::iodev::consts::*;
+use super::sched::CLOCK_FREQ;
+
+mod link;
+pub use link::{InProcessLink, LinkPacket, LinkTransport, StreamLink};
+
+const BAUD_RATES: [usize; 4] = [9600, 38400, 57600, 115200];
+
+/// Each of the 4 multi-player slots is a start bit, 16 data bits and a stop bit
+const MULTI_TRANSFER_BITS: usize = 4 * 18;
+const UART_FIFO_SIZE: usize = 4;
+/// How long a transfer waits for the other side to answer before giving up, about 100ms
+const LINK_TIMEOUT_CYCLES: usize = CLOCK_FREQ / 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SioMode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    GeneralPurpose,
+    JoyBus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+struct Transfer {
+    cycles_left: usize,
+    /// Set when the transfer was sent over the link and the other side is expected to answer
+    awaiting_reply: bool,
+    reply: Option<u32>,
+    /// Cycles left to wait for the reply once the transfer is done on our side
+    reply_timeout: usize,
+}
+
+impl Transfer {
+    fn new(cycles: usize) -> Transfer {
+        Transfer {
+            cycles_left: cycles,
+            awaiting_reply: false,
+            reply: None,
+            reply_timeout: LINK_TIMEOUT_CYCLES,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SerialController {
+    siocnt: u16,
+    rcnt: u16,
+    /// SIODATA32 / SIOMULTI0-3
+    data: [u16; 4],
+    /// SIODATA8 / SIOMLT_SEND
+    data8: u16,
+
+    transfer: Option<Transfer>,
+    outbox: Vec<LinkPacket>,
+    uart_rx: VecDeque<u8>,
+    uart_error: bool,
+
+    player_id: usize,
+    connected: bool,
+}
+
+impl SerialController {
+    pub fn new() -> SerialController {
+        SerialController {
+            siocnt: 0,
+            rcnt: 0,
+            data: [0; 4],
+            data8: 0,
+            transfer: None,
+            outbox: Vec::new(),
+            uart_rx: VecDeque::with_capacity(UART_FIFO_SIZE),
+            uart_error: false,
+            player_id: 0,
+            connected: false,
+        }
+    }
+
+    pub fn mode(&self) -> SioMode {
+        if self.rcnt.bit(15) {
+            if self.rcnt.bit(14) {
+                SioMode::JoyBus
+            } else {
+                SioMode::GeneralPurpose
+            }
+        } else {
+            match self.siocnt.bit_range(12..14) {
+                0 => SioMode::Normal8,
+                1 => SioMode::Normal32,
+                2 => SioMode::Multiplayer,
+                3 => SioMode::Uart,
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn data32(&self) -> u32 {
+        (self.data[0] as u32) | (self.data[1] as u32) << 16
+    }
+
+    fn set_data32(&mut self, value: u32) {
+        self.data[0] = value as u16;
+        self.data[1] = (value >> 16) as u16;
+    }
+
+    fn irq_enabled(&self) -> bool {
+        self.siocnt.bit(14)
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.siocnt.bit(0)
+    }
+
+    /// Upper bound of the serialized size between two updates, with a transfer going on and a full UART FIFO
+    pub(crate) fn serialized_size_bound(&self) -> usize {
+        let mut busiest = self.clone();
+        busiest.transfer = Some(Transfer {
+            reply: Some(0),
+            ..Transfer::new(0)
+        });
+        busiest.outbox.clear();
+        busiest.uart_rx.clear();
+        busiest.uart_rx.resize(UART_FIFO_SIZE, 0);
+        bincode::serialized_size(&busiest).unwrap() as usize
+    }
+
+    fn baud_bit_cycles(&self) -> usize {
+        CLOCK_FREQ / BAUD_RATES[self.siocnt.bit_range(0..2) as usize]
+    }
+
+    fn uart_fifo_size(&self) -> usize {
+        if self.siocnt.bit(8) {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    pub fn handle_read(&self, io_addr: u32) -> u16 {
+        match io_addr {
+            REG_SIOMULTI0..=REG_SIOMULTI3 => self.data[((io_addr - REG_SIOMULTI0) / 2) as usize],
+            REG_SIOCNT => self.read_siocnt(),
+            REG_SIODATA8 => {
+                if self.mode() == SioMode::Uart {
+                    self.uart_rx.front().map(|&b| b as u16).unwrap_or(0)
+                } else {
+                    self.data8
+                }
+            }
+            REG_RCNT => self.rcnt,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The cpu reading SIODATA8 in UART mode takes the byte out of the receive FIFO
+    pub fn on_data8_read(&mut self) {
+        if self.mode() == SioMode::Uart {
+            self.uart_rx.pop_front();
+        }
+    }
+
+    fn read_siocnt(&self) -> u16 {
+        let mut value = self.siocnt;
+        match self.mode() {
+            SioMode::Multiplayer => {
+                value &= !0x007c;
+                value.set_bit(2, self.player_id != 0);
+                value.set_bit(3, self.connected);
+                value.set_bit_range(4..6, self.player_id as u16);
+            }
+            SioMode::Uart => {
+                value &= !0x0070;
+                value.set_bit(4, self.transfer.is_some());
+                value.set_bit(5, self.uart_rx.is_empty());
+                value.set_bit(6, self.uart_error);
+            }
+            _ => value &= !0x0004,
+        }
+        value
+    }
+
+    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
+        match io_addr {
+            REG_SIOMULTI0..=REG_SIOMULTI3 => {
+                self.data[((io_addr - REG_SIOMULTI0) / 2) as usize] = value
+            }
+            REG_SIOCNT => self.write_siocnt(value),
+            REG_SIODATA8 => {
+                self.data8 = value;
+                if self.mode() == SioMode::Uart && self.siocnt.bit(10) {
+                    self.start_uart_send(value as u8);
+                }
+            }
+            REG_RCNT => {
+                self.rcnt = value;
+                match self.mode() {
+                    SioMode::GeneralPurpose | SioMode::JoyBus => {
+                        warn!("SIO {:?} mode is not supported", self.mode())
+                    }
+                    _ => {}
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_siocnt(&mut self, value: u16) {
+        let was_active = self.siocnt.bit(7);
+        self.siocnt = value;
+        self.uart_error = false;
+
+        match self.mode() {
+            SioMode::Normal8 | SioMode::Normal32 => {
+                if !value.bit(7) {
+                    self.transfer = None;
+                } else if !was_active && self.internal_clock() {
+                    let (bits, packet) = if self.mode() == SioMode::Normal8 {
+                        (8, LinkPacket::Normal(self.data8 as u8 as u32))
+                    } else {
+                        (32, LinkPacket::Normal(self.data32()))
+                    };
+                    let cycles_per_bit = if value.bit(1) { 8 } else { 64 };
+                    self.transfer = Some(Transfer::new(bits * cycles_per_bit));
+                    self.outbox.push(packet);
+                }
+                // with an external clock, the transfer completes when the other side clocks it
+            }
+            SioMode::Multiplayer => {
+                if self.player_id != 0 {
+                    // only the parent can start a transfer, the busy bit is read-only for children
+                    self.siocnt.set_bit(7, was_active);
+                } else if value.bit(7) && !was_active {
+                    self.data = [0xffff; 4];
+                    self.transfer =
+                        Some(Transfer::new(MULTI_TRANSFER_BITS * self.baud_bit_cycles()));
+                    self.outbox.push(LinkPacket::Multi(self.data8));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn start_uart_send(&mut self, byte: u8) {
+        // start bit, data bits, optional parity bit and a stop bit
+        let data_bits = if self.siocnt.bit(7) { 8 } else { 7 };
+        let bits = 1 + data_bits + self.siocnt.bit(9) as usize + 1;
+        self.transfer = Some(Transfer::new(bits * self.baud_bit_cycles()));
+        self.outbox.push(LinkPacket::Uart(byte));
+    }
+
+    fn on_packet(
+        &mut self,
+        packet: LinkPacket,
+        link: &mut dyn LinkTransport,
+        irqs: &mut IrqBitmask,
+    ) {
+        let mode = self.mode();
+        let reply = match packet {
+            LinkPacket::Normal(data) => {
+                let reply = match mode {
+                    SioMode::Normal8 => self.data8 as u8 as u32,
+                    SioMode::Normal32 => self.data32(),
+                    _ => 0xffff_ffff,
+                };
+                if mode == SioMode::Normal8 || mode == SioMode::Normal32 {
+                    if mode == SioMode::Normal8 {
+                        self.data8 = (self.data8 & 0xff00) | (data & 0xff) as u16;
+                    } else {
+                        self.set_data32(data);
+                    }
+                    if self.siocnt.bit(7) && !self.internal_clock() {
+                        self.finish_transfer(irqs);
+                    }
+                }
+                Some(LinkPacket::NormalReply(reply))
+            }
+            LinkPacket::Multi(data) => {
+                let reply = if mode == SioMode::Multiplayer {
+                    let mut multi = [0xffff; 4];
+                    multi[0] = data;
+                    multi[self.player_id] = self.data8;
+                    self.data = multi;
+                    self.finish_transfer(irqs);
+                    self.data8
+                } else {
+                    0xffff
+                };
+                Some(LinkPacket::MultiReply(reply))
+            }
+            LinkPacket::NormalReply(data) => {
+                if let Some(transfer) = &mut self.transfer {
+                    transfer.reply = Some(data);
+                }
+                None
+            }
+            LinkPacket::MultiReply(data) => {
+                if let Some(transfer) = &mut self.transfer {
+                    transfer.reply = Some(data as u32);
+                }
+                None
+            }
+            LinkPacket::Uart(byte) => {
+                if mode == SioMode::Uart && self.siocnt.bit(11) {
+                    if self.uart_rx.len() < self.uart_fifo_size() {
+                        self.uart_rx.push_back(byte);
+                    } else {
+                        self.uart_error = true;
+                    }
+                    if self.irq_enabled() {
+                        irqs.set_SerialCommunication(true);
+                    }
+                }
+                None
+            }
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = link.send(reply) {
+                warn!("link: failed to reply ({})", e);
+            }
+        }
+    }
+
+    /// Called when the local side of the transfer is done
+    fn complete_transfer(&mut self, transfer: Transfer, irqs: &mut IrqBitmask) {
+        match self.mode() {
+            SioMode::Normal8 => {
+                let data = transfer.reply.unwrap_or(0xff) & 0xff;
+                self.data8 = (self.data8 & 0xff00) | data as u16;
+            }
+            SioMode::Normal32 => self.set_data32(transfer.reply.unwrap_or(0xffff_ffff)),
+            SioMode::Multiplayer => {
+                self.data = [
+                    self.data8,
+                    transfer.reply.map(|data| data as u16).unwrap_or(0xffff),
+                    0xffff,
+                    0xffff,
+                ]
+            }
+            _ => {}
+        }
+        self.finish_transfer(irqs);
+    }
+
+    fn finish_transfer(&mut self, irqs: &mut IrqBitmask) {
+        self.transfer = None;
+        if self.mode() != SioMode::Uart {
+            self.siocnt.set_bit(7, false);
+        }
+        if self.irq_enabled() {
+            irqs.set_SerialCommunication(true);
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        cycles: usize,
+        irqs: &mut IrqBitmask,
+        link: Option<&mut dyn LinkTransport>,
+    ) {
+        match link {
+            Some(link) => {
+                self.player_id = link.player_id();
+                self.connected = true;
+                for packet in self.outbox.drain(..) {
+                    match link.send(packet) {
+                        Ok(_) => {
+                            let expects_reply = match packet {
+                                LinkPacket::Normal(_) | LinkPacket::Multi(_) => true,
+                                _ => false,
+                            };
+                            if let Some(transfer) = &mut self.transfer {
+                                transfer.awaiting_reply |= expects_reply;
+                            }
+                        }
+                        Err(e) => warn!("link: failed to send {:?} ({})", packet, e),
+                    }
+                }
+                while let Some(packet) = link.recv() {
+                    self.on_packet(packet, link, irqs);
+                }
+            }
+            None => {
+                self.connected = false;
+                self.outbox.clear();
+            }
+        }
+
+        if let Some(mut transfer) = self.transfer {
+            let overtime = cycles.saturating_sub(transfer.cycles_left);
+            transfer.cycles_left = transfer.cycles_left.saturating_sub(cycles);
+            if transfer.cycles_left == 0 && transfer.awaiting_reply && transfer.reply.is_none() {
+                transfer.reply_timeout = transfer.reply_timeout.saturating_sub(overtime);
+                if transfer.reply_timeout == 0 {
+                    // the other side is gone, finish as if nothing was connected
+                    warn!("link: no reply from the other side, giving up on the transfer");
+                    transfer.awaiting_reply = false;
+                }
+            }
+            if transfer.cycles_left == 0 && !(transfer.awaiting_reply && transfer.reply.is_none()) {
+                self.complete_transfer(transfer, irqs);
+            } else {
+                self.transfer = Some(transfer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(sio: &mut SerialController, link: &mut InProcessLink, cycles: usize) -> IrqBitmask {
+        let mut irqs = IrqBitmask(0);
+        sio.update(cycles, &mut irqs, Some(link));
+        irqs
+    }
+
+    #[test]
+    fn normal32_transfer_over_in_process_link() {
+        let (mut link0, mut link1) = InProcessLink::pair();
+        let mut master = SerialController::new();
+        let mut slave = SerialController::new();
+
+        // slave: external clock, irq enabled, start
+        slave.handle_write(REG_SIOMULTI0, 0x5678);
+        slave.handle_write(REG_SIOMULTI1, 0x1234);
+        slave.handle_write(REG_SIOCNT, 0x5080);
+
+        // master: internal clock, irq enabled, start
+        master.handle_write(REG_SIOMULTI0, 0xbeef);
+        master.handle_write(REG_SIOMULTI1, 0xdead);
+        master.handle_write(REG_SIOCNT, 0x5081);
+
+        run(&mut master, &mut link0, 0);
+        let irqs = run(&mut slave, &mut link1, 0);
+        assert!(irqs.SerialCommunication());
+        assert_eq!(slave.data32(), 0xdeadbeef);
+        assert_eq!(slave.handle_read(REG_SIOCNT) & 0x80, 0);
+
+        let irqs = run(&mut master, &mut link0, 32 * 64);
+        assert!(irqs.SerialCommunication());
+        assert_eq!(master.data32(), 0x12345678);
+        assert_eq!(master.handle_read(REG_SIOCNT) & 0x80, 0);
+    }
+
+    #[test]
+    fn multiplayer_transfer_over_in_process_link() {
+        let (mut link0, mut link1) = InProcessLink::pair();
+        let mut parent = SerialController::new();
+        let mut child = SerialController::new();
+
+        for (sio, link, data) in &mut [
+            (&mut parent, &mut link0, 0x1111),
+            (&mut child, &mut link1, 0x2222),
+        ] {
+            sio.handle_write(REG_SIOCNT, 0x6000);
+            sio.handle_write(REG_SIOMLT_SEND, *data);
+            run(sio, link, 0);
+        }
+        assert_eq!(child.handle_read(REG_SIOCNT) & 0x34, 0x14);
+
+        parent.handle_write(REG_SIOCNT, 0x6080);
+        run(&mut parent, &mut link0, 0);
+        run(&mut child, &mut link1, 0);
+        let transfer_cycles = MULTI_TRANSFER_BITS * parent.baud_bit_cycles();
+        run(&mut parent, &mut link0, transfer_cycles);
+
+        for sio in &[&parent, &child] {
+            assert_eq!(sio.data, [0x1111, 0x2222, 0xffff, 0xffff]);
+        }
+    }
+
+    #[test]
+    fn uart_fifo_is_only_consumed_by_cpu_reads() {
+        let (mut link0, mut link1) = InProcessLink::pair();
+        let mut tx = SerialController::new();
+        let mut rx = SerialController::new();
+
+        // UART, 8 data bits, FIFO, send and receive enabled
+        tx.handle_write(REG_SIOCNT, 0x3d80);
+        rx.handle_write(REG_SIOCNT, 0x3d80);
+        tx.handle_write(REG_SIODATA8, 0x41);
+        run(&mut tx, &mut link0, 0);
+        run(&mut rx, &mut link1, 0);
+
+        // debugger style reads peek at the FIFO
+        assert_eq!(rx.handle_read(REG_SIODATA8), 0x41);
+        assert_eq!(rx.handle_read(REG_SIODATA8), 0x41);
+        assert_eq!(rx.handle_read(REG_SIOCNT) & 0x20, 0);
+
+        rx.on_data8_read();
+        assert_eq!(rx.handle_read(REG_SIOCNT) & 0x20, 0x20);
+        assert_eq!(rx.handle_read(REG_SIODATA8), 0);
+    }
+
+    #[test]
+    fn multiplayer_transfer_times_out_without_reply() {
+        // the other side never runs
+        let (mut link0, _link1) = InProcessLink::pair();
+        let mut parent = SerialController::new();
+        parent.handle_write(REG_SIOCNT, 0x6000);
+        parent.handle_write(REG_SIOMLT_SEND, 0x1111);
+        run(&mut parent, &mut link0, 0);
+
+        parent.handle_write(REG_SIOCNT, 0x6080);
+        run(&mut parent, &mut link0, 0);
+        let transfer_cycles = MULTI_TRANSFER_BITS * parent.baud_bit_cycles();
+        let irqs = run(&mut parent, &mut link0, transfer_cycles);
+        assert!(!irqs.SerialCommunication());
+        assert_eq!(parent.handle_read(REG_SIOCNT) & 0x80, 0x80);
+
+        let irqs = run(&mut parent, &mut link0, LINK_TIMEOUT_CYCLES);
+        assert!(irqs.SerialCommunication());
+        assert_eq!(parent.handle_read(REG_SIOCNT) & 0x80, 0);
+        assert_eq!(parent.data, [0x1111, 0xffff, 0xffff, 0xffff]);
+    }
+}
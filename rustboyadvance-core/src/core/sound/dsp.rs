@@ -1,18 +1,105 @@
-use crate::StereoSample;
+use std::collections::VecDeque;
 
-use serde::{Deserialize, Serialize};
+use crate::StereoSample;
 
 const PI: f32 = std::f32::consts::PI;
 
 pub trait Resampler {
     fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>);
+
+    /// Changes the rate of the samples fed, which follows the resolution picked in SOUNDBIAS
+    fn set_in_freq(&mut self, in_freq: f32);
+
+    /// Changes the rate of the samples output
+    fn set_out_freq(&mut self, out_freq: f32);
+}
+
+/// The resamplers `SoundController` can be built with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResamplerType {
+    /// Interpolates between consecutive samples, cheap but aliases
+    Cosine,
+    /// Windowed sinc low-pass filter, picked from a table of phases
+    Sinc,
+    /// Band-limited steps, treating the input as the staircase the DAC actually outputs
+    Blip,
+}
+
+impl Default for ResamplerType {
+    fn default() -> ResamplerType {
+        ResamplerType::Cosine
+    }
+}
+
+impl std::str::FromStr for ResamplerType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ResamplerType, String> {
+        match s {
+            "cosine" => Ok(ResamplerType::Cosine),
+            "sinc" => Ok(ResamplerType::Sinc),
+            "blip" => Ok(ResamplerType::Blip),
+            _ => Err(format!("unknown resampler {}", s)),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Clone, Debug)]
+pub enum AnyResampler {
+    Cosine(CosineResampler),
+    Sinc(SincResampler),
+    Blip(BlipResampler),
+}
+
+impl AnyResampler {
+    pub fn new(resampler_type: ResamplerType, in_freq: f32, out_freq: f32) -> AnyResampler {
+        match resampler_type {
+            ResamplerType::Cosine => AnyResampler::Cosine(CosineResampler::new(in_freq, out_freq)),
+            ResamplerType::Sinc => AnyResampler::Sinc(SincResampler::new(in_freq, out_freq)),
+            ResamplerType::Blip => AnyResampler::Blip(BlipResampler::new(in_freq, out_freq)),
+        }
+    }
+
+    pub fn resampler_type(&self) -> ResamplerType {
+        match self {
+            AnyResampler::Cosine(_) => ResamplerType::Cosine,
+            AnyResampler::Sinc(_) => ResamplerType::Sinc,
+            AnyResampler::Blip(_) => ResamplerType::Blip,
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Resampler {
+        match self {
+            AnyResampler::Cosine(r) => r,
+            AnyResampler::Sinc(r) => r,
+            AnyResampler::Blip(r) => r,
+        }
+    }
+}
+
+impl Resampler for AnyResampler {
+    fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>) {
+        match self {
+            AnyResampler::Cosine(r) => r.feed(s, output),
+            AnyResampler::Sinc(r) => r.feed(s, output),
+            AnyResampler::Blip(r) => r.feed(s, output),
+        }
+    }
+
+    fn set_in_freq(&mut self, in_freq: f32) {
+        self.inner().set_in_freq(in_freq)
+    }
+
+    fn set_out_freq(&mut self, out_freq: f32) {
+        self.inner().set_out_freq(out_freq)
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct CosineResampler {
     last_in_sample: StereoSample<f32>,
     phase: f32,
-    pub in_freq: f32,
+    in_freq: f32,
     out_freq: f32,
 }
 
@@ -32,6 +119,14 @@ impl Resampler for CosineResampler {
         self.phase = self.phase - 1.0;
         self.last_in_sample = s;
     }
+
+    fn set_in_freq(&mut self, in_freq: f32) {
+        self.in_freq = in_freq;
+    }
+
+    fn set_out_freq(&mut self, out_freq: f32) {
+        self.out_freq = out_freq;
+    }
 }
 
 impl CosineResampler {
@@ -44,3 +139,271 @@ impl CosineResampler {
         }
     }
 }
+
+/// Blackman window over [-1, 1]
+fn blackman(x: f32) -> f32 {
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+/// Low-pass impulse response at `t` samples from its center, `cutoff` being a fraction of the Nyquist
+/// frequency and `half_width` the number of samples it spans on each side
+fn windowed_sinc(t: f32, cutoff: f32, half_width: f32) -> f32 {
+    if t.abs() >= half_width {
+        return 0.0;
+    }
+    let x = PI * cutoff * t;
+    let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+    cutoff * sinc * blackman(t / half_width)
+}
+
+/// `phases + 1` rows of `taps` coefficients, row `p` being the kernel delayed by `p / phases` of a sample.
+/// Every row is scaled to a gain of 1 so a constant input stays constant.
+fn make_kernel_table(
+    phases: usize,
+    taps: usize,
+    center: f32,
+    cutoff: f32,
+    half_width: f32,
+) -> Vec<f32> {
+    let mut table = Vec::with_capacity((phases + 1) * taps);
+    for p in 0..=phases {
+        let delay = p as f32 / phases as f32;
+        let row: Vec<f32> = (0..taps)
+            .map(|k| windowed_sinc(k as f32 - center - delay, cutoff, half_width))
+            .collect();
+        let gain: f32 = row.iter().sum();
+        table.extend(row.iter().map(|h| h / gain));
+    }
+    table
+}
+
+/// Zero crossings of the sinc kept on each side of the center
+const SINC_ZERO_CROSSINGS: f32 = 8.0;
+const SINC_PHASES: usize = 256;
+/// Passband kept below the Nyquist frequency of the slower side, leaving room for the window to roll off
+const SINC_PASSBAND: f32 = 0.9;
+
+/// Polyphase windowed sinc resampler. Its cutoff is the Nyquist frequency of the slower of the two rates,
+/// so the input is band-limited before being decimated.
+#[derive(Clone, Debug)]
+pub struct SincResampler {
+    in_freq: f32,
+    out_freq: f32,
+    /// The cutoff the table was made for, kept while the rate control only nudges the ratio
+    cutoff: f32,
+    taps: usize,
+    table: Vec<f32>,
+    history: VecDeque<StereoSample<f32>>,
+    phase: f32,
+}
+
+impl SincResampler {
+    pub fn new(in_freq: f32, out_freq: f32) -> SincResampler {
+        let mut resampler = SincResampler {
+            in_freq: in_freq,
+            out_freq: out_freq,
+            cutoff: 0.0,
+            taps: 0,
+            table: Vec::new(),
+            history: VecDeque::new(),
+            phase: 0.0,
+        };
+        resampler.update_table();
+        resampler
+    }
+
+    fn update_table(&mut self) {
+        let cutoff = SINC_PASSBAND * (self.out_freq / self.in_freq).min(1.0);
+        if (cutoff - self.cutoff).abs() <= self.cutoff * 0.01 {
+            return;
+        }
+        let half_width = SINC_ZERO_CROSSINGS / cutoff;
+        let taps = 2 * half_width.ceil() as usize;
+        self.table =
+            make_kernel_table(SINC_PHASES, taps, (taps / 2 - 1) as f32, cutoff, half_width);
+        self.cutoff = cutoff;
+        self.taps = taps;
+        // keeps the newest samples, the filter fills up with silence when it grows
+        while self.history.len() > taps {
+            self.history.pop_front();
+        }
+        while self.history.len() < taps {
+            self.history.push_front((0.0, 0.0));
+        }
+    }
+}
+
+impl Resampler for SincResampler {
+    fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>) {
+        self.history.pop_front();
+        self.history.push_back(s);
+        while self.phase < 1.0 {
+            let p = (self.phase * SINC_PHASES as f32).round() as usize;
+            let row = &self.table[p * self.taps..(p + 1) * self.taps];
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for (h, x) in row.iter().zip(self.history.iter()) {
+                left += h * x.0;
+                right += h * x.1;
+            }
+            output.push((left, right));
+            self.phase += self.in_freq / self.out_freq;
+        }
+        self.phase -= 1.0;
+    }
+
+    fn set_in_freq(&mut self, in_freq: f32) {
+        self.in_freq = in_freq;
+        self.update_table();
+    }
+
+    fn set_out_freq(&mut self, out_freq: f32) {
+        self.out_freq = out_freq;
+        self.update_table();
+    }
+}
+
+/// Output samples each band-limited step spreads over
+const BLIP_TAPS: usize = 16;
+const BLIP_PHASES: usize = 64;
+
+/// Band-limited step synthesis, after blip_buf. Each change of the input level is added to the output
+/// as a step filtered to the output bandwidth, at its exact position in time, instead of interpolating.
+#[derive(Clone, Debug)]
+pub struct BlipResampler {
+    /// Output samples per input sample
+    step: f64,
+    /// Position of the next input sample in `deltas`, in output samples
+    time: f64,
+    last_in_sample: StereoSample<f32>,
+    /// Changes of level still to be summed into the output, one entry per output sample
+    deltas: VecDeque<StereoSample<f64>>,
+    level: StereoSample<f64>,
+    in_freq: f32,
+    out_freq: f32,
+    table: Vec<f32>,
+}
+
+impl BlipResampler {
+    pub fn new(in_freq: f32, out_freq: f32) -> BlipResampler {
+        let half_width = (BLIP_TAPS / 2) as f32;
+        BlipResampler {
+            step: out_freq as f64 / in_freq as f64,
+            time: 0.0,
+            last_in_sample: (0.0, 0.0),
+            deltas: VecDeque::new(),
+            level: (0.0, 0.0),
+            in_freq: in_freq,
+            out_freq: out_freq,
+            // the impulse is the derivative of the step, it is summed back up on the way out
+            table: make_kernel_table(
+                BLIP_PHASES,
+                BLIP_TAPS,
+                half_width - 1.0,
+                SINC_PASSBAND,
+                half_width,
+            ),
+        }
+    }
+}
+
+impl Resampler for BlipResampler {
+    fn feed(&mut self, s: StereoSample<f32>, output: &mut Vec<StereoSample<f32>>) {
+        let delta = (s.0 - self.last_in_sample.0, s.1 - self.last_in_sample.1);
+        self.last_in_sample = s;
+
+        let start = self.time.floor();
+        let p = ((self.time - start) * BLIP_PHASES as f64).round() as usize;
+        let start = start as usize;
+        if self.deltas.len() < start + BLIP_TAPS {
+            self.deltas.resize(start + BLIP_TAPS, (0.0, 0.0));
+        }
+        if delta != (0.0, 0.0) {
+            let row = &self.table[p * BLIP_TAPS..(p + 1) * BLIP_TAPS];
+            for (k, &h) in row.iter().enumerate() {
+                let d = &mut self.deltas[start + k];
+                d.0 += (h * delta.0) as f64;
+                d.1 += (h * delta.1) as f64;
+            }
+        }
+
+        self.time += self.step;
+        // later steps start from `time` on, everything before it is final
+        let ready = self.time.floor() as usize;
+        for _ in 0..ready {
+            let d = self.deltas.pop_front().unwrap_or((0.0, 0.0));
+            self.level.0 += d.0;
+            self.level.1 += d.1;
+            output.push((self.level.0 as f32, self.level.1 as f32));
+        }
+        self.time -= ready as f64;
+    }
+
+    fn set_in_freq(&mut self, in_freq: f32) {
+        self.in_freq = in_freq;
+        self.step = self.out_freq as f64 / in_freq as f64;
+    }
+
+    fn set_out_freq(&mut self, out_freq: f32) {
+        self.out_freq = out_freq;
+        self.step = out_freq as f64 / self.in_freq as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(resampler_type: ResamplerType, in_freq: f32, input: &[f32]) -> Vec<f32> {
+        let mut resampler = AnyResampler::new(resampler_type, in_freq, 44100.0);
+        let mut output = Vec::new();
+        for &x in input {
+            resampler.feed((x, -x), &mut output);
+        }
+        output.iter().map(|s| s.0).collect()
+    }
+
+    #[test]
+    fn test_resamplers_keep_the_rate_and_level() {
+        let input = vec![100.0; 32768];
+        for &resampler_type in &[
+            ResamplerType::Cosine,
+            ResamplerType::Sinc,
+            ResamplerType::Blip,
+        ] {
+            let output = run(resampler_type, 32768.0, &input);
+            assert!(
+                (output.len() as i32 - 44100).abs() <= 1,
+                "{:?} made {} samples",
+                resampler_type,
+                output.len()
+            );
+            // past the delay of the filters, the level comes through unchanged
+            for &y in &output[100..] {
+                assert!(
+                    (y - 100.0).abs() < 0.01,
+                    "{:?} output {}",
+                    resampler_type,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_band_limited_resamplers_alias_less() {
+        // a 30kHz tone at the highest SOUNDBIAS resolution is above the output Nyquist frequency,
+        // anything that comes out is aliasing
+        let in_freq = 262144.0;
+        let input: Vec<f32> = (0..262144)
+            .map(|i| 100.0 * (2.0 * PI * 30000.0 * i as f32 / in_freq).sin())
+            .collect();
+        let power = |resampler_type| {
+            let output = run(resampler_type, in_freq, &input);
+            output[100..].iter().map(|y| y * y).sum::<f32>() / output.len() as f32
+        };
+        let cosine = power(ResamplerType::Cosine);
+        assert!(power(ResamplerType::Sinc) < cosine / 100.0);
+        assert!(power(ResamplerType::Blip) < cosine / 10.0);
+    }
+}
@@ -1,11 +1,12 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use bit::BitIndex;
 use serde::{Deserialize, Serialize};
 
 use super::dma::DmaController;
 use super::iodev::consts::*;
+use super::iodev::io_reg_string;
+use super::sched::{EventType, Scheduler};
 
 use crate::{AudioInterface, StereoSample};
 
@@ -13,11 +14,16 @@ mod fifo;
 use fifo::SoundFifo;
 
 mod dsp;
-use dsp::{CosineResampler, Resampler};
+pub use dsp::ResamplerType;
+use dsp::{AnyResampler, Resampler};
+
+mod psg;
+use psg::Psg;
 
 const DMG_RATIOS: [f32; 4] = [0.25, 0.5, 1.0, 0.0];
+/// Bounds of `SoundController::set_rate_adjustment`
+const MAX_RATE_ADJUSTMENT: f32 = 0.05;
 const DMA_TIMERS: [usize; 2] = [0, 1];
-const DUTY_RATIOS: [f32; 4] = [0.125, 0.25, 0.5, 0.75];
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct DmaSoundChannel {
@@ -58,12 +64,10 @@ const REG_FIFO_A_H: u32 = REG_FIFO_A + 2;
 const REG_FIFO_B_L: u32 = REG_FIFO_B;
 const REG_FIFO_B_H: u32 = REG_FIFO_B + 2;
 
-type AudioDeviceRcRefCell = Rc<RefCell<dyn AudioInterface>>;
+const WAVE_RAM_END: u32 = REG_WAVE_RAM + 0xe;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct SoundController {
-    cycles: usize, // cycles count when we last provided a new sample.
-
     mse: bool,
 
     left_volume: usize,
@@ -80,14 +84,7 @@ pub struct SoundController {
 
     dmg_volume_ratio: f32,
 
-    sqr1_rate: usize,
-    sqr1_timed: bool,
-    sqr1_length: f32,
-    sqr1_duty: f32,
-    sqr1_step_time: usize,
-    sqr1_step_increase: bool,
-    sqr1_initial_vol: usize,
-    sqr1_cur_vol: usize,
+    psg: Psg,
 
     sound_bias: u16,
 
@@ -96,15 +93,56 @@ pub struct SoundController {
 
     dma_sound: [DmaSoundChannel; 2],
 
-    resampler: CosineResampler,
-    output_buffer: Vec<StereoSample<f32>>,
+    #[serde(skip)]
+    output: SoundOutput,
+}
+
+/// The host side of the sound, resampling to the rate of the audio device.
+/// It is left out of savestates, a restored `SoundController` is given the one in use with `set_output`.
+#[derive(Clone, Debug)]
+pub struct SoundOutput {
+    resampler: AnyResampler,
+    /// Rate of the audio device
+    sample_rate: f32,
+    rate_adjustment: f32,
+    resampled: Vec<StereoSample<f32>>,
+    /// The samples mixed since the last flush, see `flush_samples`
+    pending_samples: Vec<StereoSample<i16>>,
+}
+
+/// Samples kept before `needs_flush` asks for a flush, more than a frame holds at usual sample rates
+const PENDING_SAMPLES_LIMIT: usize = 1024;
+
+impl SoundOutput {
+    pub fn new(sample_rate: f32, resampler_type: ResamplerType) -> SoundOutput {
+        SoundOutput {
+            resampler: AnyResampler::new(resampler_type, 32768_f32, sample_rate),
+            sample_rate: sample_rate,
+            rate_adjustment: 0.0,
+            resampled: Vec::with_capacity(1024),
+            pending_samples: Vec::with_capacity(PENDING_SAMPLES_LIMIT),
+        }
+    }
+
+    fn out_freq(&self) -> f32 {
+        self.sample_rate * (1.0 + self.rate_adjustment)
+    }
+}
+
+impl Default for SoundOutput {
+    fn default() -> SoundOutput {
+        SoundOutput::new(44100_f32, ResamplerType::default())
+    }
 }
 
 impl SoundController {
-    pub fn new(audio_device_sample_rate: f32) -> SoundController {
-        let resampler = CosineResampler::new(32768_f32, audio_device_sample_rate);
+    pub fn new(
+        scheduler: &mut Scheduler,
+        audio_device_sample_rate: f32,
+        resampler_type: ResamplerType,
+    ) -> SoundController {
+        scheduler.schedule(EventType::SoundSample, 512);
         SoundController {
-            cycles: 0,
             mse: false,
             left_volume: 0,
             left_sqr1: false,
@@ -116,39 +154,62 @@ impl SoundController {
             right_sqr2: false,
             right_wave: false,
             right_noise: false,
-            dmg_volume_ratio: 0.0,
-            sqr1_rate: 0,
-            sqr1_timed: false,
-            sqr1_length: 0.0,
-            sqr1_duty: DUTY_RATIOS[0],
-            sqr1_step_time: 0,
-            sqr1_step_increase: false,
-            sqr1_initial_vol: 0,
-            sqr1_cur_vol: 0,
+            dmg_volume_ratio: DMG_RATIOS[0],
+            psg: Psg::new(),
             sound_bias: 0x200,
             sample_rate: 32_768f32,
             cycles_per_sample: 512,
             dma_sound: [Default::default(), Default::default()],
 
-            resampler: resampler,
-            output_buffer: Vec::with_capacity(1024),
+            output: SoundOutput::new(audio_device_sample_rate, resampler_type),
         }
     }
 
+    pub fn resampler_type(&self) -> ResamplerType {
+        self.output.resampler.resampler_type()
+    }
+
+    /// Switches to another resampler, what the current one still holds is dropped
+    pub fn set_resampler(&mut self, resampler_type: ResamplerType) {
+        self.output.resampler =
+            AnyResampler::new(resampler_type, self.sample_rate, self.output.out_freq());
+    }
+
+    /// Makes `1 + adjustment` times as many samples as the audio device plays, within +/-5%.
+    /// Frontends nudge it by a fraction of a percent to keep their buffer from running dry or overflowing.
+    pub fn set_rate_adjustment(&mut self, adjustment: f32) {
+        self.output.rate_adjustment = adjustment
+            .max(-MAX_RATE_ADJUSTMENT)
+            .min(MAX_RATE_ADJUSTMENT);
+        let out_freq = self.output.out_freq();
+        self.output.resampler.set_out_freq(out_freq);
+    }
+
+    /// Takes the output away, to give it to the controller of a restored state
+    pub(crate) fn take_output(&mut self) -> SoundOutput {
+        std::mem::replace(&mut self.output, SoundOutput::default())
+    }
+
+    pub(crate) fn set_output(&mut self, output: SoundOutput) {
+        self.output = output;
+        self.output.resampler.set_in_freq(self.sample_rate);
+    }
+
     pub fn handle_read(&self, io_addr: u32) -> u16 {
         let value = match io_addr {
-            REG_SOUNDCNT_X => cbit(7, self.mse),
+            REG_SOUND1CNT_L..=REG_SOUND4CNT_H => self.psg.read(io_addr),
+            REG_SOUNDCNT_X => cbit(7, self.mse) | self.psg.status(),
             REG_SOUNDCNT_L => {
-                self.left_volume as u16
-                    | (self.right_volume as u16) << 4
-                    | cbit(8, self.left_sqr1)
-                    | cbit(9, self.left_sqr2)
-                    | cbit(10, self.left_wave)
-                    | cbit(11, self.left_noise)
-                    | cbit(12, self.right_sqr1)
-                    | cbit(13, self.right_sqr2)
-                    | cbit(14, self.right_wave)
-                    | cbit(15, self.right_noise)
+                self.right_volume as u16
+                    | (self.left_volume as u16) << 4
+                    | cbit(8, self.right_sqr1)
+                    | cbit(9, self.right_sqr2)
+                    | cbit(10, self.right_wave)
+                    | cbit(11, self.right_noise)
+                    | cbit(12, self.left_sqr1)
+                    | cbit(13, self.left_sqr2)
+                    | cbit(14, self.left_wave)
+                    | cbit(15, self.left_noise)
             }
 
             REG_SOUNDCNT_H => {
@@ -167,6 +228,7 @@ impl SoundController {
             }
 
             REG_SOUNDBIAS => self.sound_bias,
+            REG_WAVE_RAM..=WAVE_RAM_END => self.psg.read_wave_ram(io_addr),
 
             _ => {
                 // println!(
@@ -197,6 +259,9 @@ impl SoundController {
                 if self.mse {
                     info!("MSE disabled!");
                     self.mse = false;
+                    // turning off the master enable clears all the PSG registers
+                    self.psg.reset();
+                    self.write_soundcnt_l(0);
                 }
             }
 
@@ -204,28 +269,20 @@ impl SoundController {
             return;
         }
 
-        // TODO - figure out which writes should be disabled when MSE is off
-        // if !self.mse {
-        //     warn!("MSE disabled, refusing to write");
-        //     return;
-        // }
-
         match io_addr {
-            REG_SOUNDCNT_L => {
-                self.left_volume = value.bit_range(0..2) as usize;
-                self.right_volume = value.bit_range(4..6) as usize;
-                self.left_sqr1 = value.bit(8);
-                self.left_sqr2 = value.bit(9);
-                self.left_wave = value.bit(10);
-                self.left_noise = value.bit(11);
-                self.right_sqr1 = value.bit(12);
-                self.right_sqr2 = value.bit(13);
-                self.right_wave = value.bit(14);
-                self.right_noise = value.bit(15);
+            REG_SOUND1CNT_L..=REG_SOUNDCNT_L if !self.mse => {
+                trace!(
+                    "MSE disabled, refusing to write to {}",
+                    io_reg_string(io_addr)
+                );
             }
 
+            REG_SOUND1CNT_L..=REG_SOUND4CNT_H => self.psg.write(io_addr, value),
+
+            REG_SOUNDCNT_L => self.write_soundcnt_l(value),
+
             REG_SOUNDCNT_H => {
-                self.dmg_volume_ratio = DMG_RATIOS[value.bit_range(0..1) as usize];
+                self.dmg_volume_ratio = DMG_RATIOS[value.bit_range(0..2) as usize];
                 self.dma_sound[0].volume_shift = value.bit(2) as i16;
                 self.dma_sound[1].volume_shift = value.bit(3) as i16;
                 self.dma_sound[0].enable_right = value.bit(8);
@@ -243,21 +300,7 @@ impl SoundController {
                 }
             }
 
-            REG_SOUND1CNT_H => {
-                self.sqr1_length = (64 - value.bit_range(0..5) as usize) as f32 / 256.0;
-                self.sqr1_duty = DUTY_RATIOS[value.bit_range(6..7) as usize];
-                self.sqr1_step_time = value.bit_range(8..10) as usize;
-                self.sqr1_step_increase = value.bit(11);
-                self.sqr1_initial_vol = value.bit_range(12..15) as usize;
-            }
-
-            REG_SOUND1CNT_X => {
-                self.sqr1_rate = value.bit_range(0..10) as usize;
-                self.sqr1_timed = value.bit(14);
-                if value.bit(15) {
-                    self.sqr1_cur_vol = self.sqr1_initial_vol;
-                }
-            }
+            REG_WAVE_RAM..=WAVE_RAM_END => self.psg.write_wave_ram(io_addr, value),
 
             REG_FIFO_A_L | REG_FIFO_A_H => {
                 self.dma_sound[0].fifo.write((value & 0xff) as i8);
@@ -272,9 +315,10 @@ impl SoundController {
             REG_SOUNDBIAS => {
                 self.sound_bias = value & 0xc3fe;
                 let resolution = self.sound_bias.bit_range(14..16) as usize;
-                self.sample_rate = (32768 << resolution) as f32;
-                if self.sample_rate != self.resampler.in_freq {
-                    self.resampler.in_freq = self.sample_rate;
+                let sample_rate = (32768 << resolution) as f32;
+                if sample_rate != self.sample_rate {
+                    self.sample_rate = sample_rate;
+                    self.output.resampler.set_in_freq(sample_rate);
                 }
                 self.cycles_per_sample = 512 >> resolution;
             }
@@ -289,6 +333,54 @@ impl SoundController {
         }
     }
 
+    /// 8bit writes can't be done through a plain read-modify-write, since many of the PSG
+    /// registers have write-only bits and trigger bits that must not be written twice.
+    pub fn handle_write_8(&mut self, io_addr: u32, value: u8) {
+        let aligned_addr = io_addr & !1;
+        let t = match aligned_addr {
+            REG_SOUND1CNT_L..=REG_SOUND4CNT_H => self.psg.latch(aligned_addr),
+            _ => self.handle_read(aligned_addr),
+        };
+        let t = if io_addr & 1 != 0 {
+            (t & 0xff) | (value as u16) << 8
+        } else {
+            (t & 0xff00) | (value as u16)
+        };
+        self.handle_write(aligned_addr, t);
+    }
+
+    /// The low bits of each half go to the right output, the high bits to the left one
+    fn write_soundcnt_l(&mut self, value: u16) {
+        self.right_volume = value.bit_range(0..3) as usize;
+        self.left_volume = value.bit_range(4..7) as usize;
+        self.right_sqr1 = value.bit(8);
+        self.right_sqr2 = value.bit(9);
+        self.right_wave = value.bit(10);
+        self.right_noise = value.bit(11);
+        self.left_sqr1 = value.bit(12);
+        self.left_sqr2 = value.bit(13);
+        self.left_wave = value.bit(14);
+        self.left_noise = value.bit(15);
+    }
+
+    fn psg_channels_enabled(&self, channel: usize) -> [bool; 4] {
+        match channel {
+            0 => [
+                self.left_sqr1,
+                self.left_sqr2,
+                self.left_wave,
+                self.left_noise,
+            ],
+            1 => [
+                self.right_sqr1,
+                self.right_sqr2,
+                self.right_wave,
+                self.right_noise,
+            ],
+            _ => unreachable!(),
+        }
+    }
+
     pub fn write_fifo(&mut self, id: usize, val: i8) {
         assert!(id == 0 || id == 1);
         self.dma_sound[id].fifo.write(val);
@@ -317,50 +409,83 @@ impl SoundController {
         }
     }
 
-    pub fn update(
-        &mut self,
-        cycles: usize,
-        cycles_to_next_event: &mut usize,
-        audio_device: &AudioDeviceRcRefCell,
-    ) {
-        self.cycles += cycles;
-        while self.cycles >= self.cycles_per_sample {
-            self.cycles -= self.cycles_per_sample;
-
-            // time to push a new sample!
-
-            let mut sample = [0f32; 2];
-
-            for channel in 0..=1 {
-                let mut dma_sample = 0;
-                for dma in &mut self.dma_sound {
-                    if dma.is_stereo_channel_enabled(channel) {
-                        let value = dma.value as i16;
-                        dma_sample += value * (2 << dma.volume_shift);
-                    }
+    /// Mixes a new sample, `extra_cycles` late. It is kept until the next `flush_samples`.
+    pub fn on_sample(&mut self, extra_cycles: usize, scheduler: &mut Scheduler) {
+        self.psg.step(self.cycles_per_sample);
+        let psg_output = self.psg.output();
+
+        let mut sample = [0f32; 2];
+
+        for channel in 0..=1 {
+            let mut psg_sample = 0;
+            for (&output, &enabled) in psg_output
+                .iter()
+                .zip(self.psg_channels_enabled(channel).iter())
+            {
+                if enabled {
+                    psg_sample += output;
+                }
+            }
+            let master_volume = match channel {
+                0 => self.left_volume,
+                _ => self.right_volume,
+            } as i16;
+            psg_sample *= 1 + master_volume;
+            let psg_sample = (psg_sample as f32 * self.dmg_volume_ratio) as i16;
+
+            let mut dma_sample = 0;
+            for dma in &mut self.dma_sound {
+                if dma.is_stereo_channel_enabled(channel) {
+                    let value = dma.value as i16;
+                    dma_sample += value * (2 << dma.volume_shift);
                 }
-
-                apply_bias(&mut dma_sample, self.sound_bias.bit_range(0..10) as i16);
-                sample[channel] = dma_sample as i32 as f32;
             }
 
-            let stereo_sample = (sample[0], sample[1]);
-            self.resampler.feed(stereo_sample, &mut self.output_buffer);
-
-            let mut audio = audio_device.borrow_mut();
-            self.output_buffer.drain(..).for_each(|(left, right)| {
-                audio.push_sample((
-                    (left.round() as i16) * (std::i16::MAX / 512),
-                    (right.round() as i16) * (std::i16::MAX / 512),
-                ));
-            });
+            let mut mixed_sample = psg_sample + dma_sample;
+            apply_bias(&mut mixed_sample, self.sound_bias.bit_range(0..10) as i16);
+            sample[channel] = mixed_sample as i32 as f32;
         }
-        if self.cycles_per_sample < *cycles_to_next_event {
-            *cycles_to_next_event = self.cycles_per_sample;
+
+        let stereo_sample = (sample[0], sample[1]);
+        let output = &mut self.output;
+        output.resampler.feed(stereo_sample, &mut output.resampled);
+
+        let pending_samples = &mut output.pending_samples;
+        output.resampled.drain(..).for_each(|(left, right)| {
+            pending_samples.push((to_i16(left), to_i16(right)));
+        });
+        // scheduled from when the sample was due, so missed samples are caught up on right away
+        let sample_time = scheduler.timestamp() - extra_cycles as u64;
+        scheduler.schedule_at(
+            EventType::SoundSample,
+            sample_time + self.cycles_per_sample as u64,
+        );
+    }
+
+    /// Whether enough samples piled up to flush them before the end of the frame,
+    /// for when the emulator is only driven by `step`
+    pub fn needs_flush(&self) -> bool {
+        self.output.pending_samples.len() >= PENDING_SAMPLES_LIMIT
+    }
+
+    /// Hands the samples mixed since the last call to `audio_device`, all at once
+    pub fn flush_samples<A: AudioInterface + ?Sized>(&mut self, audio_device: &Arc<Mutex<A>>) {
+        let pending_samples = &mut self.output.pending_samples;
+        if pending_samples.is_empty() {
+            return;
         }
+        audio_device.lock().unwrap().push_samples(pending_samples);
+        pending_samples.clear();
     }
 }
 
+/// Scales a resampled sample to 16 bits. The band-limited resamplers ring a little past the 10 bits of the mixer.
+#[inline(always)]
+fn to_i16(sample: f32) -> i16 {
+    let value = (sample.round() as i32) * (std::i16::MAX / 512) as i32;
+    value.max(std::i16::MIN as i32).min(std::i16::MAX as i32) as i16
+}
+
 #[inline(always)]
 fn apply_bias(sample: &mut i16, level: i16) {
     let mut s = *sample;
@@ -388,3 +513,41 @@ fn cbit(idx: u8, value: bool) -> u16 {
 fn bit(idx: u8) -> u16 {
     1 << idx
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soundcnt_h_keeps_the_psg_routing() {
+        let mut scheduler = Scheduler::new();
+        let mut sound = SoundController::new(&mut scheduler, 44100.0, ResamplerType::default());
+        sound.handle_write(REG_SOUNDCNT_X, 0x80);
+        sound.handle_write(REG_SOUNDCNT_L, 0xa573);
+        sound.handle_write(REG_SOUNDCNT_H, 0x7f0e);
+
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_L), 0xa573);
+        // bits 0-2 and 8-11 are the right output, bits 4-6 and 12-15 the left one
+        assert_eq!((sound.left_volume, sound.right_volume), (7, 3));
+        assert_eq!(sound.psg_channels_enabled(0), [false, true, false, true]);
+        assert_eq!(sound.psg_channels_enabled(1), [true, false, true, false]);
+        assert_eq!(sound.handle_read(REG_SOUNDCNT_H), 0x770e);
+    }
+
+    #[test]
+    fn test_late_sample_event_catches_up() {
+        let mut scheduler = Scheduler::new();
+        let mut sound = SoundController::new(&mut scheduler, 44100.0, ResamplerType::default());
+        // the first sample is due after 512 cycles, handle it more than three periods late
+        scheduler.update(512 + 3 * 512 + 10);
+
+        let mut samples = 0;
+        while let Some((event, extra_cycles)) = scheduler.pop_pending_event() {
+            assert_eq!(event, EventType::SoundSample);
+            sound.on_sample(extra_cycles, &mut scheduler);
+            samples += 1;
+        }
+        assert_eq!(samples, 4);
+        assert_eq!(scheduler.cycles_to_next_event(), 512 - 10);
+    }
+}
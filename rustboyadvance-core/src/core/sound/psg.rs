@@ -0,0 +1,704 @@
+//! The four legacy GameBoy "PSG" sound channels (square 1/2, wave and noise)
+use bit::BitIndex;
+use serde::{Deserialize, Serialize};
+
+use super::super::iodev::consts::*;
+
+/// The frame sequencer runs at 512hz
+const FRAME_SEQUENCER_PERIOD: usize = 32768;
+
+const DUTY_PATTERNS: [[bool; 8]; 4] = [
+    [false, false, false, false, false, false, false, true],
+    [true, false, false, false, false, false, false, true],
+    [true, false, false, false, false, true, true, true],
+    [false, true, true, true, true, true, true, false],
+];
+
+// Which bits can be read back from each PSG register, indexed by (addr - REG_SOUND1CNT_L) / 2
+const READ_MASKS: [u16; 16] = [
+    0x007f, 0xffc0, 0x4000, 0x0000, // SOUND1CNT_L, SOUND1CNT_H, SOUND1CNT_X, -
+    0xffc0, 0x0000, 0x4000, 0x0000, // SOUND2CNT_L, -, SOUND2CNT_H, -
+    0x00e0, 0xe000, 0x4000, 0x0000, // SOUND3CNT_L, SOUND3CNT_H, SOUND3CNT_X, -
+    0xff00, 0x0000, 0x40ff, 0x0000, // SOUND4CNT_L, -, SOUND4CNT_H, -
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: usize,
+}
+
+impl LengthCounter {
+    /// Returns false when the channel should be switched off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter != 0
+        } else {
+            true
+        }
+    }
+
+    fn trigger(&mut self, max: usize) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Envelope {
+    initial_volume: usize,
+    increase: bool,
+    period: usize,
+    timer: usize,
+    volume: usize,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u16) {
+        self.period = value.bit_range(0..3) as usize;
+        self.increase = value.bit(3);
+        self.initial_volume = value.bit_range(4..8) as usize;
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct Sweep {
+    shift: usize,
+    negate: bool,
+    period: usize,
+    timer: usize,
+    enabled: bool,
+    shadow_frequency: usize,
+}
+
+impl Sweep {
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Returns None on overflow
+    fn calculate(&self) -> Option<usize> {
+        let delta = self.shadow_frequency >> self.shift;
+        let new_frequency = if self.negate {
+            self.shadow_frequency.saturating_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        };
+        if new_frequency > 2047 {
+            None
+        } else {
+            Some(new_frequency)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct SquareChannel {
+    enabled: bool,
+    duty: usize,
+    duty_pos: usize,
+    frequency: usize,
+    timer: usize,
+    length: LengthCounter,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl SquareChannel {
+    fn with_sweep() -> SquareChannel {
+        SquareChannel {
+            sweep: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    fn period(&self) -> usize {
+        16 * (2048 - self.frequency)
+    }
+
+    fn write_sweep(&mut self, value: u16) {
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shift = value.bit_range(0..3) as usize;
+            sweep.negate = value.bit(3);
+            sweep.period = value.bit_range(4..7) as usize;
+        }
+    }
+
+    fn write_duty_length_envelope(&mut self, value: u16) {
+        self.length.counter = 64 - value.bit_range(0..6) as usize;
+        self.duty = value.bit_range(6..8) as usize;
+        self.envelope.write(value >> 8);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_frequency_control(&mut self, value: u16) {
+        self.frequency = value.bit_range(0..11) as usize;
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(64);
+        self.timer = self.period();
+        self.envelope.trigger();
+        let frequency = self.frequency;
+        let mut overflow = false;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 {
+                overflow = sweep.calculate().is_none();
+            }
+        }
+        if overflow {
+            self.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        match sweep.calculate() {
+            Some(new_frequency) => {
+                if sweep.shift != 0 {
+                    sweep.shadow_frequency = new_frequency;
+                    self.frequency = new_frequency;
+                    // the new frequency is checked again for overflow, but not written back
+                    if sweep.calculate().is_none() {
+                        self.enabled = false;
+                    }
+                }
+            }
+            None => self.enabled = false,
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn step(&mut self, mut cycles: usize) {
+        if !self.enabled {
+            return;
+        }
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if DUTY_PATTERNS[self.duty][self.duty_pos] {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    two_banks: bool,
+    bank: usize,
+    volume: usize,
+    force_volume: bool,
+    frequency: usize,
+    timer: usize,
+    position: usize,
+    length: LengthCounter,
+    wave_ram: [[u8; 16]; 2],
+}
+
+impl WaveChannel {
+    fn period(&self) -> usize {
+        8 * (2048 - self.frequency)
+    }
+
+    fn write_stop_select(&mut self, value: u16) {
+        self.two_banks = value.bit(5);
+        self.bank = value.bit(6) as usize;
+        self.dac_enabled = value.bit(7);
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    fn write_length_volume(&mut self, value: u16) {
+        self.length.counter = 256 - value.bit_range(0..8) as usize;
+        self.volume = value.bit_range(13..15) as usize;
+        self.force_volume = value.bit(15);
+    }
+
+    fn write_frequency_control(&mut self, value: u16) {
+        self.frequency = value.bit_range(0..11) as usize;
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(256);
+            self.timer = self.period();
+            self.position = 0;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// The CPU can only access the bank that is not selected for playback
+    fn read_ram(&self, offset: usize) -> u8 {
+        self.wave_ram[self.bank ^ 1][offset]
+    }
+
+    fn write_ram(&mut self, offset: usize, value: u8) {
+        self.wave_ram[self.bank ^ 1][offset] = value;
+    }
+
+    fn step(&mut self, mut cycles: usize) {
+        if !self.enabled {
+            return;
+        }
+        let num_samples = if self.two_banks { 64 } else { 32 };
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % num_samples;
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let index = (self.bank * 32 + self.position) % 64;
+        let byte = self.wave_ram[index / 32][(index % 32) / 2];
+        let digit = if index % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xf
+        };
+        let sample = (digit as i16) * 2 - 15;
+        if self.force_volume {
+            sample * 3 / 4
+        } else {
+            match self.volume {
+                0 => 0,
+                1 => sample,
+                2 => sample / 2,
+                3 => sample / 4,
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct NoiseChannel {
+    enabled: bool,
+    divider: usize,
+    width_7bit: bool,
+    shift: usize,
+    timer: usize,
+    lfsr: u16,
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn period(&self) -> usize {
+        if self.divider == 0 {
+            32 << self.shift
+        } else {
+            (64 * self.divider) << self.shift
+        }
+    }
+
+    fn write_length_envelope(&mut self, value: u16) {
+        self.length.counter = 64 - value.bit_range(0..6) as usize;
+        self.envelope.write(value >> 8);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_frequency_control(&mut self, value: u16) {
+        self.divider = value.bit_range(0..3) as usize;
+        self.width_7bit = value.bit(3);
+        self.shift = value.bit_range(4..8) as usize;
+        self.length.enabled = value.bit(14);
+        if value.bit(15) {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger(64);
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7fff;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn step(&mut self, mut cycles: usize) {
+        // shift clock frequencies 14 and 15 don't clock the lfsr at all
+        if !self.enabled || self.shift >= 14 {
+            return;
+        }
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.width_7bit {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    fn output(&self) -> i16 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i16;
+        if self.lfsr & 1 == 0 {
+            volume
+        } else {
+            -volume
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Psg {
+    sqr1: SquareChannel,
+    sqr2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    /// last written register values, write-only bits included, for 8bit read-modify-write
+    regs: [u16; 16],
+
+    frame_sequencer_cycles: usize,
+    frame_sequencer_step: usize,
+}
+
+impl Psg {
+    pub fn new() -> Psg {
+        Psg {
+            sqr1: SquareChannel::with_sweep(),
+            sqr2: SquareChannel::default(),
+            wave: WaveChannel::default(),
+            noise: NoiseChannel::default(),
+            regs: [0; 16],
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+        }
+    }
+
+    /// Clears all PSG registers, which is what happens when the master sound enable is cleared.
+    /// Wave RAM is left untouched.
+    pub fn reset(&mut self) {
+        let wave_ram = self.wave.wave_ram;
+        *self = Psg::new();
+        self.wave.wave_ram = wave_ram;
+    }
+
+    fn reg_index(io_addr: u32) -> usize {
+        ((io_addr - REG_SOUND1CNT_L) / 2) as usize
+    }
+
+    pub fn read(&self, io_addr: u32) -> u16 {
+        let index = Psg::reg_index(io_addr);
+        self.regs[index] & READ_MASKS[index]
+    }
+
+    /// Like `read`, but includes the write-only bits
+    pub fn latch(&self, io_addr: u32) -> u16 {
+        self.regs[Psg::reg_index(io_addr)]
+    }
+
+    pub fn write(&mut self, io_addr: u32, value: u16) {
+        // the trigger bit is a one-shot and should never be latched
+        self.regs[Psg::reg_index(io_addr)] = match io_addr {
+            REG_SOUND1CNT_X | REG_SOUND2CNT_H | REG_SOUND3CNT_X | REG_SOUND4CNT_H => value & 0x7fff,
+            _ => value,
+        };
+        match io_addr {
+            REG_SOUND1CNT_L => self.sqr1.write_sweep(value),
+            REG_SOUND1CNT_H => self.sqr1.write_duty_length_envelope(value),
+            REG_SOUND1CNT_X => self.sqr1.write_frequency_control(value),
+            REG_SOUND2CNT_L => self.sqr2.write_duty_length_envelope(value),
+            REG_SOUND2CNT_H => self.sqr2.write_frequency_control(value),
+            REG_SOUND3CNT_L => self.wave.write_stop_select(value),
+            REG_SOUND3CNT_H => self.wave.write_length_volume(value),
+            REG_SOUND3CNT_X => self.wave.write_frequency_control(value),
+            REG_SOUND4CNT_L => self.noise.write_length_envelope(value),
+            REG_SOUND4CNT_H => self.noise.write_frequency_control(value),
+            _ => {}
+        }
+    }
+
+    pub fn read_wave_ram(&self, io_addr: u32) -> u16 {
+        let offset = (io_addr - REG_WAVE_RAM) as usize & !1;
+        (self.wave.read_ram(offset) as u16) | (self.wave.read_ram(offset + 1) as u16) << 8
+    }
+
+    pub fn write_wave_ram(&mut self, io_addr: u32, value: u16) {
+        let offset = (io_addr - REG_WAVE_RAM) as usize & !1;
+        self.wave.write_ram(offset, value as u8);
+        self.wave.write_ram(offset + 1, (value >> 8) as u8);
+    }
+
+    /// Channel on/off flags as seen in the low nibble of SOUNDCNT_X
+    pub fn status(&self) -> u16 {
+        (self.sqr1.enabled as u16)
+            | (self.sqr2.enabled as u16) << 1
+            | (self.wave.enabled as u16) << 2
+            | (self.noise.enabled as u16) << 3
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step % 2 == 0 {
+            self.sqr1.clock_length();
+            self.sqr2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.sqr1.clock_sweep();
+        }
+        if step == 7 {
+            self.sqr1.envelope.clock();
+            self.sqr2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    pub fn step(&mut self, cycles: usize) {
+        self.frame_sequencer_cycles += cycles;
+        while self.frame_sequencer_cycles >= FRAME_SEQUENCER_PERIOD {
+            self.frame_sequencer_cycles -= FRAME_SEQUENCER_PERIOD;
+            self.clock_frame_sequencer();
+        }
+        self.sqr1.step(cycles);
+        self.sqr2.step(cycles);
+        self.wave.step(cycles);
+        self.noise.step(cycles);
+    }
+
+    /// Current output of each channel, in the range -15..=15
+    pub fn output(&self) -> [i16; 4] {
+        [
+            self.sqr1.output(),
+            self.sqr2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_disables_channel() {
+        let mut psg = Psg::new();
+        // length 63 leaves a single tick on the counter
+        psg.write(REG_SOUND1CNT_H, 0xf03f);
+        psg.write(REG_SOUND1CNT_X, 0xc000);
+        assert_eq!(psg.status(), 0b0001);
+
+        // the first frame sequencer step clocks the length counters
+        psg.step(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.status(), 0b0000);
+        assert_eq!(psg.output()[0], 0);
+    }
+
+    #[test]
+    fn test_length_counter_ignored_when_disabled() {
+        let mut psg = Psg::new();
+        psg.write(REG_SOUND2CNT_L, 0xf03f);
+        psg.write(REG_SOUND2CNT_H, 0x8000);
+        psg.step(8 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.status(), 0b0010);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut psg = Psg::new();
+        // initial volume 15, decreasing, one step per 64hz tick
+        psg.write(REG_SOUND2CNT_L, 0xf100);
+        psg.write(REG_SOUND2CNT_H, 0x8000);
+        assert_eq!(psg.sqr2.envelope.volume, 15);
+
+        // the envelope is clocked on step 7
+        psg.step(7 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr2.envelope.volume, 15);
+        psg.step(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr2.envelope.volume, 14);
+
+        for _ in 0..20 {
+            psg.step(8 * FRAME_SEQUENCER_PERIOD);
+        }
+        assert_eq!(psg.sqr2.envelope.volume, 0);
+        assert_eq!(psg.output()[1], 0);
+    }
+
+    #[test]
+    fn test_envelope_increase() {
+        let mut psg = Psg::new();
+        // initial volume 14, increasing
+        psg.write(REG_SOUND4CNT_L, 0xe900);
+        psg.write(REG_SOUND4CNT_H, 0x8000);
+        for _ in 0..4 {
+            psg.step(8 * FRAME_SEQUENCER_PERIOD);
+        }
+        assert_eq!(psg.noise.envelope.volume, 15);
+    }
+
+    #[test]
+    fn test_sweep_decrease() {
+        let mut psg = Psg::new();
+        // sweep period 1, subtract, shift 1
+        psg.write(REG_SOUND1CNT_L, 0x0019);
+        psg.write(REG_SOUND1CNT_H, 0xf000);
+        psg.write(REG_SOUND1CNT_X, 0x8400);
+
+        // the sweep unit is clocked on steps 2 and 6
+        psg.step(2 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr1.frequency, 0x400);
+        psg.step(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr1.frequency, 0x200);
+        psg.step(4 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr1.frequency, 0x100);
+        assert_eq!(psg.status(), 0b0001);
+    }
+
+    #[test]
+    fn test_sweep_overflow_disables_channel() {
+        let mut psg = Psg::new();
+        // sweep period 1, add, shift 1
+        psg.write(REG_SOUND1CNT_L, 0x0011);
+        psg.write(REG_SOUND1CNT_H, 0xf000);
+        psg.write(REG_SOUND1CNT_X, 0x8400);
+        assert_eq!(psg.status(), 0b0001);
+
+        // 0x400 + 0x200 fits, but the next step would overflow
+        psg.step(3 * FRAME_SEQUENCER_PERIOD);
+        assert_eq!(psg.sqr1.frequency, 0x600);
+        assert_eq!(psg.status(), 0b0000);
+    }
+
+    #[test]
+    fn test_sweep_overflow_on_trigger() {
+        let mut psg = Psg::new();
+        psg.write(REG_SOUND1CNT_L, 0x0011);
+        psg.write(REG_SOUND1CNT_H, 0xf000);
+        psg.write(REG_SOUND1CNT_X, 0x8000 | 0x7ff);
+        assert_eq!(psg.status(), 0b0000);
+    }
+
+    fn noise_lfsr_after_trigger(psg: &mut Psg, control: u16, shifts: usize) -> u16 {
+        psg.write(REG_SOUND4CNT_L, 0xf000);
+        psg.write(REG_SOUND4CNT_H, 0x8000 | control);
+        let period = psg.noise.period();
+        psg.step(period * shifts);
+        psg.noise.lfsr
+    }
+
+    #[test]
+    fn test_noise_lfsr_15bit() {
+        let mut psg = Psg::new();
+        assert_eq!(noise_lfsr_after_trigger(&mut psg, 0, 0), 0x7fff);
+        assert_eq!(noise_lfsr_after_trigger(&mut psg, 0, 1), 0x3fff);
+        // bit 0 xor bit 1 is shifted into bit 14
+        assert_eq!(noise_lfsr_after_trigger(&mut psg, 0, 15), 0x4000);
+        assert_eq!(noise_lfsr_after_trigger(&mut psg, 0, 32767), 0x7fff);
+    }
+
+    #[test]
+    fn test_noise_lfsr_7bit() {
+        let mut psg = Psg::new();
+        // in 7bit mode the feedback also goes into bit 6
+        assert_eq!(noise_lfsr_after_trigger(&mut psg, 0x8, 1), 0x3fbf);
+        let start = noise_lfsr_after_trigger(&mut psg, 0x8, 20) & 0x7f;
+        assert_eq!(
+            noise_lfsr_after_trigger(&mut psg, 0x8, 20 + 127) & 0x7f,
+            start
+        );
+        assert_ne!(
+            noise_lfsr_after_trigger(&mut psg, 0x8, 20 + 63) & 0x7f,
+            start
+        );
+    }
+
+    #[test]
+    fn test_noise_output_follows_lfsr() {
+        let mut psg = Psg::new();
+        noise_lfsr_after_trigger(&mut psg, 0, 0);
+        assert_eq!(psg.output()[3], -15);
+        noise_lfsr_after_trigger(&mut psg, 0, 15);
+        assert_eq!(psg.output()[3], 15);
+    }
+}
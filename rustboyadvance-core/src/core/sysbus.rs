@@ -1,10 +1,10 @@
 use std::fmt;
-use std::ops::{Deref, DerefMut};
 
 use serde::{Deserialize, Serialize};
 
-use super::cartridge::Cartridge;
+use super::cartridge::{rom_checksum, Cartridge};
 use super::gpu::VIDEO_RAM_SIZE;
+use super::iodev::consts::{IO_BASE, REG_WAITCNT};
 use super::iodev::{IoDevices, WaitControl};
 use super::{Addr, Bus};
 
@@ -70,7 +70,7 @@ pub enum MemoryAccessWidth {
     MemoryAccess32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[repr(transparent)]
 pub struct BoxedMemory {
     pub mem: Box<[u8]>,
@@ -105,8 +105,88 @@ impl Bus for DummyBus {
     fn write_8(&mut self, _addr: Addr, _value: u8) {}
 }
 
+/// The value left on the data bus, returned for the bytes nothing else drives
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct OpenBus(u32);
+
+impl Bus for OpenBus {
+    fn read_32(&self, _addr: Addr) -> u32 {
+        self.0
+    }
+
+    fn read_16(&self, addr: Addr) -> u16 {
+        (self.0 >> ((addr & 2) * 8)) as u16
+    }
+
+    fn read_8(&self, addr: Addr) -> u8 {
+        (self.0 >> ((addr & 3) * 8)) as u8
+    }
+
+    fn write_8(&mut self, _addr: Addr, _value: u8) {}
+}
+
+/// What the BIOS leaves on the bus when it jumps to the cartridge after boot (`msr cpsr_fc, r11` at 0xDC)
+const BIOS_OPCODE_AFTER_BOOT: u32 = 0xE129_F000;
+
 const CYCLE_LUT_SIZE: usize = 0x10;
 
+const PREFETCH_BUFFER_SIZE: usize = 8;
+
+/// The Game Pak prefetch buffer.
+///
+/// While enabled in WAITCNT, the cartridge keeps reading the halfwords that follow the last opcode fetch
+/// whenever the cpu isn't using the gamepak bus, so sequential opcode fetches take a single cycle.
+/// The buffer is filled lazily, from the cycles that passed since the last gamepak access.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct PrefetchBuffer {
+    active: bool,
+    /// Number of halfwords ready in the buffer
+    count: usize,
+    /// Cycle at which the halfword currently being read will be ready, on the 64-bit clock of the cpu
+    next_ready: u64,
+}
+
+impl PrefetchBuffer {
+    fn fill(&mut self, now: u64, s_cycles: usize) {
+        while self.count < PREFETCH_BUFFER_SIZE && self.next_ready <= now {
+            self.count += 1;
+            self.next_ready += s_cycles as u64;
+        }
+    }
+
+    /// Returns how many cycles it takes to fetch `halfwords` from the buffer, starting at cycle `now`
+    fn fetch(&mut self, now: u64, halfwords: usize, s_cycles: usize) -> usize {
+        self.fill(now, s_cycles);
+        let s_cycles = s_cycles as u64;
+        let mut cycles = 0;
+        for _ in 0..halfwords {
+            if self.count == PREFETCH_BUFFER_SIZE {
+                // the buffer was full and stalled, it resumes reading as soon as a slot frees up
+                self.next_ready = now + cycles + s_cycles;
+            } else if self.count == 0 {
+                // wait for the halfword being read
+                cycles += self.next_ready.saturating_sub(now + cycles);
+                self.count = 1;
+                self.next_ready = now + cycles + s_cycles;
+            }
+            self.count -= 1;
+            cycles += 1;
+        }
+        cycles as usize
+    }
+
+    fn restart(&mut self, ready: u64) {
+        self.active = true;
+        self.count = 0;
+        self.next_ready = ready;
+    }
+
+    fn stop(&mut self) {
+        self.active = false;
+        self.count = 0;
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 struct CycleLookupTables {
     n_cycles32: [usize; CYCLE_LUT_SIZE],
@@ -203,48 +283,32 @@ impl CycleLookupTables {
 pub struct SysBus {
     pub io: IoDevices,
 
+    /// Left out of savestates like the cartridge ROM, see `attach_bios`
+    #[serde(skip)]
     bios: BoxedMemory,
     onboard_work_ram: BoxedMemory,
     internal_work_ram: BoxedMemory,
     pub cartridge: Cartridge,
     dummy: DummyBus,
 
+    /// Last opcode prefetched by the cpu
+    open_bus: OpenBus,
+    /// Last opcode fetched from the BIOS, which is what BIOS reads return while executing outside of it
+    bios_open_bus: OpenBus,
+    bios_readable: bool,
+
     cycle_luts: CycleLookupTables,
+    prefetch_buffer: PrefetchBuffer,
+    /// Can be turned off to compare against the plain waitstates, WAITCNT still has the final say.
+    /// A frontend setting, so it is left out of savestates.
+    #[serde(skip, default = "default_prefetch_emulation")]
+    pub prefetch_emulation: bool,
 
     pub trace_access: bool,
 }
 
-#[repr(transparent)]
-#[derive(Clone)]
-pub struct SysBusPtr {
-    ptr: *mut SysBus,
-}
-
-impl Default for SysBusPtr {
-    fn default() -> SysBusPtr {
-        SysBusPtr {
-            ptr: std::ptr::null_mut::<SysBus>(),
-        }
-    }
-}
-
-impl SysBusPtr {
-    pub fn new(ptr: *mut SysBus) -> SysBusPtr {
-        SysBusPtr { ptr: ptr }
-    }
-}
-
-impl Deref for SysBusPtr {
-    type Target = SysBus;
-    fn deref(&self) -> &Self::Target {
-        unsafe { &*self.ptr }
-    }
-}
-
-impl DerefMut for SysBusPtr {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        unsafe { &mut *self.ptr }
-    }
+fn default_prefetch_emulation() -> bool {
+    true
 }
 
 macro_rules! memory_map {
@@ -252,7 +316,9 @@ macro_rules! memory_map {
         match $addr & 0xff000000 {
             BIOS_ADDR => {
                 if $addr >= 0x4000 {
-                    0
+                    $sb.open_bus.$read_fn($addr)
+                } else if !$sb.bios_readable {
+                    $sb.bios_open_bus.$read_fn($addr)
                 } else {
                     $sb.bios.$read_fn($addr)
                 }
@@ -283,8 +349,7 @@ macro_rules! memory_map {
             SRAM_LO | SRAM_HI => $sb.cartridge.$read_fn($addr),
             _ => {
                 // warn!("trying to read invalid address {:#x}", $addr);
-                // TODO open bus
-                0
+                $sb.open_bus.$read_fn($addr)
             }
         }
     };
@@ -299,7 +364,11 @@ macro_rules! memory_map {
                 } else {
                     $addr & 0x7ff
                 };
-                $sb.io.$write_fn(addr, $value)
+                $sb.io.$write_fn(addr, $value);
+                // the waitstates live on the bus, WAITCNT itself is kept with the other registers
+                if addr & !3 == REG_WAITCNT - IO_BASE {
+                    $sb.on_waitcnt_written($sb.io.waitcnt);
+                }
             }
             PALRAM_ADDR => $sb.io.gpu.palette_ram.$write_fn($addr & 0x3ff, $value),
             VRAM_ADDR => {
@@ -310,7 +379,8 @@ macro_rules! memory_map {
                 $sb.io.gpu.vram.$write_fn(ofs, $value)
             }
             OAM_ADDR => $sb.io.gpu.oam.$write_fn($addr & 0x3ff, $value),
-            GAMEPAK_WS0_LO | GAMEPAK_WS0_HI => {}
+            GAMEPAK_WS0_LO => $sb.cartridge.$write_fn($addr, $value),
+            GAMEPAK_WS0_HI => {}
             GAMEPAK_WS2_HI => $sb.cartridge.$write_fn($addr, $value),
             SRAM_LO | SRAM_HI => $sb.cartridge.$write_fn($addr, $value),
             _ => {
@@ -336,21 +406,132 @@ impl SysBus {
             cartridge: cartridge,
             dummy: DummyBus([0; 4]),
 
+            open_bus: OpenBus(0),
+            bios_open_bus: OpenBus(BIOS_OPCODE_AFTER_BOOT),
+            bios_readable: true,
+
             cycle_luts: luts,
+            prefetch_buffer: PrefetchBuffer::default(),
+            prefetch_emulation: default_prefetch_emulation(),
 
             trace_access: false,
         }
     }
 
-    /// must be called whenever this object is instanciated
-    pub fn created(&mut self) {
-        let ptr = SysBusPtr::new(self as *mut SysBus);
-        // HACK
-        self.io.set_sysbus_ptr(ptr.clone());
+    pub(crate) fn take_bios(&mut self) -> Box<[u8]> {
+        std::mem::replace(&mut self.bios.mem, Box::default())
+    }
+
+    /// Puts back the BIOS image of a bus restored from a savestate
+    pub(crate) fn attach_bios(&mut self, bios_rom: Box<[u8]>) {
+        self.bios = BoxedMemory::new(bios_rom);
+    }
+
+    /// CRC32 of the BIOS image
+    pub(crate) fn bios_checksum(&self) -> u32 {
+        rom_checksum(&self.bios.mem)
+    }
+
+    /// Feeds both work RAMs to `hasher`
+    pub(crate) fn hash_work_ram(&self, hasher: &mut crc32fast::Hasher) {
+        hasher.update(&self.onboard_work_ram.mem);
+        hasher.update(&self.internal_work_ram.mem);
     }
 
     pub fn on_waitcnt_written(&mut self, waitcnt: WaitControl) {
         self.cycle_luts.update_gamepak_waitstates(waitcnt);
+        if !waitcnt.prefetch() {
+            self.prefetch_buffer.stop();
+        }
+    }
+
+    #[inline(always)]
+    fn is_gamepak_rom(addr: Addr) -> bool {
+        let page = (addr >> 24) as usize;
+        page >= PAGE_GAMEPAK_WS0 && page < PAGE_SRAM_LO
+    }
+
+    /// Cycles taken by an opcode fetch starting at cycle `now`.
+    /// Sequential fetches from the gamepak are served by the prefetch buffer when it is enabled.
+    #[inline(always)]
+    pub fn get_fetch_cycles(
+        &mut self,
+        now: u64,
+        addr: Addr,
+        access: MemoryAccessType,
+        width: MemoryAccessWidth,
+    ) -> usize {
+        let cycles = self.get_cycles(addr, access, width);
+        if !SysBus::is_gamepak_rom(addr) || !self.prefetch_emulation || !self.io.waitcnt.prefetch()
+        {
+            return cycles;
+        }
+        let page = (addr >> 24) as usize;
+        let s_cycles = self.cycle_luts.s_cycles16[page];
+        match access {
+            MemoryAccessType::Seq if self.prefetch_buffer.active => {
+                let halfwords = if width == MemoryAccessWidth::MemoryAccess32 {
+                    2
+                } else {
+                    1
+                };
+                self.prefetch_buffer.fetch(now, halfwords, s_cycles)
+            }
+            _ => {
+                // the prefetcher starts over from the address that follows
+                self.prefetch_buffer
+                    .restart(now + cycles as u64 + s_cycles as u64);
+                cycles
+            }
+        }
+    }
+
+    /// Cycles taken by a data access. Data accesses to the gamepak interrupt the prefetcher.
+    #[inline(always)]
+    pub fn get_data_cycles(
+        &mut self,
+        addr: Addr,
+        access: MemoryAccessType,
+        width: MemoryAccessWidth,
+    ) -> usize {
+        if SysBus::is_gamepak_rom(addr) {
+            self.prefetch_buffer.stop();
+        }
+        self.get_cycles(addr, access, width)
+    }
+
+    /// Must be called by the cpu before fetching opcodes from `pc`, as the BIOS is only readable while executing from it
+    #[inline(always)]
+    pub fn set_fetch_addr(&mut self, pc: Addr) {
+        self.bios_readable = pc < 0x4000;
+    }
+
+    /// Called by the cpu with the value its last prefetch left on the bus
+    #[inline(always)]
+    pub fn set_open_bus(&mut self, value: u32) {
+        self.open_bus.0 = value;
+        if self.bios_readable {
+            self.bios_open_bus.0 = value;
+        }
+    }
+
+    /// For the HLE BIOS, which doesn't fetch its opcodes through the bus
+    pub(crate) fn set_bios_open_bus(&mut self, value: u32) {
+        self.bios_open_bus.0 = value;
+    }
+
+    /// Side effects of data reads done by the cpu. Plain `Bus` reads have none, as they are also
+    /// used by the debugger and memory viewers to peek at memory.
+    #[inline(always)]
+    pub fn on_cpu_read(&mut self, addr: Addr, width: MemoryAccessWidth) {
+        if addr & 0xff000000 == IOMEM_ADDR && addr & 0xffff < 0x800 {
+            let len = match width {
+                MemoryAccessWidth::MemoryAccess8 => 1,
+                MemoryAccessWidth::MemoryAccess16 => 2,
+                MemoryAccessWidth::MemoryAccess32 => 4,
+            };
+            self.io.on_cpu_read(addr & 0x7ff, len);
+        }
     }
 
     #[inline(always)]
@@ -407,3 +588,175 @@ impl Bus for SysBus {
         memory_map!(write(self, write_8, addr, value));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cartridge::GamepakBuilder;
+    use crate::core::gpu::Gpu;
+    use crate::core::sched::Scheduler;
+    use crate::core::sound::{ResamplerType, SoundController};
+
+    use MemoryAccessType::*;
+    use MemoryAccessWidth::*;
+
+    fn make_sysbus() -> Box<SysBus> {
+        let cartridge = GamepakBuilder::new()
+            .buffer(&[0; 0x200])
+            .with_sram()
+            .without_backup_to_file()
+            .build()
+            .unwrap();
+        let mut scheduler = Scheduler::new();
+        let gpu = Box::new(Gpu::new(&mut scheduler));
+        let sound_controller = Box::new(SoundController::new(
+            &mut scheduler,
+            44100.0,
+            ResamplerType::default(),
+        ));
+        let io = IoDevices::new(scheduler, gpu, sound_controller);
+        let mut bios = vec![0; 0x4000];
+        bios[0..4].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+        Box::new(SysBus::new(io, bios.into_boxed_slice(), cartridge))
+    }
+
+    const ROM: Addr = GAMEPAK_WS0_LO;
+    /// WS0 with WAITCNT at 0, 4 waitstates for the first access and 2 for the sequential ones
+    const N_CYCLES: usize = 5;
+    const S_CYCLES: usize = 3;
+
+    fn enable_prefetch(sb: &mut SysBus) {
+        sb.write_16(REG_WAITCNT, 1 << 14);
+    }
+
+    #[test]
+    fn test_fetch_without_prefetch() {
+        let mut sb = make_sysbus();
+        assert_eq!(
+            sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess16),
+            N_CYCLES
+        );
+        assert_eq!(
+            sb.get_fetch_cycles(100, ROM + 2, Seq, MemoryAccess16),
+            S_CYCLES
+        );
+        assert_eq!(
+            sb.get_fetch_cycles(200, ROM + 4, Seq, MemoryAccess32),
+            2 * S_CYCLES
+        );
+    }
+
+    #[test]
+    fn test_prefetch_buffer_fills_while_idle() {
+        let mut sb = make_sysbus();
+        enable_prefetch(&mut sb);
+        assert_eq!(
+            sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess16),
+            N_CYCLES
+        );
+
+        // long enough for the 8 halfwords to be prefetched
+        let mut now = 100;
+        for i in 0..PREFETCH_BUFFER_SIZE {
+            let cycles = sb.get_fetch_cycles(now, ROM + 2 * (i as u32 + 1), Seq, MemoryAccess16);
+            assert_eq!(cycles, 1);
+            now += cycles as u64;
+        }
+        // the cartridge can't keep up with single cycle fetches, so the buffer eventually runs dry
+        let mut addr = ROM + 18;
+        let stall = (0..32)
+            .map(|_| {
+                let cycles = sb.get_fetch_cycles(now, addr, Seq, MemoryAccess16);
+                now += cycles as u64;
+                addr += 2;
+                cycles
+            })
+            .find(|&cycles| cycles > 1);
+        assert!(stall.map_or(false, |cycles| cycles <= S_CYCLES + 1));
+    }
+
+    #[test]
+    fn test_prefetch_buffer_32bit_fetch() {
+        let mut sb = make_sysbus();
+        enable_prefetch(&mut sb);
+        sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess32);
+        assert_eq!(sb.get_fetch_cycles(100, ROM + 4, Seq, MemoryAccess32), 2);
+    }
+
+    #[test]
+    fn test_prefetch_buffer_past_32bit_cycles() {
+        let mut sb = make_sysbus();
+        enable_prefetch(&mut sb);
+        let now = u32::max_value() as u64 - 2;
+        sb.get_fetch_cycles(now, ROM, NonSeq, MemoryAccess16);
+        assert_eq!(
+            sb.get_fetch_cycles(now + 100, ROM + 2, Seq, MemoryAccess16),
+            1
+        );
+    }
+
+    #[test]
+    fn test_prefetch_buffer_stopped_by_data_access() {
+        let mut sb = make_sysbus();
+        enable_prefetch(&mut sb);
+        sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess16);
+        sb.get_data_cycles(ROM + 0x100, NonSeq, MemoryAccess16);
+        assert_eq!(
+            sb.get_fetch_cycles(100, ROM + 2, Seq, MemoryAccess16),
+            S_CYCLES
+        );
+    }
+
+    #[test]
+    fn test_prefetch_emulation_off() {
+        let mut sb = make_sysbus();
+        enable_prefetch(&mut sb);
+        sb.prefetch_emulation = false;
+        sb.get_fetch_cycles(0, ROM, NonSeq, MemoryAccess16);
+        assert_eq!(
+            sb.get_fetch_cycles(100, ROM + 2, Seq, MemoryAccess16),
+            S_CYCLES
+        );
+    }
+
+    #[test]
+    fn test_prefetch_emulation_not_in_savestate() {
+        let mut sb = make_sysbus();
+        sb.prefetch_emulation = false;
+        let restored: SysBus = bincode::deserialize(&bincode::serialize(&*sb).unwrap()).unwrap();
+        assert!(restored.prefetch_emulation);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut sb = make_sysbus();
+        sb.set_fetch_addr(ROM);
+        sb.set_open_bus(0x1234_5678);
+        assert_eq!(sb.read_32(0x1000_0000), 0x1234_5678);
+        assert_eq!(sb.read_16(0x1000_0002), 0x1234);
+        assert_eq!(sb.read_8(0x1000_0001), 0x56);
+        // past the end of the BIOS
+        assert_eq!(sb.read_32(0x0000_4000), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_bios_read_protection() {
+        let mut sb = make_sysbus();
+        sb.set_fetch_addr(0);
+        assert_eq!(sb.read_32(0), 0xdead_beef);
+        sb.set_open_bus(0xe3a0_0001);
+
+        // from outside the BIOS, reads return the last opcode fetched from it
+        sb.set_fetch_addr(ROM);
+        sb.set_open_bus(0x1234_5678);
+        assert_eq!(sb.read_32(0), 0xe3a0_0001);
+        assert_eq!(sb.read_16(0x100), 0x0001);
+    }
+
+    #[test]
+    fn test_bios_open_bus_after_boot() {
+        let mut sb = make_sysbus();
+        sb.set_fetch_addr(ROM);
+        assert_eq!(sb.read_32(0), BIOS_OPCODE_AFTER_BOOT);
+    }
+}
@@ -1,6 +1,8 @@
+use super::dma::DmaController;
 use super::interrupt::{Interrupt, IrqBitmask};
 use super::iodev::consts::*;
-use super::sysbus::SysBus;
+use super::sched::{EventType, Scheduler};
+use super::sound::SoundController;
 
 use num::FromPrimitive;
 use serde::{Deserialize, Serialize};
@@ -16,8 +18,9 @@ pub struct Timer {
 
     irq: Interrupt,
     timer_id: usize,
-    cycles: usize,
     prescalar_shift: usize,
+    /// While running, the counter is `data` plus the ticks elapsed since this timestamp
+    start_time: u64,
 }
 
 impl Timer {
@@ -31,8 +34,8 @@ impl Timer {
             data: 0,
             ctl: TimerCtl(0),
             initial_data: 0,
-            cycles: 0,
             prescalar_shift: 0,
+            start_time: 0,
         }
     }
 
@@ -41,31 +44,23 @@ impl Timer {
         0x1_0000 - (self.data as u32)
     }
 
-    /// increments the timer with an amount of ticks
-    /// returns the number of times it overflowed
-    fn update(&mut self, ticks: usize, irqs: &mut IrqBitmask) -> usize {
-        let mut ticks = ticks as u32;
-        let mut num_overflows = 0;
-
-        let ticks_remaining = self.ticks_to_overflow();
-
-        if ticks >= ticks_remaining {
-            num_overflows += 1;
-            ticks -= ticks_remaining;
-            self.data = self.initial_data;
-
-            let ticks_remaining = self.ticks_to_overflow();
-            num_overflows += ticks / ticks_remaining;
-            ticks = ticks % ticks_remaining;
-
-            if self.ctl.irq_enabled() {
-                irqs.add_irq(self.irq);
-            }
-        }
+    #[inline]
+    fn cycles_to_overflow(&self) -> usize {
+        (self.ticks_to_overflow() as usize) << self.prescalar_shift
+    }
 
-        self.data += ticks as u16;
+    /// The counter value at `now`, for a timer that is running
+    #[inline]
+    fn counter_at(&self, now: u64) -> u16 {
+        let ticks = (now - self.start_time) >> self.prescalar_shift;
+        self.data.wrapping_add(ticks as u16)
+    }
 
-        num_overflows as usize
+    /// Brings `data` up to date, for a timer that is running
+    fn sync(&mut self, now: u64) {
+        let ticks = (now - self.start_time) >> self.prescalar_shift;
+        self.data = self.data.wrapping_add(ticks as u16);
+        self.start_time += ticks << self.prescalar_shift;
     }
 }
 
@@ -98,15 +93,30 @@ impl Timers {
         }
     }
 
-    pub fn write_timer_ctl(&mut self, id: usize, value: u16) {
+    fn is_running(&self, id: usize) -> bool {
+        self.running_timers & (1 << id) != 0
+    }
+
+    pub fn write_timer_ctl(&mut self, id: usize, value: u16, scheduler: &mut Scheduler) {
+        let now = scheduler.timestamp();
+        if self.is_running(id) {
+            self.timers[id].sync(now);
+            scheduler.cancel(EventType::TimerOverflow(id));
+        }
+
         let new_ctl = TimerCtl(value);
         let old_enabled = self[id].ctl.enabled();
         let new_enabled = new_ctl.enabled();
         let cascade = new_ctl.cascade();
-        self[id].cycles = 0;
-        self[id].prescalar_shift = SHIFT_LUT[new_ctl.prescalar() as usize];
-        self[id].ctl = new_ctl;
+        let timer = &mut self.timers[id];
+        timer.prescalar_shift = SHIFT_LUT[new_ctl.prescalar() as usize];
+        timer.ctl = new_ctl;
+        if new_enabled && !old_enabled {
+            timer.data = timer.initial_data;
+        }
         if new_enabled && !cascade {
+            timer.start_time = now;
+            scheduler.schedule(EventType::TimerOverflow(id), timer.cycles_to_overflow());
             self.running_timers |= 1 << id;
         } else {
             self.running_timers &= !(1 << id);
@@ -120,78 +130,88 @@ impl Timers {
         }
     }
 
-    pub fn handle_read(&self, io_addr: u32) -> u16 {
+    fn read_counter(&self, id: usize, now: u64) -> u16 {
+        if self.is_running(id) {
+            self.timers[id].counter_at(now)
+        } else {
+            self.timers[id].data
+        }
+    }
+
+    pub fn handle_read(&self, io_addr: u32, now: u64) -> u16 {
         match io_addr {
-            REG_TM0CNT_L => self.timers[0].data,
+            REG_TM0CNT_L => self.read_counter(0, now),
             REG_TM0CNT_H => self.timers[0].ctl.0,
-            REG_TM1CNT_L => self.timers[1].data,
+            REG_TM1CNT_L => self.read_counter(1, now),
             REG_TM1CNT_H => self.timers[1].ctl.0,
-            REG_TM2CNT_L => self.timers[2].data,
+            REG_TM2CNT_L => self.read_counter(2, now),
             REG_TM2CNT_H => self.timers[2].ctl.0,
-            REG_TM3CNT_L => self.timers[3].data,
+            REG_TM3CNT_L => self.read_counter(3, now),
             REG_TM3CNT_H => self.timers[3].ctl.0,
             _ => unreachable!(),
         }
     }
 
-    pub fn handle_write(&mut self, io_addr: u32, value: u16) {
+    /// Writes to TMxCNT_L only set the reload value, which is loaded when the timer starts or overflows
+    pub fn handle_write(&mut self, io_addr: u32, value: u16, scheduler: &mut Scheduler) {
         match io_addr {
-            REG_TM0CNT_L => {
-                self.timers[0].data = value;
-                self.timers[0].initial_data = value;
-            }
-            REG_TM0CNT_H => self.write_timer_ctl(0, value),
-
-            REG_TM1CNT_L => {
-                self.timers[1].data = value;
-                self.timers[1].initial_data = value;
-            }
-            REG_TM1CNT_H => self.write_timer_ctl(1, value),
-
-            REG_TM2CNT_L => {
-                self.timers[2].data = value;
-                self.timers[2].initial_data = value;
-            }
-            REG_TM2CNT_H => self.write_timer_ctl(2, value),
-
-            REG_TM3CNT_L => {
-                self.timers[3].data = value;
-                self.timers[3].initial_data = value;
-            }
-            REG_TM3CNT_H => self.write_timer_ctl(3, value),
+            REG_TM0CNT_L => self.timers[0].initial_data = value,
+            REG_TM0CNT_H => self.write_timer_ctl(0, value, scheduler),
+            REG_TM1CNT_L => self.timers[1].initial_data = value,
+            REG_TM1CNT_H => self.write_timer_ctl(1, value, scheduler),
+            REG_TM2CNT_L => self.timers[2].initial_data = value,
+            REG_TM2CNT_H => self.write_timer_ctl(2, value, scheduler),
+            REG_TM3CNT_L => self.timers[3].initial_data = value,
+            REG_TM3CNT_H => self.write_timer_ctl(3, value, scheduler),
             _ => unreachable!(),
         }
     }
 
-    pub fn update(&mut self, cycles: usize, sb: &mut SysBus, irqs: &mut IrqBitmask) {
-        for id in 0..4 {
-            if self.running_timers & (1 << id) == 0 {
-                continue;
-            }
+    /// Reloads a running timer whose overflow event is due, `extra_cycles` late
+    pub fn handle_overflow_event(
+        &mut self,
+        id: usize,
+        extra_cycles: usize,
+        sound: &mut SoundController,
+        dmac: &mut DmaController,
+        scheduler: &mut Scheduler,
+        irqs: &mut IrqBitmask,
+    ) {
+        let timer = &mut self.timers[id];
+        timer.data = timer.initial_data;
+        timer.start_time = scheduler.timestamp() - extra_cycles as u64;
+        // scheduled from when the overflow happened, it might already be due again with short periods
+        scheduler.schedule_at(
+            EventType::TimerOverflow(id),
+            timer.start_time + timer.cycles_to_overflow() as u64,
+        );
+        self.handle_overflow(id, sound, dmac, irqs);
+    }
 
-            if !self.timers[id].ctl.cascade() {
-                let timer = &mut self.timers[id];
-
-                let cycles = timer.cycles + cycles;
-                let inc = cycles >> timer.prescalar_shift;
-                let num_overflows = timer.update(inc, irqs);
-                timer.cycles = cycles & ((1 << timer.prescalar_shift) - 1);
-
-                if num_overflows > 0 {
-                    if id != 3 {
-                        let next_timer = &mut self.timers[id + 1];
-                        if next_timer.ctl.cascade() {
-                            next_timer.update(num_overflows, irqs);
-                        }
-                    }
-                    if id == 0 || id == 1 {
-                        sb.io
-                            .sound
-                            .handle_timer_overflow(&mut sb.io.dmac, id, num_overflows);
-                    }
+    fn handle_overflow(
+        &mut self,
+        id: usize,
+        sound: &mut SoundController,
+        dmac: &mut DmaController,
+        irqs: &mut IrqBitmask,
+    ) {
+        if self.timers[id].ctl.irq_enabled() {
+            irqs.add_irq(self.timers[id].irq);
+        }
+        if id != 3 {
+            let next_timer = &mut self.timers[id + 1];
+            if next_timer.ctl.enabled() && next_timer.ctl.cascade() {
+                if next_timer.data == 0xffff {
+                    next_timer.data = next_timer.initial_data;
+                    self.handle_overflow(id + 1, sound, dmac, irqs);
+                } else {
+                    next_timer.data += 1;
                 }
             }
         }
+        if id == 0 || id == 1 {
+            sound.handle_timer_overflow(dmac, id, 1);
+        }
     }
 }
 
@@ -1,7 +1,5 @@
 use super::core::arm7tdmi::CpuState;
 use super::core::interrupt::*;
-use super::core::iodev::IoDevices;
-use super::core::sysbus::SysBus;
 use super::core::Bus;
 use super::core::GameBoyAdvance;
 
@@ -18,52 +16,20 @@ impl Target for GameBoyAdvance {
         &mut self,
         mut _log_mem_access: impl FnMut(Access<u32>),
     ) -> Result<TargetState, Self::Error> {
-        static mut S_TOTAL_CYCLES: usize = 0;
-
-        let io = unsafe {
-            let ptr = &mut *self.sysbus as *mut SysBus;
-            &mut (*ptr).io as &mut IoDevices
-        };
-
         // clear any pending DMAs
-        let mut irqs = IrqBitmask(0);
-        while io.dmac.is_active() {
-            io.dmac.perform_work(&mut self.sysbus, &mut irqs);
+        while self.sysbus.io.dmac.is_active() {
+            self.step_dma();
         }
-        io.intc.request_irqs(irqs);
 
         // run the CPU, ignore haltcnt
-        let cycles = self.step_cpu(io);
-        io.timers.update(cycles, &mut self.sysbus, &mut irqs);
-
-        unsafe {
-            S_TOTAL_CYCLES += cycles;
-        }
-
-        if self.cycles_to_next_event <= unsafe { S_TOTAL_CYCLES } {
-            let mut cycles_to_next_event = std::usize::MAX;
-            io.gpu.update(
-                unsafe { S_TOTAL_CYCLES },
-                &mut self.sysbus,
-                &mut irqs,
-                &mut cycles_to_next_event,
-                &self.video_device,
-            );
-            io.sound.update(
-                unsafe { S_TOTAL_CYCLES },
-                &mut cycles_to_next_event,
-                &self.audio_device,
-            );
-            self.cycles_to_next_event = cycles_to_next_event;
+        let cycles = self.step_cpu();
+        self.sysbus.io.scheduler.update(cycles);
 
-            unsafe {
-                S_TOTAL_CYCLES = 0;
-            };
-        } else {
-            self.cycles_to_next_event -= unsafe { S_TOTAL_CYCLES };
-        }
-
-        io.intc.request_irqs(irqs);
+        let mut irqs = IrqBitmask(0);
+        self.handle_events(&mut irqs);
+        let io = &mut self.sysbus.io;
+        io.intc.request_irqs(irqs, &mut io.scheduler);
+        self.flush_audio();
 
         Ok(TargetState::Running)
     }
@@ -31,14 +31,16 @@ pub mod gdb;
 #[cfg(feature = "debugger")]
 pub mod debugger;
 
-pub trait VideoInterface {
+/// The devices are shared between the emulator and the frontend as `Arc<Mutex<_>>`,
+/// they have to be `Send` so the emulator can run on a thread of its own.
+pub trait VideoInterface: Send {
     #[allow(unused_variables)]
     fn render(&mut self, buffer: &[u32]) {}
 }
 
 pub type StereoSample<T> = (T, T);
 
-pub trait AudioInterface {
+pub trait AudioInterface: Send {
     fn get_sample_rate(&self) -> i32 {
         44100
     }
@@ -48,22 +50,54 @@ pub trait AudioInterface {
     /// Note: It is not guarentied that the sample will be played
     #[allow(unused_variables)]
     fn push_sample(&mut self, samples: StereoSample<i16>) {}
+
+    /// Pushes the samples mixed during a frame, oldest first. This is what the emulator calls, once per frame.
+    /// A device that cannot keep them all drops some, it must neither block nor panic.
+    /// By default they are passed on to `push_sample` one at a time.
+    fn push_samples(&mut self, samples: &[StereoSample<i16>]) {
+        for &sample in samples {
+            self.push_sample(sample);
+        }
+    }
 }
 
-pub trait InputInterface {
+pub trait InputInterface: Send {
     fn poll(&mut self) -> u16 {
         core::keypad::KEYINPUT_ALL_RELEASED
     }
 }
 
+/// Host side of the sensors and motors some cartridges come with
+pub trait SensorInterface: Send {
+    /// Light hitting the solar sensor, from 0 (darkness) to 255 (direct sunlight)
+    fn read_light_level(&mut self) -> u8 {
+        0
+    }
+
+    /// Tilt of the console on the (x, y) axes, 0 being level
+    fn read_tilt(&mut self) -> (i16, i16) {
+        (0, 0)
+    }
+
+    /// Rotation speed around the axis going through the screen, 0 when still
+    fn read_gyro(&mut self) -> i16 {
+        0
+    }
+
+    /// Called when the cartridge turns its rumble motor on or off
+    #[allow(unused_variables)]
+    fn set_rumble(&mut self, enabled: bool) {}
+}
+
 pub mod prelude {
     pub use super::core::arm7tdmi;
     pub use super::core::cartridge::{Cartridge, GamepakBuilder};
     pub use super::core::gpu::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+    pub use super::core::sound::ResamplerType;
     pub use super::core::Bus;
-    pub use super::core::{GBAError, GBAResult, GameBoyAdvance};
+    pub use super::core::{GBAError, GBAResult, GameBoyAdvance, GenericGameBoyAdvance};
     #[cfg(feature = "debugger")]
     pub use super::debugger::Debugger;
     pub use super::util::{read_bin_file, write_bin_file};
-    pub use super::{AudioInterface, InputInterface, VideoInterface};
+    pub use super::{AudioInterface, InputInterface, SensorInterface, VideoInterface};
 }
@@ -0,0 +1,204 @@
+//! Runs the test roms listed in `screenshots/manifest.txt`, and compares the last frame against a reference image.
+//! Mismatching frames are written to `target/screenshots`, along with an image highlighting the differing pixels.
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rustboyadvance_core::prelude::*;
+
+struct DummyInterface {}
+
+impl VideoInterface for DummyInterface {}
+impl AudioInterface for DummyInterface {}
+impl InputInterface for DummyInterface {}
+
+struct TestRom {
+    path: PathBuf,
+    frames: usize,
+}
+
+enum Outcome {
+    Pass,
+    Skipped(String),
+    Blessed,
+    Fail(String),
+}
+
+fn repo_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("..")
+}
+
+fn screenshots_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/screenshots")
+}
+
+fn output_dir() -> PathBuf {
+    repo_root().join("target/screenshots")
+}
+
+fn parse_manifest(text: &str) -> Vec<TestRom> {
+    text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut tokens = line.split_whitespace();
+            let path = tokens.next().unwrap();
+            let frames = tokens
+                .next()
+                .and_then(|frames| frames.parse().ok())
+                .unwrap_or_else(|| panic!("manifest line without a frame count: {}", line));
+            TestRom {
+                path: repo_root().join(path),
+                frames: frames,
+            }
+        })
+        .collect()
+}
+
+/// Goes around the output capture of the test harness, so it shows even when the test passes
+fn warn_loudly(message: &str) {
+    let _ = writeln!(io::stderr(), "WARNING: {}", message);
+}
+
+/// Frame buffer pixels are 0x00RRGGBB
+fn to_rgb(frame_buffer: &[u32]) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(frame_buffer.len() * 3);
+    for pixel in frame_buffer {
+        rgb.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+    }
+    rgb
+}
+
+fn read_png(path: &Path) -> Result<Vec<u8>, String> {
+    let decoder = png::Decoder::new(File::open(path).map_err(|e| e.to_string())?);
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    if info.width as usize != DISPLAY_WIDTH
+        || info.height as usize != DISPLAY_HEIGHT
+        || info.color_type != png::ColorType::RGB
+        || info.bit_depth != png::BitDepth::Eight
+    {
+        return Err("not a 240x160 8-bit RGB image".to_string());
+    }
+    let mut rgb = vec![0; info.buffer_size()];
+    reader.next_frame(&mut rgb).map_err(|e| e.to_string())?;
+    Ok(rgb)
+}
+
+fn write_png(path: &Path, rgb: &[u8]) {
+    let file = File::create(path).unwrap();
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        DISPLAY_WIDTH as u32,
+        DISPLAY_HEIGHT as u32,
+    );
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(rgb).unwrap();
+}
+
+/// Differing pixels are red, the matching ones are a dimmed gray version of the reference
+fn diff_image(actual: &[u8], reference: &[u8]) -> (Vec<u8>, usize) {
+    let mut diff = Vec::with_capacity(actual.len());
+    let mut count = 0;
+    for (a, r) in actual.chunks(3).zip(reference.chunks(3)) {
+        if a == r {
+            let gray = ((r[0] as u32 + r[1] as u32 + r[2] as u32) / 12) as u8;
+            diff.extend_from_slice(&[gray, gray, gray]);
+        } else {
+            diff.extend_from_slice(&[0xff, 0, 0]);
+            count += 1;
+        }
+    }
+    (diff, count)
+}
+
+fn run_rom(rom: &TestRom) -> Vec<u8> {
+    let cartridge = GamepakBuilder::new()
+        .file(&rom.path)
+        .without_backup_to_file()
+        .build()
+        .unwrap();
+    let dummy = Arc::new(Mutex::new(DummyInterface {}));
+    let mut gba =
+        GameBoyAdvance::new_with_hle_bios(cartridge, dummy.clone(), dummy.clone(), dummy.clone());
+    for _ in 0..rom.frames {
+        gba.frame();
+    }
+    to_rgb(gba.get_frame_buffer())
+}
+
+fn check_rom(rom: &TestRom, name: &str, bless: bool) -> Outcome {
+    let rom_dir = rom.path.parent().unwrap();
+    if !rom_dir.is_dir() {
+        return Outcome::Skipped(format!("{} is missing", rom_dir.display()));
+    }
+    if !rom.path.is_file() {
+        return Outcome::Fail(format!("{} not found", rom.path.display()));
+    }
+    let actual = run_rom(rom);
+    let reference_path = screenshots_dir().join(format!("{}.png", name));
+    if bless {
+        write_png(&reference_path, &actual);
+        return Outcome::Blessed;
+    }
+    if !reference_path.is_file() {
+        return Outcome::Fail(format!(
+            "no reference image {}, create it with RBA_BLESS=1",
+            reference_path.display()
+        ));
+    }
+    let reference = match read_png(&reference_path) {
+        Ok(reference) => reference,
+        Err(e) => return Outcome::Fail(format!("bad reference image: {}", e)),
+    };
+
+    let (diff, count) = diff_image(&actual, &reference);
+    if count == 0 {
+        return Outcome::Pass;
+    }
+    fs::create_dir_all(output_dir()).unwrap();
+    let actual_path = output_dir().join(format!("{}.actual.png", name));
+    let diff_path = output_dir().join(format!("{}.diff.png", name));
+    write_png(&actual_path, &actual);
+    write_png(&diff_path, &diff);
+    Outcome::Fail(format!(
+        "{} pixels differ, see {}",
+        count,
+        diff_path.display()
+    ))
+}
+
+#[test]
+fn test_rom_screenshots() {
+    let manifest = fs::read_to_string(screenshots_dir().join("manifest.txt")).unwrap();
+    let bless = env::var_os("RBA_BLESS").is_some();
+
+    let mut failures = Vec::new();
+    let mut skipped = Vec::new();
+    for rom in parse_manifest(&manifest) {
+        let name = rom.path.file_stem().unwrap().to_str().unwrap().to_string();
+        match check_rom(&rom, &name, bless) {
+            Outcome::Pass => println!("{}: pass", name),
+            Outcome::Skipped(reason) => {
+                warn_loudly(&format!("{}: SKIPPED, {}", name, reason));
+                skipped.push(name);
+            }
+            Outcome::Blessed => println!("{}: reference image updated", name),
+            Outcome::Fail(reason) => {
+                println!("{}: FAIL ({})", name, reason);
+                failures.push(name);
+            }
+        }
+    }
+    if !skipped.is_empty() {
+        warn_loudly(&format!(
+            "{} of the screenshot roms were not checked: {:?}",
+            skipped.len(),
+            skipped
+        ));
+    }
+    assert!(failures.is_empty(), "screenshots differ: {:?}", failures);
+}
@@ -0,0 +1,17 @@
+@ Source of gradient.gba, hand assembled and padded with zeros to 0x200 bytes.
+@ Fills the mode 3 frame buffer with each pixel's index as its color, then loops forever.
+
+    mov     r0, #0x04000000
+    mov     r1, #0x400
+    orr     r1, r1, #3          @ mode 3, BG2 on
+    strh    r1, [r0]
+    mov     r0, #0x06000000
+    mov     r2, #0
+    mov     r3, #0x9600         @ 240 * 160 pixels
+loop:
+    strh    r2, [r0], #2
+    add     r2, r2, #1
+    cmp     r2, r3
+    blt     loop
+done:
+    b       done
@@ -0,0 +1,95 @@
+@ Source of layers.gba, built with
+@
+@   llvm-mc -triple=armv4t-none-eabi -filetype=obj layers.s -o layers.o
+@   llvm-objcopy -O binary layers.o layers.gba
+@
+@ and padded with zeros to 0x200 bytes. Draws two text backgrounds and two sprites in mode 0: BG0 is a red
+@ checkerboard, BG1 green stripes alpha blended over BG0 and the backdrop, with a semi-transparent sprite on top
+@ of them and an opaque one. Then loops forever.
+
+    b       start
+    .space  0xbc                @ cartridge header, left blank
+start:
+    @ palettes: backdrop blue, BG red and green, OBJ yellow and cyan
+    mov     r0, #0x05000000
+    ldr     r1, =0x00007c00 | (0x001f << 16)
+    str     r1, [r0]
+    ldr     r1, =0x03e0
+    strh    r1, [r0, #4]
+    add     r0, r0, #0x200
+    ldr     r1, =0x03ff << 16
+    str     r1, [r0]
+    ldr     r1, =0x7fe0
+    strh    r1, [r0, #4]
+
+    @ BG tile 1 is solid color 1, BG tile 2 has a stripe of color 2 on every other row
+    ldr     r0, =0x06000020
+    ldr     r1, =0x11111111
+    mov     r2, #8
+bg_tile1:
+    str     r1, [r0], #4
+    subs    r2, r2, #1
+    bne     bg_tile1
+    ldr     r1, =0x22222222
+    mov     r3, #0
+    mov     r2, #4
+bg_tile2:
+    str     r1, [r0], #4
+    str     r3, [r0], #4
+    subs    r2, r2, #1
+    bne     bg_tile2
+
+    @ BG0 map at screen block 8 is a checkerboard of tiles 1 and 0, BG1 map at screen block 9 is all tile 2
+    ldr     r0, =0x06004000
+    add     r4, r0, #0x800
+    mov     r2, #0
+    mov     r5, #2
+bg_maps:
+    eor     r1, r2, r2, lsr #5
+    and     r1, r1, #1
+    strh    r1, [r0], #2
+    strh    r5, [r4], #2
+    add     r2, r2, #1
+    cmp     r2, #0x400
+    blt     bg_maps
+
+    @ OBJ tiles 0-15 have vertical stripes of colors 1 and 2
+    ldr     r0, =0x06010000
+    ldr     r1, =0x21212121
+    mov     r2, #128
+obj_tiles:
+    str     r1, [r0], #4
+    subs    r2, r2, #1
+    bne     obj_tiles
+
+    @ hide all sprites, then show a semi-transparent 32x32 one and an opaque 16x16 one
+    mov     r0, #0x07000000
+    mov     r1, #0x200
+    mov     r2, #128
+obj_hide:
+    strh    r1, [r0], #8
+    subs    r2, r2, #1
+    bne     obj_hide
+    mov     r0, #0x07000000
+    ldr     r1, =(40 | 0x400) | ((0x8000 | 48) << 16)
+    str     r1, [r0]
+    ldr     r1, =96 | ((0x4000 | 140) << 16)
+    str     r1, [r0, #8]
+
+    @ BG0 and BG1 from char block 0, BG1 in front and scrolled 3 lines up
+    mov     r0, #0x04000000
+    ldr     r1, =0x0801 | (0x0900 << 16)
+    str     r1, [r0, #8]
+    mov     r1, #3
+    strh    r1, [r0, #0x16]
+
+    @ blend BG1 over BG0 and the backdrop at 10/16 and 6/16
+    ldr     r1, =0x2142 | (0x060a << 16)
+    str     r1, [r0, #0x50]
+
+    @ mode 0, BG0, BG1 and OBJ on, 1d OBJ mapping
+    ldr     r1, =0x1340
+    strh    r1, [r0]
+done:
+    b       done
+    .ltorg